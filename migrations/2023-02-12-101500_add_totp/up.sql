@@ -0,0 +1,14 @@
+ALTER TABLE users
+    ADD COLUMN totp_secret         VARCHAR(64) DEFAULT NULL,  -- base32 encoded shared secret of the authenticator
+    ADD COLUMN totp_enabled        BOOLEAN     NOT NULL DEFAULT false,
+    ADD COLUMN totp_last_used_step BIGINT      DEFAULT NULL;  -- prevents the reuse of an already accepted code
+
+CREATE TABLE IF NOT EXISTS totp_recovery_codes
+(
+    id         serial PRIMARY KEY,
+    user_id    int         NOT NULL,
+    code_hash  varchar(64) NOT NULL, -- SHA-256 of the normalized recovery code
+    used_at    timestamptz          DEFAULT NULL,
+    created_at timestamptz NOT NULL DEFAULT NOW(),
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
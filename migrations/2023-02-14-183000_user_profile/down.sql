@@ -0,0 +1,12 @@
+ALTER TABLE personal_access_tokens
+    DROP CONSTRAINT personal_access_tokens_user_id_fkey,
+    ADD CONSTRAINT personal_access_tokens_user_id_fkey FOREIGN KEY (user_id) REFERENCES users (id);
+
+ALTER TABLE tasks
+    DROP CONSTRAINT tasks_owner_fkey,
+    ADD CONSTRAINT tasks_owner_fkey FOREIGN KEY (owner) REFERENCES users (id);
+
+ALTER TABLE users
+    DROP COLUMN pending_email,
+    DROP COLUMN email_verification_token,
+    DROP COLUMN email_verification_expires_at;
@@ -0,0 +1,13 @@
+ALTER TABLE users
+    ADD COLUMN pending_email                 VARCHAR(255) DEFAULT NULL, -- new address which still has to be verified
+    ADD COLUMN email_verification_token      VARCHAR(64)  DEFAULT NULL, -- SHA-256 of the verification token
+    ADD COLUMN email_verification_expires_at TIMESTAMPTZ  DEFAULT NULL;
+
+-- ensure that deleting a user also deletes everything which belongs to the user
+ALTER TABLE tasks
+    DROP CONSTRAINT tasks_owner_fkey,
+    ADD CONSTRAINT tasks_owner_fkey FOREIGN KEY (owner) REFERENCES users (id) ON DELETE CASCADE;
+
+ALTER TABLE personal_access_tokens
+    DROP CONSTRAINT personal_access_tokens_user_id_fkey,
+    ADD CONSTRAINT personal_access_tokens_user_id_fkey FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE;
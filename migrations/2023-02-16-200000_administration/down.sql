@@ -0,0 +1,5 @@
+ALTER TABLE users
+    DROP COLUMN is_admin,
+    DROP COLUMN disabled,
+    DROP COLUMN password_change_required,
+    DROP COLUMN tokens_revoked_at;
@@ -0,0 +1,10 @@
+ALTER TABLE users
+    ADD COLUMN is_admin                 BOOLEAN     NOT NULL DEFAULT false,
+    ADD COLUMN disabled                 BOOLEAN     NOT NULL DEFAULT false,
+    ADD COLUMN password_change_required BOOLEAN     NOT NULL DEFAULT false, -- set if an administrator reset the password
+    ADD COLUMN tokens_revoked_at        TIMESTAMPTZ DEFAULT NULL;           -- access tokens issued before are not valid
+
+-- the first user of an existing installation becomes the administrator
+UPDATE users
+SET is_admin = true
+WHERE id = (SELECT min(id) FROM users);
@@ -0,0 +1,5 @@
+ALTER TABLE users
+    DROP COLUMN invitation_code_id,
+    DROP COLUMN invited_by;
+
+DROP TABLE IF EXISTS invitation_codes;
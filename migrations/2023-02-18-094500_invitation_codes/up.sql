@@ -0,0 +1,18 @@
+CREATE TABLE IF NOT EXISTS invitation_codes
+(
+    id         serial PRIMARY KEY,
+    code_hash  varchar(64) NOT NULL UNIQUE, -- SHA-256 of the normalized invitation code
+    created_by int         NOT NULL,
+    max_uses   int         NOT NULL DEFAULT 1,
+    uses       int         NOT NULL DEFAULT 0,
+    expires_at timestamptz NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT NOW(),
+    FOREIGN KEY (created_by) REFERENCES users (id) ON DELETE CASCADE,
+    CHECK (uses <= max_uses)
+);
+
+ALTER TABLE users
+    ADD COLUMN invited_by         int DEFAULT NULL, -- the user who created the used invitation code
+    ADD COLUMN invitation_code_id int DEFAULT NULL, -- the invitation code used for the registration
+    ADD CONSTRAINT users_invited_by_fkey FOREIGN KEY (invited_by) REFERENCES users (id) ON DELETE SET NULL,
+    ADD CONSTRAINT users_invitation_code_id_fkey FOREIGN KEY (invitation_code_id) REFERENCES invitation_codes (id) ON DELETE SET NULL;
@@ -0,0 +1,16 @@
+CREATE TABLE IF NOT EXISTS audit_events
+(
+    id         bigserial PRIMARY KEY,
+    user_id    int                   DEFAULT NULL, -- the user the event is about (NULL for unknown accounts)
+    actor_id   int                   DEFAULT NULL, -- the user who caused the event (e.g. an administrator)
+    event_type varchar(64)  NOT NULL,
+    ip_address varchar(45)           DEFAULT NULL,
+    user_agent varchar(512)          DEFAULT NULL,
+    details    text                  DEFAULT NULL,
+    created_at timestamptz  NOT NULL DEFAULT NOW(),
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE,
+    FOREIGN KEY (actor_id) REFERENCES users (id) ON DELETE SET NULL
+);
+
+CREATE INDEX audit_events_user_id_created_at_idx ON audit_events (user_id, created_at DESC);
+CREATE INDEX audit_events_created_at_idx ON audit_events (created_at DESC);
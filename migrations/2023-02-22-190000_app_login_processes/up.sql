@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS app_login_processes
+(
+    id                      varchar(36) PRIMARY KEY,         -- the login process id handed to the login page
+    code_challenge          varchar(128) NOT NULL,           -- the PKCE code challenge (S256) of the app
+    state                   varchar(255)          DEFAULT NULL, -- opaque value of the app which is returned unchanged
+    user_id                 int                   DEFAULT NULL, -- set as soon as the user authenticated
+    authorization_code_hash varchar(64)           DEFAULT NULL, -- SHA-256 of the issued authorization code
+    expires_at              timestamptz  NOT NULL,
+    created_at              timestamptz  NOT NULL DEFAULT NOW(),
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE UNIQUE INDEX app_login_processes_authorization_code_hash_idx ON app_login_processes (authorization_code_hash);
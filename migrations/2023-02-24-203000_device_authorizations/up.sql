@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS device_authorizations
+(
+    id                   serial PRIMARY KEY,
+    device_code_hash     varchar(64)  NOT NULL UNIQUE,     -- SHA-256 of the device code the device polls with
+    user_code            varchar(8)   NOT NULL UNIQUE,     -- the code the user enters (without the dash)
+    client_name          varchar(255)          DEFAULT NULL, -- the name the device identified itself with
+    user_id              int                   DEFAULT NULL, -- set as soon as the user approved the device
+    denied               boolean      NOT NULL DEFAULT FALSE,
+    polling_interval     int          NOT NULL DEFAULT 5,  -- the minimal number of seconds between two polls
+    last_polled_at       timestamptz           DEFAULT NULL,
+    expires_at           timestamptz  NOT NULL,
+    created_at           timestamptz  NOT NULL DEFAULT NOW(),
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
@@ -0,0 +1,26 @@
+CREATE TABLE IF NOT EXISTS oidc_clients
+(
+    id                 serial PRIMARY KEY,
+    client_id          varchar(64)  NOT NULL UNIQUE,
+    client_secret_hash varchar(64)           DEFAULT NULL, -- SHA-256 of the secret, NULL for public clients
+    name               varchar(255) NOT NULL,
+    redirect_uris      text[]       NOT NULL,
+    created_by         int          NOT NULL,
+    created_at         timestamptz  NOT NULL DEFAULT NOW(),
+    FOREIGN KEY (created_by) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS oidc_authorization_codes
+(
+    code_hash      varchar(64) PRIMARY KEY,               -- SHA-256 of the issued authorization code
+    client_id      int          NOT NULL,
+    user_id        int          NOT NULL,
+    redirect_uri   text         NOT NULL,
+    scope          varchar(255) NOT NULL,
+    nonce          varchar(255)          DEFAULT NULL,
+    code_challenge varchar(128)          DEFAULT NULL,    -- the PKCE code challenge (S256) of the client
+    expires_at     timestamptz  NOT NULL,
+    created_at     timestamptz  NOT NULL DEFAULT NOW(),
+    FOREIGN KEY (client_id) REFERENCES oidc_clients (id) ON DELETE CASCADE,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
@@ -0,0 +1,25 @@
+CREATE TABLE IF NOT EXISTS external_identities
+(
+    id            serial PRIMARY KEY,
+    user_id       int          NOT NULL,
+    provider      varchar(64)  NOT NULL, -- the id of the configured identity provider
+    subject       varchar(255) NOT NULL, -- the `sub` claim of the identity provider
+    email         varchar(255) NOT NULL, -- the email address the identity was linked with
+    last_login_at timestamptz           DEFAULT NULL,
+    created_at    timestamptz  NOT NULL DEFAULT NOW(),
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE,
+    UNIQUE (provider, subject)
+);
+
+CREATE INDEX external_identities_user_id_idx ON external_identities (user_id);
+
+CREATE TABLE IF NOT EXISTS external_login_states
+(
+    state_hash       varchar(64) PRIMARY KEY,             -- SHA-256 of the state sent to the identity provider
+    provider         varchar(64)  NOT NULL,
+    nonce            varchar(64)  NOT NULL,
+    code_verifier    varchar(128) NOT NULL,               -- the PKCE code verifier used with the identity provider
+    login_process_id varchar(36)           DEFAULT NULL,  -- the app login process which is completed by the login
+    expires_at       timestamptz  NOT NULL,
+    created_at       timestamptz  NOT NULL DEFAULT NOW()
+);
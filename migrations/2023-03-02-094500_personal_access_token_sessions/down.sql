@@ -0,0 +1,3 @@
+ALTER TABLE personal_access_tokens
+    DROP COLUMN user_agent,
+    DROP COLUMN last_used_at;
@@ -0,0 +1,3 @@
+ALTER TABLE personal_access_tokens
+    ADD COLUMN user_agent   VARCHAR(512) DEFAULT NULL, -- the user agent of the client the token was issued to
+    ADD COLUMN last_used_at TIMESTAMPTZ  DEFAULT NULL; -- updated (throttled) whenever the token is used for authentication
@@ -0,0 +1,4 @@
+ALTER TABLE external_login_states
+    DROP COLUMN link_user_id;
+ALTER TABLE users
+    DROP COLUMN has_local_password;
@@ -0,0 +1,5 @@
+ALTER TABLE users
+    ADD COLUMN has_local_password BOOLEAN NOT NULL DEFAULT true; -- false for accounts created by an identity provider until a password is set
+
+ALTER TABLE external_login_states
+    ADD COLUMN link_user_id INT DEFAULT NULL REFERENCES users (id) ON DELETE CASCADE; -- the user who links the identity to the own account
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS session_access_tokens
+(
+    jti                      varchar(64) PRIMARY KEY,         -- the jti claim of the issued access token
+    personal_access_token_id int         NOT NULL,            -- the session the access token was issued for
+    expires_at               timestamptz NOT NULL,
+    created_at               timestamptz NOT NULL DEFAULT NOW(),
+    FOREIGN KEY (personal_access_token_id) REFERENCES personal_access_tokens (id) ON DELETE CASCADE
+);
@@ -0,0 +1,8 @@
+DELETE FROM oidc_clients
+WHERE created_by IS NULL;
+ALTER TABLE oidc_clients
+    DROP CONSTRAINT oidc_clients_created_by_fkey;
+ALTER TABLE oidc_clients
+    ALTER COLUMN created_by SET NOT NULL;
+ALTER TABLE oidc_clients
+    ADD CONSTRAINT oidc_clients_created_by_fkey FOREIGN KEY (created_by) REFERENCES users (id) ON DELETE CASCADE;
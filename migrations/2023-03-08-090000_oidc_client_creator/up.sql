@@ -0,0 +1,6 @@
+ALTER TABLE oidc_clients
+    DROP CONSTRAINT oidc_clients_created_by_fkey;
+ALTER TABLE oidc_clients
+    ALTER COLUMN created_by DROP NOT NULL;
+ALTER TABLE oidc_clients
+    ADD CONSTRAINT oidc_clients_created_by_fkey FOREIGN KEY (created_by) REFERENCES users (id) ON DELETE SET NULL; -- the clients stay registered if their creator deletes the account
@@ -0,0 +1,179 @@
+use crate::schema::audit_events;
+use diesel::PgConnection;
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+use std::convert::Infallible;
+use std::net::IpAddr;
+
+/// The maximum number of characters of a user agent which are stored with an audit event.
+const MAX_USER_AGENT_LENGTH: usize = 512;
+
+/// The security-relevant events which are recorded in the audit log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditEventType {
+    /// A user logged in successfully.
+    LoginSucceeded,
+    /// A login attempt failed (unknown account, wrong password, wrong second factor, disabled account).
+    LoginFailed,
+    /// A new user account was registered.
+    UserRegistered,
+    /// A new personal access token was created.
+    PersonalAccessTokenCreated,
+    /// A personal access token was disabled.
+    PersonalAccessTokenDisabled,
+    /// The password of a user was changed.
+    PasswordChanged,
+    /// A user requested to change the email address.
+    EmailChangeRequested,
+    /// A user verified a new email address.
+    EmailChanged,
+    /// A user enabled the second factor for the login.
+    TotpEnabled,
+    /// A user disabled the second factor for the login.
+    TotpDisabled,
+    /// A user exported all data stored about the account.
+    DataExported,
+    /// A user deleted the own account.
+    AccountDeleted,
+    /// A user created an invitation code.
+    InvitationCodeCreated,
+    /// An administrator disabled the account of a user.
+    AdminUserDisabled,
+    /// An administrator enabled the account of a user.
+    AdminUserEnabled,
+    /// An administrator reset the password of a user.
+    AdminPasswordReset,
+    /// An administrator revoked all tokens of a user.
+    AdminTokensRevoked,
+    /// An administrator exported all data stored about a user.
+    AdminDataExported,
+    /// An administrator registered a new OpenID Connect client.
+    OidcClientCreated,
+    /// An administrator removed an OpenID Connect client.
+    OidcClientDeleted,
+    /// An account of an external identity provider was linked to a user.
+    ExternalIdentityLinked,
+}
+
+impl AuditEventType {
+    /// Get the name of the event type which is stored in the database.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditEventType::LoginSucceeded => "login_succeeded",
+            AuditEventType::LoginFailed => "login_failed",
+            AuditEventType::UserRegistered => "user_registered",
+            AuditEventType::PersonalAccessTokenCreated => "personal_access_token_created",
+            AuditEventType::PersonalAccessTokenDisabled => "personal_access_token_disabled",
+            AuditEventType::PasswordChanged => "password_changed",
+            AuditEventType::EmailChangeRequested => "email_change_requested",
+            AuditEventType::EmailChanged => "email_changed",
+            AuditEventType::TotpEnabled => "totp_enabled",
+            AuditEventType::TotpDisabled => "totp_disabled",
+            AuditEventType::DataExported => "data_exported",
+            AuditEventType::AccountDeleted => "account_deleted",
+            AuditEventType::InvitationCodeCreated => "invitation_code_created",
+            AuditEventType::AdminUserDisabled => "admin_user_disabled",
+            AuditEventType::AdminUserEnabled => "admin_user_enabled",
+            AuditEventType::AdminPasswordReset => "admin_password_reset",
+            AuditEventType::AdminTokensRevoked => "admin_tokens_revoked",
+            AuditEventType::AdminDataExported => "admin_data_exported",
+            AuditEventType::OidcClientCreated => "oidc_client_created",
+            AuditEventType::OidcClientDeleted => "oidc_client_deleted",
+            AuditEventType::ExternalIdentityLinked => "external_identity_linked",
+        }
+    }
+}
+
+/// Information about the client which sent the current request. It is stored with every audit
+/// event to be able to trace where an action came from.
+pub struct RequestMetadata {
+    /// The IP address of the client (if known).
+    pub ip_address: Option<IpAddr>,
+    /// The user agent the client sent (if any).
+    pub user_agent: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RequestMetadata {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<RequestMetadata, Infallible> {
+        use crate::fairings::BackendConfiguration;
+
+        // the X-Real-IP header can be set by every client, so it is only used if the backend is
+        // configured to run behind a reverse proxy which sets it
+        let trust_proxy_ip_header = request
+            .rocket()
+            .state::<BackendConfiguration>()
+            .map_or(false, |config| config.trust_proxy_ip_header);
+        let ip_address = if trust_proxy_ip_header {
+            request.client_ip()
+        } else {
+            request.remote().map(|remote| remote.ip())
+        };
+
+        Outcome::Success(RequestMetadata {
+            ip_address,
+            user_agent: request
+                .headers()
+                .get_one("User-Agent")
+                .map(|user_agent| user_agent.chars().take(MAX_USER_AGENT_LENGTH).collect()),
+        })
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = audit_events)]
+struct NewAuditEvent<'a> {
+    user_id: Option<i32>,
+    actor_id: Option<i32>,
+    event_type: &'a str,
+    ip_address: Option<String>,
+    user_agent: Option<&'a str>,
+    details: Option<&'a str>,
+}
+
+/// Record a security-relevant event in the audit log. The `user_id` is the user the event is
+/// about and the `actor_id` the user who caused it (e.g. the administrator). Failing to write the
+/// audit log is logged but does not abort the request which caused the event.
+pub fn record_audit_event(
+    connection: &mut PgConnection,
+    event_type: AuditEventType,
+    user_id: Option<i32>,
+    actor_id: Option<i32>,
+    request_metadata: &RequestMetadata,
+    details: Option<&str>,
+) {
+    use crate::metrics::METRICS;
+    use diesel::RunQueryDsl;
+    use log::error;
+
+    // the outcome of the logins is counted for the metrics as well
+    match event_type {
+        AuditEventType::LoginSucceeded => METRICS.record_login(true),
+        AuditEventType::LoginFailed => METRICS.record_login(false),
+        _ => {}
+    }
+
+    let new_event = NewAuditEvent {
+        user_id,
+        actor_id,
+        event_type: event_type.as_str(),
+        ip_address: request_metadata
+            .ip_address
+            .map(|ip_address| ip_address.to_string()),
+        user_agent: request_metadata.user_agent.as_deref(),
+        details,
+    };
+    if let Err(error) = diesel::insert_into(audit_events::table)
+        .values(&new_event)
+        .execute(connection)
+    {
+        error!(
+            "Could not record the audit event '{}' for the user with the id {:?}. The error was: {}",
+            event_type.as_str(),
+            user_id,
+            error
+        );
+    }
+}
@@ -0,0 +1,919 @@
+use crate::audit::{record_audit_event, AuditEventType, RequestMetadata};
+use crate::routes::user::User;
+use crate::schema::{personal_access_tokens, users};
+use crate::settings::Settings;
+use diesel::PgConnection;
+use std::fmt;
+use std::path::PathBuf;
+
+/// The help text which is shown for `--help` or if the command line arguments are invalid.
+pub const USAGE: &str = "Usage: minne-backend [--config <file>] [--print-config] [<command>]
+
+Commands:
+  serve                                   Start the server (default)
+  migrate                                 Run the pending database migrations and exit
+  migrate status                          Show the applied and the pending database migrations
+  migrate revert --yes                    Revert the database migration which was applied last
+  check-config                            Validate the configuration including all key files
+  user create --email <email> --first-name <name> --last-name <name> [--admin] [--password-stdin]
+                                          Create a user (with a temporary password unless it is read from stdin)
+  user list                               List all users
+  user reset-password <email|id>          Replace the password with a temporary one and revoke all tokens
+  user disable <email|id>                 Disable the account of a user
+  user enable <email|id>                  Enable the account of a user again
+  pat list [<email|id>]                   List the personal access tokens (of a single user)
+  pat revoke <id>                         Disable a personal access token";
+
+/// The details which are recorded in the audit log for changes made on the command line.
+const AUDIT_DETAILS: &str = "via the command line";
+
+/// The command the backend binary was asked to execute.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Start the server (the default if no command was supplied).
+    Serve,
+    /// Manage the database migrations.
+    Migrate(MigrateCommand),
+    /// Validate the configuration and all files it refers to.
+    CheckConfig,
+    /// Manage the users.
+    User(UserCommand),
+    /// Manage the personal access tokens.
+    Pat(PatCommand),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MigrateCommand {
+    /// Run the pending database migrations.
+    Run,
+    /// Show the applied and the pending database migrations.
+    Status,
+    /// Revert the database migration which was applied last.
+    RevertLast,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum UserCommand {
+    Create {
+        /// The email address of the new user which is used as the username.
+        email: String,
+        /// The first name of the new user.
+        first_name: String,
+        /// The last name of the new user.
+        last_name: String,
+        /// A flag which indicates if the new user should be an administrator.
+        admin: bool,
+        /// A flag which indicates if the password should be read from the standard input instead
+        /// of generating a temporary one.
+        password_from_stdin: bool,
+    },
+    List,
+    ResetPassword {
+        /// The email address or the id of the user.
+        user: String,
+    },
+    Disable {
+        /// The email address or the id of the user.
+        user: String,
+    },
+    Enable {
+        /// The email address or the id of the user.
+        user: String,
+    },
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PatCommand {
+    List {
+        /// The email address or the id of the user whose tokens should be listed (all if unset).
+        user: Option<String>,
+    },
+    Revoke {
+        /// The id of the personal access token.
+        id: i32,
+    },
+}
+
+/// The parsed command line arguments of the backend binary.
+#[derive(Debug)]
+pub struct CommandLine {
+    /// The configuration file which was selected with `--config` (if any).
+    pub config_file: Option<PathBuf>,
+    /// A flag which indicates if the effective configuration should just be printed.
+    pub print_config: bool,
+    /// A flag which indicates if just the help text should be shown.
+    pub show_help: bool,
+    /// The command which should be executed.
+    pub command: Command,
+}
+
+/// The reason why the command line arguments could not be parsed.
+#[derive(Debug)]
+pub struct UsageError(pub String);
+
+impl fmt::Display for UsageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl CommandLine {
+    /// Parse the supplied command line arguments (without the name of the binary).
+    pub fn parse(arguments: Vec<String>) -> Result<CommandLine, UsageError> {
+        let mut config_file = None;
+        let mut print_config = false;
+        let mut show_help = false;
+        let mut positional = Vec::new();
+        let mut options = Vec::new();
+
+        // separate the global options from the command and its arguments
+        let mut arguments = arguments.into_iter();
+        while let Some(argument) = arguments.next() {
+            match argument.as_str() {
+                "--print-config" => print_config = true,
+                "--help" | "-h" => show_help = true,
+                "--config" => match arguments.next() {
+                    Some(path) => config_file = Some(PathBuf::from(path)),
+                    None => {
+                        return Err(UsageError(
+                            "The argument --config requires the path to a configuration file"
+                                .to_string(),
+                        ))
+                    }
+                },
+                _ if argument.starts_with("--") => {
+                    // options of the commands which take a value consume the next argument
+                    let value = if ["--email", "--first-name", "--last-name"]
+                        .contains(&argument.as_str())
+                    {
+                        match arguments.next() {
+                            Some(value) if !value.starts_with("--") => Some(value),
+                            _ => {
+                                return Err(UsageError(format!(
+                                    "The argument {} requires a value",
+                                    argument
+                                )))
+                            }
+                        }
+                    } else {
+                        None
+                    };
+                    options.push((argument, value));
+                }
+                _ => positional.push(argument),
+            }
+        }
+
+        let command = parse_command(&positional, options)?;
+        Ok(CommandLine {
+            config_file,
+            print_config,
+            show_help,
+            command,
+        })
+    }
+}
+
+/// Get the command from the positional arguments and the options which belong to the command.
+fn parse_command(
+    positional: &[String],
+    mut options: Vec<(String, Option<String>)>,
+) -> Result<Command, UsageError> {
+    let mut take_option = |name: &str| -> Option<Option<String>> {
+        let index = options.iter().position(|(option, _)| option == name)?;
+        Some(options.remove(index).1)
+    };
+    let positional = positional.iter().map(String::as_str).collect::<Vec<_>>();
+
+    let command = match positional.as_slice() {
+        [] | ["serve"] => Command::Serve,
+        ["migrate"] => Command::Migrate(MigrateCommand::Run),
+        ["migrate", "status"] => Command::Migrate(MigrateCommand::Status),
+        ["migrate", "revert"] => {
+            // reverting a migration can drop data, so it has to be confirmed explicitly
+            if take_option("--yes").is_none() {
+                return Err(UsageError(
+                    "Reverting a migration can delete data, confirm it with 'migrate revert --yes'"
+                        .to_string(),
+                ));
+            }
+            Command::Migrate(MigrateCommand::RevertLast)
+        }
+        ["check-config"] => Command::CheckConfig,
+        ["user", "create"] => {
+            let mut required = |name: &str| match take_option(name).flatten() {
+                Some(value) if !value.trim().is_empty() => Ok(value.trim().to_string()),
+                _ => Err(UsageError(format!(
+                    "The command 'user create' requires {} <value>",
+                    name
+                ))),
+            };
+            let email = required("--email")?;
+            let first_name = required("--first-name")?;
+            let last_name = required("--last-name")?;
+            Command::User(UserCommand::Create {
+                email,
+                first_name,
+                last_name,
+                admin: take_option("--admin").is_some(),
+                password_from_stdin: take_option("--password-stdin").is_some(),
+            })
+        }
+        ["user", "list"] => Command::User(UserCommand::List),
+        ["user", "reset-password", user] => Command::User(UserCommand::ResetPassword {
+            user: user.to_string(),
+        }),
+        ["user", "disable", user] => Command::User(UserCommand::Disable {
+            user: user.to_string(),
+        }),
+        ["user", "enable", user] => Command::User(UserCommand::Enable {
+            user: user.to_string(),
+        }),
+        ["pat", "list"] => Command::Pat(PatCommand::List { user: None }),
+        ["pat", "list", user] => Command::Pat(PatCommand::List {
+            user: Some(user.to_string()),
+        }),
+        ["pat", "revoke", id] => match id.parse() {
+            Ok(id) => Command::Pat(PatCommand::Revoke { id }),
+            Err(_) => {
+                return Err(UsageError(format!(
+                    "'{}' is not a valid id of a personal access token",
+                    id
+                )))
+            }
+        },
+        _ => {
+            return Err(UsageError(format!(
+                "Unknown command '{}'",
+                positional.join(" ")
+            )))
+        }
+    };
+
+    // every option has to be known by the selected command
+    if let Some((option, _)) = options.first() {
+        return Err(UsageError(format!("Unknown argument '{}'", option)));
+    }
+    Ok(command)
+}
+
+/// Get a user by the supplied email address or id.
+fn find_user(connection: &mut PgConnection, user: &str) -> Result<User, String> {
+    use diesel::{ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
+
+    let query_result = match user.parse::<i32>() {
+        Ok(user_id) => users::table
+            .find(user_id)
+            .first::<User>(connection)
+            .optional(),
+        Err(_) => users::table
+            .filter(users::email.eq(user))
+            .first::<User>(connection)
+            .optional(),
+    };
+    match query_result {
+        Ok(Some(user)) => Ok(user),
+        Ok(None) => Err(format!(
+            "There is no user with the email address or id '{}'",
+            user
+        )),
+        Err(error) => Err(format!(
+            "Could not get the user '{}'. The error was: {}",
+            user, error
+        )),
+    }
+}
+
+/// The metadata which is recorded in the audit log for changes made on the command line.
+fn command_line_metadata() -> RequestMetadata {
+    RequestMetadata {
+        ip_address: None,
+        user_agent: None,
+    }
+}
+
+/// Execute one of the commands for managing the database migrations.
+pub fn run_migrate_command(
+    connection: &mut PgConnection,
+    command: MigrateCommand,
+) -> Result<(), String> {
+    use crate::migrations::{migration_status, revert_last_migration, run_migrations};
+
+    match command {
+        MigrateCommand::Run => run_migrations(connection).map(|_| ()),
+        MigrateCommand::Status => {
+            let migrations = migration_status(connection)?;
+            for migration in &migrations {
+                println!(
+                    "{:<8}  {}",
+                    if migration.applied {
+                        "applied"
+                    } else {
+                        "pending"
+                    },
+                    migration.name
+                );
+            }
+            println!(
+                "{} applied, {} pending",
+                migrations
+                    .iter()
+                    .filter(|migration| migration.applied)
+                    .count(),
+                migrations
+                    .iter()
+                    .filter(|migration| !migration.applied)
+                    .count()
+            );
+            Ok(())
+        }
+        MigrateCommand::RevertLast => {
+            let version = revert_last_migration(connection)?;
+            println!(
+                "Reverted the database migration with the version {}",
+                version
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Execute one of the commands for managing the users.
+pub fn run_user_command(
+    connection: &mut PgConnection,
+    settings: &Settings,
+    command: UserCommand,
+) -> Result<(), String> {
+    match command {
+        UserCommand::Create {
+            email,
+            first_name,
+            last_name,
+            admin,
+            password_from_stdin,
+        } => create_user(
+            connection,
+            settings,
+            NewUserDetails {
+                email,
+                first_name,
+                last_name,
+                admin,
+            },
+            password_from_stdin,
+        ),
+        UserCommand::List => list_users(connection),
+        UserCommand::ResetPassword { user } => reset_password(connection, settings, &user),
+        UserCommand::Disable { user } => set_user_disabled(connection, &user, true),
+        UserCommand::Enable { user } => set_user_disabled(connection, &user, false),
+    }
+}
+
+/// The details of a user which should be created on the command line.
+struct NewUserDetails {
+    email: String,
+    first_name: String,
+    last_name: String,
+    admin: bool,
+}
+
+fn create_user(
+    connection: &mut PgConnection,
+    settings: &Settings,
+    details: NewUserDetails,
+    password_from_stdin: bool,
+) -> Result<(), String> {
+    use crate::password_policy::check_password_policy;
+    use crate::passwords::hash_password;
+    use crate::routes::admin::generate_temporary_password;
+    use crate::routes::user::NewUser;
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+    use log::info;
+
+    // either take the password chosen by the operator or generate one which has to be changed
+    let password = if password_from_stdin {
+        let mut password = String::new();
+        if let Err(error) = std::io::stdin().read_line(&mut password) {
+            return Err(format!(
+                "Could not read the password from the standard input. The error was: {}",
+                error
+            ));
+        }
+        let password = password.trim_end_matches(['\r', '\n']).to_string();
+        let policy_violations = check_password_policy(
+            &settings.password_policy(),
+            &password,
+            &[&details.first_name, &details.last_name, &details.email],
+        );
+        if !policy_violations.is_empty() {
+            return Err(format!(
+                "The password does not fulfill the password policy: {}",
+                policy_violations.join(" ")
+            ));
+        }
+        password
+    } else {
+        generate_temporary_password()
+    };
+
+    // hash the password with the configured algorithm
+    let password_hashing = settings.password_hashing()?;
+    let password_hash = hash_password(&password_hashing, &password)
+        .map_err(|error| format!("Could not hash the password. The error was: {}", error))?;
+
+    // the email address is used as the username and therefore has to be unique
+    let user_already_exists = diesel::select(diesel::dsl::exists(
+        users::table.filter(users::email.eq(&details.email)),
+    ))
+    .get_result::<bool>(connection)
+    .map_err(|error| {
+        format!(
+            "Could not check the email address. The error was: {}",
+            error
+        )
+    })?;
+    if user_already_exists {
+        return Err(format!(
+            "A user with the email address '{}' already exists",
+            details.email
+        ));
+    }
+
+    // add the user to the database
+    let new_user = NewUser {
+        first_name: details.first_name,
+        last_name: details.last_name,
+        email: details.email,
+        password_hash,
+        invited_by: None,
+        invitation_code_id: None,
+        has_local_password: true,
+    };
+    let user_id = diesel::insert_into(users::table)
+        .values(&new_user)
+        .returning(users::id)
+        .get_result::<i32>(connection)
+        .map_err(|error| format!("Could not create the user. The error was: {}", error))?;
+    diesel::update(users::table.find(user_id))
+        .set((
+            users::is_admin.eq(details.admin),
+            users::password_change_required.eq(!password_from_stdin),
+        ))
+        .execute(connection)
+        .map_err(|error| {
+            format!(
+                "Could not set the flags of the user with the id {}. The error was: {}",
+                user_id, error
+            )
+        })?;
+    info!(
+        "Created the {} with the id {} on the command line",
+        if details.admin {
+            "administrator"
+        } else {
+            "user"
+        },
+        user_id
+    );
+    record_audit_event(
+        connection,
+        AuditEventType::UserRegistered,
+        Some(user_id),
+        None,
+        &command_line_metadata(),
+        Some(AUDIT_DETAILS),
+    );
+
+    println!(
+        "Created the user '{}' with the id {}",
+        new_user.email, user_id
+    );
+    if !password_from_stdin {
+        println!(
+            "Temporary password (has to be changed with the first login): {}",
+            password
+        );
+    }
+    Ok(())
+}
+
+fn list_users(connection: &mut PgConnection) -> Result<(), String> {
+    use diesel::{QueryDsl, RunQueryDsl};
+
+    let all_users = users::table
+        .order(users::id)
+        .load::<User>(connection)
+        .map_err(|error| format!("Could not get the users. The error was: {}", error))?;
+
+    println!(
+        "{:>6}  {:<40}  {:<30}  {:<5}  {:<8}  CREATED AT",
+        "ID", "EMAIL", "NAME", "ADMIN", "DISABLED"
+    );
+    for user in all_users {
+        println!(
+            "{:>6}  {:<40}  {:<30}  {:<5}  {:<8}  {}",
+            user.id,
+            user.email,
+            format!("{} {}", user.first_name, user.last_name),
+            if user.is_admin { "yes" } else { "no" },
+            if user.disabled { "yes" } else { "no" },
+            user.created_at.format("%Y-%m-%d %H:%M:%S")
+        );
+    }
+    Ok(())
+}
+
+fn reset_password(
+    connection: &mut PgConnection,
+    settings: &Settings,
+    user: &str,
+) -> Result<(), String> {
+    use crate::passwords::hash_password;
+    use crate::routes::admin::{generate_temporary_password, revoke_all_tokens_of_user};
+    use diesel::{Connection, ExpressionMethods, QueryDsl, RunQueryDsl};
+    use log::info;
+
+    let user = find_user(connection, user)?;
+
+    // generate a temporary password which has to be changed with the next login
+    let temporary_password = generate_temporary_password();
+    let password_hashing = settings.password_hashing()?;
+    let password_hash = hash_password(&password_hashing, &temporary_password).map_err(|error| {
+        format!(
+            "Could not hash the temporary password. The error was: {}",
+            error
+        )
+    })?;
+
+    // replace the password and ensure that all existing sessions of the user are terminated
+    connection
+        .transaction::<_, diesel::result::Error, _>(|connection| {
+            diesel::update(users::table.find(user.id))
+                .set((
+                    users::password_hash.eq(&password_hash),
+                    users::has_local_password.eq(true),
+                    users::password_change_required.eq(true),
+                    users::updated_at.eq(diesel::dsl::now),
+                ))
+                .execute(connection)?;
+            revoke_all_tokens_of_user(connection, user.id)
+        })
+        .map_err(|error| {
+            format!(
+                "Could not reset the password of the user with the id {}. The error was: {}",
+                user.id, error
+            )
+        })?;
+    info!(
+        "Reset the password of the user with the id {} on the command line",
+        user.id
+    );
+    record_audit_event(
+        connection,
+        AuditEventType::AdminPasswordReset,
+        Some(user.id),
+        None,
+        &command_line_metadata(),
+        Some(AUDIT_DETAILS),
+    );
+
+    println!(
+        "Temporary password for '{}' (has to be changed with the next login): {}",
+        user.email, temporary_password
+    );
+    Ok(())
+}
+
+fn set_user_disabled(
+    connection: &mut PgConnection,
+    user: &str,
+    disabled: bool,
+) -> Result<(), String> {
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+    use log::info;
+
+    let user = find_user(connection, user)?;
+    diesel::update(users::table.find(user.id))
+        .set((
+            users::disabled.eq(disabled),
+            users::updated_at.eq(diesel::dsl::now),
+        ))
+        .execute(connection)
+        .map_err(|error| {
+            format!(
+                "Could not change the state of the user with the id {}. The error was: {}",
+                user.id, error
+            )
+        })?;
+    info!(
+        "{} the account of the user with the id {} on the command line",
+        if disabled { "Disabled" } else { "Enabled" },
+        user.id
+    );
+    record_audit_event(
+        connection,
+        if disabled {
+            AuditEventType::AdminUserDisabled
+        } else {
+            AuditEventType::AdminUserEnabled
+        },
+        Some(user.id),
+        None,
+        &command_line_metadata(),
+        Some(AUDIT_DETAILS),
+    );
+
+    println!(
+        "The account of '{}' is {}",
+        user.email,
+        if disabled { "disabled" } else { "enabled" }
+    );
+    Ok(())
+}
+
+/// Execute one of the commands for managing the personal access tokens.
+pub fn run_pat_command(connection: &mut PgConnection, command: PatCommand) -> Result<(), String> {
+    match command {
+        PatCommand::List { user } => list_personal_access_tokens(connection, user.as_deref()),
+        PatCommand::Revoke { id } => revoke_personal_access_token(connection, id),
+    }
+}
+
+fn list_personal_access_tokens(
+    connection: &mut PgConnection,
+    user: Option<&str>,
+) -> Result<(), String> {
+    use chrono::{DateTime, Utc};
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+
+    // the token and the secret are never shown, just the metadata of the tokens
+    let mut query = personal_access_tokens::table
+        .inner_join(users::table)
+        .order(personal_access_tokens::id)
+        .select((
+            personal_access_tokens::id,
+            users::email,
+            personal_access_tokens::name,
+            personal_access_tokens::disabled,
+            personal_access_tokens::created_at,
+            personal_access_tokens::last_used_at,
+        ))
+        .into_boxed();
+    if let Some(user) = user {
+        let user = find_user(connection, user)?;
+        query = query.filter(personal_access_tokens::user_id.eq(user.id));
+    }
+    let tokens = query
+        .load::<(
+            i32,
+            String,
+            String,
+            bool,
+            DateTime<Utc>,
+            Option<DateTime<Utc>>,
+        )>(connection)
+        .map_err(|error| {
+            format!(
+                "Could not get the personal access tokens. The error was: {}",
+                error
+            )
+        })?;
+
+    println!(
+        "{:>6}  {:<40}  {:<30}  {:<8}  {:<19}  LAST USED AT",
+        "ID", "USER", "NAME", "DISABLED", "CREATED AT"
+    );
+    for (id, email, name, disabled, created_at, last_used_at) in tokens {
+        println!(
+            "{:>6}  {:<40}  {:<30}  {:<8}  {:<19}  {}",
+            id,
+            email,
+            name,
+            if disabled { "yes" } else { "no" },
+            created_at.format("%Y-%m-%d %H:%M:%S"),
+            last_used_at
+                .map(|last_used_at| last_used_at.format("%Y-%m-%d %H:%M:%S").to_string())
+                .unwrap_or_else(|| "never".to_string())
+        );
+    }
+    Ok(())
+}
+
+fn revoke_personal_access_token(connection: &mut PgConnection, id: i32) -> Result<(), String> {
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+    use log::info;
+
+    let user_id = diesel::update(
+        personal_access_tokens::table
+            .filter(personal_access_tokens::id.eq(id))
+            .filter(personal_access_tokens::disabled.eq(false)),
+    )
+    .set((
+        personal_access_tokens::disabled.eq(true),
+        personal_access_tokens::updated_at.eq(diesel::dsl::now),
+    ))
+    .returning(personal_access_tokens::user_id)
+    .get_results::<i32>(connection)
+    .map_err(|error| {
+        format!(
+            "Could not revoke the personal access token with the id {}. The error was: {}",
+            id, error
+        )
+    })?;
+    let user_id = match user_id.first() {
+        Some(user_id) => *user_id,
+        None => {
+            return Err(format!(
+                "There is no active personal access token with the id {}",
+                id
+            ))
+        }
+    };
+    info!(
+        "Revoked the personal access token with the id {} on the command line",
+        id
+    );
+    record_audit_event(
+        connection,
+        AuditEventType::PersonalAccessTokenDisabled,
+        Some(user_id),
+        None,
+        &command_line_metadata(),
+        Some(&format!(
+            "revoked the personal access token with the id {} {}",
+            id, AUDIT_DETAILS
+        )),
+    );
+
+    println!("Revoked the personal access token with the id {}", id);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Command, CommandLine, MigrateCommand, PatCommand, UserCommand};
+    use std::path::PathBuf;
+
+    /// Parse the command line arguments which are separated by spaces.
+    fn parse(arguments: &str) -> Result<CommandLine, String> {
+        CommandLine::parse(arguments.split_whitespace().map(str::to_string).collect())
+            .map_err(|error| error.0)
+    }
+
+    /// Parse the command line arguments and get just the command.
+    fn parse_command(arguments: &str) -> Command {
+        parse(arguments).unwrap().command
+    }
+
+    #[test]
+    fn server_is_started_without_a_command() {
+        assert_eq!(parse_command(""), Command::Serve);
+        assert_eq!(parse_command("serve"), Command::Serve);
+    }
+
+    #[test]
+    fn global_options_are_parsed_around_the_command() {
+        let command_line = parse("--config minne.toml migrate status --print-config").unwrap();
+        assert_eq!(command_line.config_file, Some(PathBuf::from("minne.toml")));
+        assert!(command_line.print_config);
+        assert!(!command_line.show_help);
+        assert_eq!(
+            command_line.command,
+            Command::Migrate(MigrateCommand::Status)
+        );
+        assert!(parse("--help").unwrap().show_help);
+        assert!(parse("-h").unwrap().show_help);
+    }
+
+    #[test]
+    fn config_option_requires_a_path() {
+        assert!(parse("--config").is_err());
+    }
+
+    #[test]
+    fn migrate_commands_are_parsed() {
+        assert_eq!(
+            parse_command("migrate"),
+            Command::Migrate(MigrateCommand::Run)
+        );
+        assert_eq!(
+            parse_command("migrate status"),
+            Command::Migrate(MigrateCommand::Status)
+        );
+        assert_eq!(
+            parse_command("migrate revert --yes"),
+            Command::Migrate(MigrateCommand::RevertLast)
+        );
+    }
+
+    #[test]
+    fn migrate_revert_requires_a_confirmation() {
+        assert!(parse("migrate revert").unwrap_err().contains("--yes"));
+    }
+
+    #[test]
+    fn check_config_is_parsed() {
+        assert_eq!(parse_command("check-config"), Command::CheckConfig);
+    }
+
+    #[test]
+    fn user_create_is_parsed() {
+        assert_eq!(
+            parse_command("user create --email jane@example.com --first-name Jane --last-name Doe"),
+            Command::User(UserCommand::Create {
+                email: "jane@example.com".to_string(),
+                first_name: "Jane".to_string(),
+                last_name: "Doe".to_string(),
+                admin: false,
+                password_from_stdin: false,
+            })
+        );
+        assert_eq!(
+            parse_command(
+                "user create --admin --last-name Doe --password-stdin --first-name Jane --email jane@example.com"
+            ),
+            Command::User(UserCommand::Create {
+                email: "jane@example.com".to_string(),
+                first_name: "Jane".to_string(),
+                last_name: "Doe".to_string(),
+                admin: true,
+                password_from_stdin: true,
+            })
+        );
+    }
+
+    #[test]
+    fn user_create_requires_all_values() {
+        // a required option is missing
+        assert!(parse("user create --email jane@example.com --first-name Jane").is_err());
+
+        // the value of the last option is missing
+        assert!(parse("user create --first-name Jane --last-name Doe --email").is_err());
+
+        // the next option is not taken as the value
+        assert!(parse("user create --email --first-name Jane --last-name Doe").is_err());
+    }
+
+    #[test]
+    fn user_commands_are_parsed() {
+        assert_eq!(parse_command("user list"), Command::User(UserCommand::List));
+        assert_eq!(
+            parse_command("user reset-password jane@example.com"),
+            Command::User(UserCommand::ResetPassword {
+                user: "jane@example.com".to_string()
+            })
+        );
+        assert_eq!(
+            parse_command("user disable 2"),
+            Command::User(UserCommand::Disable {
+                user: "2".to_string()
+            })
+        );
+        assert_eq!(
+            parse_command("user enable 2"),
+            Command::User(UserCommand::Enable {
+                user: "2".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn user_commands_require_the_user() {
+        assert!(parse("user reset-password").is_err());
+        assert!(parse("user disable").is_err());
+        assert!(parse("user enable").is_err());
+    }
+
+    #[test]
+    fn pat_commands_are_parsed() {
+        assert_eq!(
+            parse_command("pat list"),
+            Command::Pat(PatCommand::List { user: None })
+        );
+        assert_eq!(
+            parse_command("pat list jane@example.com"),
+            Command::Pat(PatCommand::List {
+                user: Some("jane@example.com".to_string())
+            })
+        );
+        assert_eq!(
+            parse_command("pat revoke 42"),
+            Command::Pat(PatCommand::Revoke { id: 42 })
+        );
+    }
+
+    #[test]
+    fn pat_revoke_requires_a_numeric_id() {
+        assert!(parse("pat revoke").is_err());
+        assert!(parse("pat revoke abc").is_err());
+    }
+
+    #[test]
+    fn unknown_commands_and_options_are_rejected() {
+        assert!(parse("migrate everything").is_err());
+        assert!(parse("user").is_err());
+        assert!(parse("serve --port 8000").is_err());
+        assert!(parse("user list --admin").is_err());
+        assert!(parse("migrate --yes").is_err());
+    }
+}
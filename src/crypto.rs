@@ -0,0 +1,26 @@
+/// Fill a buffer of the requested size with cryptographically secure random bytes.
+pub fn generate_random_bytes(length: usize) -> Vec<u8> {
+    use ring::rand::{SecureRandom, SystemRandom};
+
+    let mut buffer = vec![0u8; length];
+    SystemRandom::new()
+        .fill(&mut buffer)
+        .expect("Could not get random bytes from the operating system");
+    buffer
+}
+
+/// Generate a random token consisting of the requested number of random bytes which are hex encoded.
+pub fn generate_random_token(length_in_bytes: usize) -> String {
+    use data_encoding::HEXLOWER;
+
+    HEXLOWER.encode(&generate_random_bytes(length_in_bytes))
+}
+
+/// Get the hex encoded SHA-256 hash of the supplied value. This is used to store tokens in the
+/// database without being able to use them if the database leaks.
+pub fn sha256_hex(value: &str) -> String {
+    use data_encoding::HEXLOWER;
+    use ring::digest::{digest, SHA256};
+
+    HEXLOWER.encode(digest(&SHA256, value.as_bytes()).as_ref())
+}
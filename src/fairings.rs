@@ -1,7 +1,10 @@
+use crate::password_policy::PasswordPolicy;
+use crate::passwords::PasswordHashingConfiguration;
 use diesel::r2d2::{ConnectionManager, Pool, PooledConnection};
 use diesel::PgConnection;
 use rocket::fairing::{Fairing, Info, Kind};
 use rocket::{Request, Response};
+use std::path::PathBuf;
 
 /// Wrapper around a connection pool for the database access
 pub struct MinneDatabaseConnection(Pool<ConnectionManager<PgConnection>>);
@@ -12,6 +15,27 @@ impl MinneDatabaseConnection {
     pub fn get(&self) -> Result<PooledConnection<ConnectionManager<PgConnection>>, r2d2::Error> {
         self.0.get()
     }
+
+    /// Get a connection from the pool, but wait at most for the supplied duration.
+    #[inline(always)]
+    pub fn get_timeout(
+        &self,
+        timeout: std::time::Duration,
+    ) -> Result<PooledConnection<ConnectionManager<PgConnection>>, r2d2::Error> {
+        self.0.get_timeout(timeout)
+    }
+
+    /// Get the number of open and idle connections of the pool.
+    #[inline(always)]
+    pub fn state(&self) -> r2d2::State {
+        self.0.state()
+    }
+
+    /// Get the maximum number of connections the pool opens.
+    #[inline(always)]
+    pub fn max_size(&self) -> u32 {
+        self.0.max_size()
+    }
 }
 
 /// Implementation of the From trait to get a single database connection from a connection pool
@@ -22,16 +46,40 @@ impl From<Pool<ConnectionManager<PgConnection>>> for MinneDatabaseConnection {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct BackendConfiguration {
-    /// The pre-shared-key which is used to sign and validate the generated token.
-    pub token_signature_psk: String,
     /// The access token-lifetime in seconds.
     pub access_token_lifetime_in_seconds: usize,
     /// The refresh token-lifetime in seconds.
     pub refresh_token_lifetime_in_seconds: usize,
+    /// The requirements a new password has to fulfill.
+    pub password_policy: PasswordPolicy,
+    /// The algorithm and parameters used for hashing the passwords of the users.
+    pub password_hashing: PasswordHashingConfiguration,
+    /// The public base URL under which the instance can be reached (without a trailing slash).
+    pub public_url: String,
     /// Whether or not the user registration is enabled.
     pub user_registration_enabled: bool,
+    /// Whether or not users without administrative privileges can create invitation codes.
+    pub user_invitations_enabled: bool,
+    /// The executable which delivers the verification tokens for changed email addresses (if any).
+    pub email_verification_command: Option<PathBuf>,
+    /// The number of failed login attempts after which an account gets locked temporarily.
+    pub login_max_failed_attempts_per_account: u32,
+    /// The number of failed login attempts after which an IP address gets locked temporarily.
+    pub login_max_failed_attempts_per_ip: u32,
+    /// The base delay in seconds which gets doubled with every failed login attempt.
+    pub login_backoff_base_in_seconds: u64,
+    /// The duration in seconds an account or IP address is locked after too many failed attempts.
+    pub login_lockout_duration_in_seconds: u64,
+    /// The duration in seconds after which failed login attempts are forgotten.
+    pub login_failure_window_in_seconds: u64,
+    /// Whether or not the IP address of the client is taken from the `X-Real-IP` header.
+    pub trust_proxy_ip_header: bool,
+    /// Whether or not only administrators can see the detailed health diagnostics.
+    pub health_details_require_admin: bool,
+    /// The username and the password which are required for fetching the metrics (if any).
+    pub metrics_credentials: Option<(String, String)>,
 }
 
 /// The fairing which can be used for setting a cache-control
@@ -54,3 +102,73 @@ impl Fairing for NoCacheFairing {
         response.set_raw_header("Cache-Control", "no-cache");
     }
 }
+
+/// The time when the handling of a request started, remembered for measuring its latency.
+struct RequestStartTime(std::time::Instant);
+
+/// The fairing which counts the handled requests and measures their latency for the metrics.
+pub struct MetricsFairing;
+
+#[rocket::async_trait]
+impl Fairing for MetricsFairing {
+    /// Get some generic information about this fairing.
+    fn info(&self) -> Info {
+        Info {
+            name: "Collect the number and the latency of the handled requests.",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    /// Remember when the handling of the request started.
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut rocket::Data<'_>) {
+        request.local_cache(|| RequestStartTime(std::time::Instant::now()));
+    }
+
+    /// Record the request with the route which handled it. Requests which did not match any route
+    /// are counted together, so that unknown paths cannot create an unbounded number of metrics.
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        use crate::metrics::METRICS;
+
+        let started_at = request.local_cache(|| RequestStartTime(std::time::Instant::now()));
+        let route = request
+            .route()
+            .map(|route| route.uri.as_str())
+            .unwrap_or("unmatched");
+        METRICS.record_request(
+            request.method().as_str(),
+            route,
+            response.status().code,
+            started_at.0.elapsed(),
+        );
+    }
+}
+
+/// The fairing which ensures that every request has an id. The id supplied by the client with the
+/// `X-Request-Id` header is used (e.g. from a reverse proxy), otherwise a new one is generated. The
+/// id is sent back with the response and added to the log lines emitted while handling the request.
+pub struct RequestIdFairing;
+
+#[rocket::async_trait]
+impl Fairing for RequestIdFairing {
+    /// Get some generic information about this fairing.
+    fn info(&self) -> Info {
+        Info {
+            name: "Assign an id to each request for correlating the log lines.",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    /// Determine the id of the request before it is handled.
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut rocket::Data<'_>) {
+        use crate::logging::RequestId;
+
+        RequestId::of(request);
+    }
+
+    /// Tell the client the id of the request.
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        use crate::logging::RequestId;
+
+        response.set_header(RequestId::of(request).header());
+    }
+}
@@ -2,13 +2,26 @@ use chrono::{DateTime, Utc};
 use rocket::request::{FromRequest, Outcome};
 use rocket::Request;
 
+/// The minimum time between two updates of the last usage of a personal access token.
+const LAST_USED_UPDATE_INTERVAL_IN_SECONDS: i64 = 60;
+
 /// The representation of an authenticated user. As soon as this is included in the parameters
 /// of a route, the call can be just made with an valid token in the header.
 pub struct AuthenticatedUser {
     /// The internally used ID for the current user.
     pub id: i32,
-    /// The Personal Access Token which was used or an empty string if the user used a access token.
+    /// The Personal Access Token of the session which was used (either directly or by an access
+    /// token issued for the session) or an empty string if the access token is not bound to one.
     pub used_pat: String,
+    /// A flag which indicates if the user is an administrator of this instance.
+    pub is_admin: bool,
+}
+
+/// The representation of an authenticated user with administrative privileges. As soon as this is
+/// included in the parameters of a route, the route can only be called by administrators.
+pub struct AdminUser {
+    /// The internally used ID for the current administrator.
+    pub id: i32,
 }
 
 #[derive(Queryable, Clone)]
@@ -21,9 +34,11 @@ pub struct PersonalAccessToken {
     pub disabled: bool,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    pub user_agent: Option<String>,
+    pub last_used_at: Option<DateTime<Utc>>,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub enum AuthorizationError {
     /// Could not find any authentication header in the request.
     MissingAuthorizationHeader,
@@ -31,6 +46,12 @@ pub enum AuthorizationError {
     MalformedAuthorizationHeader,
     /// It seems that the supplied token is not valid (e.g. signature validation failed)
     InvalidToken,
+    /// The account of the user was disabled by an administrator.
+    AccountDisabled,
+    /// The user is authenticated but not allowed to use the route (e.g. it is not an administrator).
+    InsufficientPrivileges,
+    /// The credentials could not be checked since no database connection was available.
+    DatabaseUnavailable,
 }
 
 impl<'r> AuthenticatedUser {
@@ -39,30 +60,53 @@ impl<'r> AuthenticatedUser {
         request: &'r Request<'_>,
     ) -> Outcome<AuthenticatedUser, AuthorizationError> {
         use crate::fairings::MinneDatabaseConnection;
-        use crate::schema::personal_access_tokens::{disabled, secret, table, token};
+        use crate::schema::personal_access_tokens::{disabled, last_used_at, secret, table, token};
+        use crate::schema::users;
+        use chrono::Duration;
         use diesel::ExpressionMethods;
         use diesel::{QueryDsl, RunQueryDsl};
-        use log::{debug, trace};
+        use log::{debug, error, trace};
         use rocket::http::Status;
 
         // ensure that we know which flow we are using
         debug!("Using the Personal Access Token authentication flow");
 
         // get a database connection from the connection pool to fetch more token information
-        let db_connection_pool = request
+        let db_connection = &mut match request
             .rocket()
             .state::<MinneDatabaseConnection>()
-            .expect("Could not get a database connection from the pool");
+            .expect("Could not get the database connection pool")
+            .get()
+        {
+            Ok(connection) => connection,
+            Err(error) => {
+                error!(
+                    "Could not get a connection from the database connection pool. The error was: {}",
+                    error
+                );
+                return Outcome::Failure((
+                    Status::ServiceUnavailable,
+                    AuthorizationError::DatabaseUnavailable,
+                ));
+            }
+        };
 
         // get the personal access token entry from the database based on the supplied token
         let token_and_secret = authorization_information[1]
             .split(':')
             .collect::<Vec<&str>>();
+        if token_and_secret.len() != 2 {
+            error!("It seems that the personal access token is malformed. We expected the format 'token:secret'");
+            return Outcome::Failure((
+                Status::Forbidden,
+                AuthorizationError::MalformedAuthorizationHeader,
+            ));
+        }
         let pat = table
             .filter(token.eq(token_and_secret[0]))
             .filter(secret.eq(token_and_secret[1]))
             .filter(disabled.eq(false))
-            .first::<PersonalAccessToken>(&mut db_connection_pool.get().unwrap());
+            .first::<PersonalAccessToken>(db_connection);
 
         // if no pat could be found return an error
         if pat.is_err() {
@@ -75,11 +119,56 @@ impl<'r> AuthenticatedUser {
             return Outcome::Failure((Status::Forbidden, AuthorizationError::InvalidToken));
         }
 
-        // otherwise it seems that the user is authenticated and we can return the corresponding data structure
+        // get the state of the account the token belongs to
         let unwrapped_pat = pat.unwrap();
+        let (is_disabled, is_admin) = match users::table
+            .find(unwrapped_pat.user_id)
+            .select((users::disabled, users::is_admin))
+            .first::<(bool, bool)>(db_connection)
+        {
+            Ok(account_state) => account_state,
+            Err(error) => {
+                error!(
+                    "Could not get the user the personal access token belongs to. The error was: {}",
+                    error
+                );
+                return Outcome::Failure((Status::Forbidden, AuthorizationError::InvalidToken));
+            }
+        };
+
+        // the tokens of disabled accounts cannot be used anymore
+        if is_disabled {
+            debug!(
+                "The account of the user with the id {} is disabled",
+                unwrapped_pat.user_id
+            );
+            return Outcome::Failure((Status::Forbidden, AuthorizationError::AccountDisabled));
+        }
+
+        // remember when the token was used the last time, but do not write on every single request
+        let last_seen_update_due = match unwrapped_pat.last_used_at {
+            Some(last_used) => {
+                Utc::now() - last_used > Duration::seconds(LAST_USED_UPDATE_INTERVAL_IN_SECONDS)
+            }
+            None => true,
+        };
+        if last_seen_update_due {
+            if let Err(error) = diesel::update(table.find(unwrapped_pat.id))
+                .set(last_used_at.eq(diesel::dsl::now))
+                .execute(db_connection)
+            {
+                error!(
+                    "Could not update the last usage of the personal access token with the id {}. The error was: {}",
+                    unwrapped_pat.id, error
+                );
+            }
+        }
+
+        // otherwise it seems that the user is authenticated and we can return the corresponding data structure
         Outcome::Success(AuthenticatedUser {
             id: unwrapped_pat.user_id,
             used_pat: unwrapped_pat.token,
+            is_admin,
         })
     }
 
@@ -87,42 +176,58 @@ impl<'r> AuthenticatedUser {
         authorization_information: Vec<&str>,
         request: &'r Request<'_>,
     ) -> Outcome<AuthenticatedUser, AuthorizationError> {
-        use crate::fairings::{BackendConfiguration, MinneDatabaseConnection};
+        use crate::fairings::MinneDatabaseConnection;
         use crate::routes::auth::Claims;
-        use crate::schema::users::{dsl::users, email, id};
+        use crate::schema::users::{dsl::users, id, is_admin, tokens_revoked_at};
+        use crate::schema::{personal_access_tokens, session_access_tokens};
+        use crate::signing::TokenSigningKeys;
         use diesel::ExpressionMethods;
         use diesel::{QueryDsl, RunQueryDsl};
-        use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+        use jsonwebtoken::{decode, decode_header, Validation};
         use log::{debug, error};
         use rocket::http::Status;
 
         // ensure that we know which flow we are using
         debug!("Using the Bearer authentication flow");
 
-        // specify the parameter for the validation of the token
-        let mut validation_parameter = Validation::new(Algorithm::HS512);
-        validation_parameter.leeway = 5; // allow a time difference of max. 5 seconds
-        validation_parameter.validate_exp = true;
-        validation_parameter.validate_nbf = true;
+        // get the keys which can be used for validating the token
+        let signing_keys = request
+            .rocket()
+            .state::<TokenSigningKeys>()
+            .expect("Could not get the keys for validating the token");
 
-        // get the current backend configuration for the token signature psk
-        let backend_config = request.rocket().state::<BackendConfiguration>().map_or(
-            BackendConfiguration {
-                token_signature_psk: "".to_string(),
-                access_token_lifetime_in_seconds: 0,
-                refresh_token_lifetime_in_seconds: 0,
-                user_registration_enabled: false,
+        // select the key based on the key id in the header of the token
+        let signing_key = match decode_header(authorization_information[1]) {
+            Ok(header) => match signing_keys.find(header.kid.as_deref()) {
+                Some(signing_key) => signing_key,
+                None => {
+                    error!(
+                        "The supplied token was signed with an unknown key ({:?})",
+                        header.kid
+                    );
+                    return Outcome::Failure((Status::Forbidden, AuthorizationError::InvalidToken));
+                }
             },
-            |config| config.clone(),
-        );
+            Err(error) => {
+                error!(
+                    "The header of the supplied token is invalid. The error was: {}",
+                    error
+                );
+                return Outcome::Failure((Status::Forbidden, AuthorizationError::InvalidToken));
+            }
+        };
 
-        // get the 'validation' key for the token
-        let decoding_key = DecodingKey::from_secret(backend_config.token_signature_psk.as_ref());
+        // specify the parameter for the validation of the token, only the algorithm of the
+        // selected key is accepted
+        let mut validation_parameter = Validation::new(signing_key.algorithm);
+        validation_parameter.leeway = 5; // allow a time difference of max. 5 seconds
+        validation_parameter.validate_exp = true;
+        validation_parameter.validate_nbf = true;
 
         // verify the validity of the token supplied in the header
         let decoded_token = match decode::<Claims>(
             authorization_information[1],
-            &decoding_key,
+            signing_key.decoding_key(),
             &validation_parameter,
         ) {
             Ok(token) => token,
@@ -135,25 +240,119 @@ impl<'r> AuthenticatedUser {
             }
         };
 
+        // tokens issued to OpenID Connect clients are only meant for the userinfo endpoint
+        if let Some(audience) = &decoded_token.claims.aud {
+            error!(
+                "The supplied token was issued for the OpenID Connect client '{}' and cannot be used for the API",
+                audience
+            );
+            return Outcome::Failure((Status::Forbidden, AuthorizationError::InvalidToken));
+        }
+
+        // the subject of the token is the id of the user it was issued for
+        let subject = match decoded_token.claims.sub.parse::<i32>() {
+            Ok(subject) => subject,
+            Err(_) => {
+                error!(
+                    "The subject '{}' of the supplied token is not a user id",
+                    decoded_token.claims.sub
+                );
+                return Outcome::Failure((Status::Forbidden, AuthorizationError::InvalidToken));
+            }
+        };
+
         // get a database connection from the connection pool to fetch more user information
-        let db_connection_pool = request
+        let db_connection = &mut match request
             .rocket()
             .state::<MinneDatabaseConnection>()
-            .expect("Could not get a database connection from the pool");
+            .expect("Could not get the database connection pool")
+            .get()
+        {
+            Ok(connection) => connection,
+            Err(error) => {
+                error!(
+                    "Could not get a connection from the database connection pool. The error was: {}",
+                    error
+                );
+                return Outcome::Failure((
+                    Status::ServiceUnavailable,
+                    AuthorizationError::DatabaseUnavailable,
+                ));
+            }
+        };
 
-        // get the user id using diesel based on the supplied JWT tokens subject
-        let user_id = users
-            .select(id)
-            .filter(email.eq(decoded_token.claims.sub.clone()))
-            .first::<i32>(&mut db_connection_pool.get().unwrap())
-            .unwrap();
+        // get the user id using diesel based on the supplied JWT tokens subject, if the user does
+        // not exist anymore (e.g. the account was deleted), the token is not valid anymore
+        let (user_id, is_disabled, revoked_at, user_is_admin) = match users
+            .select((
+                id,
+                crate::schema::users::disabled,
+                tokens_revoked_at,
+                is_admin,
+            ))
+            .filter(id.eq(subject))
+            .first::<(i32, bool, Option<DateTime<Utc>>, bool)>(db_connection)
+        {
+            Ok(user_information) => user_information,
+            Err(error) => {
+                error!(
+                    "Could not find the user for the supplied token. The error was: {}",
+                    error
+                );
+                return Outcome::Failure((Status::Forbidden, AuthorizationError::InvalidToken));
+            }
+        };
+
+        // the tokens of disabled accounts cannot be used anymore
+        if is_disabled {
+            debug!(
+                "The account of the user with the id {} is disabled",
+                user_id
+            );
+            return Outcome::Failure((Status::Forbidden, AuthorizationError::AccountDisabled));
+        }
+
+        // tokens which were issued before all tokens of the user were revoked are not valid anymore
+        if let Some(revoked_at) = revoked_at {
+            if decoded_token.claims.iat as i64 <= revoked_at.timestamp() {
+                debug!(
+                    "The supplied token was issued before all tokens of the user with the id {} were revoked",
+                    user_id
+                );
+                return Outcome::Failure((Status::Forbidden, AuthorizationError::InvalidToken));
+            }
+        }
+
+        // tokens which were issued for a session are only valid as long as the session is
+        let used_pat = match &decoded_token.claims.jti {
+            Some(token_id) => match session_access_tokens::table
+                .inner_join(personal_access_tokens::table)
+                .filter(session_access_tokens::jti.eq(token_id))
+                .filter(session_access_tokens::expires_at.gt(diesel::dsl::now))
+                .filter(personal_access_tokens::user_id.eq(user_id))
+                .filter(personal_access_tokens::disabled.eq(false))
+                .select(personal_access_tokens::token)
+                .first::<String>(db_connection)
+            {
+                Ok(session_token) => session_token,
+                Err(error) => {
+                    debug!(
+                        "The session the supplied token was issued for is not valid anymore. The error was: {}",
+                        error
+                    );
+                    return Outcome::Failure((Status::Forbidden, AuthorizationError::InvalidToken));
+                }
+            },
+            None => "".to_string(),
+        };
 
         // if we reach this step, the validation was successful, and we can allow the user to
         // call the route
-        return Outcome::Success(AuthenticatedUser {
+        Outcome::Success(AuthenticatedUser {
             id: user_id,
-            used_pat: "".to_string(),
-        });
+            used_pat,
+            is_admin: user_is_admin,
+        })
     }
 }
 
@@ -163,6 +362,27 @@ impl<'r> FromRequest<'r> for AuthenticatedUser {
 
     async fn from_request(
         request: &'r Request<'_>,
+    ) -> Outcome<AuthenticatedUser, AuthorizationError> {
+        use crate::logging::set_current_user;
+        use crate::problem::AuthorizationFailure;
+
+        // remember why the authentication failed, so the error catcher can tell the client, or
+        // who sent the request, so it can be found in the logs
+        let outcome = Self::authenticate(request).await;
+        match &outcome {
+            Outcome::Success(authenticated_user) => set_current_user(authenticated_user.id),
+            Outcome::Failure((_, authorization_error)) => {
+                request.local_cache(|| AuthorizationFailure(Some(*authorization_error)));
+            }
+            Outcome::Forward(_) => {}
+        }
+        outcome
+    }
+}
+
+impl<'r> AuthenticatedUser {
+    async fn authenticate(
+        request: &'r Request<'_>,
     ) -> Outcome<AuthenticatedUser, AuthorizationError> {
         use log::error;
         use rocket::http::Status;
@@ -182,7 +402,7 @@ impl<'r> FromRequest<'r> for AuthenticatedUser {
                 }
 
                 // we support bearer and pat authentication flows
-                return match authorization_information[0].to_lowercase().as_ref() {
+                match authorization_information[0].to_lowercase().as_ref() {
                     "bearer" => Self::bearer_flow(authorization_information, request).await,
                     "pat" => Self::pat_flow(authorization_information, request).await,
                     _ => {
@@ -192,7 +412,7 @@ impl<'r> FromRequest<'r> for AuthenticatedUser {
                             AuthorizationError::MalformedAuthorizationHeader,
                         ))
                     }
-                };
+                }
             }
             _ => {
                 error!("No authorization header could be found for an authenticated route!");
@@ -204,3 +424,39 @@ impl<'r> FromRequest<'r> for AuthenticatedUser {
         }
     }
 }
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminUser {
+    type Error = AuthorizationError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<AdminUser, AuthorizationError> {
+        use crate::problem::AuthorizationFailure;
+        use log::warn;
+        use rocket::http::Status;
+
+        // the user has to be authenticated first
+        let authenticated_user = match request.guard::<AuthenticatedUser>().await {
+            Outcome::Success(authenticated_user) => authenticated_user,
+            Outcome::Failure(failure) => return Outcome::Failure(failure),
+            Outcome::Forward(forward) => return Outcome::Forward(forward),
+        };
+
+        // only administrators are allowed to use the route
+        if !authenticated_user.is_admin {
+            warn!(
+                "The user with the id {} tried to access an administrative route",
+                authenticated_user.id
+            );
+            request.local_cache(|| {
+                AuthorizationFailure(Some(AuthorizationError::InsufficientPrivileges))
+            });
+            return Outcome::Failure((
+                Status::Forbidden,
+                AuthorizationError::InsufficientPrivileges,
+            ));
+        }
+        Outcome::Success(AdminUser {
+            id: authenticated_user.id,
+        })
+    }
+}
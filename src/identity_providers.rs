@@ -0,0 +1,419 @@
+use rocket::serde::json::serde_json::Value;
+use serde::Deserialize;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// The number of seconds to wait for a response of an identity provider.
+const HTTP_TIMEOUT_IN_SECONDS: u64 = 10;
+
+/// The scopes which are requested if the provider configuration does not list any.
+const DEFAULT_SCOPES: &str = "openid email profile";
+
+/// The number of seconds the clocks of the identity provider and the backend may differ.
+const ID_TOKEN_LEEWAY_IN_SECONDS: u64 = 60;
+
+/// The reasons why the login with an external identity provider failed.
+#[derive(Debug)]
+pub enum IdentityProviderError {
+    /// The configuration of the identity providers is incomplete or could not be parsed.
+    InvalidConfiguration(String),
+    /// The communication with the identity provider failed.
+    Http(String),
+    /// The ID token returned by the identity provider is not valid.
+    InvalidIdToken(String),
+}
+
+impl Display for IdentityProviderError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IdentityProviderError::InvalidConfiguration(reason) => write!(
+                f,
+                "the identity provider configuration is invalid: {}",
+                reason
+            ),
+            IdentityProviderError::Http(reason) => write!(
+                f,
+                "the communication with the identity provider failed: {}",
+                reason
+            ),
+            IdentityProviderError::InvalidIdToken(reason) => {
+                write!(f, "the ID token is invalid: {}", reason)
+            }
+        }
+    }
+}
+
+/// The content of an identity provider configuration file.
+#[derive(Deserialize)]
+struct IdentityProviderSetConfiguration {
+    /// All identity providers which can be used for a login.
+    #[serde(default)]
+    providers: Vec<IdentityProviderConfiguration>,
+}
+
+/// A single identity provider as it is configured in the configuration file.
+#[derive(Deserialize)]
+struct IdentityProviderConfiguration {
+    /// The id of the provider which is used in the login URLs (e.g. `company-sso`).
+    id: String,
+    /// The name of the provider which is shown on the login button.
+    name: String,
+    /// The issuer of the provider, the discovery document is fetched relative to it.
+    issuer: String,
+    /// The id of the client registered at the provider.
+    client_id: String,
+    /// The secret of the client registered at the provider (if it is a confidential client).
+    client_secret: Option<String>,
+    /// The path to a file containing the secret of the client (instead of `client_secret`).
+    client_secret_file: Option<PathBuf>,
+    /// The scopes which are requested (default: `openid email profile`).
+    scopes: Option<String>,
+    /// Whether new accounts can be created for unknown users (default: `true`, only if the user
+    /// registration is enabled as well).
+    allow_registration: Option<bool>,
+    /// Whether the provider enforces a second factor itself, so users who enabled a second factor
+    /// for their Minne account can log in with the provider as well (default: `false`).
+    enforces_second_factor: Option<bool>,
+}
+
+/// The parts of the discovery document of an identity provider the backend uses.
+#[derive(Clone, Deserialize)]
+pub struct IdentityProviderMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+}
+
+/// The parts of the token response of an identity provider the backend uses.
+#[derive(Deserialize)]
+struct IdentityProviderTokenResponse {
+    id_token: String,
+}
+
+/// The claims about the user which are taken from the ID token of an identity provider.
+#[derive(Deserialize)]
+pub struct ExternalIdentityClaims {
+    /// The identifier of the user at the identity provider.
+    pub sub: String,
+    /// The email address of the user.
+    pub email: Option<String>,
+    /// Whether the identity provider verified the email address (some providers use a string).
+    email_verified: Option<Value>,
+    /// The full name of the user.
+    pub name: Option<String>,
+    /// The first name of the user.
+    pub given_name: Option<String>,
+    /// The last name of the user.
+    pub family_name: Option<String>,
+    /// The nonce which was sent with the authorization request.
+    nonce: Option<String>,
+}
+
+impl ExternalIdentityClaims {
+    /// Check if the identity provider verified the email address of the user.
+    pub fn is_email_verified(&self) -> bool {
+        match &self.email_verified {
+            Some(Value::Bool(verified)) => *verified,
+            Some(Value::String(verified)) => verified.eq_ignore_ascii_case("true"),
+            _ => false,
+        }
+    }
+}
+
+/// An external OpenID Connect provider the users can log in with.
+pub struct IdentityProvider {
+    /// The id of the provider which is used in the login URLs.
+    pub id: String,
+    /// The name of the provider which is shown on the login button.
+    pub name: String,
+    /// The issuer of the provider.
+    pub issuer: String,
+    /// Whether new accounts can be created for unknown users.
+    pub allow_registration: bool,
+    /// Whether the provider enforces a second factor, so users with a second factor can use it.
+    pub enforces_second_factor: bool,
+    client_id: String,
+    client_secret: Option<String>,
+    scopes: String,
+    http_client: reqwest::Client,
+    metadata: Mutex<Option<IdentityProviderMetadata>>,
+}
+
+impl IdentityProvider {
+    /// Get the discovery document of the provider. It is fetched on the first use and cached
+    /// afterwards.
+    pub async fn metadata(&self) -> Result<IdentityProviderMetadata, IdentityProviderError> {
+        use log::info;
+
+        if let Some(metadata) = self.metadata.lock().unwrap().as_ref() {
+            return Ok(metadata.clone());
+        }
+
+        // fetch the discovery document and ensure that it belongs to the configured issuer
+        let discovery_url = format!(
+            "{}/.well-known/openid-configuration",
+            self.issuer.trim_end_matches('/')
+        );
+        let metadata = self
+            .http_client
+            .get(&discovery_url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|error| IdentityProviderError::Http(error.to_string()))?
+            .json::<IdentityProviderMetadata>()
+            .await
+            .map_err(|error| IdentityProviderError::Http(error.to_string()))?;
+        if metadata.issuer != self.issuer {
+            return Err(IdentityProviderError::InvalidConfiguration(format!(
+                "the provider '{}' announced the issuer '{}' instead of '{}'",
+                self.id, metadata.issuer, self.issuer
+            )));
+        }
+        info!(
+            "Fetched the discovery document of the identity provider '{}'",
+            self.id
+        );
+        *self.metadata.lock().unwrap() = Some(metadata.clone());
+        Ok(metadata)
+    }
+
+    /// Get the URL the user has to be redirected to for logging in at the provider.
+    pub async fn authorization_url(
+        &self,
+        redirect_uri: &str,
+        state: &str,
+        nonce: &str,
+        code_challenge: &str,
+    ) -> Result<String, IdentityProviderError> {
+        use rocket::http::RawStr;
+
+        let metadata = self.metadata().await?;
+        let parameters = [
+            ("response_type", "code"),
+            ("client_id", self.client_id.as_str()),
+            ("redirect_uri", redirect_uri),
+            ("scope", self.scopes.as_str()),
+            ("state", state),
+            ("nonce", nonce),
+            ("code_challenge", code_challenge),
+            ("code_challenge_method", "S256"),
+        ];
+        let query = parameters
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, RawStr::new(value).percent_encode()))
+            .collect::<Vec<String>>()
+            .join("&");
+        let separator = if metadata.authorization_endpoint.contains('?') {
+            '&'
+        } else {
+            '?'
+        };
+        Ok(format!(
+            "{}{}{}",
+            metadata.authorization_endpoint, separator, query
+        ))
+    }
+
+    /// Exchange the authorization code returned by the provider for an ID token and return the
+    /// claims of the validated token.
+    pub async fn exchange_code(
+        &self,
+        redirect_uri: &str,
+        code: &str,
+        code_verifier: &str,
+        expected_nonce: &str,
+    ) -> Result<ExternalIdentityClaims, IdentityProviderError> {
+        use jsonwebtoken::jwk::JwkSet;
+        use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+
+        // request the tokens from the provider
+        let metadata = self.metadata().await?;
+        let mut token_request = self.http_client.post(&metadata.token_endpoint).form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", redirect_uri),
+            ("client_id", self.client_id.as_str()),
+            ("code_verifier", code_verifier),
+        ]);
+        if let Some(client_secret) = &self.client_secret {
+            token_request = token_request.basic_auth(&self.client_id, Some(client_secret));
+        }
+        let id_token = token_request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|error| IdentityProviderError::Http(error.to_string()))?
+            .json::<IdentityProviderTokenResponse>()
+            .await
+            .map_err(|error| IdentityProviderError::Http(error.to_string()))?
+            .id_token;
+
+        // only asymmetric signatures can be validated with the published keys of the provider
+        let header = decode_header(&id_token)
+            .map_err(|error| IdentityProviderError::InvalidIdToken(error.to_string()))?;
+        if matches!(
+            header.alg,
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+        ) {
+            return Err(IdentityProviderError::InvalidIdToken(format!(
+                "the algorithm {:?} is not supported",
+                header.alg
+            )));
+        }
+
+        // fetch the keys of the provider to find the one the token was signed with
+        let key_set = self
+            .http_client
+            .get(&metadata.jwks_uri)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|error| IdentityProviderError::Http(error.to_string()))?
+            .json::<JwkSet>()
+            .await
+            .map_err(|error| IdentityProviderError::Http(error.to_string()))?;
+        let key = match header.kid.as_deref() {
+            Some(key_id) => key_set.find(key_id),
+            None if key_set.keys.len() == 1 => key_set.keys.first(),
+            None => None,
+        }
+        .ok_or_else(|| {
+            IdentityProviderError::InvalidIdToken("the signing key is unknown".to_string())
+        })?;
+        let decoding_key = DecodingKey::from_jwk(key)
+            .map_err(|error| IdentityProviderError::InvalidIdToken(error.to_string()))?;
+
+        // validate the signature as well as the issuer, the audience and the lifetime
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&metadata.issuer]);
+        validation.set_audience(&[&self.client_id]);
+        validation.leeway = ID_TOKEN_LEEWAY_IN_SECONDS;
+        let claims = decode::<ExternalIdentityClaims>(&id_token, &decoding_key, &validation)
+            .map_err(|error| IdentityProviderError::InvalidIdToken(error.to_string()))?
+            .claims;
+
+        // the nonce binds the token to the login which was started by the backend
+        if claims.nonce.as_deref() != Some(expected_nonce) {
+            return Err(IdentityProviderError::InvalidIdToken(
+                "the nonce does not match".to_string(),
+            ));
+        }
+        Ok(claims)
+    }
+}
+
+/// All external identity providers the users can log in with.
+pub struct IdentityProviders {
+    providers: Vec<IdentityProvider>,
+}
+
+impl IdentityProviders {
+    /// Create an empty set of providers which is used if no providers are configured.
+    pub fn empty() -> Self {
+        IdentityProviders {
+            providers: Vec::new(),
+        }
+    }
+
+    /// Load the providers from a TOML file which lists all of them as `[[providers]]`.
+    pub fn from_file(path: &Path) -> Result<Self, IdentityProviderError> {
+        use crate::settings::read_secret_file;
+        use log::warn;
+        use rocket::figment::providers::{Format, Toml};
+        use rocket::figment::Figment;
+        use std::time::Duration;
+
+        let configuration = Figment::from(Toml::file(path))
+            .extract::<IdentityProviderSetConfiguration>()
+            .map_err(|error| IdentityProviderError::InvalidConfiguration(error.to_string()))?;
+        let http_client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(HTTP_TIMEOUT_IN_SECONDS))
+            .build()
+            .map_err(|error| IdentityProviderError::InvalidConfiguration(error.to_string()))?;
+
+        // the ids are used in the URLs, so they have to be unique and URL safe
+        let mut providers: Vec<IdentityProvider> = Vec::new();
+        for provider_configuration in configuration.providers {
+            if provider_configuration.id.is_empty()
+                || !provider_configuration.id.chars().all(|character| {
+                    character.is_ascii_lowercase()
+                        || character.is_ascii_digit()
+                        || character == '-'
+                        || character == '_'
+                })
+            {
+                return Err(IdentityProviderError::InvalidConfiguration(format!(
+                    "the provider id '{}' may only contain lowercase letters, digits, '-' and '_'",
+                    provider_configuration.id
+                )));
+            }
+            if providers
+                .iter()
+                .any(|provider| provider.id == provider_configuration.id)
+            {
+                return Err(IdentityProviderError::InvalidConfiguration(format!(
+                    "the provider id '{}' is used more than once",
+                    provider_configuration.id
+                )));
+            }
+            let client_secret = match (
+                provider_configuration.client_secret,
+                provider_configuration.client_secret_file,
+            ) {
+                (Some(_), Some(_)) => {
+                    return Err(IdentityProviderError::InvalidConfiguration(format!(
+                        "the provider '{}' can either use client_secret or client_secret_file",
+                        provider_configuration.id
+                    )))
+                }
+                (None, Some(client_secret_file)) => match read_secret_file(&client_secret_file) {
+                    Ok((client_secret, warning)) => {
+                        if let Some(warning) = warning {
+                            warn!("{}", warning);
+                        }
+                        Some(client_secret)
+                    }
+                    Err(problem) => {
+                        return Err(IdentityProviderError::InvalidConfiguration(format!(
+                            "the client secret of the provider '{}' could not be read: {}",
+                            provider_configuration.id, problem
+                        )))
+                    }
+                },
+                (client_secret, None) => client_secret,
+            };
+            providers.push(IdentityProvider {
+                id: provider_configuration.id,
+                name: provider_configuration.name,
+                issuer: provider_configuration.issuer,
+                allow_registration: provider_configuration.allow_registration.unwrap_or(true),
+                enforces_second_factor: provider_configuration
+                    .enforces_second_factor
+                    .unwrap_or(false),
+                client_id: provider_configuration.client_id,
+                client_secret: client_secret.filter(|client_secret| !client_secret.is_empty()),
+                scopes: provider_configuration
+                    .scopes
+                    .unwrap_or_else(|| DEFAULT_SCOPES.to_string()),
+                http_client: http_client.clone(),
+                metadata: Mutex::new(None),
+            });
+        }
+        Ok(IdentityProviders { providers })
+    }
+
+    /// Get the provider with the supplied id.
+    pub fn find(&self, provider_id: &str) -> Option<&IdentityProvider> {
+        self.providers
+            .iter()
+            .find(|provider| provider.id == provider_id)
+    }
+
+    /// Get all configured providers.
+    pub fn providers(&self) -> &[IdentityProvider] {
+        &self.providers
+    }
+}
@@ -1,13 +1,37 @@
 #[macro_use]
 extern crate diesel;
 
+pub mod audit;
+pub mod cli;
+pub mod crypto;
 pub mod fairings;
+pub mod identity_providers;
+pub mod logging;
+pub mod metrics;
+pub mod migrations;
+pub mod password_policy;
 pub mod routes {
+    pub mod admin;
+    pub mod audit;
     pub mod auth;
+    pub mod device;
+    pub mod export;
+    pub mod external_login;
     pub mod health;
+    pub mod invitation;
+    pub mod jwks;
+    pub mod metrics;
+    pub mod oidc;
+    pub mod session;
     pub mod task;
+    pub mod totp;
     pub mod user;
     pub mod version;
 }
 pub mod guards;
+pub mod passwords;
+pub mod problem;
 pub mod schema;
+pub mod settings;
+pub mod signing;
+pub mod throttling;
@@ -0,0 +1,247 @@
+use rocket::http::Header;
+use rocket::route::{Handler, Outcome, Route};
+use rocket::{Data, Request};
+use std::cell::Cell;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// The name of the header which carries the id of a request.
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// The maximum length of a request id which is accepted from a client.
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+rocket::tokio::task_local! {
+    /// The context of the request which is handled by the current task (if any).
+    static REQUEST_CONTEXT: RequestContext;
+}
+
+/// The information about the request which is currently handled, added to every log line.
+struct RequestContext {
+    /// The id of the request, either supplied by the client or generated.
+    request_id: String,
+    /// The method of the request (e.g. `GET`).
+    method: String,
+    /// The route which handles the request (e.g. `/v1/task/<task_id>`).
+    route: String,
+    /// The id of the user who sent the request, known after the authentication.
+    user_id: Cell<Option<i32>>,
+}
+
+/// The id of a request, either supplied by the client with the `X-Request-Id` header or generated.
+pub struct RequestId(pub String);
+
+impl RequestId {
+    /// Get the id of the supplied request. The id supplied by the client is used if it is safe to
+    /// be put into the logs, otherwise a new one is generated.
+    pub fn of<'r>(request: &'r Request<'_>) -> &'r RequestId {
+        use uuid::Uuid;
+
+        request.local_cache(|| {
+            let supplied_id = request
+                .headers()
+                .get_one(REQUEST_ID_HEADER)
+                .map(str::trim)
+                .filter(|request_id| is_valid_request_id(request_id));
+            RequestId(match supplied_id {
+                Some(request_id) => request_id.to_string(),
+                None => Uuid::new_v4().to_string(),
+            })
+        })
+    }
+
+    /// Get the header which tells the client the id of the request.
+    pub fn header(&self) -> Header<'static> {
+        Header::new(REQUEST_ID_HEADER, self.0.clone())
+    }
+}
+
+/// Check if a request id supplied by a client can be used (it is not too long and only consists
+/// of characters which cannot be used for forging log lines).
+fn is_valid_request_id(request_id: &str) -> bool {
+    !request_id.is_empty()
+        && request_id.len() <= MAX_REQUEST_ID_LENGTH
+        && request_id
+            .chars()
+            .all(|character| character.is_ascii_alphanumeric() || "-_.:".contains(character))
+}
+
+/// A handler which runs the handler of a route (including its request guards) with the context of
+/// the request, so that all log lines emitted while handling it can be correlated.
+#[derive(Clone)]
+struct ContextHandler(Box<dyn Handler>);
+
+#[rocket::async_trait]
+impl Handler for ContextHandler {
+    async fn handle<'r>(&self, request: &'r Request<'_>, data: Data<'r>) -> Outcome<'r> {
+        let context = RequestContext {
+            request_id: RequestId::of(request).0.clone(),
+            method: request.method().as_str().to_string(),
+            route: request
+                .route()
+                .map(|route| route.uri.as_str().to_string())
+                .unwrap_or_default(),
+            user_id: Cell::new(None),
+        };
+        REQUEST_CONTEXT
+            .scope(context, self.0.handle(request, data))
+            .await
+    }
+}
+
+/// Ensure that the supplied routes are handled with the context of the request.
+pub fn with_request_context(routes: impl Into<Vec<Route>>) -> Vec<Route> {
+    routes
+        .into()
+        .into_iter()
+        .map(|mut route| {
+            route.handler = Box::new(ContextHandler(route.handler));
+            route
+        })
+        .collect()
+}
+
+/// Remember the user who sent the request which is currently handled (if any).
+pub fn set_current_user(user_id: i32) {
+    let _ = REQUEST_CONTEXT.try_with(|context| context.user_id.set(Some(user_id)));
+}
+
+/// Format a log line as human-readable text.
+pub fn format_text_line(record: &log::Record, message: &std::fmt::Arguments) -> String {
+    use chrono::Utc;
+
+    let context = REQUEST_CONTEXT
+        .try_with(|context| {
+            let mut formatted = format!(
+                "[request_id={} route=\"{} {}\"",
+                context.request_id, context.method, context.route
+            );
+            if let Some(user_id) = context.user_id.get() {
+                formatted.push_str(&format!(" user_id={}", user_id));
+            }
+            formatted.push_str("] ");
+            formatted
+        })
+        .unwrap_or_default();
+    format!(
+        "{}[{}][{}] {}{}",
+        Utc::now().format("[%Y-%m-%d][%H:%M:%S]"),
+        record.target(),
+        record.level(),
+        context,
+        message
+    )
+}
+
+/// Format a log line as a single JSON object, e.g. for log aggregation systems.
+pub fn format_json_line(record: &log::Record, message: &std::fmt::Arguments) -> String {
+    use chrono::{SecondsFormat, Utc};
+    use rocket::serde::json::{serde_json, Value};
+
+    let mut line = serde_json::Map::new();
+    line.insert(
+        "timestamp".to_string(),
+        Value::from(Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)),
+    );
+    line.insert("level".to_string(), Value::from(record.level().as_str()));
+    line.insert("target".to_string(), Value::from(record.target()));
+    line.insert("message".to_string(), Value::from(message.to_string()));
+    let _ = REQUEST_CONTEXT.try_with(|context| {
+        line.insert(
+            "request_id".to_string(),
+            Value::from(context.request_id.as_str()),
+        );
+        line.insert("method".to_string(), Value::from(context.method.as_str()));
+        line.insert("route".to_string(), Value::from(context.route.as_str()));
+        if let Some(user_id) = context.user_id.get() {
+            line.insert("user_id".to_string(), Value::from(user_id));
+        }
+    });
+    Value::Object(line).to_string()
+}
+
+/// A log file which is rotated once it reaches the maximum size. The rotated files get the suffixes
+/// `.1` (the newest) to `.<max_files>` (the oldest), older files are deleted.
+pub struct RotatingFile {
+    /// The path of the file which is currently written.
+    path: PathBuf,
+    /// The size in bytes after which the file is rotated.
+    max_size_in_bytes: u64,
+    /// The number of rotated files which are kept.
+    max_files: usize,
+    /// The file which is currently written.
+    file: File,
+    /// The number of bytes which were written to the current file.
+    size_in_bytes: u64,
+    /// A flag which indicates if the next write starts a new line. Files are only rotated between
+    /// two lines, so that a line is never split.
+    at_line_start: bool,
+}
+
+impl RotatingFile {
+    /// Open the log file (new lines are appended) which is rotated once it reaches the supplied size.
+    pub fn open(path: &Path, max_size_in_bytes: u64, max_files: usize) -> io::Result<RotatingFile> {
+        let file = Self::open_for_appending(path)?;
+        let size_in_bytes = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            max_size_in_bytes,
+            max_files,
+            file,
+            size_in_bytes,
+            at_line_start: true,
+        })
+    }
+
+    fn open_for_appending(path: &Path) -> io::Result<File> {
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+    }
+
+    /// Get the path of the rotated file with the supplied number.
+    fn rotated_path(&self, number: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", number));
+        PathBuf::from(path)
+    }
+
+    /// Move the current file (and all rotated ones) by one position and start a new file.
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.max_files == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            for number in (1..self.max_files).rev() {
+                let rotated_path = self.rotated_path(number);
+                if rotated_path.exists() {
+                    std::fs::rename(&rotated_path, self.rotated_path(number + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.file = Self::open_for_appending(&self.path)?;
+        self.size_in_bytes = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        if self.at_line_start && self.size_in_bytes >= self.max_size_in_bytes {
+            self.rotate()?;
+        }
+        let written = self.file.write(buffer)?;
+        self.size_in_bytes += written as u64;
+        if written > 0 {
+            self.at_line_start = buffer[..written].ends_with(b"\n");
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
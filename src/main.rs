@@ -1,63 +1,243 @@
+use diesel::r2d2::ConnectionManager;
 use diesel::PgConnection;
-use diesel_migrations::{embed_migrations, EmbeddedMigrations};
 use log::LevelFilter;
-use rocket::figment::map;
+use minne_backend::cli::CommandLine;
+use minne_backend::fairings::MinneDatabaseConnection;
+use minne_backend::identity_providers::IdentityProviders;
+use minne_backend::problem::Problem;
+use minne_backend::settings::Settings;
+use minne_backend::signing::TokenSigningKeys;
 use rocket::get;
+use rocket::http::Status;
+use rocket::serde::json::json;
+use rocket::State;
 use rocket_dyn_templates::Template;
 
-pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations/");
+/// The exit code if the configuration could not be loaded or is invalid.
+const EXIT_CODE_INVALID_CONFIGURATION: i32 = 1;
+
+/// The exit code if the command line arguments could not be parsed.
+const EXIT_CODE_INVALID_ARGUMENTS: i32 = 2;
+
+/// The exit code if the database could not be reached before the startup deadline.
+const EXIT_CODE_DATABASE_UNAVAILABLE: i32 = 3;
+
+/// The exit code if an administrative command failed.
+const EXIT_CODE_COMMAND_FAILED: i32 = 4;
+
+/// The exit code if the database migrations failed or are pending while they are not run
+/// automatically.
+const EXIT_CODE_MIGRATIONS_FAILED: i32 = 5;
+
+/// The delay before the second attempt to connect to the database, it doubles with every attempt.
+const INITIAL_DATABASE_RETRY_DELAY_IN_MILLISECONDS: u64 = 250;
+
+/// The maximum delay between two attempts to connect to the database.
+const MAX_DATABASE_RETRY_DELAY_IN_MILLISECONDS: u64 = 8000;
 
 #[inline(always)]
 fn unset_environment_variable(name: &str) {
     std::env::remove_var(name)
 }
 
-pub fn run_migrations(connection: &mut PgConnection) {
-    use diesel_migrations::MigrationHarness;
-    use log::{error, info};
-    match connection.run_pending_migrations(MIGRATIONS) {
-        Ok(ran_migrations) => {
-            if !ran_migrations.is_empty() {
-                info!(
-                    "Successfully ran {} database migrations",
-                    ran_migrations.len()
-                );
-            } else {
-                info!("No migrations had to be run since the database is up to date");
+/// Connect to the database and create the connection pool. If the database cannot be reached
+/// (e.g. since the database server is still starting), the connection is retried with an
+/// exponential backoff until the deadline is reached.
+async fn connect_to_database(
+    database_connection_url: &str,
+    pool_size: u32,
+    deadline: std::time::Duration,
+) -> Result<r2d2::Pool<ConnectionManager<PgConnection>>, String> {
+    use diesel::Connection;
+    use log::{info, warn};
+    use std::time::{Duration, Instant};
+
+    let started_at = Instant::now();
+    let mut retry_delay = Duration::from_millis(INITIAL_DATABASE_RETRY_DELAY_IN_MILLISECONDS);
+    let mut attempt = 1;
+    loop {
+        // try to establish a single connection first, since it fails fast if the server is down
+        let error = match PgConnection::establish(database_connection_url) {
+            Ok(_) => {
+                return r2d2::Pool::builder()
+                    .max_size(pool_size)
+                    .build(ConnectionManager::new(database_connection_url))
+                    .map_err(|error| error.to_string())
             }
+            Err(error) => error
+                .to_string()
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" "),
+        };
+
+        // give up if the next attempt would be after the deadline
+        let elapsed = started_at.elapsed();
+        if elapsed + retry_delay > deadline {
+            return Err(format!(
+                "the database could not be reached within {} seconds ({} attempts), the last error was: {}",
+                deadline.as_secs(),
+                attempt, error
+            ));
         }
+        if attempt == 1 {
+            info!("The database is not reachable yet, waiting for it to start up...");
+        }
+        warn!(
+            "Could not connect to the database (attempt {}, retrying in {} ms). The error was: {}",
+            attempt,
+            retry_delay.as_millis(),
+            error
+        );
+        rocket::tokio::time::sleep(retry_delay).await;
+        retry_delay = (retry_delay * 2).min(Duration::from_millis(
+            MAX_DATABASE_RETRY_DELAY_IN_MILLISECONDS,
+        ));
+        attempt += 1;
+    }
+}
+
+/// Connect to the database for running a single administrative command. The process exits if the
+/// database cannot be reached.
+async fn connect_for_command(
+    settings: &Settings,
+) -> r2d2::PooledConnection<ConnectionManager<PgConnection>> {
+    use log::error;
+
+    let db_connection_pool = match connect_to_database(
+        settings.db_connection.as_deref().unwrap_or_default(),
+        1,
+        std::time::Duration::from_secs(settings.db_startup_timeout_in_seconds),
+    )
+    .await
+    {
+        Ok(db_connection_pool) => db_connection_pool,
+        Err(error) => {
+            error!("Could not connect to the database server: {}", error);
+            std::process::exit(EXIT_CODE_DATABASE_UNAVAILABLE);
+        }
+    };
+    match db_connection_pool.get() {
+        Ok(connection) => connection,
         Err(error) => {
             error!(
-                "Failed to run the database migrations. The error was: {}",
+                "Could not get a database connection from the connection pool. The error was: {}",
+                error
+            );
+            std::process::exit(EXIT_CODE_DATABASE_UNAVAILABLE);
+        }
+    }
+}
+
+/// Get the keys which are used to sign the access tokens, either from a key file which lists all
+/// keys (for the key rotation) or from the settings.
+fn load_token_signing_keys(settings: &Settings) -> Result<TokenSigningKeys, String> {
+    use chrono::Duration;
+
+    let access_token_lifetime = Duration::seconds(settings.access_token_lifetime_in_seconds as i64);
+    if let Some(keys_file) = &settings.token_signature_keys_file {
+        return TokenSigningKeys::from_file(keys_file, access_token_lifetime).map_err(|error| {
+            format!(
+                "Could not load the token signature keys from '{}'. The error was: {}",
+                keys_file.display(),
                 error
             )
+        });
+    }
+
+    let mut signing_keys = match settings.token_signature_key() {
+        Ok(Some(signing_key)) => TokenSigningKeys::new(signing_key),
+        Ok(None) => return Err("Could not get the token signature key. Ensure MINNE_TOKEN_SIGNATURE_PSK (or MINNE_TOKEN_SIGNATURE_KEY_FILE) is set properly".to_string()),
+        Err(error) => {
+            return Err(format!(
+                "Could not load the token signature key. The error was: {}",
+                error
+            ))
+        }
+    };
+
+    // the previous key is accepted until all tokens which were issued with it expired, this is
+    // based on the time it was retired, so the window does not restart with every start
+    match settings.previous_token_signature_key() {
+        Ok(Some(previous_key)) => {
+            let retired_at = settings.token_signature_previous_retired_at.ok_or_else(|| {
+                "Could not use the previous token signature key. Ensure MINNE_TOKEN_SIGNATURE_PREVIOUS_RETIRED_AT is set to the time the key was retired".to_string()
+            })?;
+            signing_keys
+                .add_previous(previous_key, retired_at + access_token_lifetime)
+                .map_err(|error| {
+                    format!(
+                        "Could not use the previous token signature key. The error was: {}",
+                        error
+                    )
+                })?
+        }
+        Ok(None) => {}
+        Err(error) => {
+            return Err(format!(
+                "Could not load the previous token signature key. The error was: {}",
+                error
+            ))
         }
     }
+    Ok(signing_keys)
 }
 
-fn setup_logging(logging_level: LevelFilter) {
-    use chrono::Utc;
+/// Get the external identity providers the users can log in with (if any).
+fn load_identity_providers(settings: &Settings) -> Result<IdentityProviders, String> {
+    match &settings.identity_providers_file {
+        Some(providers_file) => IdentityProviders::from_file(providers_file).map_err(|error| {
+            format!(
+                "Could not load the identity providers from '{}'. The error was: {}",
+                providers_file.display(),
+                error
+            )
+        }),
+        None => Ok(IdentityProviders::empty()),
+    }
+}
+
+fn setup_logging(settings: &Settings) -> Result<(), String> {
+    use minne_backend::logging::{format_json_line, format_text_line, RotatingFile};
+    use std::io::Write;
 
     // create an instance for the Dispatcher to create a new logging configuration
     let mut base_config = fern::Dispatch::new();
 
     // set the corresponding logging level
-    base_config = base_config.level(logging_level);
-
-    // define how a logging line should look like and attach the streams to which the output will be
-    // written to
-    let file_config = fern::Dispatch::new()
-        .format(|out, message, record| {
-            out.finish(format_args!(
-                "{}[{}][{}] {}",
-                Utc::now().format("[%Y-%m-%d][%H:%M:%S]"),
-                record.target(),
-                record.level(),
-                message
-            ))
+    base_config = base_config.level(settings.logging_level_filter().unwrap_or(LevelFilter::Info));
+
+    // define how a logging line should look like (including the context of the request which is
+    // currently handled) and attach the streams to which the output will be written to
+    let json_format = settings.log_format == "json";
+    let mut file_config = fern::Dispatch::new()
+        .format(move |out, message, record| {
+            let line = if json_format {
+                format_json_line(record, message)
+            } else {
+                format_text_line(record, message)
+            };
+            out.finish(format_args!("{}", line))
         })
         .chain(std::io::stderr());
 
+    // if requested, write the log lines to a file which is rotated once it gets too large
+    if let Some(log_file) = &settings.log_file {
+        let rotating_file = RotatingFile::open(
+            log_file,
+            settings.log_file_max_size_in_mib * 1024 * 1024,
+            settings.log_file_max_files,
+        )
+        .map_err(|error| {
+            format!(
+                "Could not open the log file '{}'. The error was: {}",
+                log_file.display(),
+                error
+            )
+        })?;
+        file_config = file_config.chain(Box::new(rotating_file) as Box<dyn Write + Send>);
+    }
+
     // now chain everything together and get ready for actually logging stuff
     base_config
         .chain(file_config)
@@ -69,29 +249,136 @@ fn setup_logging(logging_level: LevelFilter) {
         .level_for("rocket", LevelFilter::Error)
         .level_for("_", LevelFilter::Error)
         .apply()
-        .unwrap();
+        .map_err(|error| format!("Could not set up the logging. The error was: {}", error))
 }
 
-#[get("/")]
-fn show_login_page_to_user() -> Template {
-    use uuid::Uuid;
-    let context = map! {
-        "title" => "Minne iOS App Login".to_owned(),
-        "login_process_id" => Uuid::new_v4().to_string(),
+#[get("/?<code_challenge>&<code_challenge_method>&<state>")]
+fn show_login_page_to_user(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    identity_providers: &State<IdentityProviders>,
+    code_challenge: String,
+    code_challenge_method: Option<String>,
+    state: Option<String>,
+) -> Result<Template, Problem> {
+    use log::error;
+    use minne_backend::routes::auth::start_app_login_process;
+
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // the app has to start the login with a PKCE code challenge which is stored with the process
+    let login_process_id = start_app_login_process(
+        db_connection,
+        &code_challenge,
+        code_challenge_method.as_deref().unwrap_or("S256"),
+        state.as_deref(),
+    )?;
+    // the users can also log in with one of the configured identity providers
+    let providers = identity_providers
+        .providers()
+        .iter()
+        .map(|provider| json!({ "id": provider.id, "name": provider.name }))
+        .collect::<Vec<_>>();
+    let context = json!({
+        "title": "Minne iOS App Login",
+        "login_process_id": login_process_id,
+        "providers": providers,
+    });
+    Ok(Template::render("login_from_app", &context))
+}
+
+fn main() {
+    use minne_backend::cli::USAGE;
+    use std::env;
+
+    // get the configuration file and the command which should be executed from the command line
+    let command_line = match CommandLine::parse(env::args().skip(1).collect()) {
+        Ok(command_line) => command_line,
+        Err(error) => {
+            eprintln!("{}\n\n{}", error, USAGE);
+            std::process::exit(EXIT_CODE_INVALID_ARGUMENTS);
+        }
+    };
+    if command_line.show_help {
+        println!("{}", USAGE);
+        return;
+    }
+
+    // read the settings from the configuration file and the environment variables as well as the
+    // secrets which are stored in separate files
+    let mut settings = match Settings::load(command_line.config_file.as_deref()) {
+        Ok(settings) => settings,
+        Err(error) => {
+            eprintln!("Could not load the configuration. The error was: {}", error);
+            std::process::exit(EXIT_CODE_INVALID_CONFIGURATION);
+        }
+    };
+    let secret_warnings = match settings.load_secret_files() {
+        Ok(secret_warnings) => secret_warnings,
+        Err(error) => {
+            eprintln!("Could not load the secrets. The error was: {}", error);
+            std::process::exit(EXIT_CODE_INVALID_CONFIGURATION);
+        }
     };
-    Template::render("login_from_app", &context)
+
+    // the secrets are read now, so inline secrets should not be visible to child processes (they
+    // can still be seen in /proc until here, which is why the *_FILE variants should be preferred).
+    // this has to happen before the runtime is started, since changing the environment is not
+    // safe as soon as other threads could read it
+    unset_environment_variable("MINNE_DB_CONNECTION");
+    unset_environment_variable("MINNE_TOKEN_SIGNATURE_PSK");
+    unset_environment_variable("MINNE_TOKEN_SIGNATURE_PREVIOUS_PSK");
+    unset_environment_variable("MINNE_METRICS_PASSWORD");
+
+    // everything else runs on the async runtime which is started just now
+    rocket::execute(run(command_line, settings, secret_warnings));
 }
 
-#[rocket::main]
-async fn main() {
-    use log::{debug, error, info};
-    use minne_backend::fairings::{BackendConfiguration, MinneDatabaseConnection, NoCacheFairing};
+/// Execute the command of the command line with the loaded settings (e.g. start the server).
+async fn run(command_line: CommandLine, settings: Settings, secret_warnings: Vec<String>) {
+    use log::{debug, error, info, warn};
+    use minne_backend::cli::{run_migrate_command, run_pat_command, run_user_command, Command};
+    use minne_backend::fairings::{
+        BackendConfiguration, MetricsFairing, NoCacheFairing, RequestIdFairing,
+    };
+    use minne_backend::logging::{with_request_context, REQUEST_ID_HEADER};
+    use minne_backend::migrations::{pending_migrations, run_migrations};
+    use minne_backend::problem::problem_catcher;
     use minne_backend::routes::{
-        auth::authenticate_app_with_pat, auth::disable_pat, auth::get_authentication_token,
-        health::check_backend_health, task::add_new_task, task::delete_task, task::edit_task,
-        task::get_all_task_ids_from_user, task::get_task, user::create_new_user,
-        version::get_backend_version,
+        admin::disable_user, admin::enable_user, admin::export_user_data,
+        admin::force_password_reset, admin::get_user, admin::get_user_statistics,
+        admin::list_users, admin::revoke_user_tokens, audit::get_own_activity,
+        audit::query_audit_events, auth::authenticate_app_with_pat, auth::disable_pat,
+        auth::exchange_authorization_code, auth::get_authentication_token, device::approve_device,
+        device::poll_device_token, device::show_device_approval_page,
+        device::start_device_authorization, export::export_own_data,
+        external_login::finish_external_login, external_login::start_external_identity_link,
+        external_login::start_external_login, health::check_backend_health,
+        health::check_backend_liveness, health::check_backend_readiness,
+        health::get_health_details, health::BackendStartTime, invitation::create_invitation_code,
+        invitation::delete_invitation_code, invitation::get_own_invitation_codes,
+        jwks::get_json_web_key_set, metrics::get_metrics, oidc::authorize_client,
+        oidc::delete_oidc_client, oidc::exchange_oidc_authorization_code,
+        oidc::get_openid_configuration, oidc::get_userinfo, oidc::list_oidc_clients,
+        oidc::register_oidc_client, oidc::show_authorization_page,
+        session::get_session_access_token, session::list_sessions, session::revoke_other_sessions,
+        session::revoke_session, task::add_new_task, task::delete_task, task::edit_task,
+        task::get_all_task_ids_from_user, task::get_task, totp::confirm_totp, totp::disable_totp,
+        totp::enroll_totp, user::change_own_password, user::create_new_user,
+        user::delete_own_account, user::get_own_profile, user::update_own_profile,
+        user::verify_own_email, version::get_backend_version,
     };
+    use minne_backend::settings::SettingsError;
+    use minne_backend::throttling::LoginAttemptTracker;
     use rocket::config::{Shutdown, Sig};
     use rocket::figment::{
         util::map,
@@ -99,115 +386,238 @@ async fn main() {
     };
     use rocket::fs::FileServer;
     use rocket::http::Method;
-    use rocket::routes;
     use rocket::Config as RocketConfig;
+    use rocket::{catchers, routes};
     use rocket_cors::{AllowedHeaders, AllowedOrigins};
-    use std::env;
 
-    // select the logging level from a set environment variable
-    let logging_level = match env::var("MINNE_LOGGING_LEVEL") {
-        Ok(value) => match value.to_lowercase().as_str() {
-            "trace" => LevelFilter::Trace,
-            "debug" => LevelFilter::Debug,
-            "info" => LevelFilter::Info,
-            "warn" => LevelFilter::Warn,
-            "error" => LevelFilter::Error,
-            _ => LevelFilter::Info,
-        },
-        Err(_) => LevelFilter::Info,
-    };
+    // if requested, just show the effective configuration (without the secrets) and exit
+    if command_line.print_config {
+        println!("{}", settings.redacted().to_toml());
+        if let Err(error) = settings.validate() {
+            eprintln!("{}", error);
+            std::process::exit(EXIT_CODE_INVALID_CONFIGURATION);
+        }
+        return;
+    }
 
-    // setup the logging of the application based on the environment variable
-    setup_logging(logging_level);
+    // setup the logging of the application based on the configured level and format
+    if let Err(error) = setup_logging(&settings) {
+        eprintln!("{}", error);
+        std::process::exit(EXIT_CODE_INVALID_CONFIGURATION);
+    }
 
     // just inform the user that we are starting up
+    if command_line.command == Command::Serve {
+        info!(
+            "Starting Minne backend ({}, build with rustc {})...",
+            env!("VERGEN_GIT_SEMVER"),
+            env!("VERGEN_RUSTC_SEMVER")
+        );
+    }
+
+    // tell the user about secrets which could be exposed (e.g. to other processes)
+    for secret_warning in secret_warnings {
+        warn!("{}", secret_warning);
+    }
+
+    // ensure that all settings can be used before anything is started
+    if let Err(SettingsError::Invalid(problems)) = settings.validate() {
+        for problem in problems {
+            error!("Invalid configuration: {}", problem);
+        }
+        std::process::exit(EXIT_CODE_INVALID_CONFIGURATION);
+    }
+
+    // get the configuration for the database server (the validation ensured that it is set)
+    let database_connection_url = settings.db_connection.clone().unwrap_or_default();
+    let access_token_lifetime_in_seconds = settings.access_token_lifetime_in_seconds;
+
+    // the administrative commands just need a single connection to the database, a failed
+    // migration command is reported with its own exit code
+    let check_config_only = command_line.command == Command::CheckConfig;
+    let command_result = match command_line.command {
+        Command::Serve | Command::CheckConfig => None,
+        Command::Migrate(migrate_command) => {
+            let db_connection = &mut connect_for_command(&settings).await;
+            Some((
+                run_migrate_command(db_connection, migrate_command),
+                EXIT_CODE_MIGRATIONS_FAILED,
+            ))
+        }
+        Command::User(user_command) => {
+            let db_connection = &mut connect_for_command(&settings).await;
+            Some((
+                run_user_command(db_connection, &settings, user_command),
+                EXIT_CODE_COMMAND_FAILED,
+            ))
+        }
+        Command::Pat(pat_command) => {
+            let db_connection = &mut connect_for_command(&settings).await;
+            Some((
+                run_pat_command(db_connection, pat_command),
+                EXIT_CODE_COMMAND_FAILED,
+            ))
+        }
+    };
+    match command_result {
+        Some((Ok(()), _)) => return,
+        Some((Err(error), exit_code)) => {
+            error!("{}", error);
+            std::process::exit(exit_code);
+        }
+        None => {}
+    }
+
+    // get the keys which are used to sign the access tokens and the external identity providers
+    // the users can log in with (if any)
+    let token_signing_keys = match load_token_signing_keys(&settings) {
+        Ok(token_signing_keys) => token_signing_keys,
+        Err(error) => {
+            error!("{}", error);
+            std::process::exit(EXIT_CODE_INVALID_CONFIGURATION);
+        }
+    };
     info!(
-        "Starting Minne backend ({}, build with rustc {})...",
-        env!("VERGEN_GIT_SEMVER"),
-        env!("VERGEN_RUSTC_SEMVER")
+        "Signing the access tokens with {:?} and the key id '{}'",
+        token_signing_keys.active().algorithm,
+        token_signing_keys.active().key_id
     );
+    for (key_id, accepted_until) in token_signing_keys.previous() {
+        info!(
+            "Accepting tokens signed with the previous key '{}' until {}",
+            key_id, accepted_until
+        );
+    }
 
-    // get the configuration for the database server and terminate if something is missing
-    let database_connection_url =
-        env::var("MINNE_DB_CONNECTION").unwrap_or_else(|_| "".to_string());
-    if database_connection_url.is_empty() {
-        error!("Could not get the configuration for the database server. Ensure MINNE_DB_CONNECTION is set properly");
-        return;
+    // clients cannot validate ID tokens signed with the shared secret, so the OpenID Connect
+    // provider is only offered with an asymmetric key
+    let oidc_provider_enabled = token_signing_keys.active().public_key().is_some();
+    if !oidc_provider_enabled {
+        warn!("The OpenID Connect provider is disabled since the active token signature key is a shared secret");
+    }
+    let identity_providers = match load_identity_providers(&settings) {
+        Ok(identity_providers) => identity_providers,
+        Err(error) => {
+            error!("{}", error);
+            std::process::exit(EXIT_CODE_INVALID_CONFIGURATION);
+        }
+    };
+    for provider in identity_providers.providers() {
+        info!(
+            "Users can log in with the identity provider '{}' ({})",
+            provider.id, provider.issuer
+        );
     }
 
-    // get the psk for the token signature
-    let token_signature_psk =
-        env::var("MINNE_TOKEN_SIGNATURE_PSK").unwrap_or_else(|_| "".to_string());
-    if token_signature_psk.is_empty() {
-        error!("Could not get the token signature PSK. Ensure MINNE_TOKEN_SIGNATURE_PSK is set properly");
+    // the configuration is complete, so just report it if nothing else was requested
+    if check_config_only {
+        println!("The configuration is valid");
         return;
     }
 
-    // get the access token life time in seconds
-    let access_token_lifetime_in_seconds = env::var("MINNE_ACCESS_TOKEN_LIFETIME_IN_SECONDS")
-        .unwrap_or_else(|_| "300".to_string())
-        .parse::<usize>()
-        .unwrap_or(300);
-
-    // get the refresh token life time in seconds
-    let refresh_token_lifetime_in_seconds = env::var("MINNE_REFRESH_TOKEN_LIFETIME_IN_SECONDS")
-        .unwrap_or_else(|_| "3600".to_string())
-        .parse::<usize>()
-        .unwrap_or(3600);
+    // get the algorithm and the parameters which are used for hashing new passwords (they were
+    // already validated with the other settings)
+    let password_hashing = settings.password_hashing().unwrap_or_default();
+    info!(
+        "Hashing new passwords with {:?}",
+        password_hashing.algorithm
+    );
 
-    // get the refresh token life time in seconds
-    let user_registration_enabled = env::var("MINNE_ENABLE_USER_REGISTRATION")
-        .unwrap_or_else(|_| "false".to_string())
-        .parse::<bool>()
-        .unwrap_or(false);
+    // get the requirements new passwords have to fulfill
+    let password_policy = settings.password_policy();
+    if let Some(breached_passwords_path) = &password_policy.breached_passwords_path {
+        info!(
+            "Checking new passwords against the breached passwords in '{}'",
+            breached_passwords_path.display()
+        );
+    }
 
     // create a struct which holds the whole configuration
     let backend_config = BackendConfiguration {
-        token_signature_psk: token_signature_psk.to_string(),
         access_token_lifetime_in_seconds,
-        refresh_token_lifetime_in_seconds,
-        user_registration_enabled,
+        refresh_token_lifetime_in_seconds: settings.refresh_token_lifetime_in_seconds,
+        password_policy,
+        password_hashing,
+        public_url: settings.public_url(),
+        user_registration_enabled: settings.enable_user_registration,
+        user_invitations_enabled: settings.enable_user_invitations,
+        email_verification_command: settings.email_verification_command.clone(),
+        health_details_require_admin: settings.health_details_require_admin,
+        metrics_credentials: settings
+            .metrics_username
+            .clone()
+            .zip(settings.metrics_password.clone()),
+        login_max_failed_attempts_per_account: settings.login_max_failed_attempts_per_account,
+        login_max_failed_attempts_per_ip: settings.login_max_failed_attempts_per_ip,
+        login_backoff_base_in_seconds: settings.login_backoff_base_in_seconds,
+        login_lockout_duration_in_seconds: settings.login_lockout_duration_in_seconds,
+        login_failure_window_in_seconds: settings.login_failure_window_in_seconds,
+        trust_proxy_ip_header: settings.trust_proxy_ip_header,
     };
 
-    // just wait for 10 seconds until we continue. This is just an ugly fix that we have to wait until the database server
-    // has spun up
-    #[cfg(not(debug_assertions))]
+    // create the database connection pool, the database server might still be starting up
+    let db_connection_pool = match connect_to_database(
+        &database_connection_url,
+        settings.db_pool_size,
+        std::time::Duration::from_secs(settings.db_startup_timeout_in_seconds),
+    )
+    .await
     {
-        info!("Waiting for 10 seconds to ensure that the database had enough time to spin up...");
-        std::thread::sleep(std::time::Duration::from_secs(10));
-    }
-
-    // create a db connection pool manager and the corresponding pool
-    let db_connection_pool_manager =
-        diesel::r2d2::ConnectionManager::new(database_connection_url.clone());
-    let db_connection_pool = r2d2::Pool::builder()
-        .max_size(15)
-        .build(db_connection_pool_manager)
-        .unwrap();
+        Ok(db_connection_pool) => db_connection_pool,
+        Err(error) => {
+            error!("Could not connect to the database server: {}", error);
+            std::process::exit(EXIT_CODE_DATABASE_UNAVAILABLE);
+        }
+    };
     debug!("Successfully connected to the database server");
 
-    // ensure the database is setup correctly
-    run_migrations(&mut db_connection_pool.get().unwrap_or_else(|e| {
-        error!(
-            "Could not get a database connection from the connection pool. The error was: {}",
-            e
+    // ensure the database is setup correctly, either by running the migrations or (if they have to
+    // be run explicitly) by checking that none are pending
+    let migration_result = match db_connection_pool.get() {
+        Ok(mut db_connection) if settings.db_auto_migrate => {
+            run_migrations(&mut db_connection).map(|_| ())
+        }
+        Ok(mut db_connection) => match pending_migrations(&mut db_connection) {
+            Ok(pending) if pending.is_empty() => Ok(()),
+            Ok(pending) => Err(format!(
+                "The database has {} pending migration(s) ({}) which are not run automatically, run 'minne-backend migrate' first",
+                pending.len(),
+                pending.join(", ")
+            )),
+            Err(error) => Err(error),
+        },
+        Err(error) => {
+            error!(
+                "Could not get a database connection from the connection pool. The error was: {}",
+                error
+            );
+            std::process::exit(EXIT_CODE_DATABASE_UNAVAILABLE);
+        }
+    };
+    if let Err(error) = migration_result {
+        if settings.db_abort_on_migration_failure {
+            error!("{}", error);
+            std::process::exit(EXIT_CODE_MIGRATIONS_FAILED);
+        }
+        warn!(
+            "{}. Starting anyway since MINNE_DB_ABORT_ON_MIGRATION_FAILURE is disabled",
+            error
         );
-        std::process::exit(-1);
-    }));
+    }
     info!("Database preparations finished");
 
     // configure the database pool based on the supplied connection URL
     let minne_database_config: Map<_, Value> = map! {
         "url" => database_connection_url.into(),
-        "pool_size" => 25.into()
+        "pool_size" => settings.db_rocket_pool_size.into()
     };
 
     // rocket configuration figment
-    #[allow(unused_mut)]
-    let mut rocket_configuration_figment = RocketConfig::figment()
+    let rocket_configuration_figment = RocketConfig::figment()
         .merge(("databases", map!["saker" => minne_database_config]))
-        .merge(("port", 5842))
-        .merge(("address", std::net::Ipv4Addr::new(0, 0, 0, 0)))
+        .merge(("port", settings.port))
+        .merge(("address", settings.address))
+        .merge(("template_dir", &settings.template_directory))
         .merge((
             "shutdown",
             Shutdown {
@@ -217,30 +627,24 @@ async fn main() {
                     set.insert(Sig::Term);
                     set
                 },
-                grace: 2,
-                mercy: 3,
+                grace: settings.shutdown_grace_in_seconds,
+                mercy: settings.shutdown_mercy_in_seconds,
                 force: true,
                 __non_exhaustive: (),
             },
         ));
 
-    // if we are in debug mode, we are using the local template directory; otherwise use the installation directory
-    #[cfg(not(debug_assertions))]
+    // prepare the fairing for the CORS headers
+    let allowed_origins = if settings
+        .cors_allowed_origins
+        .iter()
+        .any(|origin| origin == "*")
     {
-        rocket_configuration_figment = rocket_configuration_figment
-            .merge(("template_dir", "/usr/local/share/minne-backend/templates"));
-    }
-
-    // if we are in debug mode, we are using the local static file directory; otherwise use the installation directory
-    let static_file_directory = if cfg!(debug_assertions) {
-        "static"
+        AllowedOrigins::All
     } else {
-        "/usr/local/share/minne-backend/static"
+        AllowedOrigins::some_exact(&settings.cors_allowed_origins)
     };
-
-    // prepare the fairing for the CORS headers
-    let allowed_origins = AllowedOrigins::All;
-    let cors_header = rocket_cors::CorsOptions {
+    let cors_options = rocket_cors::CorsOptions {
         allowed_origins,
         allowed_methods: vec![
             Method::Get,
@@ -253,34 +657,44 @@ async fn main() {
         .map(From::from)
         .collect(),
         allowed_headers: AllowedHeaders::All,
-        allow_credentials: true,
+        allow_credentials: settings.cors_allow_credentials,
+        expose_headers: [REQUEST_ID_HEADER.to_string()].into_iter().collect(),
         ..Default::default()
-    }
-    .to_cors()
-    .unwrap();
+    };
+    let cors_header = match cors_options.to_cors() {
+        Ok(cors_header) => cors_header,
+        Err(error) => {
+            error!(
+                "Could not configure the CORS headers. The error was: {}",
+                error
+            );
+            return;
+        }
+    };
 
     // create a fairing which is setting a cache control header to not cache
     // the API responses
     let no_cache_header = NoCacheFairing {};
 
-    // after everything is set up, we should unset ann environment variables to prevent leaking
-    // sensitive information
-    unset_environment_variable("MINNE_LOGGING_LEVEL");
-    unset_environment_variable("MINNE_DB_CONNECTION");
-    unset_environment_variable("MINNE_TOKEN_SIGNATURE_PSK");
-
-    // mount all supported routes and launch the rocket :)
-    info!("Server started and the routes are ready to process queries");
-    let _ = rocket::custom(rocket_configuration_figment)
+    // mount all supported routes
+    let mut rocket = rocket::custom(rocket_configuration_figment)
+        .attach(RequestIdFairing)
         .attach(cors_header)
         .attach(no_cache_header)
         .attach(Template::fairing())
         .manage(backend_config)
+        .manage(LoginAttemptTracker::default())
+        .manage(BackendStartTime::default())
+        .manage(token_signing_keys)
+        .manage(identity_providers)
         .manage(MinneDatabaseConnection::from(db_connection_pool))
         .mount(
             "/v1",
-            routes![
+            with_request_context(routes![
                 check_backend_health,
+                check_backend_liveness,
+                check_backend_readiness,
+                get_health_details,
                 get_backend_version,
                 create_new_user,
                 get_authentication_token,
@@ -288,13 +702,84 @@ async fn main() {
                 delete_task,
                 get_all_task_ids_from_user,
                 disable_pat,
+                list_sessions,
+                revoke_session,
+                revoke_other_sessions,
+                get_session_access_token,
                 get_task,
                 edit_task,
                 authenticate_app_with_pat,
-            ],
+                exchange_authorization_code,
+                start_device_authorization,
+                approve_device,
+                poll_device_token,
+                register_oidc_client,
+                list_oidc_clients,
+                delete_oidc_client,
+                enroll_totp,
+                confirm_totp,
+                disable_totp,
+                get_own_profile,
+                update_own_profile,
+                verify_own_email,
+                change_own_password,
+                delete_own_account,
+                start_external_identity_link,
+                export_own_data,
+                list_users,
+                get_user,
+                get_user_statistics,
+                disable_user,
+                enable_user,
+                force_password_reset,
+                revoke_user_tokens,
+                export_user_data,
+                create_invitation_code,
+                get_own_invitation_codes,
+                delete_invitation_code,
+                get_own_activity,
+                query_audit_events,
+            ]),
+        )
+        .mount(
+            "/",
+            with_request_context(routes![
+                show_login_page_to_user,
+                show_device_approval_page,
+                start_external_login,
+                finish_external_login,
+                get_json_web_key_set
+            ]),
+        )
+        .mount(
+            "/static",
+            with_request_context(FileServer::from(&settings.static_file_directory)),
         )
-        .mount("/", routes![show_login_page_to_user,])
-        .mount("/static", FileServer::from(static_file_directory))
-        .launch()
-        .await;
+        .register("/", catchers![problem_catcher]);
+
+    // the metrics are only collected if they can be fetched
+    if settings.enable_metrics {
+        rocket = rocket
+            .attach(MetricsFairing)
+            .mount("/", with_request_context(routes![get_metrics]));
+    }
+
+    // the OpenID Connect provider is only available if the clients can validate the ID tokens
+    if oidc_provider_enabled {
+        rocket = rocket
+            .mount(
+                "/v1",
+                with_request_context(routes![
+                    show_authorization_page,
+                    authorize_client,
+                    exchange_oidc_authorization_code,
+                    get_userinfo,
+                ]),
+            )
+            .mount("/", with_request_context(routes![get_openid_configuration]));
+    }
+
+    // launch the rocket :)
+    info!("Server started and the routes are ready to process queries");
+    let _ = rocket.launch().await;
 }
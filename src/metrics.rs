@@ -0,0 +1,237 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// The upper bounds (in seconds) of the buckets of the request latency histograms.
+const LATENCY_BUCKETS_IN_SECONDS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// The metrics collected while the backend is running. They are kept in a single process-wide
+/// registry, so that they can be recorded from places without access to the state of Rocket (e.g.
+/// the audit log).
+pub static METRICS: Metrics = Metrics::new();
+
+/// The labels a handled request is counted by.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct RequestLabels {
+    method: String,
+    route: String,
+    status: u16,
+}
+
+/// The number and the latency distribution of the requests with the same labels.
+#[derive(Default)]
+struct RequestStatistics {
+    /// The number of requests per bucket of `LATENCY_BUCKETS_IN_SECONDS` (not cumulative).
+    buckets: [u64; LATENCY_BUCKETS_IN_SECONDS.len()],
+    /// The number of requests in total.
+    count: u64,
+    /// The sum of the latencies of all requests in seconds.
+    sum_in_seconds: f64,
+}
+
+/// The counters and histograms which are updated while requests are handled.
+pub struct Metrics {
+    /// The statistics of the handled requests by method, route and status.
+    requests: Mutex<BTreeMap<RequestLabels, RequestStatistics>>,
+    /// The number of successful logins.
+    logins_succeeded: AtomicU64,
+    /// The number of failed login attempts.
+    logins_failed: AtomicU64,
+}
+
+/// A single value of a gauge which is determined when the metrics are rendered (e.g. the number
+/// of connections in the database connection pool).
+pub struct GaugeValue {
+    /// The name of the metric (e.g. `minne_db_pool_connections`).
+    pub name: &'static str,
+    /// The description of the metric.
+    pub help: &'static str,
+    /// The labels of the value (e.g. `("state", "idle")`).
+    pub labels: Vec<(&'static str, String)>,
+    /// The current value.
+    pub value: f64,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Metrics {
+            requests: Mutex::new(BTreeMap::new()),
+            logins_succeeded: AtomicU64::new(0),
+            logins_failed: AtomicU64::new(0),
+        }
+    }
+
+    /// Count a handled request and record how long it took.
+    pub fn record_request(&self, method: &str, route: &str, status: u16, latency: Duration) {
+        let latency_in_seconds = latency.as_secs_f64();
+        let labels = RequestLabels {
+            method: method.to_string(),
+            route: route.to_string(),
+            status,
+        };
+        let mut requests = match self.requests.lock() {
+            Ok(requests) => requests,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let statistics = requests.entry(labels).or_default();
+        if let Some(bucket) = LATENCY_BUCKETS_IN_SECONDS
+            .iter()
+            .position(|upper_bound| latency_in_seconds <= *upper_bound)
+        {
+            statistics.buckets[bucket] += 1;
+        }
+        statistics.count += 1;
+        statistics.sum_in_seconds += latency_in_seconds;
+    }
+
+    /// Count a successful or a failed login attempt.
+    pub fn record_login(&self, succeeded: bool) {
+        if succeeded {
+            self.logins_succeeded.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.logins_failed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Render all metrics and the supplied gauges in the Prometheus text format.
+    pub fn render(&self, gauges: &[GaugeValue]) -> String {
+        let mut output = String::new();
+
+        // the counters and histograms of the handled requests
+        {
+            let requests = match self.requests.lock() {
+                Ok(requests) => requests,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            write_header(
+                &mut output,
+                "minne_http_requests_total",
+                "The number of handled HTTP requests.",
+                "counter",
+            );
+            for (labels, statistics) in requests.iter() {
+                let _ = writeln!(
+                    output,
+                    "minne_http_requests_total{} {}",
+                    request_labels(labels, None),
+                    statistics.count
+                );
+            }
+            write_header(
+                &mut output,
+                "minne_http_request_duration_seconds",
+                "The time it took to handle the HTTP requests.",
+                "histogram",
+            );
+            for (labels, statistics) in requests.iter() {
+                let mut cumulative_count = 0;
+                for (upper_bound, count) in
+                    LATENCY_BUCKETS_IN_SECONDS.iter().zip(statistics.buckets)
+                {
+                    cumulative_count += count;
+                    let _ = writeln!(
+                        output,
+                        "minne_http_request_duration_seconds_bucket{} {}",
+                        request_labels(labels, Some(&upper_bound.to_string())),
+                        cumulative_count
+                    );
+                }
+                let _ = writeln!(
+                    output,
+                    "minne_http_request_duration_seconds_bucket{} {}",
+                    request_labels(labels, Some("+Inf")),
+                    statistics.count
+                );
+                let _ = writeln!(
+                    output,
+                    "minne_http_request_duration_seconds_sum{} {}",
+                    request_labels(labels, None),
+                    statistics.sum_in_seconds
+                );
+                let _ = writeln!(
+                    output,
+                    "minne_http_request_duration_seconds_count{} {}",
+                    request_labels(labels, None),
+                    statistics.count
+                );
+            }
+        }
+
+        // the outcome of the login attempts
+        write_header(
+            &mut output,
+            "minne_logins_total",
+            "The number of login attempts by their result.",
+            "counter",
+        );
+        let _ = writeln!(
+            output,
+            "minne_logins_total{{result=\"success\"}} {}",
+            self.logins_succeeded.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            output,
+            "minne_logins_total{{result=\"failure\"}} {}",
+            self.logins_failed.load(Ordering::Relaxed)
+        );
+
+        // the gauges which were determined just now, grouped by their name
+        let mut previous_name = "";
+        for gauge in gauges {
+            if gauge.name != previous_name {
+                write_header(&mut output, gauge.name, gauge.help, "gauge");
+                previous_name = gauge.name;
+            }
+            let labels = gauge
+                .labels
+                .iter()
+                .map(|(name, value)| format!("{}=\"{}\"", name, escape_label_value(value)))
+                .collect::<Vec<_>>();
+            let _ = if labels.is_empty() {
+                writeln!(output, "{} {}", gauge.name, gauge.value)
+            } else {
+                writeln!(
+                    output,
+                    "{}{{{}}} {}",
+                    gauge.name,
+                    labels.join(","),
+                    gauge.value
+                )
+            };
+        }
+        output
+    }
+}
+
+/// Write the description and the type of a metric.
+fn write_header(output: &mut String, name: &str, help: &str, metric_type: &str) {
+    let _ = writeln!(output, "# HELP {} {}", name, help);
+    let _ = writeln!(output, "# TYPE {} {}", name, metric_type);
+}
+
+/// Format the labels of a request (and optionally the upper bound of a histogram bucket).
+fn request_labels(labels: &RequestLabels, upper_bound: Option<&str>) -> String {
+    let mut formatted = format!(
+        "{{method=\"{}\",route=\"{}\",status=\"{}\"",
+        escape_label_value(&labels.method),
+        escape_label_value(&labels.route),
+        labels.status
+    );
+    if let Some(upper_bound) = upper_bound {
+        let _ = write!(formatted, ",le=\"{}\"", upper_bound);
+    }
+    formatted.push('}');
+    formatted
+}
+
+/// Escape a label value as required by the Prometheus text format.
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,125 @@
+use diesel::PgConnection;
+use diesel_migrations::{embed_migrations, EmbeddedMigrations};
+
+/// The database migrations which are embedded into the binary.
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations/");
+
+/// The state of a single database migration.
+pub struct MigrationStatus {
+    /// The name of the migration including its version (e.g. `2023-01-20-135012_create_users`).
+    pub name: String,
+    /// A flag which indicates if the migration was already applied to the database.
+    pub applied: bool,
+}
+
+/// Run all pending database migrations and get the number of migrations which were run.
+pub fn run_migrations(connection: &mut PgConnection) -> Result<usize, String> {
+    use diesel_migrations::MigrationHarness;
+    use log::info;
+
+    match connection.run_pending_migrations(MIGRATIONS) {
+        Ok(ran_migrations) => {
+            if !ran_migrations.is_empty() {
+                info!(
+                    "Successfully ran {} database migrations",
+                    ran_migrations.len()
+                );
+            } else {
+                info!("No migrations had to be run since the database is up to date");
+            }
+            Ok(ran_migrations.len())
+        }
+        Err(error) => Err(format!(
+            "Failed to run the database migrations. The error was: {}",
+            error
+        )),
+    }
+}
+
+/// Get the names of the embedded migrations which were not applied to the database yet.
+pub fn pending_migrations(connection: &mut PgConnection) -> Result<Vec<String>, String> {
+    use diesel_migrations::MigrationHarness;
+
+    connection
+        .pending_migrations(MIGRATIONS)
+        .map(|migrations| {
+            migrations
+                .iter()
+                .map(|migration| migration.name().to_string())
+                .collect()
+        })
+        .map_err(|error| {
+            format!(
+                "Could not get the pending database migrations. The error was: {}",
+                error
+            )
+        })
+}
+
+/// Get the state of all embedded migrations in the order they are applied.
+pub fn migration_status(connection: &mut PgConnection) -> Result<Vec<MigrationStatus>, String> {
+    use diesel::migration::MigrationSource;
+    use diesel_migrations::MigrationHarness;
+
+    let applied_versions = connection
+        .applied_migrations()
+        .map_err(|error| {
+            format!(
+                "Could not get the applied database migrations. The error was: {}",
+                error
+            )
+        })?
+        .iter()
+        .map(|version| version.to_string())
+        .collect::<Vec<_>>();
+    let embedded_migrations =
+        MigrationSource::<diesel::pg::Pg>::migrations(&MIGRATIONS).map_err(|error| {
+            format!(
+                "Could not read the embedded database migrations. The error was: {}",
+                error
+            )
+        })?;
+    let embedded_versions = embedded_migrations
+        .iter()
+        .map(|migration| migration.name().version().to_string())
+        .collect::<Vec<_>>();
+    let mut migrations = embedded_migrations
+        .iter()
+        .map(|migration| MigrationStatus {
+            name: migration.name().to_string(),
+            applied: applied_versions.contains(&migration.name().version().to_string()),
+        })
+        .collect::<Vec<_>>();
+    migrations.sort_by(|left, right| left.name.cmp(&right.name));
+
+    // migrations which were applied by a newer version of the backend are not embedded
+    for applied_version in applied_versions {
+        if !embedded_versions.contains(&applied_version) {
+            migrations.push(MigrationStatus {
+                name: format!("{} (unknown to this version)", applied_version),
+                applied: true,
+            });
+        }
+    }
+    Ok(migrations)
+}
+
+/// Revert the migration which was applied last and get its version.
+pub fn revert_last_migration(connection: &mut PgConnection) -> Result<String, String> {
+    use diesel_migrations::MigrationHarness;
+    use log::warn;
+
+    match connection.revert_last_migration(MIGRATIONS) {
+        Ok(version) => {
+            warn!(
+                "Reverted the database migration with the version {}",
+                version
+            );
+            Ok(version.to_string())
+        }
+        Err(error) => Err(format!(
+            "Failed to revert the last database migration. The error was: {}",
+            error
+        )),
+    }
+}
@@ -0,0 +1,557 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The default minimum number of characters of a password.
+pub const DEFAULT_MINIMUM_PASSWORD_LENGTH: usize = 8;
+
+/// The default minimum strength of a password (on a scale from 0 to 4).
+pub const DEFAULT_MINIMUM_PASSWORD_STRENGTH: u8 = 2;
+
+/// Passwords which are so common that they are guessed immediately (also with simple substitutions).
+const COMMON_PASSWORDS: &[&str] = &[
+    "123456",
+    "123456789",
+    "12345678",
+    "12345",
+    "1234567",
+    "1234567890",
+    "111111",
+    "000000",
+    "123123",
+    "654321",
+    "password",
+    "password1",
+    "passw0rd",
+    "qwerty",
+    "qwertz",
+    "qwerty123",
+    "abc123",
+    "iloveyou",
+    "admin",
+    "welcome",
+    "letmein",
+    "monkey",
+    "dragon",
+    "football",
+    "baseball",
+    "sunshine",
+    "princess",
+    "master",
+    "shadow",
+    "trustno1",
+    "superman",
+    "batman",
+    "starwars",
+    "whatever",
+    "freedom",
+    "hello",
+    "login",
+    "secret",
+    "changeme",
+    "minne",
+];
+
+/// Words which are commonly part of passwords and therefore add little to their strength.
+const COMMON_WORDS: &[&str] = &[
+    "password", "pass", "love", "admin", "welcome", "hello", "login", "secret", "master", "dragon",
+    "monkey", "summer", "winter", "spring", "autumn", "football", "soccer", "hockey", "baseball",
+    "sunshine", "princess", "shadow", "flower", "cookie", "killer", "hunter", "ninja", "pokemon",
+    "superman", "batman", "starwars", "freedom", "computer", "internet", "letmein", "qwerty",
+    "minne", "task", "todo", "test", "user", "god", "money", "secure",
+];
+
+/// The rows of a keyboard which are used to detect keyboard patterns (e.g. `asdf`).
+const KEYBOARD_ROWS: &[&str] = &[
+    "1234567890",
+    "qwertyuiop",
+    "qwertzuiop",
+    "asdfghjkl",
+    "yxcvbnm",
+    "zxcvbnm",
+];
+
+/// The configuration which decides if a password can be used for an account.
+#[derive(Clone, Debug)]
+pub struct PasswordPolicy {
+    /// The minimum number of characters of a password.
+    pub minimum_length: usize,
+    /// The minimum estimated strength of a password (on a scale from 0 to 4).
+    pub minimum_strength: u8,
+    /// The file (ordered by hash) or directory (one file per hash prefix) of the SHA-1 hashes of
+    /// breached passwords as provided by Have I Been Pwned (if any).
+    pub breached_passwords_path: Option<PathBuf>,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy {
+            minimum_length: DEFAULT_MINIMUM_PASSWORD_LENGTH,
+            minimum_strength: DEFAULT_MINIMUM_PASSWORD_STRENGTH,
+            breached_passwords_path: None,
+        }
+    }
+}
+
+/// The estimated strength of a password and some hints how it could be improved.
+pub struct PasswordStrength {
+    /// The score from 0 (guessed immediately) to 4 (very unguessable), similar to zxcvbn.
+    pub score: u8,
+    /// The base 10 logarithm of the estimated number of guesses needed to find the password.
+    pub guesses_log10: f64,
+    /// Hints about the patterns which were found in the password.
+    pub suggestions: Vec<String>,
+}
+
+/// Replace commonly used character substitutions (e.g. `p@ssw0rd`) by the original letters.
+fn remove_substitutions(password: &str) -> String {
+    password
+        .chars()
+        .map(|character| match character {
+            '0' => 'o',
+            '1' | '!' => 'i',
+            '3' => 'e',
+            '4' | '@' => 'a',
+            '5' | '$' => 's',
+            '7' => 't',
+            other => other,
+        })
+        .collect()
+}
+
+/// Get the number of characters an attacker has to try for every position of the password.
+fn character_pool_size(password: &str) -> f64 {
+    let mut pool_size = 0;
+    if password
+        .chars()
+        .any(|character| character.is_ascii_lowercase())
+    {
+        pool_size += 26;
+    }
+    if password
+        .chars()
+        .any(|character| character.is_ascii_uppercase())
+    {
+        pool_size += 26;
+    }
+    if password.chars().any(|character| character.is_ascii_digit()) {
+        pool_size += 10;
+    }
+    if password
+        .chars()
+        .any(|character| character.is_ascii_punctuation() || character == ' ')
+    {
+        pool_size += 33;
+    }
+    if !password.is_ascii() {
+        pool_size += 100;
+    }
+    pool_size.max(10) as f64
+}
+
+/// Check if the second character directly follows the first one in the alphabet, as a number or
+/// on a keyboard (in both directions).
+fn is_sequence(previous: char, current: char) -> bool {
+    let (previous, current) = (previous.to_ascii_lowercase(), current.to_ascii_lowercase());
+    if previous.is_ascii_alphanumeric()
+        && current.is_ascii_alphanumeric()
+        && (previous as i32 - current as i32).abs() == 1
+    {
+        return true;
+    }
+    KEYBOARD_ROWS.iter().any(|row| {
+        row.as_bytes().windows(2).any(|pair| {
+            (pair[0] as char == previous && pair[1] as char == current)
+                || (pair[1] as char == previous && pair[0] as char == current)
+        })
+    })
+}
+
+/// Mark all occurrences of the word in the characters of the password which were not marked before
+/// and return how often it was found.
+fn mark_word(characters: &[char], marked: &mut [bool], word: &str) -> usize {
+    let word = word.chars().collect::<Vec<char>>();
+    if word.is_empty() || word.len() > characters.len() {
+        return 0;
+    }
+    let mut occurrences = 0;
+    for start in 0..=characters.len() - word.len() {
+        let end = start + word.len();
+        if characters[start..end] == word[..] && !marked[start..end].contains(&true) {
+            marked[start..end]
+                .iter_mut()
+                .for_each(|marker| *marker = true);
+            occurrences += 1;
+        }
+    }
+    occurrences
+}
+
+/// Estimate how hard it would be to guess the password, similar to the score of zxcvbn. The user
+/// inputs (e.g. the name and the email address) are treated as known to an attacker.
+pub fn estimate_password_strength(password: &str, user_inputs: &[&str]) -> PasswordStrength {
+    let mut suggestions = Vec::new();
+    let lowercase_password = password.to_ascii_lowercase();
+    let normalized_password = remove_substitutions(&lowercase_password);
+
+    // very common passwords are guessed within the first few attempts
+    if COMMON_PASSWORDS.contains(&lowercase_password.as_str())
+        || COMMON_PASSWORDS.contains(&normalized_password.as_str())
+    {
+        suggestions.push("This is a very common password.".to_string());
+        return PasswordStrength {
+            score: 0,
+            guesses_log10: 1.0,
+            suggestions,
+        };
+    }
+
+    // words which are known to an attacker only count as a single guess out of a dictionary (the
+    // substitutions do not change the number of characters, so the positions are the same)
+    let characters = lowercase_password.chars().collect::<Vec<char>>();
+    let normalized_characters = normalized_password.chars().collect::<Vec<char>>();
+    let mut marked = vec![false; characters.len()];
+    let mut guesses_log10 = 0.0;
+    let user_words = user_inputs
+        .iter()
+        .flat_map(|input| {
+            input
+                .to_ascii_lowercase()
+                .split(|character: char| !character.is_alphanumeric())
+                .filter(|word| word.chars().count() >= 3)
+                .map(str::to_string)
+                .collect::<Vec<String>>()
+        })
+        .collect::<Vec<String>>();
+    let mut found_user_word = false;
+    for word in &user_words {
+        let occurrences = mark_word(&normalized_characters, &mut marked, word)
+            + mark_word(&characters, &mut marked, word);
+        if occurrences > 0 {
+            found_user_word = true;
+            guesses_log10 += occurrences as f64;
+        }
+    }
+    let mut found_common_word = false;
+    for word in COMMON_WORDS {
+        let occurrences = mark_word(&normalized_characters, &mut marked, word);
+        if occurrences > 0 {
+            found_common_word = true;
+            guesses_log10 += 3.0 * occurrences as f64;
+        }
+    }
+    if found_user_word {
+        suggestions.push("Avoid your name or email address in the password.".to_string());
+    }
+    if found_common_word {
+        suggestions.push(
+            "Common words are easy to guess, use uncommon words or add another word.".to_string(),
+        );
+    }
+
+    // every other character adds to the strength, but repetitions and sequences (e.g. `aaa`, `abc`,
+    // `123` or `qwe`) of three or more characters are guessed almost as easily as their first one
+    let character_guesses_log10 = character_pool_size(password).log10();
+    let mut found_repeat = false;
+    let mut found_sequence = false;
+    let mut index = 0;
+    while index < characters.len() {
+        if marked[index] {
+            index += 1;
+            continue;
+        }
+        let run_length = |matches: fn(char, char) -> bool| {
+            let mut end = index + 1;
+            while end < characters.len()
+                && !marked[end]
+                && matches(characters[end - 1], characters[end])
+            {
+                end += 1;
+            }
+            end - index
+        };
+        let repeat_length = run_length(|previous, current| previous == current);
+        let sequence_length = run_length(is_sequence);
+        if repeat_length >= 3 {
+            found_repeat = true;
+            guesses_log10 += character_guesses_log10 + (repeat_length as f64).log10();
+            index += repeat_length;
+        } else if sequence_length >= 3 {
+            found_sequence = true;
+            guesses_log10 += character_guesses_log10 + (2.0 * sequence_length as f64).log10();
+            index += sequence_length;
+        } else {
+            guesses_log10 += character_guesses_log10;
+            index += 1;
+        }
+    }
+    if found_repeat {
+        suggestions.push("Avoid repeated characters like 'aaa'.".to_string());
+    }
+    if found_sequence {
+        suggestions.push(
+            "Avoid sequences like 'abc', '123' or keyboard patterns like 'qwerty'.".to_string(),
+        );
+    }
+
+    // use the same thresholds as zxcvbn for converting the guesses into a score
+    let score = match guesses_log10 {
+        guesses if guesses < 3.0 => 0,
+        guesses if guesses < 6.0 => 1,
+        guesses if guesses < 8.0 => 2,
+        guesses if guesses < 10.0 => 3,
+        _ => 4,
+    };
+    if score < 3 && suggestions.is_empty() {
+        suggestions.push("Use a longer password, e.g. a few uncommon words.".to_string());
+    }
+    PasswordStrength {
+        score,
+        guesses_log10,
+        suggestions,
+    }
+}
+
+/// Read the first complete line which starts at or after the supplied offset. Returns the line
+/// and the offset directly after it (or nothing if the end of the file was reached).
+fn read_line_from<R: io::BufRead + io::Seek>(
+    reader: &mut R,
+    offset: u64,
+) -> io::Result<Option<(String, u64)>> {
+    use std::io::SeekFrom;
+
+    let mut buffer = Vec::new();
+    let mut line_start = offset;
+    if offset > 0 {
+        // skip the rest of the line the offset points into (if it does not point to a line start)
+        reader.seek(SeekFrom::Start(offset - 1))?;
+        line_start = offset - 1 + reader.read_until(b'\n', &mut buffer)? as u64;
+        buffer.clear();
+    } else {
+        reader.seek(SeekFrom::Start(0))?;
+    }
+    let line_length = reader.read_until(b'\n', &mut buffer)?;
+    if line_length == 0 {
+        return Ok(None);
+    }
+    Ok(Some((
+        String::from_utf8_lossy(&buffer).trim().to_string(),
+        line_start + line_length as u64,
+    )))
+}
+
+/// Check if the password is part of a known data breach. The path can either be a single file with
+/// `HASH:COUNT` lines ordered by the hash or a directory of range files named by the first five
+/// characters of the hash (e.g. `5BAA6.txt`) containing `SUFFIX:COUNT` lines.
+pub fn is_breached_password(path: &Path, password: &str) -> io::Result<bool> {
+    use data_encoding::HEXUPPER;
+    use ring::digest::{digest, SHA1_FOR_LEGACY_USE_ONLY};
+    use std::cmp::Ordering;
+    use std::fs::File;
+    use std::io::BufReader;
+
+    let password_hash =
+        HEXUPPER.encode(digest(&SHA1_FOR_LEGACY_USE_ONLY, password.as_bytes()).as_ref());
+    let (hash_prefix, hash_suffix) = password_hash.split_at(5);
+
+    // with range files only the file of the prefix has to be searched
+    if path.is_dir() {
+        let range = match std::fs::read_to_string(path.join(format!("{}.txt", hash_prefix))) {
+            Ok(range) => range,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(error) => return Err(error),
+        };
+        return Ok(range.lines().any(|line| {
+            line.split(':').next().map_or(false, |suffix| {
+                suffix.trim().eq_ignore_ascii_case(hash_suffix)
+            })
+        }));
+    }
+
+    // the complete list is too large to be read, so we do a binary search on the ordered lines
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut low = 0;
+    let mut high = reader.get_ref().metadata()?.len();
+    while low < high {
+        let middle = low + (high - low) / 2;
+        match read_line_from(&mut reader, middle)? {
+            None => high = middle,
+            Some((line, line_end)) => {
+                let line_hash = line.split(':').next().unwrap_or_default().to_uppercase();
+                match line_hash.cmp(&password_hash) {
+                    Ordering::Equal => return Ok(true),
+                    Ordering::Less => low = line_end,
+                    Ordering::Greater => high = middle,
+                }
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Check the password against the policy and return the reasons why it cannot be used. If the
+/// password fulfills the policy, the returned list is empty.
+pub fn check_password_policy(
+    policy: &PasswordPolicy,
+    password: &str,
+    user_inputs: &[&str],
+) -> Vec<String> {
+    use log::error;
+
+    let mut violations = Vec::new();
+
+    // the password has to have a minimum length
+    if password.chars().count() < policy.minimum_length {
+        violations.push(format!(
+            "The password has to be at least {} characters long.",
+            policy.minimum_length
+        ));
+    }
+
+    // the password must not be too easy to guess
+    let strength = estimate_password_strength(password, user_inputs);
+    if strength.score < policy.minimum_strength {
+        violations.push(format!(
+            "The password is too easy to guess (strength {} of 4, at least {} is required).",
+            strength.score, policy.minimum_strength
+        ));
+        violations.extend(strength.suggestions);
+    }
+
+    // the password must not be part of a known data breach, if the list is not available the
+    // password is accepted to not lock out the users
+    if let Some(breached_passwords_path) = &policy.breached_passwords_path {
+        match is_breached_password(breached_passwords_path, password) {
+            Ok(true) => violations.push(
+                "The password appeared in a data breach and can therefore not be used.".to_string(),
+            ),
+            Ok(false) => {}
+            Err(error) => error!(
+                "Could not check the password against the list of breached passwords in '{}'. The error was: {}",
+                breached_passwords_path.display(),
+                error
+            ),
+        }
+    }
+    violations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{estimate_password_strength, is_breached_password};
+    use std::path::PathBuf;
+
+    /// Get the path of a fixture of the breached passwords.
+    fn fixture(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/breached_passwords")
+            .join(name)
+    }
+
+    #[test]
+    fn first_and_last_hash_of_the_file_are_found() {
+        // correcthorse is the first hash, hunter2 the last one
+        assert!(is_breached_password(&fixture("hashes.txt"), "correcthorse").unwrap());
+        assert!(is_breached_password(&fixture("hashes.txt"), "hunter2").unwrap());
+    }
+
+    #[test]
+    fn every_hash_of_the_file_is_found() {
+        for password in [
+            "correcthorse",
+            "zebra",
+            "password",
+            "123456",
+            "qwerty",
+            "letmein",
+            "hunter2",
+        ] {
+            assert!(
+                is_breached_password(&fixture("hashes.txt"), password).unwrap(),
+                "{} was not found",
+                password
+            );
+        }
+    }
+
+    #[test]
+    fn missing_hashes_are_not_found_in_the_file() {
+        // the hashes sort before the first, between two and after the last line of the file
+        for password in ["i", "Tr0ub4dor", "word186"] {
+            assert!(
+                !is_breached_password(&fixture("hashes.txt"), password).unwrap(),
+                "{} was found",
+                password
+            );
+        }
+    }
+
+    #[test]
+    fn hashes_are_found_in_the_range_directory() {
+        assert!(is_breached_password(&fixture("ranges"), "password").unwrap());
+
+        // the suffixes are compared without regard to their case
+        assert!(is_breached_password(&fixture("ranges"), "123456").unwrap());
+    }
+
+    #[test]
+    fn missing_hashes_are_not_found_in_the_range_directory() {
+        // there is no range file for the prefix of qwerty
+        assert!(!is_breached_password(&fixture("ranges"), "qwerty").unwrap());
+
+        // the range file of the prefix exists, but does not contain the suffix
+        assert!(!is_breached_password(&fixture("ranges"), "Tr0ub4dor").unwrap());
+    }
+
+    #[test]
+    fn missing_file_is_an_error() {
+        assert!(is_breached_password(&fixture("missing.txt"), "password").is_err());
+    }
+
+    #[test]
+    fn common_passwords_get_the_lowest_score() {
+        assert_eq!(estimate_password_strength("password", &[]).score, 0);
+        assert_eq!(estimate_password_strength("Password", &[]).score, 0);
+    }
+
+    #[test]
+    fn substitutions_do_not_hide_common_passwords() {
+        assert_eq!(estimate_password_strength("p@ssw0rd", &[]).score, 0);
+        assert_eq!(estimate_password_strength("P4$$w0rd", &[]).score, 0);
+    }
+
+    #[test]
+    fn sequences_and_repetitions_are_weak() {
+        assert_eq!(estimate_password_strength("abcdefgh", &[]).score, 0);
+        assert_eq!(estimate_password_strength("asdfghjkl", &[]).score, 0);
+        assert_eq!(estimate_password_strength("aaaaaaaaaa", &[]).score, 0);
+    }
+
+    #[test]
+    fn user_inputs_are_known_to_an_attacker() {
+        let user_inputs = ["Jane Doe", "jane.doe@example.com"];
+        let with_user_inputs = estimate_password_strength("JaneDoe", &user_inputs);
+        assert_eq!(with_user_inputs.score, 0);
+        assert_eq!(estimate_password_strength("JaneDoe", &[]).score, 4);
+        assert!(with_user_inputs
+            .suggestions
+            .contains(&"Avoid your name or email address in the password.".to_string()));
+
+        // the other characters still count
+        assert_eq!(
+            estimate_password_strength("JaneDoe1985", &user_inputs).score,
+            3
+        );
+    }
+
+    #[test]
+    fn random_characters_are_strong() {
+        assert_eq!(estimate_password_strength("x7#Kq9!vLm2$", &[]).score, 4);
+    }
+
+    #[test]
+    fn passphrase_of_four_words_is_strong() {
+        let strength = estimate_password_strength("correct horse battery staple", &[]);
+        assert_eq!(strength.score, 4);
+        assert!(strength.suggestions.is_empty());
+    }
+}
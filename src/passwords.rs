@@ -0,0 +1,386 @@
+use std::fmt::{Display, Formatter};
+
+/// The default memory cost of Argon2id in KiB (the minimum recommended by OWASP).
+pub const DEFAULT_ARGON2_MEMORY_COST_IN_KIB: u32 = 19456;
+
+/// The default number of Argon2id iterations (the minimum recommended by OWASP).
+pub const DEFAULT_ARGON2_ITERATIONS: u32 = 2;
+
+/// The default degree of parallelism of Argon2id.
+pub const DEFAULT_ARGON2_PARALLELISM: u32 = 1;
+
+/// The default cost factor of bcrypt (the value which was used before the algorithm was configurable).
+pub const DEFAULT_BCRYPT_COST: u32 = 12;
+
+/// The length of the random salt used for Argon2id hashes in bytes.
+const ARGON2_SALT_LENGTH_IN_BYTES: usize = 16;
+
+#[derive(Debug)]
+pub enum PasswordHashingError {
+    /// The configured parameters of the hashing algorithm are not valid.
+    InvalidConfiguration(String),
+    /// The password could not be hashed or compared to the stored hash.
+    Hashing(String),
+    /// The stored hash was not created by one of the supported algorithms.
+    UnsupportedHash,
+}
+
+impl Display for PasswordHashingError {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PasswordHashingError::InvalidConfiguration(reason) => {
+                write!(
+                    formatter,
+                    "invalid password hashing configuration: {}",
+                    reason
+                )
+            }
+            PasswordHashingError::Hashing(reason) => {
+                write!(formatter, "could not hash the password: {}", reason)
+            }
+            PasswordHashingError::UnsupportedHash => {
+                write!(formatter, "the stored hash uses an unsupported algorithm")
+            }
+        }
+    }
+}
+
+/// The algorithms which can be used for hashing new passwords.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PasswordHashingAlgorithm {
+    Argon2id,
+    Bcrypt,
+}
+
+impl PasswordHashingAlgorithm {
+    /// Get the algorithm based on its name (`argon2id` or `bcrypt`).
+    pub fn from_name(name: &str) -> Option<PasswordHashingAlgorithm> {
+        match name.to_lowercase().as_str() {
+            "argon2id" => Some(PasswordHashingAlgorithm::Argon2id),
+            "bcrypt" => Some(PasswordHashingAlgorithm::Bcrypt),
+            _ => None,
+        }
+    }
+}
+
+/// The configuration which is used to hash new passwords. Stored hashes of all supported
+/// algorithms can be verified regardless of this configuration.
+#[derive(Clone, Debug)]
+pub struct PasswordHashingConfiguration {
+    /// The algorithm which is used for new password hashes.
+    pub algorithm: PasswordHashingAlgorithm,
+    /// The memory cost of Argon2id in KiB.
+    pub argon2_memory_cost_in_kib: u32,
+    /// The number of iterations of Argon2id.
+    pub argon2_iterations: u32,
+    /// The degree of parallelism of Argon2id.
+    pub argon2_parallelism: u32,
+    /// The cost factor of bcrypt.
+    pub bcrypt_cost: u32,
+}
+
+impl Default for PasswordHashingConfiguration {
+    fn default() -> Self {
+        PasswordHashingConfiguration {
+            algorithm: PasswordHashingAlgorithm::Argon2id,
+            argon2_memory_cost_in_kib: DEFAULT_ARGON2_MEMORY_COST_IN_KIB,
+            argon2_iterations: DEFAULT_ARGON2_ITERATIONS,
+            argon2_parallelism: DEFAULT_ARGON2_PARALLELISM,
+            bcrypt_cost: DEFAULT_BCRYPT_COST,
+        }
+    }
+}
+
+impl PasswordHashingConfiguration {
+    /// Ensure that the configured parameters can be used by the hashing algorithms.
+    pub fn validate(&self) -> Result<(), PasswordHashingError> {
+        self.argon2_params()?;
+        if !(4..=31).contains(&self.bcrypt_cost) {
+            return Err(PasswordHashingError::InvalidConfiguration(
+                "the bcrypt cost has to be between 4 and 31".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn argon2_params(&self) -> Result<argon2::Params, PasswordHashingError> {
+        argon2::Params::new(
+            self.argon2_memory_cost_in_kib,
+            self.argon2_iterations,
+            self.argon2_parallelism,
+            None,
+        )
+        .map_err(|error| PasswordHashingError::InvalidConfiguration(error.to_string()))
+    }
+
+    /// Get the scheme which is used for hashing new passwords.
+    fn preferred_scheme(&self) -> Result<Box<dyn PasswordHashScheme>, PasswordHashingError> {
+        match self.algorithm {
+            PasswordHashingAlgorithm::Argon2id => Ok(Box::new(Argon2idScheme {
+                params: self.argon2_params()?,
+            })),
+            PasswordHashingAlgorithm::Bcrypt => Ok(Box::new(BcryptScheme {
+                cost: self.bcrypt_cost,
+            })),
+        }
+    }
+
+    /// Get the scheme which created the supplied hash (configured with the current parameters).
+    fn scheme_for(
+        &self,
+        stored_hash: &str,
+    ) -> Result<Box<dyn PasswordHashScheme>, PasswordHashingError> {
+        let schemes: [Box<dyn PasswordHashScheme>; 2] = [
+            Box::new(Argon2idScheme {
+                params: self.argon2_params()?,
+            }),
+            Box::new(BcryptScheme {
+                cost: self.bcrypt_cost,
+            }),
+        ];
+        schemes
+            .into_iter()
+            .find(|scheme| scheme.identifies(stored_hash))
+            .ok_or(PasswordHashingError::UnsupportedHash)
+    }
+}
+
+/// A password hashing algorithm which can be used for storing the passwords of the users.
+trait PasswordHashScheme {
+    /// Check if the stored hash was created by this scheme.
+    fn identifies(&self, stored_hash: &str) -> bool;
+
+    /// Hash the password with the configured parameters.
+    fn hash(&self, password: &str) -> Result<String, PasswordHashingError>;
+
+    /// Check if the password matches the stored hash.
+    fn verify(&self, password: &str, stored_hash: &str) -> Result<bool, PasswordHashingError>;
+
+    /// Check if the stored hash was created with the configured parameters.
+    fn is_up_to_date(&self, stored_hash: &str) -> bool;
+}
+
+/// Argon2id (version 19) hashes which are stored as PHC strings (e.g. `$argon2id$v=19$m=...`).
+struct Argon2idScheme {
+    params: argon2::Params,
+}
+
+impl Argon2idScheme {
+    fn hasher(&self) -> argon2::Argon2<'static> {
+        argon2::Argon2::new(
+            argon2::Algorithm::Argon2id,
+            argon2::Version::V0x13,
+            self.params.clone(),
+        )
+    }
+}
+
+impl PasswordHashScheme for Argon2idScheme {
+    fn identifies(&self, stored_hash: &str) -> bool {
+        stored_hash.starts_with("$argon2id$")
+    }
+
+    fn hash(&self, password: &str) -> Result<String, PasswordHashingError> {
+        use crate::crypto::generate_random_bytes;
+        use argon2::password_hash::{PasswordHasher, SaltString};
+
+        let salt = SaltString::b64_encode(&generate_random_bytes(ARGON2_SALT_LENGTH_IN_BYTES))
+            .map_err(|error| PasswordHashingError::Hashing(error.to_string()))?;
+        self.hasher()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|password_hash| password_hash.to_string())
+            .map_err(|error| PasswordHashingError::Hashing(error.to_string()))
+    }
+
+    fn verify(&self, password: &str, stored_hash: &str) -> Result<bool, PasswordHashingError> {
+        use argon2::password_hash::{Error, PasswordHash, PasswordVerifier};
+
+        let parsed_hash = PasswordHash::new(stored_hash)
+            .map_err(|error| PasswordHashingError::Hashing(error.to_string()))?;
+        match self
+            .hasher()
+            .verify_password(password.as_bytes(), &parsed_hash)
+        {
+            Ok(()) => Ok(true),
+            Err(Error::Password) => Ok(false),
+            Err(error) => Err(PasswordHashingError::Hashing(error.to_string())),
+        }
+    }
+
+    fn is_up_to_date(&self, stored_hash: &str) -> bool {
+        use argon2::password_hash::PasswordHash;
+
+        let parsed_hash = match PasswordHash::new(stored_hash) {
+            Ok(parsed_hash) => parsed_hash,
+            Err(_) => return false,
+        };
+        let stored_params = match argon2::Params::try_from(&parsed_hash) {
+            Ok(stored_params) => stored_params,
+            Err(_) => return false,
+        };
+        parsed_hash.version == Some(argon2::Version::V0x13.into())
+            && stored_params.m_cost() == self.params.m_cost()
+            && stored_params.t_cost() == self.params.t_cost()
+            && stored_params.p_cost() == self.params.p_cost()
+    }
+}
+
+/// bcrypt hashes which are stored in the modular crypt format (e.g. `$2b$12$...`).
+struct BcryptScheme {
+    cost: u32,
+}
+
+impl PasswordHashScheme for BcryptScheme {
+    fn identifies(&self, stored_hash: &str) -> bool {
+        ["$2a$", "$2b$", "$2x$", "$2y$"]
+            .iter()
+            .any(|prefix| stored_hash.starts_with(prefix))
+    }
+
+    fn hash(&self, password: &str) -> Result<String, PasswordHashingError> {
+        bcrypt::hash(password, self.cost)
+            .map_err(|error| PasswordHashingError::Hashing(error.to_string()))
+    }
+
+    fn verify(&self, password: &str, stored_hash: &str) -> Result<bool, PasswordHashingError> {
+        bcrypt::verify(password, stored_hash)
+            .map_err(|error| PasswordHashingError::Hashing(error.to_string()))
+    }
+
+    fn is_up_to_date(&self, stored_hash: &str) -> bool {
+        match stored_hash.parse::<bcrypt::HashParts>() {
+            Ok(hash_parts) => hash_parts.get_cost() == self.cost,
+            Err(_) => false,
+        }
+    }
+}
+
+/// Hash a password with the configured algorithm and parameters.
+pub fn hash_password(
+    config: &PasswordHashingConfiguration,
+    password: &str,
+) -> Result<String, PasswordHashingError> {
+    config.preferred_scheme()?.hash(password)
+}
+
+/// Check if the password matches the stored hash. The hash can be created by any of the supported
+/// algorithms, independent of the algorithm which is configured for new hashes.
+pub fn verify_password(
+    config: &PasswordHashingConfiguration,
+    password: &str,
+    stored_hash: &str,
+) -> Result<bool, PasswordHashingError> {
+    config
+        .scheme_for(stored_hash)?
+        .verify(password, stored_hash)
+}
+
+/// Check if the stored hash should be replaced since it was created with another algorithm or with
+/// outdated parameters.
+pub fn needs_rehash(config: &PasswordHashingConfiguration, stored_hash: &str) -> bool {
+    let preferred_scheme = match config.preferred_scheme() {
+        Ok(preferred_scheme) => preferred_scheme,
+        Err(_) => return false,
+    };
+    !preferred_scheme.identifies(stored_hash) || !preferred_scheme.is_up_to_date(stored_hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        hash_password, needs_rehash, verify_password, PasswordHashingAlgorithm,
+        PasswordHashingConfiguration, PasswordHashingError,
+    };
+
+    const PASSWORD: &str = "Tr0ub4dor&3-legacy";
+
+    /// A hash of `PASSWORD` as it was stored before the algorithm was configurable (bcrypt, cost 12).
+    const LEGACY_BCRYPT_HASH: &str = "$2b$12$0KuM5sTbvC85b3qATnHjHuFudJZ2YV65GrF5nJzn7GqnOOaT4hXae";
+
+    /// Get a configuration with the lowest possible costs to keep the tests fast.
+    fn config(algorithm: PasswordHashingAlgorithm) -> PasswordHashingConfiguration {
+        PasswordHashingConfiguration {
+            algorithm,
+            argon2_memory_cost_in_kib: 64,
+            argon2_iterations: 1,
+            argon2_parallelism: 1,
+            bcrypt_cost: 4,
+        }
+    }
+
+    #[test]
+    fn argon2id_hash_is_verified() {
+        let config = config(PasswordHashingAlgorithm::Argon2id);
+        let stored_hash = hash_password(&config, PASSWORD).unwrap();
+        assert!(stored_hash.starts_with("$argon2id$v=19$m=64,t=1,p=1$"));
+        assert!(verify_password(&config, PASSWORD, &stored_hash).unwrap());
+        assert!(!verify_password(&config, "Tr0ub4dor&4-legacy", &stored_hash).unwrap());
+        assert!(!needs_rehash(&config, &stored_hash));
+    }
+
+    #[test]
+    fn legacy_bcrypt_hash_is_verified() {
+        let config = config(PasswordHashingAlgorithm::Argon2id);
+        assert!(verify_password(&config, PASSWORD, LEGACY_BCRYPT_HASH).unwrap());
+        assert!(!verify_password(&config, "Tr0ub4dor&4-legacy", LEGACY_BCRYPT_HASH).unwrap());
+    }
+
+    #[test]
+    fn hash_of_another_algorithm_needs_rehash() {
+        let argon2id_config = config(PasswordHashingAlgorithm::Argon2id);
+        let bcrypt_config = config(PasswordHashingAlgorithm::Bcrypt);
+        let argon2id_hash = hash_password(&argon2id_config, PASSWORD).unwrap();
+        let bcrypt_hash = hash_password(&bcrypt_config, PASSWORD).unwrap();
+
+        assert!(needs_rehash(&argon2id_config, LEGACY_BCRYPT_HASH));
+        assert!(needs_rehash(&argon2id_config, &bcrypt_hash));
+        assert!(needs_rehash(&bcrypt_config, &argon2id_hash));
+        assert!(!needs_rehash(&bcrypt_config, &bcrypt_hash));
+    }
+
+    #[test]
+    fn hash_with_other_parameters_needs_rehash() {
+        let argon2id_config = config(PasswordHashingAlgorithm::Argon2id);
+        let argon2id_hash = hash_password(&argon2id_config, PASSWORD).unwrap();
+        for changed_config in [
+            PasswordHashingConfiguration {
+                argon2_memory_cost_in_kib: 128,
+                ..argon2id_config.clone()
+            },
+            PasswordHashingConfiguration {
+                argon2_iterations: 2,
+                ..argon2id_config.clone()
+            },
+            PasswordHashingConfiguration {
+                argon2_parallelism: 2,
+                ..argon2id_config.clone()
+            },
+        ] {
+            assert!(needs_rehash(&changed_config, &argon2id_hash));
+        }
+
+        let bcrypt_config = config(PasswordHashingAlgorithm::Bcrypt);
+        let bcrypt_hash = hash_password(&bcrypt_config, PASSWORD).unwrap();
+        let changed_config = PasswordHashingConfiguration {
+            bcrypt_cost: 5,
+            ..bcrypt_config
+        };
+        assert!(needs_rehash(&changed_config, &bcrypt_hash));
+        assert!(needs_rehash(&changed_config, LEGACY_BCRYPT_HASH));
+    }
+
+    #[test]
+    fn hash_with_unknown_prefix_is_unsupported() {
+        let config = config(PasswordHashingAlgorithm::Argon2id);
+        for stored_hash in [
+            "$1$saltsalt$qjXMvbEw8oaL.CzflDugX/",
+            "$argon2i$v=19$m=64,t=1,p=1$c2FsdHNhbHQ$aGFzaGhhc2hoYXNoaGFzaA",
+            PASSWORD,
+        ] {
+            assert!(matches!(
+                verify_password(&config, PASSWORD, stored_hash),
+                Err(PasswordHashingError::UnsupportedHash)
+            ));
+        }
+    }
+}
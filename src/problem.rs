@@ -0,0 +1,218 @@
+use crate::guards::AuthorizationError;
+use rocket::http::{ContentType, Header, Status};
+use rocket::response::{self, Responder};
+use rocket::serde::json::serde_json;
+use rocket::{catch, Request, Response};
+use serde::Serialize;
+use std::io::Cursor;
+
+/// A problem with a single field of the data supplied by the client.
+#[derive(Debug, Serialize)]
+pub struct FieldError {
+    /// The name of the field which was rejected (e.g. `password`).
+    pub field: String,
+    /// The reasons why the value of the field was rejected.
+    pub messages: Vec<String>,
+}
+
+/// The reason why a request failed, sent as problem details (RFC 7807) to the client. Besides the
+/// human-readable `detail`, each problem has a stable `code` clients can rely on.
+#[derive(Debug)]
+pub struct Problem {
+    /// The status code of the response.
+    pub status: Status,
+    /// The machine-readable code of the problem (e.g. `passwords_do_not_match`).
+    pub code: &'static str,
+    /// A human-readable explanation of the problem.
+    pub detail: String,
+    /// The fields of the request which were rejected (if any).
+    pub field_errors: Vec<FieldError>,
+    /// The number of seconds the client has to wait before it tries again (if any).
+    pub retry_after: Option<u64>,
+}
+
+/// The JSON representation of a problem as defined by RFC 7807 with some extension members.
+#[derive(Serialize)]
+struct ProblemDocument<'a> {
+    #[serde(rename = "type")]
+    problem_type: String,
+    title: &'a str,
+    status: u16,
+    detail: &'a str,
+    code: &'a str,
+    #[serde(skip_serializing_if = "<[FieldError]>::is_empty")]
+    errors: &'a [FieldError],
+}
+
+impl Problem {
+    /// Create a new problem with the supplied status, stable code and human-readable explanation.
+    pub fn new(status: Status, code: &'static str, detail: &str) -> Self {
+        Problem {
+            status,
+            code,
+            detail: detail.to_string(),
+            field_errors: Vec::new(),
+            retry_after: None,
+        }
+    }
+
+    /// Create a problem which rejects the supplied field of the request for the supplied reasons.
+    pub fn invalid_field(field: &str, messages: Vec<String>) -> Self {
+        Problem {
+            status: Status::UnprocessableEntity,
+            code: "invalid_field",
+            detail: format!("The value of the field '{}' is not valid.", field),
+            field_errors: vec![FieldError {
+                field: field.to_string(),
+                messages,
+            }],
+            retry_after: None,
+        }
+    }
+
+    /// Create a problem which tells the client that the current password of the user, which is
+    /// required for confirming a sensitive change, is wrong.
+    pub fn wrong_password() -> Self {
+        Problem::new(
+            Status::Unauthorized,
+            "wrong_password",
+            "The current password is not correct.",
+        )
+    }
+
+    /// Create a problem which tells the client that a code of the second factor is required.
+    pub fn second_factor_required() -> Self {
+        Problem::new(
+            Status::PreconditionRequired,
+            "second_factor_required",
+            "The account is protected by a second factor, a TOTP or recovery code has to be supplied.",
+        )
+    }
+
+    /// Create a problem which tells the client that the supplied code of the second factor is wrong.
+    pub fn invalid_second_factor() -> Self {
+        Problem::new(
+            Status::Unauthorized,
+            "invalid_second_factor",
+            "The supplied TOTP or recovery code is not valid.",
+        )
+    }
+
+    /// Create a problem which tells the client that the requested user does not exist.
+    pub fn user_not_found() -> Self {
+        Problem::new(
+            Status::NotFound,
+            "user_not_found",
+            "The user does not exist.",
+        )
+    }
+
+    /// Create a problem which tells the client why the authentication of the request failed.
+    pub fn authorization_failed(status: Status, error: AuthorizationError) -> Self {
+        match error {
+            AuthorizationError::MissingAuthorizationHeader => Problem::new(
+                status,
+                "missing_authorization",
+                "The request requires an Authorization header with a bearer token or a personal access token.",
+            ),
+            AuthorizationError::MalformedAuthorizationHeader => Problem::new(
+                status,
+                "malformed_authorization",
+                "The Authorization header has to be 'Bearer <token>' or 'PAT <token>:<secret>'.",
+            ),
+            AuthorizationError::InvalidToken => Problem::new(
+                status,
+                "invalid_token",
+                "The supplied token is invalid, expired or was revoked.",
+            ),
+            AuthorizationError::AccountDisabled => Problem::new(
+                status,
+                "account_disabled",
+                "The account was disabled by an administrator.",
+            ),
+            AuthorizationError::InsufficientPrivileges => Problem::new(
+                status,
+                "insufficient_privileges",
+                "Only administrators are allowed to use this route.",
+            ),
+            AuthorizationError::DatabaseUnavailable => Problem::from(status),
+        }
+    }
+
+    /// Ask the client to wait for the supplied number of seconds before trying again.
+    pub fn with_retry_after(mut self, retry_after_in_seconds: u64) -> Self {
+        self.retry_after = Some(retry_after_in_seconds);
+        self
+    }
+}
+
+/// Get the code of a problem which is only described by its status code.
+fn code_for_status(status: Status) -> &'static str {
+    match status.code {
+        400 => "bad_request",
+        401 => "unauthorized",
+        403 => "forbidden",
+        404 => "not_found",
+        409 => "conflict",
+        413 => "payload_too_large",
+        415 => "unsupported_media_type",
+        422 => "unprocessable_entity",
+        428 => "precondition_required",
+        429 => "too_many_requests",
+        500 => "internal_error",
+        503 => "service_unavailable",
+        code if code < 500 => "client_error",
+        _ => "server_error",
+    }
+}
+
+impl From<Status> for Problem {
+    fn from(status: Status) -> Self {
+        Problem::new(
+            status,
+            code_for_status(status),
+            status
+                .reason()
+                .unwrap_or("The request could not be processed."),
+        )
+    }
+}
+
+impl<'r> Responder<'r, 'static> for Problem {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let document = ProblemDocument {
+            problem_type: format!("urn:minne:problem:{}", self.code),
+            title: self.status.reason().unwrap_or("Error"),
+            status: self.status.code,
+            detail: &self.detail,
+            code: self.code,
+            errors: &self.field_errors,
+        };
+        let body = serde_json::to_string(&document).map_err(|_| Status::InternalServerError)?;
+
+        let mut response = Response::build();
+        response
+            .status(self.status)
+            .header(ContentType::new("application", "problem+json"))
+            .sized_body(body.len(), Cursor::new(body));
+        if let Some(retry_after) = self.retry_after {
+            response.header(Header::new("Retry-After", retry_after.to_string()));
+        }
+        Ok(response.finalize())
+    }
+}
+
+/// The reason why the authentication of a request failed, remembered for the error catcher.
+pub struct AuthorizationFailure(pub Option<AuthorizationError>);
+
+/// Turn all errors which are not handled by the routes themselves (e.g. failed request guards,
+/// unknown routes or invalid request bodies) into problem details.
+#[catch(default)]
+pub fn problem_catcher(status: Status, request: &Request) -> Problem {
+    // if the authentication failed, tell the client why
+    let authorization_failure = &request.local_cache(|| AuthorizationFailure(None)).0;
+    match authorization_failure {
+        Some(error) => Problem::authorization_failed(status, *error),
+        None => Problem::from(status),
+    }
+}
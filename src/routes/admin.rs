@@ -0,0 +1,594 @@
+use crate::audit::{record_audit_event, AuditEventType, RequestMetadata};
+use crate::fairings::{BackendConfiguration, MinneDatabaseConnection};
+use crate::guards::AdminUser;
+use crate::problem::Problem;
+use crate::routes::export::DataExportArchive;
+use crate::routes::user::User;
+use crate::schema::{personal_access_tokens, tasks, users};
+use chrono::{DateTime, Utc};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use rocket::{get, post};
+use serde::Serialize;
+
+/// The number of users which are returned if the caller did not request a specific amount.
+const DEFAULT_USER_LIST_LIMIT: i64 = 50;
+
+/// The maximum number of users which can be requested at once.
+const MAX_USER_LIST_LIMIT: i64 = 200;
+
+/// The number of characters of a temporary password generated for a password reset.
+const TEMPORARY_PASSWORD_LENGTH_IN_BYTES: usize = 12;
+
+#[derive(Serialize)]
+pub struct UserSummary {
+    pub id: i32,
+    pub first_name: String,
+    pub last_name: String,
+    pub email: String,
+    pub is_admin: bool,
+    pub disabled: bool,
+    pub password_change_required: bool,
+    pub totp_enabled: bool,
+    pub invited_by: Option<i32>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl From<User> for UserSummary {
+    fn from(user: User) -> Self {
+        use chrono::TimeZone;
+
+        UserSummary {
+            id: user.id,
+            first_name: user.first_name,
+            last_name: user.last_name,
+            email: user.email,
+            is_admin: user.is_admin,
+            disabled: user.disabled,
+            password_change_required: user.password_change_required,
+            totp_enabled: user.totp_enabled,
+            invited_by: user.invited_by,
+            created_at: Utc.from_utc_datetime(&user.created_at),
+            updated_at: Utc.from_utc_datetime(&user.updated_at),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct UserStatistics {
+    /// The number of all tasks of the user.
+    pub tasks_total: i64,
+    /// The number of tasks of the user which are done.
+    pub tasks_done: i64,
+    /// The number of tasks of the user which are still open.
+    pub tasks_open: i64,
+    /// The time when the user created or changed a task for the last time.
+    pub last_task_activity: Option<DateTime<Utc>>,
+    /// The number of personal access tokens of the user which can still be used.
+    pub personal_access_tokens_active: i64,
+    /// The number of personal access tokens of the user which were disabled.
+    pub personal_access_tokens_disabled: i64,
+}
+
+#[derive(Serialize)]
+pub struct PasswordResetResponse {
+    /// The temporary password which has to be handed over to the user. The user has to choose a
+    /// new password with the next login.
+    pub temporary_password: String,
+}
+
+/// Escape the characters which have a special meaning in a SQL `LIKE` pattern.
+fn escape_like_pattern(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+#[get("/admin/users?<search>&<offset>&<limit>")]
+pub async fn list_users(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    _admin_user: AdminUser,
+    search: Option<String>,
+    offset: Option<i64>,
+    limit: Option<i64>,
+) -> Result<Json<Vec<UserSummary>>, Problem> {
+    use diesel::{BoolExpressionMethods, PgTextExpressionMethods, QueryDsl, RunQueryDsl};
+    use log::error;
+
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // build the query for the requested page of users
+    let mut query = users::table
+        .order(users::id)
+        .offset(offset.unwrap_or(0).max(0))
+        .limit(
+            limit
+                .unwrap_or(DEFAULT_USER_LIST_LIMIT)
+                .clamp(1, MAX_USER_LIST_LIMIT),
+        )
+        .into_boxed();
+
+    // if a search term was supplied, only return the users where the name or email address matches
+    if let Some(search_term) = search.filter(|search_term| !search_term.trim().is_empty()) {
+        let pattern = format!("%{}%", escape_like_pattern(search_term.trim()));
+        query = query.filter(
+            users::email
+                .ilike(pattern.clone())
+                .or(users::first_name.ilike(pattern.clone()))
+                .or(users::last_name.ilike(pattern)),
+        );
+    }
+
+    // fetch the users and return them to the calling party
+    match query.load::<User>(db_connection) {
+        Ok(found_users) => Ok(Json(
+            found_users.into_iter().map(UserSummary::from).collect(),
+        )),
+        Err(error) => {
+            error!(
+                "Could not get the list of users from the database. The error was: {}",
+                error
+            );
+            Err(Status::InternalServerError.into())
+        }
+    }
+}
+
+#[get("/admin/users/<user_id>")]
+pub async fn get_user(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    _admin_user: AdminUser,
+    user_id: i32,
+) -> Result<Json<UserSummary>, Problem> {
+    use diesel::{QueryDsl, RunQueryDsl};
+    use log::error;
+
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // get the requested user and return it to the calling party
+    match users::table.find(user_id).first::<User>(db_connection) {
+        Ok(user) => Ok(Json(UserSummary::from(user))),
+        Err(diesel::NotFound) => Err(Problem::user_not_found()),
+        Err(error) => {
+            error!(
+                "Could not get the user with the id {} from the database. The error was: {}",
+                user_id, error
+            );
+            Err(Status::InternalServerError.into())
+        }
+    }
+}
+
+#[get("/admin/users/<user_id>/statistics")]
+pub async fn get_user_statistics(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    _admin_user: AdminUser,
+    user_id: i32,
+) -> Result<Json<UserStatistics>, Problem> {
+    use diesel::dsl::{count_star, exists, max};
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+    use log::error;
+
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // collect all statistics in a single read-only transaction
+    let statistics = db_connection
+        .build_transaction()
+        .read_only()
+        .run::<_, diesel::result::Error, _>(|connection| {
+            if !diesel::select(exists(users::table.find(user_id))).get_result::<bool>(connection)? {
+                return Err(diesel::NotFound);
+            }
+            let tasks_total = tasks::table
+                .filter(tasks::owner.eq(user_id))
+                .select(count_star())
+                .first::<i64>(connection)?;
+            let tasks_done = tasks::table
+                .filter(tasks::owner.eq(user_id))
+                .filter(tasks::done_at.is_not_null())
+                .select(count_star())
+                .first::<i64>(connection)?;
+            let last_task_activity = tasks::table
+                .filter(tasks::owner.eq(user_id))
+                .select(max(tasks::updated_at))
+                .first::<Option<DateTime<Utc>>>(connection)?;
+            let personal_access_tokens_active = personal_access_tokens::table
+                .filter(personal_access_tokens::user_id.eq(user_id))
+                .filter(personal_access_tokens::disabled.eq(false))
+                .select(count_star())
+                .first::<i64>(connection)?;
+            let personal_access_tokens_disabled = personal_access_tokens::table
+                .filter(personal_access_tokens::user_id.eq(user_id))
+                .filter(personal_access_tokens::disabled.eq(true))
+                .select(count_star())
+                .first::<i64>(connection)?;
+            Ok(UserStatistics {
+                tasks_total,
+                tasks_done,
+                tasks_open: tasks_total - tasks_done,
+                last_task_activity,
+                personal_access_tokens_active,
+                personal_access_tokens_disabled,
+            })
+        });
+
+    // return the collected statistics to the calling party
+    match statistics {
+        Ok(statistics) => Ok(Json(statistics)),
+        Err(diesel::NotFound) => Err(Problem::user_not_found()),
+        Err(error) => {
+            error!(
+                "Could not collect the statistics of the user with the id {}. The error was: {}",
+                user_id, error
+            );
+            Err(Status::InternalServerError.into())
+        }
+    }
+}
+
+/// Enable or disable the account of the supplied user.
+fn set_user_disabled(
+    db_connection_pool: &MinneDatabaseConnection,
+    admin_user: &AdminUser,
+    user_id: i32,
+    disabled: bool,
+    request_metadata: &RequestMetadata,
+) -> Result<Status, Problem> {
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+    use log::{error, info};
+
+    // administrators cannot lock themselves out
+    if disabled && admin_user.id == user_id {
+        return Err(Problem::new(
+            Status::BadRequest,
+            "cannot_disable_own_account",
+            "Administrators cannot disable their own account.",
+        ));
+    }
+
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // change the state of the account
+    match diesel::update(users::table.find(user_id))
+        .set((
+            users::disabled.eq(disabled),
+            users::updated_at.eq(diesel::dsl::now),
+        ))
+        .execute(db_connection)
+    {
+        Ok(0) => Err(Problem::user_not_found()),
+        Ok(_) => {
+            info!(
+                "The administrator with the id {} {} the account of the user with the id {}",
+                admin_user.id,
+                if disabled { "disabled" } else { "enabled" },
+                user_id
+            );
+            let event_type = if disabled {
+                AuditEventType::AdminUserDisabled
+            } else {
+                AuditEventType::AdminUserEnabled
+            };
+            record_audit_event(
+                db_connection,
+                event_type,
+                Some(user_id),
+                Some(admin_user.id),
+                request_metadata,
+                None,
+            );
+            Ok(Status::NoContent)
+        }
+        Err(error) => {
+            error!(
+                "Could not change the state of the user with the id {}. The error was: {}",
+                user_id, error
+            );
+            Err(Status::InternalServerError.into())
+        }
+    }
+}
+
+#[post("/admin/users/<user_id>/disable")]
+pub async fn disable_user(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    admin_user: AdminUser,
+    user_id: i32,
+    request_metadata: RequestMetadata,
+) -> Result<Status, Problem> {
+    set_user_disabled(
+        db_connection_pool,
+        &admin_user,
+        user_id,
+        true,
+        &request_metadata,
+    )
+}
+
+#[post("/admin/users/<user_id>/enable")]
+pub async fn enable_user(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    admin_user: AdminUser,
+    user_id: i32,
+    request_metadata: RequestMetadata,
+) -> Result<Status, Problem> {
+    set_user_disabled(
+        db_connection_pool,
+        &admin_user,
+        user_id,
+        false,
+        &request_metadata,
+    )
+}
+
+/// Disable all personal access tokens of the supplied user and invalidate all access tokens which
+/// were issued until now.
+pub fn revoke_all_tokens_of_user(
+    connection: &mut diesel::PgConnection,
+    user_id: i32,
+) -> Result<(), diesel::result::Error> {
+    use diesel::{Connection, ExpressionMethods, QueryDsl, RunQueryDsl};
+
+    connection.transaction::<_, diesel::result::Error, _>(|connection| {
+        diesel::update(
+            personal_access_tokens::table
+                .filter(personal_access_tokens::user_id.eq(user_id))
+                .filter(personal_access_tokens::disabled.eq(false)),
+        )
+        .set((
+            personal_access_tokens::disabled.eq(true),
+            personal_access_tokens::updated_at.eq(diesel::dsl::now),
+        ))
+        .execute(connection)?;
+        diesel::update(users::table.find(user_id))
+            .set((
+                users::tokens_revoked_at.eq(diesel::dsl::now),
+                users::updated_at.eq(diesel::dsl::now),
+            ))
+            .execute(connection)?;
+        Ok(())
+    })
+}
+
+#[post("/admin/users/<user_id>/revoke-tokens")]
+pub async fn revoke_user_tokens(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    admin_user: AdminUser,
+    user_id: i32,
+    request_metadata: RequestMetadata,
+) -> Result<Status, Problem> {
+    use diesel::dsl::exists;
+    use diesel::{QueryDsl, RunQueryDsl};
+    use log::{error, info};
+
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // ensure that the user exists at all
+    match diesel::select(exists(users::table.find(user_id))).get_result::<bool>(db_connection) {
+        Ok(true) => {}
+        Ok(false) => return Err(Problem::user_not_found()),
+        Err(error) => {
+            error!(
+                "Could not check if the user with the id {} exists. The error was: {}",
+                user_id, error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    }
+
+    // revoke all tokens of the user
+    if let Err(error) = revoke_all_tokens_of_user(db_connection, user_id) {
+        error!(
+            "Could not revoke the tokens of the user with the id {}. The error was: {}",
+            user_id, error
+        );
+        return Err(Status::InternalServerError.into());
+    }
+    info!(
+        "The administrator with the id {} revoked all tokens of the user with the id {}",
+        admin_user.id, user_id
+    );
+    record_audit_event(
+        db_connection,
+        AuditEventType::AdminTokensRevoked,
+        Some(user_id),
+        Some(admin_user.id),
+        &request_metadata,
+        None,
+    );
+
+    // we assume that we've succeeded and can return with an appropriate status code
+    Ok(Status::NoContent)
+}
+
+/// Generate a random password which can be handed over to a user who has to replace it with the
+/// next login.
+pub fn generate_temporary_password() -> String {
+    use crate::crypto::generate_random_bytes;
+    use data_encoding::BASE64URL_NOPAD;
+
+    BASE64URL_NOPAD.encode(&generate_random_bytes(TEMPORARY_PASSWORD_LENGTH_IN_BYTES))
+}
+
+#[post("/admin/users/<user_id>/password-reset")]
+pub async fn force_password_reset(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    config: &State<BackendConfiguration>,
+    admin_user: AdminUser,
+    user_id: i32,
+    request_metadata: RequestMetadata,
+) -> Result<Json<PasswordResetResponse>, Problem> {
+    use crate::passwords::hash_password;
+    use diesel::{Connection, ExpressionMethods, QueryDsl, RunQueryDsl};
+    use log::{error, info};
+
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // generate a temporary password which has to be changed with the next login
+    let temporary_password = generate_temporary_password();
+    let password_hash = match hash_password(&config.password_hashing, &temporary_password) {
+        Ok(password_hash) => password_hash,
+        Err(error) => {
+            error!(
+                "Could not hash the temporary password. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // replace the password and ensure that all existing sessions of the user are terminated
+    let reset_result = db_connection.transaction::<_, diesel::result::Error, _>(|connection| {
+        let updated_users = diesel::update(users::table.find(user_id))
+            .set((
+                users::password_hash.eq(&password_hash),
+                users::has_local_password.eq(true),
+                users::password_change_required.eq(true),
+                users::updated_at.eq(diesel::dsl::now),
+            ))
+            .execute(connection)?;
+        if updated_users == 0 {
+            return Err(diesel::NotFound);
+        }
+        revoke_all_tokens_of_user(connection, user_id)
+    });
+    match reset_result {
+        Ok(()) => {}
+        Err(diesel::NotFound) => return Err(Problem::user_not_found()),
+        Err(error) => {
+            error!(
+                "Could not reset the password of the user with the id {}. The error was: {}",
+                user_id, error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    }
+    info!(
+        "The administrator with the id {} reset the password of the user with the id {}",
+        admin_user.id, user_id
+    );
+    record_audit_event(
+        db_connection,
+        AuditEventType::AdminPasswordReset,
+        Some(user_id),
+        Some(admin_user.id),
+        &request_metadata,
+        None,
+    );
+
+    // return the temporary password to the administrator
+    Ok(Json(PasswordResetResponse { temporary_password }))
+}
+
+#[get("/admin/users/<user_id>/export")]
+pub async fn export_user_data(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    admin_user: AdminUser,
+    user_id: i32,
+    request_metadata: RequestMetadata,
+) -> Result<DataExportArchive, Problem> {
+    use crate::routes::export::{build_user_data_export, DataExportError};
+    use log::{error, info};
+
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // create the archive with all data of the requested user and return it
+    match build_user_data_export(db_connection, user_id) {
+        Ok(content) => {
+            info!(
+                "The administrator with the id {} exported all data of the user with the id {}",
+                admin_user.id, user_id
+            );
+            record_audit_event(
+                db_connection,
+                AuditEventType::AdminDataExported,
+                Some(user_id),
+                Some(admin_user.id),
+                &request_metadata,
+                None,
+            );
+            Ok(DataExportArchive::new(user_id, content))
+        }
+        Err(DataExportError::UnknownUser) => Err(Problem::user_not_found()),
+        Err(error) => {
+            error!(
+                "Could not export the data of the user with the id {}. The error was: {}",
+                user_id, error
+            );
+            Err(Status::InternalServerError.into())
+        }
+    }
+}
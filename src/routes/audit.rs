@@ -0,0 +1,169 @@
+use crate::fairings::MinneDatabaseConnection;
+use crate::guards::{AdminUser, AuthenticatedUser};
+use crate::problem::Problem;
+use crate::schema::audit_events;
+use chrono::{DateTime, Utc};
+use rocket::get;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::Serialize;
+
+/// The number of audit events which are returned if the caller did not request a specific amount.
+const DEFAULT_AUDIT_EVENT_LIMIT: i64 = 50;
+
+/// The maximum number of audit events which can be requested at once.
+const MAX_AUDIT_EVENT_LIMIT: i64 = 500;
+
+#[derive(Queryable, Serialize)]
+pub struct AuditEvent {
+    /// The internal id of the event.
+    pub id: i64,
+    /// The id of the user the event is about (if the account is known).
+    pub user_id: Option<i32>,
+    /// The id of the user who caused the event (e.g. an administrator).
+    pub actor_id: Option<i32>,
+    /// The kind of the event (e.g. `login_failed`).
+    pub event_type: String,
+    /// The IP address of the client which caused the event.
+    pub ip_address: Option<String>,
+    /// The user agent of the client which caused the event.
+    pub user_agent: Option<String>,
+    /// Additional information about the event (e.g. the reason of a failed login).
+    pub details: Option<String>,
+    /// The time when the event happened.
+    pub created_at: DateTime<Utc>,
+}
+
+/// Parse a point in time supplied as a RFC 3339 query parameter.
+fn parse_timestamp(value: Option<String>) -> Result<Option<DateTime<Utc>>, Problem> {
+    match value {
+        Some(value) => DateTime::parse_from_rfc3339(&value)
+            .map(|timestamp| Some(timestamp.with_timezone(&Utc)))
+            .map_err(|_| {
+                Problem::new(
+                    Status::BadRequest,
+                    "invalid_timestamp",
+                    "The timestamps have to be RFC 3339 timestamps.",
+                )
+            }),
+        None => Ok(None),
+    }
+}
+
+#[get("/user/me/activity?<limit>")]
+pub async fn get_own_activity(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    authenticated_user: AuthenticatedUser,
+    limit: Option<i64>,
+) -> Result<Json<Vec<AuditEvent>>, Problem> {
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+    use log::error;
+
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // get the most recent events of the authenticated user
+    match audit_events::table
+        .filter(audit_events::user_id.eq(authenticated_user.id))
+        .order(audit_events::created_at.desc())
+        .limit(
+            limit
+                .unwrap_or(DEFAULT_AUDIT_EVENT_LIMIT)
+                .clamp(1, MAX_AUDIT_EVENT_LIMIT),
+        )
+        .load::<AuditEvent>(db_connection)
+    {
+        Ok(events) => Ok(Json(events)),
+        Err(error) => {
+            error!(
+                "Could not get the audit events of the user with the id {}. The error was: {}",
+                authenticated_user.id, error
+            );
+            Err(Status::InternalServerError.into())
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+#[get(
+    "/admin/audit?<user_id>&<actor_id>&<event_type>&<ip_address>&<since>&<until>&<offset>&<limit>"
+)]
+pub async fn query_audit_events(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    _admin_user: AdminUser,
+    user_id: Option<i32>,
+    actor_id: Option<i32>,
+    event_type: Option<String>,
+    ip_address: Option<String>,
+    since: Option<String>,
+    until: Option<String>,
+    offset: Option<i64>,
+    limit: Option<i64>,
+) -> Result<Json<Vec<AuditEvent>>, Problem> {
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+    use log::error;
+
+    // the time range has to be supplied as RFC 3339 timestamps
+    let since = parse_timestamp(since)?;
+    let until = parse_timestamp(until)?;
+
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // build the query with all supplied filters
+    let mut query = audit_events::table
+        .order(audit_events::created_at.desc())
+        .offset(offset.unwrap_or(0).max(0))
+        .limit(
+            limit
+                .unwrap_or(DEFAULT_AUDIT_EVENT_LIMIT)
+                .clamp(1, MAX_AUDIT_EVENT_LIMIT),
+        )
+        .into_boxed();
+    if let Some(user_id) = user_id {
+        query = query.filter(audit_events::user_id.eq(user_id));
+    }
+    if let Some(actor_id) = actor_id {
+        query = query.filter(audit_events::actor_id.eq(actor_id));
+    }
+    if let Some(event_type) = event_type {
+        query = query.filter(audit_events::event_type.eq(event_type));
+    }
+    if let Some(ip_address) = ip_address {
+        query = query.filter(audit_events::ip_address.eq(ip_address));
+    }
+    if let Some(since) = since {
+        query = query.filter(audit_events::created_at.ge(since));
+    }
+    if let Some(until) = until {
+        query = query.filter(audit_events::created_at.lt(until));
+    }
+
+    // fetch the matching events and return them to the calling party
+    match query.load::<AuditEvent>(db_connection) {
+        Ok(events) => Ok(Json(events)),
+        Err(error) => {
+            error!("Could not query the audit events. The error was: {}", error);
+            Err(Status::InternalServerError.into())
+        }
+    }
+}
@@ -1,9 +1,16 @@
+use crate::audit::{record_audit_event, AuditEventType, RequestMetadata};
 use crate::fairings::{BackendConfiguration, MinneDatabaseConnection};
 use crate::guards::AuthenticatedUser;
+use crate::problem::Problem;
+use crate::routes::user::User;
 use crate::schema::personal_access_tokens;
+use crate::signing::{TokenSigningKey, TokenSigningKeys};
+use crate::throttling::LoginAttemptTracker;
 use chrono::NaiveDateTime;
+use diesel::PgConnection;
 use rocket::form::Form;
 use rocket::http::Status;
+use rocket::response::status::Custom;
 use rocket::response::Redirect;
 use rocket::serde::json::Json;
 use rocket::FromForm;
@@ -11,6 +18,15 @@ use rocket::State;
 use rocket::{delete, post};
 use serde::{Deserialize, Serialize};
 
+/// The number of minutes a user has to log in after the app started a login process.
+const APP_LOGIN_PROCESS_LIFETIME_IN_MINUTES: i64 = 10;
+
+/// The number of seconds the app has to exchange an authorization code for a personal access token.
+const AUTHORIZATION_CODE_LIFETIME_IN_SECONDS: i64 = 60;
+
+/// The number of random bytes used for generating an authorization code.
+const AUTHORIZATION_CODE_LENGTH_IN_BYTES: usize = 32;
+
 #[derive(Deserialize)]
 pub struct NewPersonalAccessTokenData {
     /// The name of the new personal access token.
@@ -23,13 +39,25 @@ pub struct Credentials {
     pub email: String,
     /// The password of the user.
     pub password: String,
+    /// The code of the authenticator app (or a recovery code) if the user enabled a second factor.
+    pub totp_code: Option<String>,
+    /// A new password for the user which is required if an administrator reset the password.
+    pub new_password: Option<String>,
 }
 
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct TokenResponse {
     /// The access token to use for API requests.
-    access_token: String,
+    pub access_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct AuthorizationCodeExchangeData {
+    /// The authorization code the app received with the redirect after the login.
+    pub code: String,
+    /// The PKCE code verifier which matches the code challenge used for starting the login.
+    pub code_verifier: String,
 }
 
 #[derive(Serialize)]
@@ -38,6 +66,12 @@ pub struct PersonalAccessTokenResponse {
     pub secret: String,
 }
 
+#[derive(Serialize)]
+pub struct OAuthErrorResponse {
+    /// The error code as defined by OAuth 2.0 (e.g. `invalid_grant` or `authorization_pending`).
+    pub error: String,
+}
+
 #[derive(Queryable)]
 pub struct PersonalAccessToken {
     pub id: i32,
@@ -48,6 +82,8 @@ pub struct PersonalAccessToken {
     pub disabled: bool,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    pub user_agent: Option<String>,
+    pub last_used_at: Option<NaiveDateTime>,
 }
 
 #[derive(Insertable)]
@@ -57,22 +93,33 @@ pub struct NewPersonalAccessToken {
     pub user_id: i32,
     pub token: String,
     pub secret: String,
+    /// The user agent of the client the token is issued to (used to identify the session).
+    pub user_agent: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
     exp: usize,
-    iat: usize,
+    pub iat: usize,
     nbf: usize,
     pub sub: String,
+    /// The audience of the token. It is only set for tokens which were issued to OpenID Connect
+    /// clients, such tokens cannot be used for the Minne API.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
+    /// The id of the token. It is only set for tokens which were issued for a session, such
+    /// tokens are only valid as long as the session was not revoked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
 }
 
-fn get_token_for_user(
-    subject: &String,
-    signature_psk: &String,
+pub fn get_token_for_user(
+    user_id: i32,
+    token_id: Option<String>,
+    signing_key: &TokenSigningKey,
     access_token_lifetime: usize,
 ) -> Option<String> {
-    use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+    use jsonwebtoken::{encode, Header};
     use log::error;
     use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -96,16 +143,16 @@ fn get_token_for_user(
         exp: token_expires_at,
         iat: token_issued_at,
         nbf: token_issued_at + 1,
-        sub: subject.clone(),
+        sub: user_id.to_string(),
+        aud: None,
+        jti: token_id,
     };
 
-    // get the signing key for the token
-    let encoding_key = EncodingKey::from_secret(signature_psk.as_ref());
-
-    // generate a new JWT for the supplied header and token claims. if we were successful, return
-    // the token
-    let header = Header::new(Algorithm::HS512);
-    if let Ok(token) = encode(&header, &token_claims, &encoding_key) {
+    // generate a new JWT for the supplied header and token claims, the key id allows the
+    // validating party to select the right key. if we were successful, return the token
+    let mut header = Header::new(signing_key.algorithm);
+    header.kid = Some(signing_key.key_id.clone());
+    if let Ok(token) = encode(&header, &token_claims, signing_key.encoding_key()) {
         return Some(token);
     }
 
@@ -113,11 +160,467 @@ fn get_token_for_user(
     None
 }
 
+/// Get an OAuth 2.0 error response with the supplied status and error code.
+pub fn oauth_error(status: Status, error: &str) -> Custom<Json<OAuthErrorResponse>> {
+    Custom(
+        status,
+        Json(OAuthErrorResponse {
+            error: error.to_string(),
+        }),
+    )
+}
+
+/// Ensure that the account of a user, who already supplied valid credentials, can be used for a
+/// login. Disabled accounts are rejected and if an administrator reset the password, the user has
+/// to choose a new password while logging in.
+fn check_account_state(
+    connection: &mut PgConnection,
+    config: &BackendConfiguration,
+    user: &User,
+    new_password: Option<&str>,
+    request_metadata: &RequestMetadata,
+) -> Result<(), Problem> {
+    use crate::password_policy::check_password_policy;
+    use crate::passwords::hash_password;
+    use crate::schema::users;
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+    use log::{error, info, warn};
+
+    // disabled accounts cannot be used for a login at all
+    if user.disabled {
+        warn!(
+            "The user with the id {} tried to log in but the account is disabled",
+            user.id
+        );
+        record_audit_event(
+            connection,
+            AuditEventType::LoginFailed,
+            Some(user.id),
+            None,
+            request_metadata,
+            Some("account disabled"),
+        );
+        return Err(Problem::new(
+            Status::Forbidden,
+            "account_disabled",
+            "The account was disabled by an administrator.",
+        ));
+    }
+
+    // if the password was reset, the user has to supply a new one
+    if !user.password_change_required {
+        return Ok(());
+    }
+    let new_password =
+        match new_password {
+            Some(password) if !password.is_empty() => password,
+            _ => return Err(Problem::new(
+                Status::Forbidden,
+                "password_change_required",
+                "The password was reset by an administrator, a new password has to be supplied.",
+            )),
+        };
+    let policy_violations = check_password_policy(
+        &config.password_policy,
+        new_password,
+        &[&user.first_name, &user.last_name, &user.email],
+    );
+    if !policy_violations.is_empty() {
+        info!(
+            "The new password of the user with the id {} was rejected: {}",
+            user.id,
+            policy_violations.join(" ")
+        );
+        return Err(Problem::invalid_field("new_password", policy_violations));
+    }
+    let password_hash = match hash_password(&config.password_hashing, new_password) {
+        Ok(password_hash) => password_hash,
+        Err(error) => {
+            error!(
+                "Could not hash the new password of the user. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+    if let Err(error) = diesel::update(users::table.find(user.id))
+        .set((
+            users::password_hash.eq(password_hash),
+            users::has_local_password.eq(true),
+            users::password_change_required.eq(false),
+            users::updated_at.eq(diesel::dsl::now),
+        ))
+        .execute(connection)
+    {
+        error!(
+            "Could not change the password of the user with the id {}. The error was: {}",
+            user.id, error
+        );
+        return Err(Status::InternalServerError.into());
+    }
+    info!(
+        "The user with the id {} changed the password which was reset by an administrator",
+        user.id
+    );
+    record_audit_event(
+        connection,
+        AuditEventType::PasswordChanged,
+        Some(user.id),
+        Some(user.id),
+        request_metadata,
+        Some("changed during the login after a reset by an administrator"),
+    );
+    Ok(())
+}
+
+/// The problem which is reported if an app login process does not exist (anymore).
+fn unknown_login_process() -> Problem {
+    Problem::new(
+        Status::BadRequest,
+        "unknown_login_process",
+        "The login process is unknown, expired or already completed.",
+    )
+}
+
+/// The problem which is reported if the PKCE code verifier of an app is not valid.
+fn invalid_code_verifier() -> Problem {
+    Problem::new(
+        Status::BadRequest,
+        "invalid_code_verifier",
+        "The code verifier is not valid or does not match the code challenge.",
+    )
+}
+
+/// The problem which is reported if the username or the password is wrong. Both cases are reported
+/// the same way to prevent easy checking if an account exists or not.
+fn invalid_credentials() -> Problem {
+    Problem::new(
+        Status::Unauthorized,
+        "invalid_credentials",
+        "The username or the password is not correct.",
+    )
+}
+
+/// Verify the credentials a user supplied for a login (password and, if enabled, the second
+/// factor) and ensure that the account can be used. Failed attempts are tracked for the throttling
+/// of logins and every attempt is recorded in the audit log. The `login_type` describes the kind
+/// of login in the audit log (e.g. `app login`).
+#[allow(clippy::too_many_arguments)]
+pub fn verify_login_credentials(
+    connection: &mut PgConnection,
+    config: &BackendConfiguration,
+    login_attempts: &LoginAttemptTracker,
+    request_metadata: &RequestMetadata,
+    username: &str,
+    password: &str,
+    totp_code: Option<&str>,
+    new_password: Option<&str>,
+    login_type: &str,
+) -> Result<User, Problem> {
+    use crate::passwords::{hash_password, needs_rehash, verify_password};
+    use crate::routes::totp::verify_second_factor;
+    use crate::schema::users::dsl::{email, password_hash, updated_at, users};
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+    use log::{error, info};
+
+    // try to get the user record for the supplied username
+    let supplied_username = username.to_string();
+    let maybe_user_result = connection
+        .build_transaction()
+        .read_only()
+        .run::<_, diesel::result::Error, _>(move |connection| {
+            if let Ok(found_users) = users
+                .filter(email.eq(supplied_username))
+                .load::<User>(connection)
+            {
+                // if we did not get exactly one user, return an 'error'
+                if found_users.len() != 1 {
+                    return Err(diesel::result::Error::NotFound);
+                }
+
+                // return the found user
+                return Ok(found_users[0].clone());
+            }
+
+            //
+            return Err(diesel::result::Error::NotFound); // TODO: not the real error
+        });
+
+    // try to get the actual user object or delay a bit and then return with the corresponding error
+    let user = match maybe_user_result {
+        Ok(user) => user,
+        Err(_) => {
+            // ensure that we know what happened
+            error!("Could not get the user record for '{}'", username);
+
+            // track the failed attempt for the account as well as for the IP address
+            login_attempts.record_failure(config, username, request_metadata.ip_address);
+            record_audit_event(
+                connection,
+                AuditEventType::LoginFailed,
+                None,
+                None,
+                request_metadata,
+                Some("unknown account"),
+            );
+
+            // just slow down the process to prevent easy checking if a user name exists or not
+            let _ = hash_password(&config.password_hashing, "some_password");
+
+            // finally we can tell teh user that he/she is not authorized
+            return Err(invalid_credentials());
+        }
+    };
+
+    // check if the supplied password matches the one we stored in the database using the same
+    // algorithm and parameters which were used for creating the stored hash
+    match verify_password(
+        &config.password_hashing,
+        password,
+        user.password_hash.as_str(),
+    ) {
+        Ok(is_password_correct) => {
+            if !is_password_correct {
+                login_attempts.record_failure(config, username, request_metadata.ip_address);
+                record_audit_event(
+                    connection,
+                    AuditEventType::LoginFailed,
+                    Some(user.id),
+                    None,
+                    request_metadata,
+                    Some("wrong password"),
+                );
+                return Err(invalid_credentials());
+            }
+        }
+        Err(error) => {
+            error!("Could not verify the supplied password with the one stored in the database. The error was: {}", error);
+            return Err(Status::InternalServerError.into());
+        }
+    }
+
+    // if the user enabled a second factor, a valid code has to be supplied as well
+    if user.totp_enabled {
+        let supplied_code = match totp_code {
+            Some(code) if !code.trim().is_empty() => code,
+            _ => return Err(Problem::second_factor_required()),
+        };
+        match verify_second_factor(connection, &user, supplied_code) {
+            Ok(true) => {}
+            Ok(false) => {
+                login_attempts.record_failure(config, username, request_metadata.ip_address);
+                record_audit_event(
+                    connection,
+                    AuditEventType::LoginFailed,
+                    Some(user.id),
+                    None,
+                    request_metadata,
+                    Some("invalid second factor"),
+                );
+                return Err(Problem::invalid_second_factor());
+            }
+            Err(error) => {
+                error!(
+                    "Could not verify the second factor of the user with the id {}. The error was: {}",
+                    user.id, error
+                );
+                return Err(Status::InternalServerError.into());
+            }
+        }
+    }
+
+    // ensure that the account can be used for a login at all
+    check_account_state(connection, config, &user, new_password, request_metadata)?;
+
+    // if the stored hash uses an outdated algorithm or outdated parameters, replace it while we
+    // know the password (a password which was just changed is already hashed with the current ones)
+    if !user.password_change_required
+        && needs_rehash(&config.password_hashing, user.password_hash.as_str())
+    {
+        match hash_password(&config.password_hashing, password) {
+            Ok(new_password_hash) => {
+                match diesel::update(users.find(user.id))
+                    .set((
+                        password_hash.eq(new_password_hash),
+                        updated_at.eq(diesel::dsl::now),
+                    ))
+                    .execute(connection)
+                {
+                    Ok(_) => info!(
+                        "Upgraded the password hash of the user with the id {}",
+                        user.id
+                    ),
+                    Err(error) => error!(
+                        "Could not store the upgraded password hash of the user with the id {}. The error was: {}",
+                        user.id, error
+                    ),
+                }
+            }
+            Err(error) => error!(
+                "Could not upgrade the password hash of the user with the id {}. The error was: {}",
+                user.id, error
+            ),
+        }
+    }
+
+    // the login was successful, so the failed attempts of the account can be forgotten
+    login_attempts.record_success(username);
+    record_audit_event(
+        connection,
+        AuditEventType::LoginSucceeded,
+        Some(user.id),
+        Some(user.id),
+        request_metadata,
+        Some(login_type),
+    );
+    Ok(user)
+}
+
+/// Check if the supplied value only contains characters which are allowed in a PKCE code
+/// verifier (RFC 7636) and has a length within the supplied range.
+pub fn is_pkce_value(value: &str, min_length: usize, max_length: usize) -> bool {
+    (min_length..=max_length).contains(&value.len())
+        && value
+            .chars()
+            .all(|character| character.is_ascii_alphanumeric() || "-._~".contains(character))
+}
+
+/// Check if the supplied PKCE code verifier matches the supplied `S256` code challenge.
+pub fn verify_pkce_challenge(code_verifier: &str, code_challenge: &str) -> bool {
+    use data_encoding::BASE64URL_NOPAD;
+    use ring::constant_time::verify_slices_are_equal;
+    use ring::digest::{digest, SHA256};
+
+    let calculated_challenge =
+        BASE64URL_NOPAD.encode(digest(&SHA256, code_verifier.as_bytes()).as_ref());
+    is_pkce_value(code_verifier, 43, 128)
+        && verify_slices_are_equal(calculated_challenge.as_bytes(), code_challenge.as_bytes())
+            .is_ok()
+}
+
+/// Start a new login process for the app. The app has to supply a PKCE code challenge (only
+/// `S256` is supported) and can supply an opaque state which is returned with the redirect. The
+/// id of the new process is returned and has to be posted back with the credentials.
+pub fn start_app_login_process(
+    connection: &mut PgConnection,
+    code_challenge: &str,
+    code_challenge_method: &str,
+    state: Option<&str>,
+) -> Result<String, Problem> {
+    use crate::schema::app_login_processes;
+    use chrono::{Duration, Utc};
+    use diesel::{ExpressionMethods, RunQueryDsl};
+    use log::error;
+    use uuid::Uuid;
+
+    // the challenge is the base64url encoded SHA-256 hash of the verifier (43 characters)
+    if code_challenge_method != "S256" || !is_pkce_value(code_challenge, 43, 43) {
+        return Err(Problem::new(
+            Status::BadRequest,
+            "invalid_code_challenge",
+            "The code challenge has to be a S256 PKCE code challenge.",
+        ));
+    }
+    if let Some(state) = state {
+        if !is_pkce_value(state, 1, 255) {
+            return Err(Problem::new(
+                Status::BadRequest,
+                "invalid_state",
+                "The state has to consist of 1 to 255 unreserved characters.",
+            ));
+        }
+    }
+
+    // remove all processes which expired in the meantime
+    if let Err(error) = diesel::delete(app_login_processes::table)
+        .filter(app_login_processes::expires_at.lt(diesel::dsl::now))
+        .execute(connection)
+    {
+        error!(
+            "Could not remove the expired app login processes. The error was: {}",
+            error
+        );
+    }
+
+    // store the new process
+    let login_process_id = Uuid::new_v4().to_string();
+    match diesel::insert_into(app_login_processes::table)
+        .values((
+            app_login_processes::id.eq(&login_process_id),
+            app_login_processes::code_challenge.eq(code_challenge),
+            app_login_processes::state.eq(state),
+            app_login_processes::expires_at
+                .eq(Utc::now() + Duration::minutes(APP_LOGIN_PROCESS_LIFETIME_IN_MINUTES)),
+        ))
+        .execute(connection)
+    {
+        Ok(_) => Ok(login_process_id),
+        Err(error) => {
+            error!(
+                "Could not store the new app login process. The error was: {}",
+                error
+            );
+            Err(Status::InternalServerError.into())
+        }
+    }
+}
+
+/// Complete a pending login process of the app for the supplied (authenticated) user. An
+/// authorization code is issued which the app can exchange for a personal access token together
+/// with the code verifier. The returned redirect passes the code to the app.
+pub fn complete_app_login_process(
+    connection: &mut PgConnection,
+    login_process_id: &str,
+    user_id: i32,
+) -> Result<Redirect, Problem> {
+    use crate::crypto::{generate_random_token, sha256_hex};
+    use crate::schema::app_login_processes;
+    use chrono::{Duration, Utc};
+    use diesel::{ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
+    use log::error;
+
+    // store the hash of the code with the process, a process can only be completed once
+    let authorization_code = generate_random_token(AUTHORIZATION_CODE_LENGTH_IN_BYTES);
+    let login_process_state = match diesel::update(
+        app_login_processes::table.find(login_process_id),
+    )
+    .filter(app_login_processes::authorization_code_hash.is_null())
+    .filter(app_login_processes::expires_at.gt(diesel::dsl::now))
+    .set((
+        app_login_processes::user_id.eq(user_id),
+        app_login_processes::authorization_code_hash.eq(sha256_hex(&authorization_code)),
+        app_login_processes::expires_at
+            .eq(Utc::now() + Duration::seconds(AUTHORIZATION_CODE_LIFETIME_IN_SECONDS)),
+    ))
+    .returning(app_login_processes::state)
+    .get_result::<Option<String>>(connection)
+    .optional()
+    {
+        Ok(Some(state)) => state,
+        Ok(None) => return Err(unknown_login_process()),
+        Err(error) => {
+            error!(
+                "Could not store the authorization code for the app login process. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // only the short-lived authorization code (and the state of the app) is passed to the app
+    let mut redirect_target = format!("minne-app://auth?code={}", authorization_code);
+    if let Some(state) = login_process_state {
+        redirect_target.push_str(&format!("&state={}", state));
+    }
+    Ok(Redirect::to(redirect_target))
+}
+
 #[delete("/auth/pat")]
 pub async fn disable_pat(
     db_connection_pool: &State<MinneDatabaseConnection>,
     authenticated_user: AuthenticatedUser,
-) -> Status {
+    request_metadata: RequestMetadata,
+) -> Result<Status, Problem> {
     use crate::schema::personal_access_tokens::{disabled, table, token, updated_at};
     use diesel::ExpressionMethods;
     use diesel::RunQueryDsl;
@@ -125,7 +628,11 @@ pub async fn disable_pat(
 
     // if no personal access token was used, exit early
     if authenticated_user.used_pat.is_empty() {
-        return Status::BadRequest;
+        return Err(Problem::new(
+            Status::BadRequest,
+            "no_personal_access_token_used",
+            "Only personal access tokens can be disabled, but the request was authenticated with another token.",
+        ));
     }
 
     // get a connection to the database for dealing with the request
@@ -136,39 +643,58 @@ pub async fn disable_pat(
                 "Could not get a connection from the database connection pool. The error was: {}",
                 error
             );
-            return Status::InternalServerError;
+            return Err(Status::InternalServerError.into());
         }
     };
 
     // set the personal access token to disabled based on the use personal access token for authentication
-    diesel::update(table)
-        .filter(token.eq(authenticated_user.used_pat))
+    if let Err(error) = diesel::update(table)
+        .filter(token.eq(&authenticated_user.used_pat))
         .set((disabled.eq(true), updated_at.eq(diesel::dsl::now)))
         .execute(db_connection)
-        .unwrap();
+    {
+        error!(
+            "Could not disable the personal access token of the user with the id {}. The error was: {}",
+            authenticated_user.id, error
+        );
+        return Err(Status::InternalServerError.into());
+    }
+    record_audit_event(
+        db_connection,
+        AuditEventType::PersonalAccessTokenDisabled,
+        Some(authenticated_user.id),
+        Some(authenticated_user.id),
+        &request_metadata,
+        None,
+    );
 
     // we assume that we've succeeded and can return with an appropriate status code
-    Status::NoContent
+    Ok(Status::NoContent)
 }
 
 #[derive(FromForm)]
 pub struct LoginFromForm {
     username: String,
     password: String,
+    totp_code: Option<String>,
+    new_password: Option<String>,
     login_process_id: String,
 }
 
 #[post("/auth/app", data = "<credentials>")]
 pub async fn authenticate_app_with_pat(
     db_connection_pool: &State<MinneDatabaseConnection>,
+    config: &State<BackendConfiguration>,
+    login_attempts: &State<LoginAttemptTracker>,
+    request_metadata: RequestMetadata,
     credentials: Form<LoginFromForm>,
-) -> Result<Redirect, Status> {
-    use crate::routes::user::User;
-    use crate::schema::users::dsl::{email, users};
-    use bcrypt::verify;
+) -> Result<Redirect, Problem> {
+    use crate::schema::app_login_processes;
     use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
-    use log::error;
-    use uuid::Uuid;
+    use log::{debug, error};
+
+    // reject the login attempt early if there were too many failed attempts for the account or IP address
+    login_attempts.check(&credentials.username, request_metadata.ip_address)?;
 
     // get a connection to the database for dealing with the request
     let db_connection = &mut match db_connection_pool.get() {
@@ -178,106 +704,174 @@ pub async fn authenticate_app_with_pat(
                 "Could not get a connection from the database connection pool. The error was: {}",
                 error
             );
-            return Err(Status::InternalServerError);
+            return Err(Status::InternalServerError.into());
         }
     };
 
-    // try to get the user record for the supplied username
-    let supplied_username = credentials.username.clone();
-    let maybe_user_result = db_connection
-        .build_transaction()
-        .read_only()
-        .run::<_, diesel::result::Error, _>(move |connection| {
-            if let Ok(found_users) = users
-                .filter(email.eq(supplied_username))
-                .load::<User>(connection)
-            {
-                // if we did not get exactly one user, return an 'error'
-                if found_users.len() != 1 {
-                    return Err(diesel::result::Error::NotFound);
-                }
+    // the login has to belong to a process which was started by the app and is still pending
+    match app_login_processes::table
+        .find(&credentials.login_process_id)
+        .filter(app_login_processes::authorization_code_hash.is_null())
+        .filter(app_login_processes::expires_at.gt(diesel::dsl::now))
+        .select(app_login_processes::id)
+        .first::<String>(db_connection)
+    {
+        Ok(_) => {}
+        Err(diesel::NotFound) => {
+            debug!(
+                "The app login process '{}' is unknown, expired or already completed",
+                credentials.login_process_id
+            );
+            return Err(unknown_login_process());
+        }
+        Err(error) => {
+            error!(
+                "Could not get the app login process. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    }
 
-                // return the found user
-                return Ok(found_users[0].clone());
-            }
+    // verify the supplied credentials of the user
+    let user = verify_login_credentials(
+        db_connection,
+        config,
+        login_attempts,
+        &request_metadata,
+        &credentials.username,
+        &credentials.password,
+        credentials.totp_code.as_deref(),
+        credentials.new_password.as_deref(),
+        "app login",
+    )?;
+
+    // the login was successful, so the app can get its personal access token
+    complete_app_login_process(db_connection, &credentials.login_process_id, user.id)
+}
 
-            //
-            return Err(diesel::result::Error::NotFound); // TODO: not the real error
-        });
+#[post("/auth/app/token", data = "<exchange_data>")]
+pub async fn exchange_authorization_code(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    request_metadata: RequestMetadata,
+    exchange_data: Json<AuthorizationCodeExchangeData>,
+) -> Result<Json<PersonalAccessTokenResponse>, Problem> {
+    use crate::crypto::sha256_hex;
+    use crate::schema::app_login_processes;
+    use diesel::{ExpressionMethods, OptionalExtension, RunQueryDsl};
+    use log::{error, info, warn};
+    use uuid::Uuid;
 
-    // try to get the actual user object or delay a bit and then return with the corresponding error
-    let user = match maybe_user_result {
-        Ok(user) => user,
-        Err(_) => {
-            // ensure that we know what happened
-            error!(
-                "Could not get the user record for '{}'",
-                credentials.username
-            );
+    // the verifier has to be a valid PKCE code verifier
+    if !is_pkce_value(&exchange_data.code_verifier, 43, 128) {
+        return Err(invalid_code_verifier());
+    }
 
-            // just slow down the process to prevent easy checking if a user name exists or not
-            let _ = verify(
-                "some_password",
-                "$2y$12$7xMzqvnHyizkumZYpIRXheGMAqDKVo8HKtpmQSn51JUfY0N2VN4ua",
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
             );
-
-            // finally we can tell teh user that he/she is not authorized
-            return Err(Status::Unauthorized);
+            return Err(Status::InternalServerError.into());
         }
     };
 
-    // check if the supplied password matches the one we stored in the database using the same bcrypt
-    // parameters
-    match verify(&credentials.password, user.password_hash.as_str()) {
-        Ok(is_password_correct) => {
-            if !is_password_correct {
-                return Err(Status::Unauthorized);
-            }
+    // the authorization code can only be used once, so the process is removed directly (even if
+    // the verifier turns out to be wrong)
+    let login_process = match diesel::delete(app_login_processes::table)
+        .filter(
+            app_login_processes::authorization_code_hash.eq(sha256_hex(exchange_data.code.trim())),
+        )
+        .filter(app_login_processes::expires_at.gt(diesel::dsl::now))
+        .returning((
+            app_login_processes::code_challenge,
+            app_login_processes::user_id,
+        ))
+        .get_result::<(String, Option<i32>)>(db_connection)
+        .optional()
+    {
+        Ok(Some((code_challenge, Some(user_id)))) => (code_challenge, user_id),
+        Ok(_) => {
+            return Err(Problem::new(
+                Status::BadRequest,
+                "invalid_authorization_code",
+                "The authorization code is unknown, expired or was already used.",
+            ))
         }
         Err(error) => {
-            error!("Could not verify the supplied password with the one stored in the database. The error was: {}", error);
-            return Err(Status::InternalServerError);
+            error!(
+                "Could not get the app login process for the authorization code. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
         }
+    };
+    let (code_challenge, user_id) = login_process;
+
+    // the verifier has to match the challenge the app used for starting the login
+    if !verify_pkce_challenge(&exchange_data.code_verifier, &code_challenge) {
+        warn!(
+            "The code verifier for the app login of the user with the id {} did not match the code challenge",
+            user_id
+        );
+        return Err(invalid_code_verifier());
     }
 
-    // since the login seems to be valid create a new personal access token for that user
+    // create a new personal access token for the user
     let new_pat = NewPersonalAccessToken {
         name: "App Login".to_string(),
-        user_id: user.id,
+        user_id,
         token: Uuid::new_v4().to_string(),
         secret: Uuid::new_v4().to_string(),
+        user_agent: request_metadata.user_agent.clone(),
     };
-
-    // store that token in the database
-    let entries_added = diesel::insert_into(personal_access_tokens::table)
+    if let Err(error) = diesel::insert_into(personal_access_tokens::table)
         .values(&new_pat)
         .execute(db_connection)
-        .unwrap();
-
-    // if we did not add exactly one entry, return an error
-    if entries_added != 1 {
-        return Err(Status::InternalServerError);
+    {
+        error!(
+            "Could not store the personal access token for the user with the id {}. The error was: {}",
+            user_id, error
+        );
+        return Err(Status::InternalServerError.into());
     }
-
-    // return the token as well as the corresponding secret to the calling party
-    Ok(Redirect::to(format!(
-        "minne-app://auth?token={}&secret={}&process_id={}",
-        new_pat.token, new_pat.secret, credentials.login_process_id
-    )))
+    info!(
+        "Issued a personal access token for the app login of the user with the id {}",
+        user_id
+    );
+    record_audit_event(
+        db_connection,
+        AuditEventType::PersonalAccessTokenCreated,
+        Some(user_id),
+        Some(user_id),
+        &request_metadata,
+        Some(&new_pat.name),
+    );
+
+    // return the token as well as the corresponding secret to the app
+    Ok(Json(PersonalAccessTokenResponse {
+        token: new_pat.token,
+        secret: new_pat.secret,
+    }))
 }
 
 #[post("/auth/login", data = "<credentials>")]
 pub async fn get_authentication_token(
     db_connection_pool: &State<MinneDatabaseConnection>,
     config: &State<BackendConfiguration>,
+    signing_keys: &State<TokenSigningKeys>,
+    login_attempts: &State<LoginAttemptTracker>,
+    request_metadata: RequestMetadata,
     credentials: Json<Credentials>,
-) -> Result<Json<TokenResponse>, Status> {
-    use crate::routes::user::User;
-    use crate::schema::users::dsl::{email, users};
-    use bcrypt::verify;
-    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+) -> Result<Json<TokenResponse>, Problem> {
     use log::error;
 
+    // reject the login attempt early if there were too many failed attempts for the account or IP address
+    login_attempts.check(&credentials.email, request_metadata.ip_address)?;
+
     // get a connection to the database for dealing with the request
     let db_connection = &mut match db_connection_pool.get() {
         Ok(connection) => connection,
@@ -286,70 +880,29 @@ pub async fn get_authentication_token(
                 "Could not get a connection from the database connection pool. The error was: {}",
                 error
             );
-            return Err(Status::InternalServerError);
-        }
-    };
-
-    // try to get the user record for the supplied username
-    let supplied_username = credentials.email.clone();
-    let maybe_user_result = db_connection
-        .build_transaction()
-        .read_only()
-        .run::<_, diesel::result::Error, _>(move |connection| {
-            if let Ok(found_users) = users
-                .filter(email.eq(supplied_username))
-                .load::<User>(connection)
-            {
-                // if we did not get exactly one user, return an 'error'
-                if found_users.len() != 1 {
-                    return Err(diesel::result::Error::NotFound);
-                }
-
-                // return the found user
-                return Ok(found_users[0].clone());
-            }
-
-            //
-            return Err(diesel::result::Error::NotFound); // TODO: not the real error
-        });
-
-    // try to get the actual user object or delay a bit and then return with the corresponding error
-    let user = match maybe_user_result {
-        Ok(user) => user,
-        Err(_) => {
-            // ensure that we know what happened
-            error!("Could not get the user record for '{}'", credentials.email);
-
-            // just slow down the process to prevent easy checking if a user name exists or not
-            let _ = verify(
-                "some_password",
-                "$2y$12$7xMzqvnHyizkumZYpIRXheGMAqDKVo8HKtpmQSn51JUfY0N2VN4ua",
-            );
-
-            // finally we can tell teh user that he/she is not authorized
-            return Err(Status::Unauthorized);
+            return Err(Status::InternalServerError.into());
         }
     };
 
-    // check if the supplied password matches the one we stored in the database using the same bcrypt
-    // parameters
-    match verify(&credentials.password, user.password_hash.as_str()) {
-        Ok(is_password_correct) => {
-            if !is_password_correct {
-                return Err(Status::Unauthorized);
-            }
-        }
-        Err(error) => {
-            error!("Could not verify the supplied password with the one stored in the database. The error was: {}", error);
-            return Err(Status::InternalServerError);
-        }
-    }
+    // verify the supplied credentials of the user
+    let user = verify_login_credentials(
+        db_connection,
+        config,
+        login_attempts,
+        &request_metadata,
+        &credentials.email,
+        &credentials.password,
+        credentials.totp_code.as_deref(),
+        credentials.new_password.as_deref(),
+        "access token",
+    )?;
 
     // if we get here, the we ensured that the user is known and that the supplied password
     // was valid, we can generate a new access token and return it to the calling party
     if let Some(token) = get_token_for_user(
-        &credentials.email,
-        &config.token_signature_psk,
+        user.id,
+        None,
+        signing_keys.active(),
         config.access_token_lifetime_in_seconds,
     ) {
         return Ok(Json(TokenResponse {
@@ -359,5 +912,5 @@ pub async fn get_authentication_token(
 
     // it seems that we failed to generate a valid token, this should never happen, something
     // seems to be REALLY wrong
-    Err(Status::InternalServerError)
+    Err(Status::InternalServerError.into())
 }
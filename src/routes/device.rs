@@ -0,0 +1,613 @@
+use crate::audit::{record_audit_event, AuditEventType, RequestMetadata};
+use crate::fairings::{BackendConfiguration, MinneDatabaseConnection};
+use crate::problem::Problem;
+use crate::routes::auth::{
+    oauth_error, verify_login_credentials, NewPersonalAccessToken, OAuthErrorResponse,
+};
+use crate::schema::{device_authorizations, personal_access_tokens};
+use crate::throttling::LoginAttemptTracker;
+use diesel::PgConnection;
+use rocket::form::Form;
+use rocket::http::Status;
+use rocket::response::status::Custom;
+use rocket::serde::json::Json;
+use rocket::State;
+use rocket::{get, post, FromForm};
+use rocket_dyn_templates::Template;
+use serde::Serialize;
+
+/// The grant type a device has to use for polling the token endpoint (RFC 8628).
+const DEVICE_CODE_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+/// The number of seconds a device authorization can be approved by the user.
+const DEVICE_AUTHORIZATION_LIFETIME_IN_SECONDS: i64 = 600;
+
+/// The number of seconds a device has to wait between two polls of the token endpoint.
+const DEFAULT_POLLING_INTERVAL_IN_SECONDS: i32 = 5;
+
+/// The number of random bytes used for generating a device code.
+const DEVICE_CODE_LENGTH_IN_BYTES: usize = 32;
+
+/// The characters a user code consists of. Vowels and characters which can be mixed up easily are
+/// left out to prevent accidental words and typos.
+const USER_CODE_ALPHABET: &[u8] = b"BCDFGHJKLMNPQRSTVWXZ";
+
+/// The number of characters of a user code (without the dash in the middle).
+const USER_CODE_LENGTH: usize = 8;
+
+#[derive(FromForm)]
+pub struct DeviceAuthorizationRequestData {
+    /// The name the device identifies itself with. It is shown to the user while approving.
+    client_id: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct DeviceAuthorizationResponse {
+    /// The code the device uses for polling the token endpoint.
+    device_code: String,
+    /// The code the user has to enter on the verification page.
+    user_code: String,
+    /// The page on which the user can approve the device.
+    verification_uri: String,
+    /// The page on which the user can approve the device with the user code already filled in.
+    verification_uri_complete: String,
+    /// The number of seconds the codes are valid.
+    expires_in: i64,
+    /// The number of seconds the device has to wait between two polls.
+    interval: i32,
+}
+
+#[derive(FromForm)]
+pub struct DeviceApprovalForm {
+    username: String,
+    password: String,
+    totp_code: Option<String>,
+    new_password: Option<String>,
+    user_code: String,
+    approve: bool,
+}
+
+#[derive(FromForm)]
+pub struct DeviceTokenRequestData {
+    /// Has to be `urn:ietf:params:oauth:grant-type:device_code`.
+    grant_type: String,
+    /// The device code which was returned when the device authorization was started.
+    device_code: String,
+}
+
+#[derive(Serialize)]
+pub struct DeviceTokenResponse {
+    /// The personal access token in the format which is used in the `Authorization` header.
+    access_token: String,
+    /// The type of the access token which is always `PAT`.
+    token_type: String,
+    /// The token part of the personal access token.
+    token: String,
+    /// The secret part of the personal access token.
+    secret: String,
+}
+
+#[derive(Serialize)]
+struct DevicePageContext {
+    title: String,
+    user_code: String,
+    /// The name of the device which is waiting for the approval. The user is only asked to sign in
+    /// if a pending authorization belongs to the user code.
+    device_name: Option<String>,
+    /// Whether the supplied user code does not belong to a pending authorization.
+    invalid_user_code: bool,
+    message: Option<String>,
+}
+
+/// Generate a new random user code (without the dash).
+fn generate_user_code() -> String {
+    use crate::crypto::generate_random_bytes;
+
+    let mut user_code = String::with_capacity(USER_CODE_LENGTH);
+    while user_code.len() < USER_CODE_LENGTH {
+        // only use bytes which do not favor some characters of the alphabet over others
+        for byte in generate_random_bytes(USER_CODE_LENGTH) {
+            let limit = 256 - 256 % USER_CODE_ALPHABET.len();
+            if (byte as usize) < limit && user_code.len() < USER_CODE_LENGTH {
+                user_code
+                    .push(USER_CODE_ALPHABET[byte as usize % USER_CODE_ALPHABET.len()] as char);
+            }
+        }
+    }
+    user_code
+}
+
+/// Remove the dash and whitespaces a user may have entered with the user code.
+fn normalize_user_code(user_code: &str) -> String {
+    user_code
+        .chars()
+        .filter(|character| character.is_ascii_alphanumeric())
+        .map(|character| character.to_ascii_uppercase())
+        .collect()
+}
+
+/// Format a user code for displaying it to the user (e.g. `BCDF-GHJK`).
+fn format_user_code(user_code: &str) -> String {
+    let (first_half, second_half) = user_code.split_at(user_code.len() / 2);
+    format!("{}-{}", first_half, second_half)
+}
+
+#[post("/auth/device", data = "<request_data>")]
+pub async fn start_device_authorization(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    config: &State<BackendConfiguration>,
+    request_data: Form<DeviceAuthorizationRequestData>,
+) -> Result<Json<DeviceAuthorizationResponse>, Problem> {
+    use crate::crypto::{generate_random_token, sha256_hex};
+    use chrono::{Duration, Utc};
+    use diesel::{ExpressionMethods, RunQueryDsl};
+    use log::{error, info};
+
+    // the name of the device is optional but limited in its length
+    let client_name = request_data
+        .client_id
+        .as_deref()
+        .map(str::trim)
+        .filter(|client_name| !client_name.is_empty());
+    if client_name.map_or(false, |client_name| client_name.len() > 255) {
+        return Err(Problem::new(
+            Status::BadRequest,
+            "invalid_client_id",
+            "The client id must not be longer than 255 characters.",
+        ));
+    }
+
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // remove all authorizations which expired in the meantime
+    if let Err(error) = diesel::delete(device_authorizations::table)
+        .filter(device_authorizations::expires_at.lt(diesel::dsl::now))
+        .execute(db_connection)
+    {
+        error!(
+            "Could not remove the expired device authorizations. The error was: {}",
+            error
+        );
+    }
+
+    // store the new authorization, a colliding user code is very unlikely but we try a few times
+    let device_code = generate_random_token(DEVICE_CODE_LENGTH_IN_BYTES);
+    for _ in 0..3 {
+        let user_code = generate_user_code();
+        match diesel::insert_into(device_authorizations::table)
+            .values((
+                device_authorizations::device_code_hash.eq(sha256_hex(&device_code)),
+                device_authorizations::user_code.eq(&user_code),
+                device_authorizations::client_name.eq(client_name),
+                device_authorizations::polling_interval.eq(DEFAULT_POLLING_INTERVAL_IN_SECONDS),
+                device_authorizations::expires_at
+                    .eq(Utc::now() + Duration::seconds(DEVICE_AUTHORIZATION_LIFETIME_IN_SECONDS)),
+            ))
+            .execute(db_connection)
+        {
+            Ok(_) => {
+                info!(
+                    "Started a new device authorization for '{}'",
+                    client_name.unwrap_or("an unnamed device")
+                );
+                let verification_uri = format!("{}/device", config.public_url);
+                let formatted_user_code = format_user_code(&user_code);
+                return Ok(Json(DeviceAuthorizationResponse {
+                    device_code,
+                    verification_uri_complete: format!(
+                        "{}?user_code={}",
+                        verification_uri, formatted_user_code
+                    ),
+                    user_code: formatted_user_code,
+                    verification_uri,
+                    expires_in: DEVICE_AUTHORIZATION_LIFETIME_IN_SECONDS,
+                    interval: DEFAULT_POLLING_INTERVAL_IN_SECONDS,
+                }));
+            }
+            Err(diesel::result::Error::DatabaseError(
+                diesel::result::DatabaseErrorKind::UniqueViolation,
+                _,
+            )) => continue,
+            Err(error) => {
+                error!(
+                    "Could not store the new device authorization. The error was: {}",
+                    error
+                );
+                return Err(Status::InternalServerError.into());
+            }
+        }
+    }
+    error!("Could not find an unused user code for the new device authorization");
+    Err(Status::InternalServerError.into())
+}
+
+/// Get the id and the client name of the authorization the user code belongs to, if it is still
+/// pending (not approved, denied or expired).
+fn find_pending_device_authorization(
+    connection: &mut PgConnection,
+    user_code: &str,
+) -> Result<Option<(i32, Option<String>)>, diesel::result::Error> {
+    use diesel::{ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
+
+    device_authorizations::table
+        .filter(device_authorizations::user_code.eq(user_code))
+        .filter(device_authorizations::user_id.is_null())
+        .filter(device_authorizations::denied.eq(false))
+        .filter(device_authorizations::expires_at.gt(diesel::dsl::now))
+        .select((
+            device_authorizations::id,
+            device_authorizations::client_name,
+        ))
+        .first::<(i32, Option<String>)>(connection)
+        .optional()
+}
+
+#[get("/device?<user_code>")]
+pub async fn show_device_approval_page(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    user_code: Option<String>,
+) -> Result<Template, Problem> {
+    use log::error;
+
+    // without a user code, the user has to enter it first
+    let user_code = match user_code.as_deref().map(normalize_user_code) {
+        Some(user_code) if !user_code.is_empty() => user_code,
+        _ => {
+            return Ok(Template::render(
+                "device_authorization",
+                DevicePageContext {
+                    title: "Minne Device Login".to_string(),
+                    user_code: String::new(),
+                    device_name: None,
+                    invalid_user_code: false,
+                    message: None,
+                },
+            ))
+        }
+    };
+
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // show the name of the device before the user signs in, so the user can check that the code
+    // belongs to the own device (RFC 8628, section 5.4)
+    let pending_authorization = match find_pending_device_authorization(db_connection, &user_code) {
+        Ok(pending_authorization) => pending_authorization,
+        Err(error) => {
+            error!(
+                "Could not get the device authorization. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+    Ok(Template::render(
+        "device_authorization",
+        DevicePageContext {
+            title: "Minne Device Login".to_string(),
+            invalid_user_code: pending_authorization.is_none(),
+            device_name: pending_authorization.map(|(_, client_name)| {
+                client_name.unwrap_or_else(|| "An unnamed device".to_string())
+            }),
+            user_code: format_user_code(&user_code),
+            message: None,
+        },
+    ))
+}
+
+#[post("/auth/device/approve", data = "<approval>")]
+pub async fn approve_device(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    config: &State<BackendConfiguration>,
+    login_attempts: &State<LoginAttemptTracker>,
+    request_metadata: RequestMetadata,
+    approval: Form<DeviceApprovalForm>,
+) -> Result<Template, Problem> {
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+    use log::{debug, error, info};
+
+    // reject the login attempt early if there were too many failed attempts for the account or IP address
+    login_attempts.check(&approval.username, request_metadata.ip_address)?;
+
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // the user code has to belong to an authorization which is still pending
+    let user_code = normalize_user_code(&approval.user_code);
+    let (authorization_id, client_name) =
+        match find_pending_device_authorization(db_connection, &user_code) {
+            Ok(Some(authorization)) => authorization,
+            Ok(None) => {
+                debug!(
+                    "The user code '{}' is unknown, expired or already used",
+                    user_code
+                );
+                return Err(Problem::new(
+                    Status::BadRequest,
+                    "invalid_user_code",
+                    "The user code is unknown, expired or was already used.",
+                ));
+            }
+            Err(error) => {
+                error!(
+                    "Could not get the device authorization. The error was: {}",
+                    error
+                );
+                return Err(Status::InternalServerError.into());
+            }
+        };
+
+    // only an authenticated user can approve (or deny) the device
+    let user = verify_login_credentials(
+        db_connection,
+        config,
+        login_attempts,
+        &request_metadata,
+        &approval.username,
+        &approval.password,
+        approval.totp_code.as_deref(),
+        approval.new_password.as_deref(),
+        "device login",
+    )?;
+
+    // store the decision of the user, the device picks it up with the next poll
+    let update_result = if approval.approve {
+        diesel::update(device_authorizations::table.find(authorization_id))
+            .filter(device_authorizations::user_id.is_null())
+            .set(device_authorizations::user_id.eq(user.id))
+            .execute(db_connection)
+    } else {
+        diesel::update(device_authorizations::table.find(authorization_id))
+            .filter(device_authorizations::user_id.is_null())
+            .set(device_authorizations::denied.eq(true))
+            .execute(db_connection)
+    };
+    if let Err(error) = update_result {
+        error!(
+            "Could not store the decision for the device authorization with the id {}. The error was: {}",
+            authorization_id, error
+        );
+        return Err(Status::InternalServerError.into());
+    }
+    info!(
+        "The user with the id {} {} the device authorization with the id {}",
+        user.id,
+        if approval.approve {
+            "approved"
+        } else {
+            "denied"
+        },
+        authorization_id
+    );
+
+    // tell the user that the device can be used now (or was rejected)
+    let device_name = client_name.unwrap_or_else(|| "The device".to_string());
+    Ok(Template::render(
+        "device_authorization",
+        DevicePageContext {
+            title: "Minne Device Login".to_string(),
+            user_code: String::new(),
+            device_name: None,
+            invalid_user_code: false,
+            message: Some(if approval.approve {
+                format!("{} is now signed in. You can close this page.", device_name)
+            } else {
+                format!(
+                    "{} was not signed in. You can close this page.",
+                    device_name
+                )
+            }),
+        },
+    ))
+}
+
+#[post("/auth/device/token", data = "<token_request>")]
+pub async fn poll_device_token(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    request_metadata: RequestMetadata,
+    token_request: Form<DeviceTokenRequestData>,
+) -> Result<Json<DeviceTokenResponse>, Custom<Json<OAuthErrorResponse>>> {
+    use crate::crypto::sha256_hex;
+    use chrono::{DateTime, Duration, Utc};
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+    use log::{error, info};
+    use uuid::Uuid;
+
+    // only the device code grant is supported by this endpoint
+    if token_request.grant_type != DEVICE_CODE_GRANT_TYPE {
+        return Err(oauth_error(Status::BadRequest, "unsupported_grant_type"));
+    }
+
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Err(oauth_error(Status::InternalServerError, "server_error"));
+        }
+    };
+
+    // get the authorization the device code belongs to
+    let device_code_hash = sha256_hex(token_request.device_code.trim());
+    #[allow(clippy::type_complexity)]
+    let authorization = match device_authorizations::table
+        .filter(device_authorizations::device_code_hash.eq(&device_code_hash))
+        .select((
+            device_authorizations::id,
+            device_authorizations::client_name,
+            device_authorizations::user_id,
+            device_authorizations::denied,
+            device_authorizations::polling_interval,
+            device_authorizations::last_polled_at,
+            device_authorizations::expires_at,
+        ))
+        .first::<(
+            i32,
+            Option<String>,
+            Option<i32>,
+            bool,
+            i32,
+            Option<DateTime<Utc>>,
+            DateTime<Utc>,
+        )>(db_connection)
+    {
+        Ok(authorization) => authorization,
+        Err(diesel::NotFound) => {
+            return Err(oauth_error(Status::BadRequest, "invalid_grant"));
+        }
+        Err(error) => {
+            error!(
+                "Could not get the device authorization. The error was: {}",
+                error
+            );
+            return Err(oauth_error(Status::InternalServerError, "server_error"));
+        }
+    };
+    let (
+        authorization_id,
+        client_name,
+        approving_user_id,
+        denied,
+        polling_interval,
+        last_polled_at,
+        expires_at,
+    ) = authorization;
+
+    // expired and denied authorizations are removed since the device has to start over anyway
+    if expires_at <= Utc::now() || denied {
+        let _ = diesel::delete(device_authorizations::table.find(authorization_id))
+            .execute(db_connection);
+        return Err(oauth_error(
+            Status::BadRequest,
+            if denied {
+                "access_denied"
+            } else {
+                "expired_token"
+            },
+        ));
+    }
+
+    // as long as the user did not decide, the device has to keep polling (but not too often)
+    if approving_user_id.is_none() {
+        let polled_too_early = last_polled_at.map_or(false, |last_polled_at| {
+            Utc::now() < last_polled_at + Duration::seconds(polling_interval as i64)
+        });
+        let new_polling_interval = if polled_too_early {
+            polling_interval + DEFAULT_POLLING_INTERVAL_IN_SECONDS
+        } else {
+            polling_interval
+        };
+        if let Err(error) = diesel::update(device_authorizations::table.find(authorization_id))
+            .set((
+                device_authorizations::last_polled_at.eq(Utc::now()),
+                device_authorizations::polling_interval.eq(new_polling_interval),
+            ))
+            .execute(db_connection)
+        {
+            error!(
+                "Could not update the polling state of the device authorization with the id {}. The error was: {}",
+                authorization_id, error
+            );
+        }
+        return Err(oauth_error(
+            Status::BadRequest,
+            if polled_too_early {
+                "slow_down"
+            } else {
+                "authorization_pending"
+            },
+        ));
+    }
+
+    // the authorization was approved, removing it ensures that the token is only issued once
+    let user_id = match diesel::delete(device_authorizations::table.find(authorization_id))
+        .filter(device_authorizations::user_id.is_not_null())
+        .returning(device_authorizations::user_id)
+        .get_result::<Option<i32>>(db_connection)
+    {
+        Ok(Some(user_id)) => user_id,
+        Ok(None) | Err(diesel::NotFound) => {
+            return Err(oauth_error(Status::BadRequest, "invalid_grant"));
+        }
+        Err(error) => {
+            error!(
+                "Could not remove the approved device authorization with the id {}. The error was: {}",
+                authorization_id, error
+            );
+            return Err(oauth_error(Status::InternalServerError, "server_error"));
+        }
+    };
+
+    // create a new personal access token for the device
+    let new_pat = NewPersonalAccessToken {
+        name: match client_name {
+            Some(client_name) => format!("Device Login ({})", client_name),
+            None => "Device Login".to_string(),
+        },
+        user_id,
+        token: Uuid::new_v4().to_string(),
+        secret: Uuid::new_v4().to_string(),
+        user_agent: request_metadata.user_agent.clone(),
+    };
+    if let Err(error) = diesel::insert_into(personal_access_tokens::table)
+        .values(&new_pat)
+        .execute(db_connection)
+    {
+        error!(
+            "Could not store the personal access token for the user with the id {}. The error was: {}",
+            user_id, error
+        );
+        return Err(oauth_error(Status::InternalServerError, "server_error"));
+    }
+    info!(
+        "Issued a personal access token for the device login of the user with the id {}",
+        user_id
+    );
+    record_audit_event(
+        db_connection,
+        AuditEventType::PersonalAccessTokenCreated,
+        Some(user_id),
+        Some(user_id),
+        &request_metadata,
+        Some(&new_pat.name),
+    );
+
+    // return the token as well as the corresponding secret to the device
+    Ok(Json(DeviceTokenResponse {
+        access_token: format!("{}:{}", new_pat.token, new_pat.secret),
+        token_type: "PAT".to_string(),
+        token: new_pat.token,
+        secret: new_pat.secret,
+    }))
+}
@@ -0,0 +1,400 @@
+use crate::audit::{record_audit_event, AuditEventType, RequestMetadata};
+use crate::fairings::MinneDatabaseConnection;
+use crate::guards::AuthenticatedUser;
+use crate::problem::Problem;
+use chrono::{DateTime, Utc};
+use diesel::PgConnection;
+use rocket::get;
+use rocket::http::{Header, Status};
+use rocket::{Responder, State};
+use serde::Serialize;
+use std::fmt::{Display, Formatter};
+
+/// The version of the format of the exported data. It has to be increased as soon as the
+/// structure of one of the exported files changes.
+const EXPORT_FORMAT_VERSION: u32 = 5;
+
+/// The archive containing all data stored about a user.
+#[derive(Responder)]
+#[response(content_type = "application/zip")]
+pub struct DataExportArchive {
+    inner: Vec<u8>,
+    content_disposition: Header<'static>,
+}
+
+impl DataExportArchive {
+    /// Wrap the content of an export archive of the supplied user into a response which is
+    /// downloaded as a file.
+    pub fn new(user_id: i32, content: Vec<u8>) -> Self {
+        DataExportArchive {
+            inner: content,
+            content_disposition: Header::new(
+                "Content-Disposition",
+                format!(
+                    "attachment; filename=\"minne-export-{}-{}.zip\"",
+                    user_id,
+                    Utc::now().format("%Y%m%d%H%M%S")
+                ),
+            ),
+        }
+    }
+}
+
+/// The reasons why an export of the user data could not be created.
+#[derive(Debug)]
+pub enum DataExportError {
+    /// The user for which the data should be exported does not exist.
+    UnknownUser,
+    /// The data could not be fetched from the database.
+    Database(diesel::result::Error),
+    /// The data could not be serialized into its JSON representation.
+    Serialization(rocket::serde::json::serde_json::Error),
+    /// The archive containing the exported files could not be created.
+    Archive(zip::result::ZipError),
+}
+
+impl Display for DataExportError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DataExportError::UnknownUser => write!(f, "the user does not exist"),
+            DataExportError::Database(error) => write!(f, "database error: {}", error),
+            DataExportError::Serialization(error) => write!(f, "serialization error: {}", error),
+            DataExportError::Archive(error) => write!(f, "archive error: {}", error),
+        }
+    }
+}
+
+impl From<diesel::result::Error> for DataExportError {
+    fn from(error: diesel::result::Error) -> Self {
+        match error {
+            diesel::result::Error::NotFound => DataExportError::UnknownUser,
+            error => DataExportError::Database(error),
+        }
+    }
+}
+
+impl From<rocket::serde::json::serde_json::Error> for DataExportError {
+    fn from(error: rocket::serde::json::serde_json::Error) -> Self {
+        DataExportError::Serialization(error)
+    }
+}
+
+impl From<zip::result::ZipError> for DataExportError {
+    fn from(error: zip::result::ZipError) -> Self {
+        DataExportError::Archive(error)
+    }
+}
+
+impl From<std::io::Error> for DataExportError {
+    fn from(error: std::io::Error) -> Self {
+        DataExportError::Archive(zip::result::ZipError::Io(error))
+    }
+}
+
+#[derive(Serialize)]
+struct ExportManifest {
+    /// The version of the export format.
+    format_version: u32,
+    /// The time when the export was created.
+    generated_at: DateTime<Utc>,
+    /// The internal id of the user the export belongs to.
+    user_id: i32,
+    /// The names of all files contained in the archive.
+    files: Vec<&'static str>,
+}
+
+#[derive(Serialize)]
+struct ExportedProfile {
+    id: i32,
+    first_name: String,
+    last_name: String,
+    email: String,
+    pending_email: Option<String>,
+    totp_enabled: bool,
+    unused_recovery_codes: i64,
+    invited_by: Option<i32>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct ExportedTask {
+    id: i32,
+    title: String,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    done_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+struct ExportedInvitationCode {
+    id: i32,
+    max_uses: i32,
+    uses: i32,
+    expires_at: DateTime<Utc>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct ExportedPersonalAccessToken {
+    id: i32,
+    name: String,
+    disabled: bool,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    user_agent: Option<String>,
+    last_used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+struct ExportedExternalIdentity {
+    provider: String,
+    subject: String,
+    email: String,
+    last_login_at: Option<DateTime<Utc>>,
+    created_at: DateTime<Utc>,
+}
+
+/// Collect everything which is stored about the supplied user and put it into a ZIP archive
+/// containing one JSON file per kind of data. Secrets (password hash, TOTP secret, recovery codes,
+/// the secrets of personal access tokens and invitation codes) are never part of the export.
+pub fn build_user_data_export(
+    connection: &mut PgConnection,
+    user_id: i32,
+) -> Result<Vec<u8>, DataExportError> {
+    use crate::routes::audit::AuditEvent;
+    use crate::routes::invitation::InvitationCode;
+    use crate::routes::task::Task;
+    use crate::routes::user::User;
+    use crate::schema::{
+        audit_events, external_identities, invitation_codes, personal_access_tokens, tasks,
+        totp_recovery_codes, users,
+    };
+    use chrono::TimeZone;
+    use diesel::dsl::count_star;
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+    use rocket::serde::json::to_pretty_string;
+    use std::io::{Cursor, Write};
+    use zip::write::FileOptions;
+    use zip::{CompressionMethod, ZipWriter};
+
+    // fetch all data in a single transaction to get a consistent snapshot
+    let (
+        user,
+        unused_recovery_codes,
+        user_tasks,
+        user_tokens,
+        user_invitation_codes,
+        user_external_identities,
+        user_audit_events,
+    ) = connection
+        .build_transaction()
+        .read_only()
+        .run::<_, diesel::result::Error, _>(|connection| {
+            let user = users::table.find(user_id).first::<User>(connection)?;
+            let unused_recovery_codes = totp_recovery_codes::table
+                .filter(totp_recovery_codes::user_id.eq(user_id))
+                .filter(totp_recovery_codes::used_at.is_null())
+                .select(count_star())
+                .first::<i64>(connection)?;
+            let user_tasks = tasks::table
+                .filter(tasks::owner.eq(user_id))
+                .order(tasks::id)
+                .load::<Task>(connection)?;
+            let user_tokens = personal_access_tokens::table
+                .filter(personal_access_tokens::user_id.eq(user_id))
+                .order(personal_access_tokens::id)
+                .select((
+                    personal_access_tokens::id,
+                    personal_access_tokens::name,
+                    personal_access_tokens::disabled,
+                    personal_access_tokens::created_at,
+                    personal_access_tokens::updated_at,
+                    personal_access_tokens::user_agent,
+                    personal_access_tokens::last_used_at,
+                ))
+                .load::<(
+                    i32,
+                    String,
+                    bool,
+                    DateTime<Utc>,
+                    DateTime<Utc>,
+                    Option<String>,
+                    Option<DateTime<Utc>>,
+                )>(connection)?;
+            let user_invitation_codes = invitation_codes::table
+                .filter(invitation_codes::created_by.eq(user_id))
+                .order(invitation_codes::id)
+                .load::<InvitationCode>(connection)?;
+            let user_external_identities = external_identities::table
+                .filter(external_identities::user_id.eq(user_id))
+                .order(external_identities::id)
+                .select((
+                    external_identities::provider,
+                    external_identities::subject,
+                    external_identities::email,
+                    external_identities::last_login_at,
+                    external_identities::created_at,
+                ))
+                .load::<(String, String, String, Option<DateTime<Utc>>, DateTime<Utc>)>(
+                    connection,
+                )?;
+            let user_audit_events = audit_events::table
+                .filter(audit_events::user_id.eq(user_id))
+                .order(audit_events::id)
+                .load::<AuditEvent>(connection)?;
+            Ok((
+                user,
+                unused_recovery_codes,
+                user_tasks,
+                user_tokens,
+                user_invitation_codes,
+                user_external_identities,
+                user_audit_events,
+            ))
+        })?;
+
+    // convert the database records into the documented export format
+    let profile = ExportedProfile {
+        id: user.id,
+        first_name: user.first_name,
+        last_name: user.last_name,
+        email: user.email,
+        pending_email: user.pending_email,
+        totp_enabled: user.totp_enabled,
+        unused_recovery_codes,
+        invited_by: user.invited_by,
+        created_at: Utc.from_utc_datetime(&user.created_at),
+        updated_at: Utc.from_utc_datetime(&user.updated_at),
+    };
+    let exported_tasks = user_tasks
+        .into_iter()
+        .map(|task| ExportedTask {
+            id: task.id,
+            title: task.title,
+            created_at: task.created_at,
+            updated_at: task.updated_at,
+            done_at: task.done_at,
+        })
+        .collect::<Vec<ExportedTask>>();
+    let exported_tokens = user_tokens
+        .into_iter()
+        .map(
+            |(id, name, disabled, created_at, updated_at, user_agent, last_used_at)| {
+                ExportedPersonalAccessToken {
+                    id,
+                    name,
+                    disabled,
+                    created_at,
+                    updated_at,
+                    user_agent,
+                    last_used_at,
+                }
+            },
+        )
+        .collect::<Vec<ExportedPersonalAccessToken>>();
+    let exported_invitation_codes = user_invitation_codes
+        .into_iter()
+        .map(|invitation_code| ExportedInvitationCode {
+            id: invitation_code.id,
+            max_uses: invitation_code.max_uses,
+            uses: invitation_code.uses,
+            expires_at: invitation_code.expires_at,
+            created_at: invitation_code.created_at,
+        })
+        .collect::<Vec<ExportedInvitationCode>>();
+    let exported_external_identities = user_external_identities
+        .into_iter()
+        .map(
+            |(provider, subject, email, last_login_at, created_at)| ExportedExternalIdentity {
+                provider,
+                subject,
+                email,
+                last_login_at,
+                created_at,
+            },
+        )
+        .collect::<Vec<ExportedExternalIdentity>>();
+
+    // serialize all files which should be part of the archive
+    let mut files = vec![
+        ("profile.json", to_pretty_string(&profile)?),
+        ("tasks.json", to_pretty_string(&exported_tasks)?),
+        (
+            "personal_access_tokens.json",
+            to_pretty_string(&exported_tokens)?,
+        ),
+        (
+            "invitation_codes.json",
+            to_pretty_string(&exported_invitation_codes)?,
+        ),
+        (
+            "external_identities.json",
+            to_pretty_string(&exported_external_identities)?,
+        ),
+        ("audit_events.json", to_pretty_string(&user_audit_events)?),
+    ];
+    let manifest = ExportManifest {
+        format_version: EXPORT_FORMAT_VERSION,
+        generated_at: Utc::now(),
+        user_id,
+        files: files.iter().map(|(name, _)| *name).collect(),
+    };
+    files.insert(0, ("manifest.json", to_pretty_string(&manifest)?));
+
+    // write all files into a single archive
+    let mut archive = ZipWriter::new(Cursor::new(Vec::new()));
+    let file_options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    for (name, content) in files {
+        archive.start_file(name, file_options)?;
+        archive.write_all(content.as_bytes())?;
+    }
+    Ok(archive.finish()?.into_inner())
+}
+
+#[get("/user/me/export")]
+pub async fn export_own_data(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    authenticated_user: AuthenticatedUser,
+    request_metadata: RequestMetadata,
+) -> Result<DataExportArchive, Problem> {
+    use log::{error, info};
+
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // create the archive with all data of the authenticated user and return it
+    match build_user_data_export(db_connection, authenticated_user.id) {
+        Ok(content) => {
+            info!(
+                "Exported all data of the user with the id {}",
+                authenticated_user.id
+            );
+            record_audit_event(
+                db_connection,
+                AuditEventType::DataExported,
+                Some(authenticated_user.id),
+                Some(authenticated_user.id),
+                &request_metadata,
+                None,
+            );
+            Ok(DataExportArchive::new(authenticated_user.id, content))
+        }
+        Err(error) => {
+            error!(
+                "Could not export the data of the user with the id {}. The error was: {}",
+                authenticated_user.id, error
+            );
+            Err(Status::InternalServerError.into())
+        }
+    }
+}
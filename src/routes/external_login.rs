@@ -0,0 +1,721 @@
+use crate::audit::{record_audit_event, AuditEventType, RequestMetadata};
+use crate::fairings::{BackendConfiguration, MinneDatabaseConnection};
+use crate::guards::AuthenticatedUser;
+use crate::identity_providers::{ExternalIdentityClaims, IdentityProvider, IdentityProviders};
+use crate::problem::Problem;
+use crate::routes::auth::{complete_app_login_process, get_token_for_user, TokenResponse};
+use crate::routes::user::User;
+use crate::schema::{external_identities, external_login_states, users};
+use crate::signing::TokenSigningKeys;
+use diesel::PgConnection;
+use rocket::http::Status;
+use rocket::response::Redirect;
+use rocket::serde::json::Json;
+use rocket::{get, post};
+use rocket::{Responder, State};
+use serde::Serialize;
+
+/// The number of minutes a user has to log in at the identity provider.
+const EXTERNAL_LOGIN_LIFETIME_IN_MINUTES: i64 = 10;
+
+/// The number of random bytes used for generating the state sent to the identity provider.
+const STATE_LENGTH_IN_BYTES: usize = 32;
+
+/// The number of random bytes used for generating the nonce sent to the identity provider.
+const NONCE_LENGTH_IN_BYTES: usize = 16;
+
+/// The number of random bytes used for generating the PKCE code verifier.
+const CODE_VERIFIER_LENGTH_IN_BYTES: usize = 32;
+
+/// The possible responses after a successful login with an identity provider.
+#[allow(clippy::large_enum_variant)]
+#[derive(Responder)]
+pub enum ExternalLoginResponse {
+    /// The login completed a login process of the app which gets the authorization code.
+    Redirect(Redirect),
+    /// The login was started without the app, so an access token is returned directly.
+    Token(Json<TokenResponse>),
+    /// The login was started by a logged-in user for linking the identity to the own account.
+    Linked(Json<LinkedExternalIdentity>),
+}
+
+#[derive(Serialize)]
+pub struct ExternalIdentityLinkStart {
+    /// The URL the user has to open for logging in at the identity provider.
+    pub authorization_url: String,
+}
+
+#[derive(Serialize)]
+pub struct LinkedExternalIdentity {
+    /// The id of the identity provider the identity belongs to.
+    pub provider: String,
+}
+
+/// Get the URI the identity provider redirects the user to after the login.
+fn get_redirect_uri(config: &BackendConfiguration, provider: &IdentityProvider) -> String {
+    format!("{}/login/{}/callback", config.public_url, provider.id)
+}
+
+/// Escape the wildcards of a `LIKE` pattern, so the value is matched literally.
+fn escape_like_pattern(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// Get the user the external identity belongs to. Known identities are used directly, otherwise
+/// the identity is linked to the user with the same (verified) email address, but only if the
+/// account has no password of its own (e.g. since it was created by an identity provider). Otherwise
+/// anybody who registered the email address with a password first would get access to the identity.
+/// Such users have to link the identity after logging in with their password. If there is no user
+/// and the registration is allowed, a new account is created.
+fn resolve_external_identity(
+    connection: &mut PgConnection,
+    config: &BackendConfiguration,
+    provider: &IdentityProvider,
+    claims: &ExternalIdentityClaims,
+    request_metadata: &RequestMetadata,
+) -> Result<User, Problem> {
+    use crate::crypto::generate_random_token;
+    use crate::passwords::hash_password;
+    use crate::routes::user::NewUser;
+    use diesel::{
+        ExpressionMethods, OptionalExtension, PgTextExpressionMethods, QueryDsl, RunQueryDsl,
+    };
+    use log::{error, info, warn};
+
+    // if the identity was already linked, the linked user is used
+    let linked_user = external_identities::table
+        .inner_join(users::table)
+        .filter(external_identities::provider.eq(&provider.id))
+        .filter(external_identities::subject.eq(&claims.sub))
+        .select(users::all_columns)
+        .first::<User>(connection)
+        .optional()
+        .map_err(|error| {
+            error!(
+                "Could not get the user linked to the external identity. The error was: {}",
+                error
+            );
+            Status::InternalServerError
+        })?;
+    if let Some(user) = linked_user {
+        let _ = diesel::update(
+            external_identities::table
+                .filter(external_identities::provider.eq(&provider.id))
+                .filter(external_identities::subject.eq(&claims.sub)),
+        )
+        .set(external_identities::last_login_at.eq(diesel::dsl::now))
+        .execute(connection);
+        return Ok(user);
+    }
+
+    // new identities can only be linked by a verified email address
+    let email = match claims.email.as_deref() {
+        Some(email) if claims.is_email_verified() => email.trim().to_string(),
+        _ => {
+            warn!(
+                "The identity provider '{}' did not supply a verified email address for the subject '{}'",
+                provider.id, claims.sub
+            );
+            return Err(Problem::new(
+                Status::Forbidden,
+                "unverified_email",
+                "The identity provider did not supply a verified email address.",
+            ));
+        }
+    };
+    let existing_user = users::table
+        .filter(users::email.ilike(escape_like_pattern(&email)))
+        .first::<User>(connection)
+        .optional()
+        .map_err(|error| {
+            error!(
+                "Could not get the user for the email address of the external identity. The error was: {}",
+                error
+            );
+            Status::InternalServerError
+        })?;
+
+    // create a new account if the user is unknown and the registration is allowed
+    let (user, registered) = match existing_user {
+        Some(user) if user.has_local_password => {
+            info!(
+                "Did not link an identity of the provider '{}' to the user with the id {} since the account has a password",
+                provider.id, user.id
+            );
+            return Err(Problem::new(
+                Status::Conflict,
+                "identity_link_required",
+                "There already is an account with a password for the email address. Log in with the password and link the identity to the account first.",
+            ));
+        }
+        Some(user) => (user, false),
+        None => {
+            if !config.user_registration_enabled || !provider.allow_registration {
+                info!(
+                    "Rejected the login of an unknown user with the identity provider '{}' since the registration is disabled",
+                    provider.id
+                );
+                return Err(Problem::new(
+                    Status::Forbidden,
+                    "registration_disabled",
+                    "There is no account for the email address and new accounts cannot be registered.",
+                ));
+            }
+            let (first_name, last_name) = match (&claims.given_name, &claims.family_name) {
+                (Some(given_name), Some(family_name)) => (given_name.clone(), family_name.clone()),
+                _ => {
+                    let name = claims.name.clone().unwrap_or_default();
+                    match name.split_once(' ') {
+                        Some((first_name, last_name)) => {
+                            (first_name.to_string(), last_name.to_string())
+                        }
+                        None => (name, String::new()),
+                    }
+                }
+            };
+
+            // the account gets a random password, so it can only be used with the identity provider
+            // until the user resets the password
+            let password_hash =
+                match hash_password(&config.password_hashing, &generate_random_token(32)) {
+                    Ok(password_hash) => password_hash,
+                    Err(error) => {
+                        error!(
+                            "Could not hash the password of the new user. The error was: {}",
+                            error
+                        );
+                        return Err(Status::InternalServerError.into());
+                    }
+                };
+            let new_user = NewUser {
+                first_name,
+                last_name,
+                email: email.clone(),
+                password_hash,
+                invited_by: None,
+                invitation_code_id: None,
+                has_local_password: false,
+            };
+            match diesel::insert_into(users::table)
+                .values(&new_user)
+                .get_result::<User>(connection)
+            {
+                Ok(user) => (user, true),
+                Err(error) => {
+                    error!(
+                        "Could not create the user for the external identity. The error was: {}",
+                        error
+                    );
+                    return Err(Status::InternalServerError.into());
+                }
+            }
+        }
+    };
+    if registered {
+        info!(
+            "Created the user with the id {} for a login with the identity provider '{}'",
+            user.id, provider.id
+        );
+        record_audit_event(
+            connection,
+            AuditEventType::UserRegistered,
+            Some(user.id),
+            Some(user.id),
+            request_metadata,
+            Some(&format!("via the identity provider {}", provider.name)),
+        );
+    }
+
+    // link the identity, so it can be used for the next logins directly
+    link_external_identity(connection, user.id, provider, claims, request_metadata)?;
+    Ok(user)
+}
+
+/// Link the external identity to the supplied user, so it can be used for the next logins.
+fn link_external_identity(
+    connection: &mut PgConnection,
+    user_id: i32,
+    provider: &IdentityProvider,
+    claims: &ExternalIdentityClaims,
+    request_metadata: &RequestMetadata,
+) -> Result<(), Problem> {
+    use diesel::{ExpressionMethods, RunQueryDsl};
+    use log::{error, info};
+
+    if let Err(error) = diesel::insert_into(external_identities::table)
+        .values((
+            external_identities::user_id.eq(user_id),
+            external_identities::provider.eq(&provider.id),
+            external_identities::subject.eq(&claims.sub),
+            external_identities::email.eq(claims.email.as_deref().unwrap_or_default().trim()),
+            external_identities::last_login_at.eq(diesel::dsl::now),
+        ))
+        .execute(connection)
+    {
+        error!(
+            "Could not link the external identity to the user with the id {}. The error was: {}",
+            user_id, error
+        );
+        return Err(Status::InternalServerError.into());
+    }
+    info!(
+        "Linked an identity of the provider '{}' to the user with the id {}",
+        provider.id, user_id
+    );
+    record_audit_event(
+        connection,
+        AuditEventType::ExternalIdentityLinked,
+        Some(user_id),
+        Some(user_id),
+        request_metadata,
+        Some(&provider.name),
+    );
+    Ok(())
+}
+
+/// Link the external identity to the account of a user who started the login for linking it. The
+/// user already proved the ownership of the account, so the email addresses do not have to match.
+fn link_external_identity_to_account(
+    connection: &mut PgConnection,
+    user_id: i32,
+    provider: &IdentityProvider,
+    claims: &ExternalIdentityClaims,
+    request_metadata: &RequestMetadata,
+) -> Result<(), Problem> {
+    use diesel::{ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
+    use log::{error, warn};
+
+    // an identity can only belong to a single account
+    let linked_user_id = external_identities::table
+        .filter(external_identities::provider.eq(&provider.id))
+        .filter(external_identities::subject.eq(&claims.sub))
+        .select(external_identities::user_id)
+        .first::<i32>(connection)
+        .optional()
+        .map_err(|error| {
+            error!(
+                "Could not get the user linked to the external identity. The error was: {}",
+                error
+            );
+            Status::InternalServerError
+        })?;
+    match linked_user_id {
+        Some(linked_user_id) if linked_user_id == user_id => Ok(()),
+        Some(linked_user_id) => {
+            warn!(
+                "The user with the id {} tried to link an identity of the provider '{}' which belongs to the user with the id {}",
+                user_id, provider.id, linked_user_id
+            );
+            Err(Problem::new(
+                Status::Conflict,
+                "identity_already_linked",
+                "The identity is already linked to another account.",
+            ))
+        }
+        None => link_external_identity(connection, user_id, provider, claims, request_metadata),
+    }
+}
+
+/// Remember a new login at the identity provider and get the URL the user has to be sent to. The
+/// login either completes an app login process, links the identity to the account of a logged-in
+/// user or just returns an access token.
+async fn start_login_at_provider(
+    connection: &mut PgConnection,
+    config: &BackendConfiguration,
+    provider: &IdentityProvider,
+    login_process_id: Option<String>,
+    link_user_id: Option<i32>,
+) -> Result<String, Problem> {
+    use crate::crypto::{generate_random_bytes, generate_random_token, sha256_hex};
+    use chrono::{Duration, Utc};
+    use data_encoding::BASE64URL_NOPAD;
+    use diesel::{ExpressionMethods, RunQueryDsl};
+    use log::error;
+    use ring::digest::{digest, SHA256};
+
+    // remove all logins which expired in the meantime
+    if let Err(error) = diesel::delete(external_login_states::table)
+        .filter(external_login_states::expires_at.lt(diesel::dsl::now))
+        .execute(connection)
+    {
+        error!(
+            "Could not remove the expired external logins. The error was: {}",
+            error
+        );
+    }
+
+    // remember the login, so the response of the provider can be checked
+    let state = generate_random_token(STATE_LENGTH_IN_BYTES);
+    let nonce = generate_random_token(NONCE_LENGTH_IN_BYTES);
+    let code_verifier =
+        BASE64URL_NOPAD.encode(&generate_random_bytes(CODE_VERIFIER_LENGTH_IN_BYTES));
+    let code_challenge = BASE64URL_NOPAD.encode(digest(&SHA256, code_verifier.as_bytes()).as_ref());
+    if let Err(error) = diesel::insert_into(external_login_states::table)
+        .values((
+            external_login_states::state_hash.eq(sha256_hex(&state)),
+            external_login_states::provider.eq(&provider.id),
+            external_login_states::nonce.eq(&nonce),
+            external_login_states::code_verifier.eq(&code_verifier),
+            external_login_states::login_process_id.eq(&login_process_id),
+            external_login_states::link_user_id.eq(link_user_id),
+            external_login_states::expires_at
+                .eq(Utc::now() + Duration::minutes(EXTERNAL_LOGIN_LIFETIME_IN_MINUTES)),
+        ))
+        .execute(connection)
+    {
+        error!(
+            "Could not store the external login. The error was: {}",
+            error
+        );
+        return Err(Status::InternalServerError.into());
+    }
+
+    // send the user to the identity provider
+    match provider
+        .authorization_url(
+            &get_redirect_uri(config, provider),
+            &state,
+            &nonce,
+            &code_challenge,
+        )
+        .await
+    {
+        Ok(authorization_url) => Ok(authorization_url),
+        Err(error) => {
+            error!(
+                "Could not start the login with the identity provider '{}'. The error was: {}",
+                provider.id, error
+            );
+            Err(Status::BadGateway.into())
+        }
+    }
+}
+
+/// The problem which is reported if no identity provider with the requested id is configured.
+fn unknown_identity_provider() -> Problem {
+    Problem::new(
+        Status::NotFound,
+        "unknown_identity_provider",
+        "The identity provider is not configured.",
+    )
+}
+
+#[get("/login/<provider_id>?<login_process_id>")]
+pub async fn start_external_login(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    config: &State<BackendConfiguration>,
+    identity_providers: &State<IdentityProviders>,
+    provider_id: &str,
+    login_process_id: Option<String>,
+) -> Result<Redirect, Problem> {
+    use crate::schema::app_login_processes;
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+    use log::error;
+
+    // the provider has to be configured
+    let provider = match identity_providers.find(provider_id) {
+        Some(provider) => provider,
+        None => return Err(unknown_identity_provider()),
+    };
+
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // if the login was started from the login page of the app, the process has to be pending
+    if let Some(login_process_id) = &login_process_id {
+        match app_login_processes::table
+            .find(login_process_id)
+            .filter(app_login_processes::authorization_code_hash.is_null())
+            .filter(app_login_processes::expires_at.gt(diesel::dsl::now))
+            .count()
+            .get_result::<i64>(db_connection)
+        {
+            Ok(1) => {}
+            Ok(_) => {
+                return Err(Problem::new(
+                    Status::BadRequest,
+                    "unknown_login_process",
+                    "The login process is unknown, expired or already completed.",
+                ))
+            }
+            Err(error) => {
+                error!(
+                    "Could not get the app login process. The error was: {}",
+                    error
+                );
+                return Err(Status::InternalServerError.into());
+            }
+        }
+    }
+
+    // send the user to the identity provider
+    start_login_at_provider(db_connection, config, provider, login_process_id, None)
+        .await
+        .map(Redirect::to)
+}
+
+#[post("/user/me/external-identities/<provider_id>")]
+pub async fn start_external_identity_link(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    config: &State<BackendConfiguration>,
+    identity_providers: &State<IdentityProviders>,
+    authenticated_user: AuthenticatedUser,
+    provider_id: &str,
+) -> Result<Json<ExternalIdentityLinkStart>, Problem> {
+    use log::error;
+
+    // the provider has to be configured
+    let provider = match identity_providers.find(provider_id) {
+        Some(provider) => provider,
+        None => return Err(unknown_identity_provider()),
+    };
+
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // the callback links the identity to the logged-in user instead of logging in
+    let authorization_url = start_login_at_provider(
+        db_connection,
+        config,
+        provider,
+        None,
+        Some(authenticated_user.id),
+    )
+    .await?;
+    Ok(Json(ExternalIdentityLinkStart { authorization_url }))
+}
+
+#[allow(clippy::too_many_arguments)]
+#[get("/login/<provider_id>/callback?<code>&<state>&<error>")]
+pub async fn finish_external_login(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    config: &State<BackendConfiguration>,
+    signing_keys: &State<TokenSigningKeys>,
+    identity_providers: &State<IdentityProviders>,
+    request_metadata: RequestMetadata,
+    provider_id: &str,
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+) -> Result<ExternalLoginResponse, Problem> {
+    use crate::crypto::sha256_hex;
+    use diesel::{ExpressionMethods, OptionalExtension, RunQueryDsl};
+    use log::{error, info, warn};
+
+    // the provider has to be configured
+    let provider = match identity_providers.find(provider_id) {
+        Some(provider) => provider,
+        None => return Err(unknown_identity_provider()),
+    };
+
+    // the provider reports errors (e.g. if the user canceled the login) with a parameter
+    if let Some(error) = error {
+        info!(
+            "The identity provider '{}' reported the error '{}'",
+            provider.id, error
+        );
+        return Err(Problem::new(
+            Status::Unauthorized,
+            "identity_provider_error",
+            "The identity provider reported an error (e.g. since the login was canceled).",
+        ));
+    }
+    let (code, state) = match (code, state) {
+        (Some(code), Some(state)) => (code, state),
+        _ => {
+            return Err(Problem::new(
+                Status::BadRequest,
+                "missing_fields",
+                "The identity provider has to supply a code and a state.",
+            ))
+        }
+    };
+
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // the state has to belong to a login which was started for the provider, it can only be used once
+    let (nonce, code_verifier, login_process_id, link_user_id) =
+        match diesel::delete(external_login_states::table)
+            .filter(external_login_states::state_hash.eq(sha256_hex(&state)))
+            .filter(external_login_states::provider.eq(&provider.id))
+            .filter(external_login_states::expires_at.gt(diesel::dsl::now))
+            .returning((
+                external_login_states::nonce,
+                external_login_states::code_verifier,
+                external_login_states::login_process_id,
+                external_login_states::link_user_id,
+            ))
+            .get_result::<(String, String, Option<String>, Option<i32>)>(db_connection)
+            .optional()
+        {
+            Ok(Some(login_state)) => login_state,
+            Ok(None) => {
+                warn!(
+                    "Got an unknown or expired state from the identity provider '{}'",
+                    provider.id
+                );
+                return Err(Problem::new(
+                    Status::BadRequest,
+                    "invalid_state",
+                    "The state is unknown or expired, the login has to be started again.",
+                ));
+            }
+            Err(error) => {
+                error!("Could not get the external login. The error was: {}", error);
+                return Err(Status::InternalServerError.into());
+            }
+        };
+
+    // exchange the code for the validated claims of the user
+    let claims = match provider
+        .exchange_code(
+            &get_redirect_uri(config, provider),
+            &code,
+            &code_verifier,
+            &nonce,
+        )
+        .await
+    {
+        Ok(claims) => claims,
+        Err(error) => {
+            warn!(
+                "The login with the identity provider '{}' failed. The error was: {}",
+                provider.id, error
+            );
+            record_audit_event(
+                db_connection,
+                AuditEventType::LoginFailed,
+                None,
+                None,
+                &request_metadata,
+                Some(&format!("identity provider {}: {}", provider.name, error)),
+            );
+            return Err(Problem::new(
+                Status::Unauthorized,
+                "identity_provider_error",
+                "The login with the identity provider could not be verified.",
+            ));
+        }
+    };
+
+    // if a logged-in user started the login, the identity is just linked to the account
+    if let Some(link_user_id) = link_user_id {
+        link_external_identity_to_account(
+            db_connection,
+            link_user_id,
+            provider,
+            &claims,
+            &request_metadata,
+        )?;
+        return Ok(ExternalLoginResponse::Linked(Json(
+            LinkedExternalIdentity {
+                provider: provider.id.clone(),
+            },
+        )));
+    }
+
+    // find (or create) the user the identity belongs to, disabled accounts cannot be used
+    let user =
+        resolve_external_identity(db_connection, config, provider, &claims, &request_metadata)?;
+    if user.disabled {
+        warn!(
+            "The user with the id {} tried to log in with the identity provider '{}' but the account is disabled",
+            user.id, provider.id
+        );
+        record_audit_event(
+            db_connection,
+            AuditEventType::LoginFailed,
+            Some(user.id),
+            None,
+            &request_metadata,
+            Some("account disabled"),
+        );
+        return Err(Problem::new(
+            Status::Forbidden,
+            "account_disabled",
+            "The account was disabled by an administrator.",
+        ));
+    }
+
+    // the provider has to enforce a second factor itself, otherwise logging in with it would bypass
+    // the second factor the user enabled
+    if user.totp_enabled && !provider.enforces_second_factor {
+        warn!(
+            "The user with the id {} tried to log in with the identity provider '{}' which does not enforce a second factor",
+            user.id, provider.id
+        );
+        record_audit_event(
+            db_connection,
+            AuditEventType::LoginFailed,
+            Some(user.id),
+            None,
+            &request_metadata,
+            Some(&format!(
+                "identity provider {} does not enforce a second factor",
+                provider.name
+            )),
+        );
+        return Err(Problem::new(
+            Status::Forbidden,
+            "second_factor_not_enforced",
+            "The account is protected with a second factor which the identity provider does not enforce. Log in with the password and the code instead.",
+        ));
+    }
+    record_audit_event(
+        db_connection,
+        AuditEventType::LoginSucceeded,
+        Some(user.id),
+        Some(user.id),
+        &request_metadata,
+        Some(&format!("identity provider {}", provider.name)),
+    );
+
+    // the app gets an authorization code for its personal access token, everybody else gets an
+    // access token like with the regular login
+    if let Some(login_process_id) = login_process_id {
+        return complete_app_login_process(db_connection, &login_process_id, user.id)
+            .map(ExternalLoginResponse::Redirect);
+    }
+    match get_token_for_user(
+        user.id,
+        None,
+        signing_keys.active(),
+        config.access_token_lifetime_in_seconds,
+    ) {
+        Some(access_token) => Ok(ExternalLoginResponse::Token(Json(TokenResponse {
+            access_token,
+        }))),
+        None => Err(Status::InternalServerError.into()),
+    }
+}
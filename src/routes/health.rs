@@ -1,9 +1,25 @@
-use crate::fairings::MinneDatabaseConnection;
+use crate::fairings::{BackendConfiguration, MinneDatabaseConnection};
+use crate::guards::{AdminUser, AuthorizationError};
+use crate::problem::Problem;
 use rocket::get;
 use rocket::http::Status;
 use rocket::serde::json::Json;
 use rocket::State;
 use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// The maximum time the health checks wait for a connection from the database connection pool.
+const HEALTH_CHECK_CONNECTION_TIMEOUT_IN_MILLISECONDS: u64 = 1000;
+
+/// The time when the backend was started, used for reporting the uptime.
+pub struct BackendStartTime(pub Instant);
+
+impl Default for BackendStartTime {
+    fn default() -> Self {
+        BackendStartTime(Instant::now())
+    }
+}
 
 #[derive(Serialize)]
 pub struct HealthCheck {
@@ -13,48 +29,259 @@ pub struct HealthCheck {
     pub backend_healthy: bool,
 }
 
-#[get("/health")]
-pub async fn check_backend_health(
-    db_connection_pool: &State<MinneDatabaseConnection>,
-) -> Result<Json<HealthCheck>, Status> {
-    use crate::schema::users::dsl::users;
-    use diesel::dsl::count_star;
-    use diesel::{QueryDsl, RunQueryDsl};
-    use log::{debug, error};
-
-    // get a connection to the database for dealing with the request
-    let db_connection = &mut match db_connection_pool.get() {
+#[derive(Serialize)]
+pub struct ProbeResult {
+    /// The result of the probe (`alive` or `ready`).
+    pub status: &'static str,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ComponentStatus {
+    /// The component works as expected.
+    Up,
+    /// The component works, but should be looked at (e.g. all database connections are in use).
+    Degraded,
+    /// The component does not work.
+    Down,
+}
+
+#[derive(Serialize)]
+pub struct ComponentHealth {
+    /// The state of the component.
+    pub status: ComponentStatus,
+    /// The time the check of the component took in milliseconds (if it was checked actively).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_in_milliseconds: Option<f64>,
+    /// A human-readable explanation of the state of the component.
+    pub detail: String,
+}
+
+#[derive(Serialize)]
+pub struct DatabasePoolStatistics {
+    /// The maximum number of connections the pool opens.
+    pub max_size: u32,
+    /// The number of connections which are currently open.
+    pub connections: u32,
+    /// The number of open connections which are currently not in use.
+    pub idle_connections: u32,
+    /// The number of connections which are currently in use.
+    pub used_connections: u32,
+}
+
+#[derive(Serialize)]
+pub struct HealthDiagnostics {
+    /// The overall state of the backend, the worst state of all components.
+    pub status: ComponentStatus,
+    /// The version of the backend which is currently running.
+    pub backend_version: &'static str,
+    /// The number of seconds since the backend was started.
+    pub uptime_in_seconds: u64,
+    /// The state of each component the backend depends on.
+    pub components: BTreeMap<&'static str, ComponentHealth>,
+    /// The statistics of the database connection pool.
+    pub database_pool: DatabasePoolStatistics,
+}
+
+/// Check all components the backend depends on: the database connection pool, the database itself
+/// and the database migrations. Only a single cheap query is sent to the database per component.
+fn check_components(
+    db_connection_pool: &MinneDatabaseConnection,
+) -> BTreeMap<&'static str, ComponentHealth> {
+    use crate::migrations::pending_migrations;
+    use diesel::RunQueryDsl;
+    use log::error;
+
+    let mut components = BTreeMap::new();
+    let milliseconds_since =
+        |started_at: Instant| (started_at.elapsed().as_secs_f64() * 1_000_000.0).round() / 1000.0;
+
+    // a saturated pool means that requests have to wait for a connection
+    let pool_state = db_connection_pool.state();
+    let max_size = db_connection_pool.max_size();
+    let used_connections = pool_state.connections - pool_state.idle_connections;
+    let pool_saturated = pool_state.idle_connections == 0 && pool_state.connections >= max_size;
+
+    // get a connection without waiting as long as a regular request would
+    let started_at = Instant::now();
+    let connection_result = db_connection_pool.get_timeout(Duration::from_millis(
+        HEALTH_CHECK_CONNECTION_TIMEOUT_IN_MILLISECONDS,
+    ));
+    components.insert(
+        "database_pool",
+        ComponentHealth {
+            status: match (&connection_result, pool_saturated) {
+                (Err(_), _) => ComponentStatus::Down,
+                (Ok(_), true) => ComponentStatus::Degraded,
+                (Ok(_), false) => ComponentStatus::Up,
+            },
+            latency_in_milliseconds: Some(milliseconds_since(started_at)),
+            detail: format!("{} of {} connections in use", used_connections, max_size),
+        },
+    );
+    let db_connection = &mut match connection_result {
         Ok(connection) => connection,
         Err(error) => {
             error!(
-                "Could not get a connection from the database connection pool. The error was: {}",
+                "The health check could not get a connection from the database connection pool. The error was: {}",
                 error
             );
-            return Err(Status::InternalServerError);
+            let detail = "No database connection is available".to_string();
+            for component in ["database", "migrations"] {
+                components.insert(
+                    component,
+                    ComponentHealth {
+                        status: ComponentStatus::Down,
+                        latency_in_milliseconds: None,
+                        detail: detail.clone(),
+                    },
+                );
+            }
+            return components;
         }
     };
 
-    // check if the connection to the database is working or not
-    let database_is_healthy = db_connection
-        .build_transaction()
-        .read_only()
-        .run::<_, diesel::result::Error, _>(|connection| {
-            if let Err(error) = users.select(count_star()).first::<i64>(connection) {
-                error!("The health check of the database connection failed with the following error: {}", error);
-                return Err(error);
+    // check if the database answers queries at all
+    let started_at = Instant::now();
+    let database_result = diesel::sql_query("SELECT 1").execute(db_connection);
+    components.insert(
+        "database",
+        match database_result {
+            Ok(_) => ComponentHealth {
+                status: ComponentStatus::Up,
+                latency_in_milliseconds: Some(milliseconds_since(started_at)),
+                detail: "The database answers queries".to_string(),
+            },
+            Err(error) => {
+                error!(
+                    "The health check of the database connection failed with the following error: {}",
+                    error
+                );
+                ComponentHealth {
+                    status: ComponentStatus::Down,
+                    latency_in_milliseconds: Some(milliseconds_since(started_at)),
+                    detail: "The database does not answer queries".to_string(),
+                }
             }
-            debug!("Last health check was successful");
-            return Ok(());
-        });
+        },
+    );
 
-    // if the database is healthy, we can return the status immediately
-    if database_is_healthy.is_ok() {
-        return Ok(Json(HealthCheck {
+    // the backend must not serve requests against an outdated schema
+    let started_at = Instant::now();
+    let migrations_result = pending_migrations(db_connection);
+    components.insert(
+        "migrations",
+        match migrations_result {
+            Ok(pending) if pending.is_empty() => ComponentHealth {
+                status: ComponentStatus::Up,
+                latency_in_milliseconds: Some(milliseconds_since(started_at)),
+                detail: "All migrations are applied".to_string(),
+            },
+            Ok(pending) => ComponentHealth {
+                status: ComponentStatus::Down,
+                latency_in_milliseconds: Some(milliseconds_since(started_at)),
+                detail: format!("{} migration(s) are pending", pending.len()),
+            },
+            Err(error) => {
+                error!("{}", error);
+                ComponentHealth {
+                    status: ComponentStatus::Down,
+                    latency_in_milliseconds: Some(milliseconds_since(started_at)),
+                    detail: "The state of the migrations is unknown".to_string(),
+                }
+            }
+        },
+    );
+    components
+}
+
+/// The legacy health check, which is the same as the readiness check.
+#[get("/health")]
+pub async fn check_backend_health(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+) -> Result<Json<HealthCheck>, Problem> {
+    check_backend_readiness(db_connection_pool).await.map(|_| {
+        Json(HealthCheck {
             database_healthy: true,
             backend_healthy: true,
-        }));
+        })
+    })
+}
+
+/// Check if the process is running and able to answer requests. This does not check any
+/// dependencies, so a failing database does not cause the backend to be restarted.
+#[get("/health/live")]
+pub async fn check_backend_liveness() -> Json<ProbeResult> {
+    Json(ProbeResult { status: "alive" })
+}
+
+/// Check if the backend can serve requests, i.e. the database answers, a connection is available
+/// and the schema is up to date.
+#[get("/health/ready")]
+pub async fn check_backend_readiness(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+) -> Result<Json<ProbeResult>, Problem> {
+    use log::{debug, warn};
+
+    let components = check_components(db_connection_pool);
+    let failed_components = components
+        .iter()
+        .filter(|(_, component)| component.status != ComponentStatus::Up)
+        .map(|(name, component)| format!("{}: {}", name, component.detail))
+        .collect::<Vec<_>>();
+    if !failed_components.is_empty() {
+        warn!("The backend is not ready: {}", failed_components.join(", "));
+        return Err(Problem::new(
+            Status::ServiceUnavailable,
+            "not_ready",
+            &format!(
+                "The backend is not ready ({}).",
+                failed_components.join(", ")
+            ),
+        ));
     }
+    debug!("Last readiness check was successful");
+    Ok(Json(ProbeResult { status: "ready" }))
+}
 
-    // if seems that the health check failed, indicate that by returning a 500
-    Err(Status::InternalServerError)
+/// Get the state of each component, their latency, the statistics of the database connection pool
+/// and the uptime. Unless configured otherwise, only administrators can see the diagnostics.
+#[get("/health/details")]
+pub async fn get_health_details(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    config: &State<BackendConfiguration>,
+    start_time: &State<BackendStartTime>,
+    admin_user: Result<AdminUser, AuthorizationError>,
+) -> Result<(Status, Json<HealthDiagnostics>), Problem> {
+    // the diagnostics reveal some internals, so they are protected by default
+    if let (true, Err(error)) = (config.health_details_require_admin, admin_user) {
+        return Err(Problem::authorization_failed(Status::Forbidden, error));
+    }
+
+    // the overall state is the worst state of all components
+    let components = check_components(db_connection_pool);
+    let status = components
+        .values()
+        .map(|component| component.status)
+        .max()
+        .unwrap_or(ComponentStatus::Up);
+    let pool_state = db_connection_pool.state();
+    let diagnostics = HealthDiagnostics {
+        status,
+        backend_version: env!("VERGEN_GIT_SEMVER"),
+        uptime_in_seconds: start_time.0.elapsed().as_secs(),
+        components,
+        database_pool: DatabasePoolStatistics {
+            max_size: db_connection_pool.max_size(),
+            connections: pool_state.connections,
+            idle_connections: pool_state.idle_connections,
+            used_connections: pool_state.connections - pool_state.idle_connections,
+        },
+    };
+    let status_code = if status == ComponentStatus::Down {
+        Status::ServiceUnavailable
+    } else {
+        Status::Ok
+    };
+    Ok((status_code, Json(diagnostics)))
 }
@@ -0,0 +1,326 @@
+use crate::audit::{record_audit_event, AuditEventType, RequestMetadata};
+use crate::fairings::{BackendConfiguration, MinneDatabaseConnection};
+use crate::guards::AuthenticatedUser;
+use crate::problem::Problem;
+use crate::schema::{invitation_codes, users};
+use chrono::{DateTime, Utc};
+use diesel::PgConnection;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::{delete, get, post, State};
+use serde::{Deserialize, Serialize};
+
+/// The number of random bytes used for generating a new invitation code.
+const INVITATION_CODE_LENGTH_IN_BYTES: usize = 16;
+
+/// The number of hours an invitation code is valid if the creator did not request a specific lifetime.
+const DEFAULT_INVITATION_LIFETIME_IN_HOURS: i64 = 7 * 24;
+
+/// The maximum number of hours an invitation code can be valid.
+const MAX_INVITATION_LIFETIME_IN_HOURS: i64 = 90 * 24;
+
+/// The maximum number of registrations a single invitation code of a regular user can be used for.
+const MAX_INVITATION_USES: i32 = 25;
+
+#[derive(Queryable)]
+pub struct InvitationCode {
+    pub id: i32,
+    pub code_hash: String,
+    pub created_by: i32,
+    pub max_uses: i32,
+    pub uses: i32,
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = invitation_codes)]
+pub struct NewInvitationCode {
+    pub code_hash: String,
+    pub created_by: i32,
+    pub max_uses: i32,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct InvitationCodeRequestData {
+    /// The number of registrations the code can be used for (default: 1).
+    pub max_uses: Option<i32>,
+    /// The number of hours the code is valid (default: 7 days).
+    pub expires_in_hours: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct CreatedInvitationCode {
+    /// The internal id of the invitation code which can be used to revoke it.
+    pub id: i32,
+    /// The code which has to be handed over to the invited persons. It is only returned once.
+    pub code: String,
+    /// The number of registrations the code can be used for.
+    pub max_uses: i32,
+    /// The time when the code expires.
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+pub struct InvitationCodeInformation {
+    /// The internal id of the invitation code.
+    pub id: i32,
+    /// The number of registrations the code can be used for.
+    pub max_uses: i32,
+    /// The number of registrations the code was already used for.
+    pub uses: i32,
+    /// The time when the code expires.
+    pub expires_at: DateTime<Utc>,
+    /// The time when the code was created.
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<InvitationCode> for InvitationCodeInformation {
+    fn from(invitation_code: InvitationCode) -> Self {
+        InvitationCodeInformation {
+            id: invitation_code.id,
+            max_uses: invitation_code.max_uses,
+            uses: invitation_code.uses,
+            expires_at: invitation_code.expires_at,
+            created_at: invitation_code.created_at,
+        }
+    }
+}
+
+/// Get the representation of an invitation code which is used for storing it in the database.
+fn hash_invitation_code(code: &str) -> String {
+    use crate::crypto::sha256_hex;
+
+    sha256_hex(&code.trim().to_lowercase())
+}
+
+/// Consume one use of the supplied invitation code. The id of the code and the id of the user
+/// who created it are returned if the code is valid, `None` if the code is unknown, expired,
+/// already used up or was created by a disabled user. This has to be called within the
+/// transaction which creates the new user to ensure the use is not lost.
+pub fn redeem_invitation_code(
+    connection: &mut PgConnection,
+    code: &str,
+) -> Result<Option<(i32, i32)>, diesel::result::Error> {
+    use diesel::{ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
+
+    // count the use of the code only if it can still be used, codes of users who were disabled
+    // in the meantime cannot be used anymore
+    let redeemed_code = diesel::update(
+        invitation_codes::table
+            .filter(invitation_codes::code_hash.eq(hash_invitation_code(code)))
+            .filter(invitation_codes::uses.lt(invitation_codes::max_uses))
+            .filter(invitation_codes::expires_at.gt(diesel::dsl::now))
+            .filter(
+                invitation_codes::created_by.eq_any(
+                    users::table
+                        .filter(users::disabled.eq(false))
+                        .select(users::id),
+                ),
+            ),
+    )
+    .set(invitation_codes::uses.eq(invitation_codes::uses + 1))
+    .returning((invitation_codes::id, invitation_codes::created_by))
+    .get_result::<(i32, i32)>(connection)
+    .optional()?;
+    Ok(redeemed_code)
+}
+
+#[post("/invitation", data = "<invitation_request>")]
+pub async fn create_invitation_code(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    config: &State<BackendConfiguration>,
+    authenticated_user: AuthenticatedUser,
+    request_metadata: RequestMetadata,
+    invitation_request: Json<InvitationCodeRequestData>,
+) -> Result<Json<CreatedInvitationCode>, Problem> {
+    use crate::crypto::generate_random_token;
+    use chrono::Duration;
+    use diesel::RunQueryDsl;
+    use log::{error, info};
+
+    // regular users can only invite other people if it is allowed by the configuration
+    if !authenticated_user.is_admin && !config.user_invitations_enabled {
+        return Err(Problem::new(
+            Status::Forbidden,
+            "invitations_disabled",
+            "Only administrators are allowed to create invitation codes.",
+        ));
+    }
+
+    // ensure the requested limits are within the allowed range, administrators are not limited
+    // in the number of uses
+    let max_uses = invitation_request.max_uses.unwrap_or(1);
+    let expires_in_hours = invitation_request
+        .expires_in_hours
+        .unwrap_or(DEFAULT_INVITATION_LIFETIME_IN_HOURS);
+    if max_uses < 1
+        || (!authenticated_user.is_admin && max_uses > MAX_INVITATION_USES)
+        || !(1..=MAX_INVITATION_LIFETIME_IN_HOURS).contains(&expires_in_hours)
+    {
+        return Err(Problem::new(
+            Status::BadRequest,
+            "invalid_invitation_limits",
+            &format!(
+                "The number of uses has to be at least 1 (at most {} for regular users) and the lifetime has to be between 1 and {} hours.",
+                MAX_INVITATION_USES, MAX_INVITATION_LIFETIME_IN_HOURS
+            ),
+        ));
+    }
+
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // generate a new code and store its hash in the database
+    let code = generate_random_token(INVITATION_CODE_LENGTH_IN_BYTES);
+    let new_invitation_code = NewInvitationCode {
+        code_hash: hash_invitation_code(&code),
+        created_by: authenticated_user.id,
+        max_uses,
+        expires_at: Utc::now() + Duration::hours(expires_in_hours),
+    };
+    let (id, expires_at) = match diesel::insert_into(invitation_codes::table)
+        .values(&new_invitation_code)
+        .returning((invitation_codes::id, invitation_codes::expires_at))
+        .get_result::<(i32, DateTime<Utc>)>(db_connection)
+    {
+        Ok(inserted_code) => inserted_code,
+        Err(error) => {
+            error!(
+                "Could not store the invitation code of the user with the id {}. The error was: {}",
+                authenticated_user.id, error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+    info!(
+        "The user with the id {} created the invitation code with the id {} for {} registration(s)",
+        authenticated_user.id, id, max_uses
+    );
+    record_audit_event(
+        db_connection,
+        AuditEventType::InvitationCodeCreated,
+        Some(authenticated_user.id),
+        Some(authenticated_user.id),
+        &request_metadata,
+        Some(&format!(
+            "invitation code {} for {} registration(s)",
+            id, max_uses
+        )),
+    );
+
+    // return the code to the calling party, it cannot be fetched again
+    Ok(Json(CreatedInvitationCode {
+        id,
+        code,
+        max_uses,
+        expires_at,
+    }))
+}
+
+#[get("/invitation")]
+pub async fn get_own_invitation_codes(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    authenticated_user: AuthenticatedUser,
+) -> Result<Json<Vec<InvitationCodeInformation>>, Problem> {
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+    use log::error;
+
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // get all codes the user created
+    match invitation_codes::table
+        .filter(invitation_codes::created_by.eq(authenticated_user.id))
+        .order(invitation_codes::id)
+        .load::<InvitationCode>(db_connection)
+    {
+        Ok(codes) => Ok(Json(
+            codes
+                .into_iter()
+                .map(InvitationCodeInformation::from)
+                .collect(),
+        )),
+        Err(error) => {
+            error!(
+                "Could not get the invitation codes of the user with the id {}. The error was: {}",
+                authenticated_user.id, error
+            );
+            Err(Status::InternalServerError.into())
+        }
+    }
+}
+
+#[delete("/invitation/<invitation_code_id>")]
+pub async fn delete_invitation_code(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    authenticated_user: AuthenticatedUser,
+    invitation_code_id: i32,
+) -> Result<Status, Problem> {
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+    use log::{error, info};
+
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // users can only delete their own codes, administrators can delete every code
+    let delete_result = if authenticated_user.is_admin {
+        diesel::delete(invitation_codes::table.find(invitation_code_id)).execute(db_connection)
+    } else {
+        diesel::delete(
+            invitation_codes::table
+                .find(invitation_code_id)
+                .filter(invitation_codes::created_by.eq(authenticated_user.id)),
+        )
+        .execute(db_connection)
+    };
+    match delete_result {
+        Ok(0) => Err(Problem::new(
+            Status::NotFound,
+            "invitation_code_not_found",
+            "The invitation code does not exist.",
+        )),
+        Ok(_) => {
+            info!(
+                "The user with the id {} deleted the invitation code with the id {}",
+                authenticated_user.id, invitation_code_id
+            );
+            Ok(Status::NoContent)
+        }
+        Err(error) => {
+            error!(
+                "Could not delete the invitation code with the id {}. The error was: {}",
+                invitation_code_id, error
+            );
+            Err(Status::InternalServerError.into())
+        }
+    }
+}
@@ -0,0 +1,12 @@
+use crate::signing::TokenSigningKeys;
+use jsonwebtoken::jwk::JwkSet;
+use rocket::get;
+use rocket::serde::json::Json;
+use rocket::State;
+
+#[get("/.well-known/jwks.json")]
+pub async fn get_json_web_key_set(signing_keys: &State<TokenSigningKeys>) -> Json<JwkSet> {
+    // only the public keys of asymmetric algorithms are published, shared secrets never leave
+    // the backend
+    Json(signing_keys.public_keys())
+}
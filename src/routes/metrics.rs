@@ -0,0 +1,219 @@
+use crate::fairings::{BackendConfiguration, MinneDatabaseConnection};
+use crate::metrics::GaugeValue;
+use crate::problem::Problem;
+use crate::routes::health::BackendStartTime;
+use rocket::http::{ContentType, Header, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::{self, Responder};
+use rocket::{get, Request, Response, State};
+use std::convert::Infallible;
+use std::io::Cursor;
+
+/// The credentials a client sent with the basic authentication scheme (if any).
+pub struct BasicCredentials(Option<(String, String)>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for BasicCredentials {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<BasicCredentials, Infallible> {
+        use data_encoding::BASE64;
+
+        let credentials = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|header| header.strip_prefix("Basic "))
+            .and_then(|encoded| BASE64.decode(encoded.trim().as_bytes()).ok())
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+            .and_then(|decoded| {
+                decoded
+                    .split_once(':')
+                    .map(|(username, password)| (username.to_string(), password.to_string()))
+            });
+        Outcome::Success(BasicCredentials(credentials))
+    }
+}
+
+/// The metrics in the Prometheus text format.
+pub struct PrometheusMetrics(String);
+
+impl<'r> Responder<'r, 'static> for PrometheusMetrics {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        Response::build()
+            .header(ContentType::new("text", "plain").with_params([("version", "0.0.4")]))
+            .sized_body(self.0.len(), Cursor::new(self.0))
+            .ok()
+    }
+}
+
+/// The answer if the metrics are protected and the client did not supply valid credentials. It
+/// asks the client (e.g. the browser) for the credentials.
+pub struct MetricsAuthenticationRequired;
+
+impl<'r> Responder<'r, 'static> for MetricsAuthenticationRequired {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Problem::new(
+            Status::Unauthorized,
+            "metrics_authentication_required",
+            "The metrics require a username and a password (basic authentication).",
+        )
+        .respond_to(request)?;
+        response.set_header(Header::new(
+            "WWW-Authenticate",
+            "Basic realm=\"minne metrics\", charset=\"UTF-8\"",
+        ));
+        Ok(response)
+    }
+}
+
+/// Get the gauges which describe the state of the database connection pool.
+fn database_pool_gauges(db_connection_pool: &MinneDatabaseConnection) -> Vec<GaugeValue> {
+    let pool_state = db_connection_pool.state();
+    vec![
+        GaugeValue {
+            name: "minne_db_pool_connections",
+            help: "The number of open database connections by their state.",
+            labels: vec![("state", "idle".to_string())],
+            value: pool_state.idle_connections as f64,
+        },
+        GaugeValue {
+            name: "minne_db_pool_connections",
+            help: "The number of open database connections by their state.",
+            labels: vec![("state", "used".to_string())],
+            value: (pool_state.connections - pool_state.idle_connections) as f64,
+        },
+        GaugeValue {
+            name: "minne_db_pool_max_connections",
+            help: "The maximum number of database connections the pool opens.",
+            labels: Vec::new(),
+            value: db_connection_pool.max_size() as f64,
+        },
+    ]
+}
+
+/// Get the gauges which describe the data stored by the users (e.g. the number of tasks).
+fn business_gauges(
+    db_connection_pool: &MinneDatabaseConnection,
+) -> Result<Vec<GaugeValue>, String> {
+    use crate::schema::{tasks, users};
+    use diesel::dsl::{count_star, sql};
+    use diesel::sql_types::BigInt;
+    use diesel::{QueryDsl, RunQueryDsl};
+
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut db_connection_pool.get().map_err(|error| {
+        format!(
+            "Could not get a connection from the database connection pool. The error was: {}",
+            error
+        )
+    })?;
+
+    // count the tasks by their state and the users by the state of their account, each table is
+    // just scanned once by counting all rows and the ones in a specific state at the same time
+    let count_error = |error: diesel::result::Error| {
+        format!(
+            "Could not count the tasks and users for the metrics. The error was: {}",
+            error
+        )
+    };
+    let (tasks_total, tasks_done) = tasks::table
+        .select((
+            count_star(),
+            sql::<BigInt>("count(*) FILTER (WHERE done_at IS NOT NULL)"),
+        ))
+        .first::<(i64, i64)>(db_connection)
+        .map_err(count_error)?;
+    let (users_total, users_disabled) = users::table
+        .select((
+            count_star(),
+            sql::<BigInt>("count(*) FILTER (WHERE disabled)"),
+        ))
+        .first::<(i64, i64)>(db_connection)
+        .map_err(count_error)?;
+
+    let tasks_help = "The number of tasks by their state.";
+    let users_help = "The number of user accounts by their state.";
+    Ok(vec![
+        GaugeValue {
+            name: "minne_tasks",
+            help: tasks_help,
+            labels: vec![("state", "open".to_string())],
+            value: (tasks_total - tasks_done) as f64,
+        },
+        GaugeValue {
+            name: "minne_tasks",
+            help: tasks_help,
+            labels: vec![("state", "done".to_string())],
+            value: tasks_done as f64,
+        },
+        GaugeValue {
+            name: "minne_users",
+            help: users_help,
+            labels: vec![("state", "active".to_string())],
+            value: (users_total - users_disabled) as f64,
+        },
+        GaugeValue {
+            name: "minne_users",
+            help: users_help,
+            labels: vec![("state", "disabled".to_string())],
+            value: users_disabled as f64,
+        },
+    ])
+}
+
+#[get("/metrics")]
+pub async fn get_metrics(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    config: &State<BackendConfiguration>,
+    start_time: &State<BackendStartTime>,
+    credentials: BasicCredentials,
+) -> Result<PrometheusMetrics, MetricsAuthenticationRequired> {
+    use crate::metrics::METRICS;
+    use log::{error, warn};
+    use ring::constant_time::verify_slices_are_equal;
+
+    // if credentials are configured, the client has to supply the same ones
+    if let Some((expected_username, expected_password)) = &config.metrics_credentials {
+        let authorized = match &credentials.0 {
+            Some((username, password)) => {
+                let username_matches =
+                    verify_slices_are_equal(username.as_bytes(), expected_username.as_bytes())
+                        .is_ok();
+                let password_matches =
+                    verify_slices_are_equal(password.as_bytes(), expected_password.as_bytes())
+                        .is_ok();
+                username_matches && password_matches
+            }
+            None => false,
+        };
+        if !authorized {
+            if credentials.0.is_some() {
+                warn!("A client tried to fetch the metrics with invalid credentials");
+            }
+            return Err(MetricsAuthenticationRequired);
+        }
+    }
+
+    // collect the gauges which are determined when the metrics are fetched
+    let mut gauges = vec![
+        GaugeValue {
+            name: "minne_build_info",
+            help: "The version of the backend which is running.",
+            labels: vec![("version", env!("VERGEN_GIT_SEMVER").to_string())],
+            value: 1.0,
+        },
+        GaugeValue {
+            name: "minne_uptime_seconds",
+            help: "The number of seconds since the backend was started.",
+            labels: Vec::new(),
+            value: start_time.0.elapsed().as_secs_f64(),
+        },
+    ];
+    gauges.extend(database_pool_gauges(db_connection_pool));
+    match business_gauges(db_connection_pool) {
+        Ok(business_gauges) => gauges.extend(business_gauges),
+        Err(error) => error!("{}", error),
+    }
+
+    Ok(PrometheusMetrics(METRICS.render(&gauges)))
+}
@@ -0,0 +1,1144 @@
+use crate::audit::{record_audit_event, AuditEventType, RequestMetadata};
+use crate::fairings::{BackendConfiguration, MinneDatabaseConnection};
+use crate::guards::{AdminUser, AuthorizationError};
+use crate::problem::Problem;
+use crate::routes::auth::{
+    is_pkce_value, oauth_error, verify_login_credentials, verify_pkce_challenge, OAuthErrorResponse,
+};
+use crate::routes::user::User;
+use crate::schema::{oidc_authorization_codes, oidc_clients};
+use crate::signing::TokenSigningKeys;
+use crate::throttling::LoginAttemptTracker;
+use chrono::{DateTime, Utc};
+use diesel::PgConnection;
+use rocket::form::Form;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::status::Custom;
+use rocket::response::Redirect;
+use rocket::serde::json::Json;
+use rocket::{delete, get, post, FromForm, Request, Responder, State};
+use rocket_dyn_templates::Template;
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+
+/// The number of seconds a client has to exchange an authorization code for the tokens.
+const AUTHORIZATION_CODE_LIFETIME_IN_SECONDS: i64 = 60;
+
+/// The number of random bytes used for generating an authorization code.
+const AUTHORIZATION_CODE_LENGTH_IN_BYTES: usize = 32;
+
+/// The number of random bytes used for generating a client id.
+const CLIENT_ID_LENGTH_IN_BYTES: usize = 16;
+
+/// The number of random bytes used for generating a client secret.
+const CLIENT_SECRET_LENGTH_IN_BYTES: usize = 32;
+
+/// The scopes a client can request. `openid` is required for every request.
+const SUPPORTED_SCOPES: [&str; 3] = ["openid", "email", "profile"];
+
+#[derive(Queryable)]
+pub struct OidcClient {
+    pub id: i32,
+    pub client_id: String,
+    pub client_secret_hash: Option<String>,
+    pub name: String,
+    pub redirect_uris: Vec<String>,
+    /// The administrator who registered the client, `None` if the account was deleted since.
+    pub created_by: Option<i32>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct OidcClientRequestData {
+    /// The name of the client which is shown to the users while logging in.
+    pub name: String,
+    /// The URIs the users can be redirected to after the login.
+    pub redirect_uris: Vec<String>,
+    /// Whether the client cannot keep a secret (e.g. a single page application). Public clients
+    /// have to use PKCE.
+    #[serde(default)]
+    pub public: bool,
+}
+
+#[derive(Serialize)]
+pub struct OidcClientInformation {
+    /// The internal id of the client which can be used to remove it.
+    pub id: i32,
+    /// The id the client uses for identifying itself.
+    pub client_id: String,
+    /// The secret of the client. It is only returned once while registering a confidential client.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_secret: Option<String>,
+    /// Whether the client is a public client without a secret.
+    pub public: bool,
+    /// The name of the client.
+    pub name: String,
+    /// The URIs the users can be redirected to after the login.
+    pub redirect_uris: Vec<String>,
+    /// The time when the client was registered.
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<OidcClient> for OidcClientInformation {
+    fn from(client: OidcClient) -> Self {
+        OidcClientInformation {
+            id: client.id,
+            client_id: client.client_id,
+            client_secret: None,
+            public: client.client_secret_hash.is_none(),
+            name: client.name,
+            redirect_uris: client.redirect_uris,
+            created_at: client.created_at,
+        }
+    }
+}
+
+#[derive(FromForm, Serialize, Default)]
+pub struct AuthorizationRequest {
+    response_type: Option<String>,
+    client_id: Option<String>,
+    redirect_uri: Option<String>,
+    scope: Option<String>,
+    state: Option<String>,
+    nonce: Option<String>,
+    code_challenge: Option<String>,
+    code_challenge_method: Option<String>,
+}
+
+#[derive(FromForm)]
+pub struct AuthorizationLoginForm {
+    username: String,
+    password: String,
+    totp_code: Option<String>,
+    new_password: Option<String>,
+    request: AuthorizationRequest,
+}
+
+#[derive(FromForm)]
+pub struct TokenRequestData {
+    /// Has to be `authorization_code`.
+    grant_type: String,
+    /// The authorization code the client received with the redirect.
+    code: String,
+    /// The redirect URI which was used for requesting the authorization code.
+    redirect_uri: String,
+    /// The id of the client (if the client does not use HTTP basic authentication).
+    client_id: Option<String>,
+    /// The secret of the client (if the client does not use HTTP basic authentication).
+    client_secret: Option<String>,
+    /// The PKCE code verifier which matches the code challenge of the authorization request.
+    code_verifier: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct TokenResponse {
+    /// The access token which can only be used for the userinfo endpoint.
+    access_token: String,
+    /// The type of the access token which is always `Bearer`.
+    token_type: String,
+    /// The number of seconds the access token is valid.
+    expires_in: usize,
+    /// The signed ID token with the claims about the user.
+    id_token: String,
+    /// The scopes which were granted.
+    scope: String,
+}
+
+#[derive(Serialize)]
+pub struct IdTokenClaims {
+    iss: String,
+    sub: String,
+    aud: String,
+    exp: usize,
+    iat: usize,
+    auth_time: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nonce: Option<String>,
+    email: String,
+    name: String,
+    given_name: String,
+    family_name: String,
+}
+
+/// The claims of the access tokens issued to the clients. They are bound to the client and the
+/// granted scopes, so a client cannot use them for the Minne API.
+#[derive(Serialize, Deserialize)]
+pub struct OidcAccessTokenClaims {
+    iss: String,
+    sub: String,
+    aud: String,
+    exp: usize,
+    iat: usize,
+    nbf: usize,
+    scope: String,
+}
+
+#[derive(Serialize)]
+pub struct UserInfoResponse {
+    /// The stable identifier of the user.
+    sub: String,
+    /// The email address of the user (only with the `email` scope).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+    /// The full name of the user (only with the `profile` scope).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    /// The first name of the user (only with the `profile` scope).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    given_name: Option<String>,
+    /// The last name of the user (only with the `profile` scope).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    family_name: Option<String>,
+}
+
+/// A user who is authenticated with an access token which was issued to an OpenID Connect client.
+/// Neither the regular access tokens nor personal access tokens are accepted.
+pub struct OidcAuthenticatedUser {
+    /// The id of the user the token was issued for.
+    pub id: i32,
+    /// The id of the client the token was issued to.
+    pub client_id: String,
+    /// The scopes which were granted to the client.
+    pub scope: String,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for OidcAuthenticatedUser {
+    type Error = AuthorizationError;
+
+    async fn from_request(
+        request: &'r Request<'_>,
+    ) -> Outcome<OidcAuthenticatedUser, AuthorizationError> {
+        use crate::problem::AuthorizationFailure;
+
+        // remember why the authentication failed, so the error catcher can tell the client
+        match Self::authenticate(request) {
+            Ok(authenticated_user) => Outcome::Success(authenticated_user),
+            Err(authorization_error) => {
+                request.local_cache(|| AuthorizationFailure(Some(authorization_error)));
+                Outcome::Failure((Status::Unauthorized, authorization_error))
+            }
+        }
+    }
+}
+
+impl OidcAuthenticatedUser {
+    fn authenticate(request: &Request<'_>) -> Result<OidcAuthenticatedUser, AuthorizationError> {
+        use crate::schema::users;
+        use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+        use jsonwebtoken::{decode, decode_header, Validation};
+        use log::{debug, error};
+
+        // only bearer tokens can be used
+        let token = match request.headers().get_one("Authorization") {
+            Some(authorization) => match authorization.split_once(' ') {
+                Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => token.trim(),
+                _ => return Err(AuthorizationError::MalformedAuthorizationHeader),
+            },
+            None => return Err(AuthorizationError::MissingAuthorizationHeader),
+        };
+        let signing_keys = request
+            .rocket()
+            .state::<TokenSigningKeys>()
+            .expect("Could not get the keys for validating the token");
+        let config = request
+            .rocket()
+            .state::<BackendConfiguration>()
+            .expect("Could not get the configuration of the backend");
+        let db_connection_pool = request
+            .rocket()
+            .state::<MinneDatabaseConnection>()
+            .expect("Could not get a database connection from the pool");
+
+        // the token has to be signed by one of our keys and has to be issued by us
+        let signing_key = decode_header(token)
+            .ok()
+            .and_then(|header| signing_keys.find(header.kid.as_deref()))
+            .ok_or(AuthorizationError::InvalidToken)?;
+        let mut validation_parameter = Validation::new(signing_key.algorithm);
+        validation_parameter.leeway = 5; // allow a time difference of max. 5 seconds
+        validation_parameter.validate_nbf = true;
+        validation_parameter.set_issuer(&[&config.public_url]);
+        let claims = match decode::<OidcAccessTokenClaims>(
+            token,
+            signing_key.decoding_key(),
+            &validation_parameter,
+        ) {
+            Ok(decoded_token) => decoded_token.claims,
+            Err(error) => {
+                debug!(
+                    "The supplied OpenID Connect access token seems to be invalid. The error was: {}",
+                    error
+                );
+                return Err(AuthorizationError::InvalidToken);
+            }
+        };
+        let user_id = claims
+            .sub
+            .parse::<i32>()
+            .map_err(|_| AuthorizationError::InvalidToken)?;
+
+        // get a connection to the database for dealing with the request
+        let db_connection = &mut db_connection_pool.get().map_err(|error| {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            AuthorizationError::InvalidToken
+        })?;
+
+        // the client has to be registered still and the user must be able to log in
+        let client_exists = oidc_clients::table
+            .filter(oidc_clients::client_id.eq(&claims.aud))
+            .count()
+            .get_result::<i64>(db_connection)
+            .map(|count| count > 0)
+            .unwrap_or(false);
+        if !client_exists {
+            debug!(
+                "The OpenID Connect client '{}' the access token was issued to does not exist anymore",
+                claims.aud
+            );
+            return Err(AuthorizationError::InvalidToken);
+        }
+        let (is_disabled, revoked_at) = users::table
+            .find(user_id)
+            .select((users::disabled, users::tokens_revoked_at))
+            .first::<(bool, Option<DateTime<Utc>>)>(db_connection)
+            .map_err(|_| AuthorizationError::InvalidToken)?;
+        if is_disabled {
+            return Err(AuthorizationError::AccountDisabled);
+        }
+        if let Some(revoked_at) = revoked_at {
+            if claims.iat as i64 <= revoked_at.timestamp() {
+                return Err(AuthorizationError::InvalidToken);
+            }
+        }
+        Ok(OidcAuthenticatedUser {
+            id: user_id,
+            client_id: claims.aud,
+            scope: claims.scope,
+        })
+    }
+}
+
+#[derive(Serialize)]
+pub struct DiscoveryDocument {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: String,
+    jwks_uri: String,
+    response_types_supported: Vec<&'static str>,
+    grant_types_supported: Vec<&'static str>,
+    subject_types_supported: Vec<&'static str>,
+    id_token_signing_alg_values_supported: Vec<String>,
+    scopes_supported: Vec<&'static str>,
+    token_endpoint_auth_methods_supported: Vec<&'static str>,
+    code_challenge_methods_supported: Vec<&'static str>,
+    claims_supported: Vec<&'static str>,
+}
+
+#[derive(Serialize)]
+struct AuthorizationPageContext<'a> {
+    title: String,
+    client_name: String,
+    request: &'a AuthorizationRequest,
+}
+
+/// The possible responses of the authorization endpoint.
+#[allow(clippy::large_enum_variant)]
+#[derive(Responder)]
+pub enum AuthorizationResponse {
+    /// The login page which is shown to the user.
+    Page(Template),
+    /// The redirect back to the client (with the code or an error).
+    Redirect(Redirect),
+    /// The request could not be processed at all (e.g. an unknown client or redirect URI).
+    Failed(Problem),
+}
+
+impl From<Status> for AuthorizationResponse {
+    fn from(status: Status) -> Self {
+        AuthorizationResponse::Failed(status.into())
+    }
+}
+
+impl From<Problem> for AuthorizationResponse {
+    fn from(problem: Problem) -> Self {
+        AuthorizationResponse::Failed(problem)
+    }
+}
+
+/// The reasons why an authorization request cannot be processed.
+enum AuthorizationRequestError {
+    /// The request is rejected without redirecting the user (e.g. the client is unknown).
+    Rejected(Status),
+    /// The error is reported to the client by redirecting the user to the contained URI.
+    Reported(String),
+}
+
+impl From<AuthorizationRequestError> for AuthorizationResponse {
+    fn from(error: AuthorizationRequestError) -> Self {
+        match error {
+            AuthorizationRequestError::Rejected(status) => status.into(),
+            AuthorizationRequestError::Reported(target) => {
+                AuthorizationResponse::Redirect(Redirect::to(target))
+            }
+        }
+    }
+}
+
+/// An authorization request which was checked against the registered client.
+struct ValidatedAuthorizationRequest {
+    client: OidcClient,
+    redirect_uri: String,
+    scope: String,
+    state: Option<String>,
+    nonce: Option<String>,
+    code_challenge: Option<String>,
+}
+
+/// The client credentials which were supplied with HTTP basic authentication (if any).
+pub struct ClientCredentials(Option<(String, String)>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientCredentials {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<ClientCredentials, Infallible> {
+        use data_encoding::BASE64;
+
+        let credentials = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|header| header.strip_prefix("Basic "))
+            .and_then(|encoded| BASE64.decode(encoded.trim().as_bytes()).ok())
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+            .and_then(|decoded| {
+                decoded.split_once(':').map(|(client_id, client_secret)| {
+                    (client_id.to_string(), client_secret.to_string())
+                })
+            });
+        Outcome::Success(ClientCredentials(credentials))
+    }
+}
+
+/// Get the value of an optional parameter, empty values are treated as missing.
+fn parameter(value: &Option<String>) -> Option<&str> {
+    value.as_deref().filter(|value| !value.is_empty())
+}
+
+/// Get the URI for redirecting to the client with the supplied parameters appended to the query.
+fn client_redirect_target(redirect_uri: &str, parameters: &[(&str, Option<&str>)]) -> String {
+    use rocket::http::RawStr;
+
+    let mut target = redirect_uri.to_string();
+    let mut separator = if redirect_uri.contains('?') { '&' } else { '?' };
+    for (name, value) in parameters {
+        if let Some(value) = value {
+            target.push_str(&format!(
+                "{}{}={}",
+                separator,
+                name,
+                RawStr::new(value).percent_encode()
+            ));
+            separator = '&';
+        }
+    }
+    target
+}
+
+/// Check if the supplied redirect URI can be registered for a client. Only absolute URIs without
+/// a fragment are allowed.
+fn is_valid_redirect_uri(redirect_uri: &str) -> bool {
+    use rocket::http::uri::Absolute;
+
+    !redirect_uri.contains('#') && Absolute::parse(redirect_uri).is_ok()
+}
+
+/// Check the supplied authorization request against the registered client. If the client or the
+/// redirect URI is unknown, the request is rejected without redirecting the user. All other errors
+/// are returned to the client with a redirect.
+fn validate_authorization_request(
+    connection: &mut PgConnection,
+    request: &AuthorizationRequest,
+) -> Result<ValidatedAuthorizationRequest, AuthorizationRequestError> {
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+    use log::{debug, error};
+
+    // the client and the redirect URI have to be known before we can redirect back to the client
+    let (client_id, redirect_uri) = match (
+        parameter(&request.client_id),
+        parameter(&request.redirect_uri),
+    ) {
+        (Some(client_id), Some(redirect_uri)) => (client_id, redirect_uri),
+        _ => return Err(AuthorizationRequestError::Rejected(Status::BadRequest)),
+    };
+    let client = match oidc_clients::table
+        .filter(oidc_clients::client_id.eq(client_id))
+        .first::<OidcClient>(connection)
+    {
+        Ok(client) => client,
+        Err(diesel::NotFound) => {
+            debug!("The OpenID Connect client '{}' is unknown", client_id);
+            return Err(AuthorizationRequestError::Rejected(Status::BadRequest));
+        }
+        Err(error) => {
+            error!(
+                "Could not get the OpenID Connect client. The error was: {}",
+                error
+            );
+            return Err(AuthorizationRequestError::Rejected(
+                Status::InternalServerError,
+            ));
+        }
+    };
+    if !client
+        .redirect_uris
+        .iter()
+        .any(|registered_uri| registered_uri == redirect_uri)
+    {
+        debug!(
+            "The redirect URI '{}' is not registered for the OpenID Connect client '{}'",
+            redirect_uri, client_id
+        );
+        return Err(AuthorizationRequestError::Rejected(Status::BadRequest));
+    }
+
+    // all further errors can be reported to the client
+    let state = parameter(&request.state);
+    let report_error = |error: &str| {
+        Err(AuthorizationRequestError::Reported(client_redirect_target(
+            redirect_uri,
+            &[("error", Some(error)), ("state", state)],
+        )))
+    };
+    if parameter(&request.response_type) != Some("code") {
+        return report_error("unsupported_response_type");
+    }
+    let scopes = parameter(&request.scope)
+        .unwrap_or_default()
+        .split_whitespace()
+        .filter(|scope| SUPPORTED_SCOPES.contains(scope))
+        .collect::<Vec<&str>>();
+    if !scopes.contains(&"openid") {
+        return report_error("invalid_scope");
+    }
+
+    // public clients have to use PKCE, confidential clients can use it
+    let code_challenge = parameter(&request.code_challenge);
+    if let Some(code_challenge) = code_challenge {
+        if parameter(&request.code_challenge_method).unwrap_or("plain") != "S256"
+            || !is_pkce_value(code_challenge, 43, 43)
+        {
+            return report_error("invalid_request");
+        }
+    } else if client.client_secret_hash.is_none() {
+        return report_error("invalid_request");
+    }
+
+    Ok(ValidatedAuthorizationRequest {
+        redirect_uri: redirect_uri.to_string(),
+        scope: scopes.join(" "),
+        state: state.map(str::to_string),
+        nonce: parameter(&request.nonce).map(str::to_string),
+        code_challenge: code_challenge.map(str::to_string),
+        client,
+    })
+}
+
+/// Encode the supplied claims as a JWT signed with the active signing key.
+fn sign_claims<T: Serialize>(signing_keys: &TokenSigningKeys, claims: &T) -> Option<String> {
+    use jsonwebtoken::{encode, Header};
+    use log::error;
+
+    let signing_key = signing_keys.active();
+    let mut header = Header::new(signing_key.algorithm);
+    header.kid = Some(signing_key.key_id.clone());
+    match encode(&header, claims, signing_key.encoding_key()) {
+        Ok(token) => Some(token),
+        Err(error) => {
+            error!("Could not sign the token. The error was: {}", error);
+            None
+        }
+    }
+}
+
+#[get("/.well-known/openid-configuration")]
+pub async fn get_openid_configuration(
+    config: &State<BackendConfiguration>,
+    signing_keys: &State<TokenSigningKeys>,
+) -> Json<DiscoveryDocument> {
+    Json(DiscoveryDocument {
+        issuer: config.public_url.clone(),
+        authorization_endpoint: format!("{}/v1/oauth/authorize", config.public_url),
+        token_endpoint: format!("{}/v1/oauth/token", config.public_url),
+        userinfo_endpoint: format!("{}/v1/oauth/userinfo", config.public_url),
+        jwks_uri: format!("{}/.well-known/jwks.json", config.public_url),
+        response_types_supported: vec!["code"],
+        grant_types_supported: vec!["authorization_code"],
+        subject_types_supported: vec!["public"],
+        id_token_signing_alg_values_supported: vec![format!(
+            "{:?}",
+            signing_keys.active().algorithm
+        )],
+        scopes_supported: SUPPORTED_SCOPES.to_vec(),
+        token_endpoint_auth_methods_supported: vec![
+            "client_secret_basic",
+            "client_secret_post",
+            "none",
+        ],
+        code_challenge_methods_supported: vec!["S256"],
+        claims_supported: vec![
+            "sub",
+            "iss",
+            "aud",
+            "exp",
+            "iat",
+            "auth_time",
+            "nonce",
+            "email",
+            "name",
+            "given_name",
+            "family_name",
+        ],
+    })
+}
+
+#[get("/oauth/authorize?<request..>")]
+pub async fn show_authorization_page(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    request: AuthorizationRequest,
+) -> AuthorizationResponse {
+    use log::error;
+
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Status::InternalServerError.into();
+        }
+    };
+
+    // the request has to be valid before the user is asked to log in
+    let validated_request = match validate_authorization_request(db_connection, &request) {
+        Ok(validated_request) => validated_request,
+        Err(error) => return error.into(),
+    };
+
+    // show the login page, the parameters of the request are posted back with the credentials
+    AuthorizationResponse::Page(Template::render(
+        "oidc_authorize",
+        AuthorizationPageContext {
+            title: format!("Sign in to {}", validated_request.client.name),
+            client_name: validated_request.client.name,
+            request: &request,
+        },
+    ))
+}
+
+#[post("/oauth/authorize", data = "<login>")]
+pub async fn authorize_client(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    config: &State<BackendConfiguration>,
+    login_attempts: &State<LoginAttemptTracker>,
+    request_metadata: RequestMetadata,
+    login: Form<AuthorizationLoginForm>,
+) -> AuthorizationResponse {
+    use crate::crypto::{generate_random_token, sha256_hex};
+    use chrono::Duration;
+    use diesel::{ExpressionMethods, RunQueryDsl};
+    use log::{error, info};
+
+    // reject the login attempt early if there were too many failed attempts for the account or IP address
+    if let Err(too_many_attempts) =
+        login_attempts.check(&login.username, request_metadata.ip_address)
+    {
+        return Problem::from(too_many_attempts).into();
+    }
+
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Status::InternalServerError.into();
+        }
+    };
+
+    // the request could have been modified, so it has to be checked again
+    let validated_request = match validate_authorization_request(db_connection, &login.request) {
+        Ok(validated_request) => validated_request,
+        Err(error) => return error.into(),
+    };
+
+    // verify the supplied credentials of the user
+    let user = match verify_login_credentials(
+        db_connection,
+        config,
+        login_attempts,
+        &request_metadata,
+        &login.username,
+        &login.password,
+        login.totp_code.as_deref(),
+        login.new_password.as_deref(),
+        &format!("openid connect login for {}", validated_request.client.name),
+    ) {
+        Ok(user) => user,
+        Err(status) => return status.into(),
+    };
+
+    // issue an authorization code which the client can exchange for the tokens
+    let authorization_code = generate_random_token(AUTHORIZATION_CODE_LENGTH_IN_BYTES);
+    if let Err(error) = diesel::insert_into(oidc_authorization_codes::table)
+        .values((
+            oidc_authorization_codes::code_hash.eq(sha256_hex(&authorization_code)),
+            oidc_authorization_codes::client_id.eq(validated_request.client.id),
+            oidc_authorization_codes::user_id.eq(user.id),
+            oidc_authorization_codes::redirect_uri.eq(&validated_request.redirect_uri),
+            oidc_authorization_codes::scope.eq(&validated_request.scope),
+            oidc_authorization_codes::nonce.eq(&validated_request.nonce),
+            oidc_authorization_codes::code_challenge.eq(&validated_request.code_challenge),
+            oidc_authorization_codes::expires_at
+                .eq(Utc::now() + Duration::seconds(AUTHORIZATION_CODE_LIFETIME_IN_SECONDS)),
+        ))
+        .execute(db_connection)
+    {
+        error!(
+            "Could not store the authorization code for the OpenID Connect client with the id {}. The error was: {}",
+            validated_request.client.id, error
+        );
+        return Status::InternalServerError.into();
+    }
+    info!(
+        "The user with the id {} logged in to the OpenID Connect client with the id {}",
+        user.id, validated_request.client.id
+    );
+
+    // send the user back to the client with the code
+    AuthorizationResponse::Redirect(Redirect::to(client_redirect_target(
+        &validated_request.redirect_uri,
+        &[
+            ("code", Some(&authorization_code)),
+            ("state", validated_request.state.as_deref()),
+        ],
+    )))
+}
+
+#[post("/oauth/token", data = "<token_request>")]
+pub async fn exchange_oidc_authorization_code(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    config: &State<BackendConfiguration>,
+    signing_keys: &State<TokenSigningKeys>,
+    client_credentials: ClientCredentials,
+    token_request: Form<TokenRequestData>,
+) -> Result<Json<TokenResponse>, Custom<Json<OAuthErrorResponse>>> {
+    use crate::crypto::sha256_hex;
+    use crate::schema::users;
+    use diesel::{ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
+    use log::{error, warn};
+    use ring::constant_time::verify_slices_are_equal;
+
+    // only the authorization code grant is supported
+    if token_request.grant_type != "authorization_code" {
+        return Err(oauth_error(Status::BadRequest, "unsupported_grant_type"));
+    }
+
+    // the client can authenticate with HTTP basic authentication or with the form parameters
+    let (client_id, client_secret) = match &client_credentials.0 {
+        Some((client_id, client_secret)) => (client_id.as_str(), Some(client_secret.as_str())),
+        None => match parameter(&token_request.client_id) {
+            Some(client_id) => (client_id, parameter(&token_request.client_secret)),
+            None => return Err(oauth_error(Status::Unauthorized, "invalid_client")),
+        },
+    };
+
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Err(oauth_error(Status::InternalServerError, "server_error"));
+        }
+    };
+
+    // the client has to be known and confidential clients have to supply the right secret
+    let client = match oidc_clients::table
+        .filter(oidc_clients::client_id.eq(client_id))
+        .first::<OidcClient>(db_connection)
+        .optional()
+    {
+        Ok(Some(client)) => client,
+        Ok(None) => return Err(oauth_error(Status::Unauthorized, "invalid_client")),
+        Err(error) => {
+            error!(
+                "Could not get the OpenID Connect client. The error was: {}",
+                error
+            );
+            return Err(oauth_error(Status::InternalServerError, "server_error"));
+        }
+    };
+    if let Some(client_secret_hash) = &client.client_secret_hash {
+        let supplied_secret_hash = sha256_hex(client_secret.unwrap_or_default());
+        if verify_slices_are_equal(
+            supplied_secret_hash.as_bytes(),
+            client_secret_hash.as_bytes(),
+        )
+        .is_err()
+        {
+            warn!(
+                "The OpenID Connect client with the id {} supplied a wrong secret",
+                client.id
+            );
+            return Err(oauth_error(Status::Unauthorized, "invalid_client"));
+        }
+    }
+
+    // the authorization code can only be used once, so it is removed directly (even if the
+    // verification fails later on)
+    let authorization_code = match diesel::delete(oidc_authorization_codes::table)
+        .filter(oidc_authorization_codes::code_hash.eq(sha256_hex(token_request.code.trim())))
+        .filter(oidc_authorization_codes::client_id.eq(client.id))
+        .filter(oidc_authorization_codes::expires_at.gt(diesel::dsl::now))
+        .returning((
+            oidc_authorization_codes::user_id,
+            oidc_authorization_codes::redirect_uri,
+            oidc_authorization_codes::scope,
+            oidc_authorization_codes::nonce,
+            oidc_authorization_codes::code_challenge,
+            oidc_authorization_codes::created_at,
+        ))
+        .get_result::<(
+            i32,
+            String,
+            String,
+            Option<String>,
+            Option<String>,
+            DateTime<Utc>,
+        )>(db_connection)
+        .optional()
+    {
+        Ok(Some(authorization_code)) => authorization_code,
+        Ok(None) => return Err(oauth_error(Status::BadRequest, "invalid_grant")),
+        Err(error) => {
+            error!(
+                "Could not get the authorization code of the OpenID Connect client with the id {}. The error was: {}",
+                client.id, error
+            );
+            return Err(oauth_error(Status::InternalServerError, "server_error"));
+        }
+    };
+    let (user_id, redirect_uri, scope, nonce, code_challenge, authenticated_at) =
+        authorization_code;
+
+    // the redirect URI and the PKCE code verifier have to match the authorization request
+    if redirect_uri != token_request.redirect_uri {
+        return Err(oauth_error(Status::BadRequest, "invalid_grant"));
+    }
+    if let Some(code_challenge) = code_challenge {
+        let code_verifier = parameter(&token_request.code_verifier).unwrap_or_default();
+        if !verify_pkce_challenge(code_verifier, &code_challenge) {
+            warn!(
+                "The code verifier of the OpenID Connect client with the id {} did not match the code challenge",
+                client.id
+            );
+            return Err(oauth_error(Status::BadRequest, "invalid_grant"));
+        }
+    }
+
+    // get the user the tokens are issued for, disabled accounts cannot be used anymore
+    let user = match users::table.find(user_id).first::<User>(db_connection) {
+        Ok(user) if !user.disabled => user,
+        Ok(_) => return Err(oauth_error(Status::BadRequest, "invalid_grant")),
+        Err(error) => {
+            error!(
+                "Could not get the user with the id {}. The error was: {}",
+                user_id, error
+            );
+            return Err(oauth_error(Status::InternalServerError, "server_error"));
+        }
+    };
+
+    // generate the access token, which is bound to the client and the granted scopes, as well as
+    // the ID token
+    let issued_at = Utc::now().timestamp() as usize;
+    let access_token_claims = OidcAccessTokenClaims {
+        iss: config.public_url.clone(),
+        sub: user.id.to_string(),
+        aud: client.client_id.clone(),
+        exp: issued_at + config.access_token_lifetime_in_seconds,
+        iat: issued_at,
+        nbf: issued_at,
+        scope: scope.clone(),
+    };
+    let access_token = match sign_claims(signing_keys, &access_token_claims) {
+        Some(access_token) => access_token,
+        None => return Err(oauth_error(Status::InternalServerError, "server_error")),
+    };
+    let id_token_claims = IdTokenClaims {
+        iss: config.public_url.clone(),
+        sub: user.id.to_string(),
+        aud: client.client_id.clone(),
+        exp: issued_at + config.access_token_lifetime_in_seconds,
+        iat: issued_at,
+        auth_time: authenticated_at.timestamp() as usize,
+        nonce,
+        email: user.email.clone(),
+        name: format!("{} {}", user.first_name, user.last_name),
+        given_name: user.first_name.clone(),
+        family_name: user.last_name.clone(),
+    };
+    let id_token = match sign_claims(signing_keys, &id_token_claims) {
+        Some(id_token) => id_token,
+        None => return Err(oauth_error(Status::InternalServerError, "server_error")),
+    };
+
+    Ok(Json(TokenResponse {
+        access_token,
+        token_type: "Bearer".to_string(),
+        expires_in: config.access_token_lifetime_in_seconds,
+        id_token,
+        scope,
+    }))
+}
+
+#[get("/oauth/userinfo")]
+pub async fn get_userinfo(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    authenticated_user: OidcAuthenticatedUser,
+) -> Result<Json<UserInfoResponse>, Problem> {
+    use crate::schema::users;
+    use diesel::{QueryDsl, RunQueryDsl};
+    use log::error;
+
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // return the claims about the authenticated user the client was allowed to see
+    let granted_scopes = authenticated_user.scope.split(' ').collect::<Vec<_>>();
+    let email_granted = granted_scopes.contains(&"email");
+    let profile_granted = granted_scopes.contains(&"profile");
+    match users::table
+        .find(authenticated_user.id)
+        .first::<User>(db_connection)
+    {
+        Ok(user) => Ok(Json(UserInfoResponse {
+            sub: user.id.to_string(),
+            email: Some(user.email).filter(|_| email_granted),
+            name: Some(format!("{} {}", user.first_name, user.last_name))
+                .filter(|_| profile_granted),
+            given_name: Some(user.first_name).filter(|_| profile_granted),
+            family_name: Some(user.last_name).filter(|_| profile_granted),
+        })),
+        Err(error) => {
+            error!(
+                "Could not get the user with the id {}. The error was: {}",
+                authenticated_user.id, error
+            );
+            Err(Status::InternalServerError.into())
+        }
+    }
+}
+
+#[post("/admin/oidc/client", data = "<client_request>")]
+pub async fn register_oidc_client(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    admin_user: AdminUser,
+    request_metadata: RequestMetadata,
+    client_request: Json<OidcClientRequestData>,
+) -> Result<Json<OidcClientInformation>, Problem> {
+    use crate::crypto::{generate_random_token, sha256_hex};
+    use diesel::{ExpressionMethods, RunQueryDsl};
+    use log::{error, info};
+
+    // the client needs a name and at least one valid redirect URI
+    let name = client_request.name.trim();
+    if name.is_empty()
+        || name.len() > 255
+        || client_request.redirect_uris.is_empty()
+        || !client_request
+            .redirect_uris
+            .iter()
+            .all(|redirect_uri| is_valid_redirect_uri(redirect_uri))
+    {
+        return Err(Problem::new(
+            Status::BadRequest,
+            "invalid_client_metadata",
+            "The client needs a name and at least one valid redirect URI.",
+        ));
+    }
+
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // generate the credentials of the client, only the hash of the secret is stored
+    let client_secret = if client_request.public {
+        None
+    } else {
+        Some(generate_random_token(CLIENT_SECRET_LENGTH_IN_BYTES))
+    };
+    let client = match diesel::insert_into(oidc_clients::table)
+        .values((
+            oidc_clients::client_id.eq(generate_random_token(CLIENT_ID_LENGTH_IN_BYTES)),
+            oidc_clients::client_secret_hash.eq(client_secret.as_deref().map(sha256_hex)),
+            oidc_clients::name.eq(name),
+            oidc_clients::redirect_uris.eq(&client_request.redirect_uris),
+            oidc_clients::created_by.eq(admin_user.id),
+        ))
+        .get_result::<OidcClient>(db_connection)
+    {
+        Ok(client) => client,
+        Err(error) => {
+            error!(
+                "Could not store the new OpenID Connect client. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+    info!(
+        "The administrator with the id {} registered the OpenID Connect client '{}' with the id {}",
+        admin_user.id, client.name, client.id
+    );
+    record_audit_event(
+        db_connection,
+        AuditEventType::OidcClientCreated,
+        None,
+        Some(admin_user.id),
+        &request_metadata,
+        Some(&client.name),
+    );
+
+    // return the credentials to the administrator, the secret cannot be fetched again
+    let mut client_information = OidcClientInformation::from(client);
+    client_information.client_secret = client_secret;
+    Ok(Json(client_information))
+}
+
+#[get("/admin/oidc/client")]
+pub async fn list_oidc_clients(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    _admin_user: AdminUser,
+) -> Result<Json<Vec<OidcClientInformation>>, Problem> {
+    use diesel::{QueryDsl, RunQueryDsl};
+    use log::error;
+
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // get all registered clients
+    match oidc_clients::table
+        .order(oidc_clients::id)
+        .load::<OidcClient>(db_connection)
+    {
+        Ok(clients) => Ok(Json(
+            clients
+                .into_iter()
+                .map(OidcClientInformation::from)
+                .collect(),
+        )),
+        Err(error) => {
+            error!(
+                "Could not get the OpenID Connect clients. The error was: {}",
+                error
+            );
+            Err(Status::InternalServerError.into())
+        }
+    }
+}
+
+#[delete("/admin/oidc/client/<oidc_client_id>")]
+pub async fn delete_oidc_client(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    admin_user: AdminUser,
+    request_metadata: RequestMetadata,
+    oidc_client_id: i32,
+) -> Result<Status, Problem> {
+    use diesel::{QueryDsl, RunQueryDsl};
+    use log::{error, info};
+
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // removing the client also removes all of its pending authorization codes
+    match diesel::delete(oidc_clients::table.find(oidc_client_id))
+        .returning(oidc_clients::name)
+        .get_result::<String>(db_connection)
+    {
+        Ok(name) => {
+            info!(
+                "The administrator with the id {} removed the OpenID Connect client with the id {}",
+                admin_user.id, oidc_client_id
+            );
+            record_audit_event(
+                db_connection,
+                AuditEventType::OidcClientDeleted,
+                None,
+                Some(admin_user.id),
+                &request_metadata,
+                Some(&name),
+            );
+            Ok(Status::NoContent)
+        }
+        Err(diesel::NotFound) => Err(Problem::new(
+            Status::NotFound,
+            "client_not_found",
+            "The OpenID Connect client does not exist.",
+        )),
+        Err(error) => {
+            error!(
+                "Could not remove the OpenID Connect client with the id {}. The error was: {}",
+                oidc_client_id, error
+            );
+            Err(Status::InternalServerError.into())
+        }
+    }
+}
@@ -0,0 +1,338 @@
+use crate::audit::{record_audit_event, AuditEventType, RequestMetadata};
+use crate::fairings::{BackendConfiguration, MinneDatabaseConnection};
+use crate::guards::AuthenticatedUser;
+use crate::problem::Problem;
+use crate::routes::auth::TokenResponse;
+use crate::schema::{personal_access_tokens, session_access_tokens};
+use crate::signing::TokenSigningKeys;
+use chrono::{DateTime, Utc};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use rocket::{delete, get, post};
+use serde::Serialize;
+
+/// The number of random bytes used for generating the id of an access token issued for a session.
+const SESSION_ACCESS_TOKEN_ID_LENGTH_IN_BYTES: usize = 16;
+
+/// A session is a personal access token which was issued to a client of the user (e.g. by logging
+/// in from the app or a device). Access tokens issued for a session belong to it and are revoked
+/// with it, the access tokens from the login are not tracked and therefore no sessions.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Session {
+    /// The internal id of the personal access token behind the session.
+    pub id: i32,
+    /// The name of the personal access token (e.g. `Device Login (TV)`).
+    pub name: String,
+    /// The user agent of the client the session was started from (if known).
+    pub user_agent: Option<String>,
+    /// The time when the session was started.
+    pub created_at: DateTime<Utc>,
+    /// The time when the session was used the last time (accurate to about a minute).
+    pub last_used_at: Option<DateTime<Utc>>,
+    /// A flag which indicates if this is the session which was used for the current request.
+    pub current: bool,
+}
+
+#[get("/auth/sessions")]
+pub async fn list_sessions(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    authenticated_user: AuthenticatedUser,
+) -> Result<Json<Vec<Session>>, Problem> {
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+    use log::error;
+
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // get all personal access tokens of the user which can still be used
+    let tokens = match personal_access_tokens::table
+        .filter(personal_access_tokens::user_id.eq(authenticated_user.id))
+        .filter(personal_access_tokens::disabled.eq(false))
+        .order(personal_access_tokens::created_at.desc())
+        .select((
+            personal_access_tokens::id,
+            personal_access_tokens::name,
+            personal_access_tokens::token,
+            personal_access_tokens::user_agent,
+            personal_access_tokens::created_at,
+            personal_access_tokens::last_used_at,
+        ))
+        .load::<(
+            i32,
+            String,
+            String,
+            Option<String>,
+            DateTime<Utc>,
+            Option<DateTime<Utc>>,
+        )>(db_connection)
+    {
+        Ok(tokens) => tokens,
+        Err(error) => {
+            error!(
+                "Could not get the sessions of the user with the id {}. The error was: {}",
+                authenticated_user.id, error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // the token itself is never returned, it is just used to mark the current session
+    Ok(Json(
+        tokens
+            .into_iter()
+            .map(
+                |(id, name, token, user_agent, created_at, last_used_at)| Session {
+                    id,
+                    name,
+                    user_agent,
+                    created_at,
+                    last_used_at,
+                    current: !authenticated_user.used_pat.is_empty()
+                        && token == authenticated_user.used_pat,
+                },
+            )
+            .collect(),
+    ))
+}
+
+#[delete("/auth/sessions/<session_id>")]
+pub async fn revoke_session(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    authenticated_user: AuthenticatedUser,
+    request_metadata: RequestMetadata,
+    session_id: i32,
+) -> Result<Status, Problem> {
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+    use log::{error, info};
+
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // disable the token, but only if it belongs to the authenticated user
+    match diesel::update(
+        personal_access_tokens::table
+            .filter(personal_access_tokens::id.eq(session_id))
+            .filter(personal_access_tokens::user_id.eq(authenticated_user.id))
+            .filter(personal_access_tokens::disabled.eq(false)),
+    )
+    .set((
+        personal_access_tokens::disabled.eq(true),
+        personal_access_tokens::updated_at.eq(diesel::dsl::now),
+    ))
+    .execute(db_connection)
+    {
+        Ok(0) => Err(Problem::new(
+            Status::NotFound,
+            "session_not_found",
+            "The session does not exist or was already revoked.",
+        )),
+        Ok(_) => {
+            info!(
+                "The user with the id {} revoked the session with the id {}",
+                authenticated_user.id, session_id
+            );
+            record_audit_event(
+                db_connection,
+                AuditEventType::PersonalAccessTokenDisabled,
+                Some(authenticated_user.id),
+                Some(authenticated_user.id),
+                &request_metadata,
+                Some(&format!("revoked the session with the id {}", session_id)),
+            );
+            Ok(Status::NoContent)
+        }
+        Err(error) => {
+            error!(
+                "Could not revoke the session with the id {}. The error was: {}",
+                session_id, error
+            );
+            Err(Status::InternalServerError.into())
+        }
+    }
+}
+
+#[delete("/auth/sessions")]
+pub async fn revoke_other_sessions(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    authenticated_user: AuthenticatedUser,
+    request_metadata: RequestMetadata,
+) -> Result<Status, Problem> {
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+    use log::{error, info};
+
+    // without a session there is no session to keep, so this would revoke every session
+    if authenticated_user.used_pat.is_empty() {
+        return Err(Problem::new(
+            Status::BadRequest,
+            "no_session_used",
+            "All other sessions can only be revoked with a session, but the request was authenticated with an access token which does not belong to one.",
+        ));
+    }
+
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // disable every token of the user except the one of the session used for this request
+    match diesel::update(
+        personal_access_tokens::table
+            .filter(personal_access_tokens::user_id.eq(authenticated_user.id))
+            .filter(personal_access_tokens::disabled.eq(false))
+            .filter(personal_access_tokens::token.ne(&authenticated_user.used_pat)),
+    )
+    .set((
+        personal_access_tokens::disabled.eq(true),
+        personal_access_tokens::updated_at.eq(diesel::dsl::now),
+    ))
+    .execute(db_connection)
+    {
+        Ok(revoked_sessions) => {
+            info!(
+                "The user with the id {} revoked {} other session(s)",
+                authenticated_user.id, revoked_sessions
+            );
+            if revoked_sessions > 0 {
+                record_audit_event(
+                    db_connection,
+                    AuditEventType::PersonalAccessTokenDisabled,
+                    Some(authenticated_user.id),
+                    Some(authenticated_user.id),
+                    &request_metadata,
+                    Some(&format!("revoked {} other session(s)", revoked_sessions)),
+                );
+            }
+            Ok(Status::NoContent)
+        }
+        Err(error) => {
+            error!(
+                "Could not revoke the other sessions of the user with the id {}. The error was: {}",
+                authenticated_user.id, error
+            );
+            Err(Status::InternalServerError.into())
+        }
+    }
+}
+
+#[post("/auth/sessions/current/token")]
+pub async fn get_session_access_token(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    config: &State<BackendConfiguration>,
+    signing_keys: &State<TokenSigningKeys>,
+    authenticated_user: AuthenticatedUser,
+) -> Result<Json<TokenResponse>, Problem> {
+    use crate::crypto::generate_random_token;
+    use crate::routes::auth::get_token_for_user;
+    use chrono::Duration;
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+    use log::error;
+
+    // the access token has to be bound to a session, otherwise it could not be revoked
+    if authenticated_user.used_pat.is_empty() {
+        return Err(Problem::new(
+            Status::BadRequest,
+            "no_session_used",
+            "Access tokens can only be issued for a session, but the request was authenticated with an access token which does not belong to one.",
+        ));
+    }
+
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // get the session the access token is issued for
+    let session_id = match personal_access_tokens::table
+        .filter(personal_access_tokens::token.eq(&authenticated_user.used_pat))
+        .filter(personal_access_tokens::user_id.eq(authenticated_user.id))
+        .filter(personal_access_tokens::disabled.eq(false))
+        .select(personal_access_tokens::id)
+        .first::<i32>(db_connection)
+    {
+        Ok(session_id) => session_id,
+        Err(error) => {
+            error!(
+                "Could not get the session of the user with the id {}. The error was: {}",
+                authenticated_user.id, error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // remove all access tokens of sessions which expired in the meantime
+    if let Err(error) = diesel::delete(session_access_tokens::table)
+        .filter(session_access_tokens::expires_at.lt(diesel::dsl::now))
+        .execute(db_connection)
+    {
+        error!(
+            "Could not remove the expired access tokens of the sessions. The error was: {}",
+            error
+        );
+    }
+
+    // remember the id of the new access token, so it can be checked against the session later
+    let token_id = generate_random_token(SESSION_ACCESS_TOKEN_ID_LENGTH_IN_BYTES);
+    let expires_at =
+        Utc::now() + Duration::seconds(config.access_token_lifetime_in_seconds as i64 + 1);
+    if let Err(error) = diesel::insert_into(session_access_tokens::table)
+        .values((
+            session_access_tokens::jti.eq(&token_id),
+            session_access_tokens::personal_access_token_id.eq(session_id),
+            session_access_tokens::expires_at.eq(expires_at),
+        ))
+        .execute(db_connection)
+    {
+        error!(
+            "Could not store the access token for the session with the id {}. The error was: {}",
+            session_id, error
+        );
+        return Err(Status::InternalServerError.into());
+    }
+
+    // issue the access token which is only valid as long as the session is
+    match get_token_for_user(
+        authenticated_user.id,
+        Some(token_id),
+        signing_keys.active(),
+        config.access_token_lifetime_in_seconds,
+    ) {
+        Some(token) => Ok(Json(TokenResponse {
+            access_token: token,
+        })),
+        None => Err(Status::InternalServerError.into()),
+    }
+}
@@ -1,5 +1,6 @@
 use crate::fairings::MinneDatabaseConnection;
 use crate::guards::AuthenticatedUser;
+use crate::problem::Problem;
 use crate::schema::tasks;
 use chrono::{DateTime, FixedOffset, Utc};
 use rocket::http::Status;
@@ -53,11 +54,20 @@ pub struct TaskEditData {
     pub updated_at: Option<DateTime<FixedOffset>>,
 }
 
+/// The problem which is reported if a task does not exist (or does not belong to the user).
+fn task_not_found() -> Problem {
+    Problem::new(
+        Status::NotFound,
+        "task_not_found",
+        "The task does not exist.",
+    )
+}
+
 #[get("/task/list")]
 pub async fn get_all_task_ids_from_user(
     db_connection_pool: &State<MinneDatabaseConnection>,
     authenticated_user: AuthenticatedUser,
-) -> Result<Json<Vec<i32>>, Status> {
+) -> Result<Json<Vec<i32>>, Problem> {
     use diesel::ExpressionMethods;
     use diesel::QueryDsl;
     use diesel::RunQueryDsl;
@@ -71,7 +81,7 @@ pub async fn get_all_task_ids_from_user(
                 "Could not get a connection from the database connection pool. The error was: {}",
                 error
             );
-            return Err(Status::InternalServerError);
+            return Err(Status::InternalServerError.into());
         }
     };
 
@@ -86,7 +96,7 @@ pub async fn get_all_task_ids_from_user(
                 "Could not get all tasks of the user from the database. The error was: {}",
                 error
             );
-            return Err(Status::InternalServerError);
+            return Err(Status::InternalServerError.into());
         }
     };
 
@@ -103,7 +113,7 @@ pub async fn edit_task(
     authenticated_user: AuthenticatedUser,
     task_change_data: Json<TaskEditData>,
     task_id: i32,
-) -> Status {
+) -> Result<Status, Problem> {
     use crate::diesel::ExpressionMethods;
     use crate::diesel::QueryDsl;
     use crate::diesel::RunQueryDsl;
@@ -113,7 +123,11 @@ pub async fn edit_task(
 
     // if non of the fields for the task were supplied, return an error
     if task_change_data.title.is_none() && task_change_data.updated_at.is_none() {
-        return Status::BadRequest;
+        return Err(Problem::new(
+            Status::BadRequest,
+            "missing_fields",
+            "At least the title or the time of the last update has to be supplied.",
+        ));
     }
 
     // get a connection to the database for dealing with the request
@@ -124,7 +138,7 @@ pub async fn edit_task(
                 "Could not get a connection from the database connection pool. The error was: {}",
                 error
             );
-            return Status::InternalServerError;
+            return Err(Status::InternalServerError.into());
         }
     };
 
@@ -135,7 +149,7 @@ pub async fn edit_task(
         .first::<Task>(db_connection)
         .is_err()
     {
-        return Status::NotFound;
+        return Err(task_not_found());
     }
 
     // if the title was supplied, update the task with the new title
@@ -149,7 +163,7 @@ pub async fn edit_task(
                 "Could not update the title of the task with id {}.",
                 task_id
             );
-            return Status::InternalServerError;
+            return Err(Status::InternalServerError.into());
         }
     }
 
@@ -164,12 +178,12 @@ pub async fn edit_task(
                 "Could not update the updated_at time of the task with id {}.",
                 task_id
             );
-            return Status::InternalServerError;
+            return Err(Status::InternalServerError.into());
         }
     }
 
     // if we reach this place, we executed all the changes successfully
-    return Status::NoContent;
+    return Ok(Status::NoContent);
 }
 
 #[post("/task", data = "<new_task_data>")]
@@ -177,13 +191,17 @@ pub async fn add_new_task(
     db_connection_pool: &State<MinneDatabaseConnection>,
     authenticated_user: AuthenticatedUser,
     new_task_data: Json<NewTaskSuppliedData>,
-) -> Result<Json<i32>, Status> {
+) -> Result<Json<i32>, Problem> {
     use diesel::RunQueryDsl;
     use log::error;
 
     // if no text for the task was submitted, return an error
     if new_task_data.title.is_empty() {
-        return Err(Status::BadRequest);
+        return Err(Problem::new(
+            Status::BadRequest,
+            "missing_fields",
+            "The title of the task is required.",
+        ));
     }
 
     // prepare the DTO for creating the new task
@@ -206,23 +224,25 @@ pub async fn add_new_task(
                 "Could not get a connection from the database connection pool. The error was: {}",
                 error
             );
-            return Err(Status::InternalServerError);
+            return Err(Status::InternalServerError.into());
         }
     };
 
-    // add the DTO to the database and get the generated id of the new task
-    let maybe_task_id = diesel::insert_into(tasks::table)
+    // add the DTO to the database and return the generated id of the new task
+    match diesel::insert_into(tasks::table)
         .values(&new_task)
         .returning(tasks::id)
-        .get_result::<i32>(db_connection);
-
-    // check if the task was added to the database and return an error if we failed to do so
-    if maybe_task_id.is_err() {
-        return Err(Status::InternalServerError);
+        .get_result::<i32>(db_connection)
+    {
+        Ok(task_id) => Ok(Json(task_id)),
+        Err(error) => {
+            error!(
+                "Could not add the new task of the user with the id {}. The error was: {}",
+                authenticated_user.id, error
+            );
+            Err(Status::InternalServerError.into())
+        }
     }
-
-    // return the generated id for the corresponding task
-    Ok(Json(maybe_task_id.unwrap()))
 }
 
 #[delete("/task/<task_id>")]
@@ -230,7 +250,7 @@ pub async fn delete_task(
     db_connection_pool: &State<MinneDatabaseConnection>,
     authenticated_user: AuthenticatedUser,
     task_id: i32,
-) -> Status {
+) -> Result<Status, Problem> {
     use crate::schema::tasks::{dsl::tasks, id};
     use diesel::ExpressionMethods;
     use diesel::{QueryDsl, RunQueryDsl};
@@ -244,15 +264,12 @@ pub async fn delete_task(
                 "Could not get a connection from the database connection pool. The error was: {}",
                 error
             );
-            return Status::InternalServerError;
+            return Err(Status::InternalServerError.into());
         }
     };
 
     // get the task DTO from the database based on the supplied task id
-    let task = match tasks
-        .filter(id.eq(task_id))
-        .first::<Task>(&mut db_connection_pool.get().unwrap())
-    {
+    let task = match tasks.filter(id.eq(task_id)).first::<Task>(db_connection) {
         Ok(task) => task,
         Err(error) => {
             if error == diesel::NotFound {
@@ -260,31 +277,43 @@ pub async fn delete_task(
                     "The user tried to delete a task with the id {} that does not exist.",
                     task_id
                 );
-                return Status::NotFound;
+                return Err(task_not_found());
             }
             error!(
                 "Could not get the task with the id {} from the database. The error was: {}",
                 task_id, error
             );
-            return Status::InternalServerError;
+            return Err(Status::InternalServerError.into());
         }
     };
 
     // if the tasks does not belong to the authenticated user, return an error
     if task.owner != authenticated_user.id {
-        return Status::Forbidden;
+        return Err(Problem::new(
+            Status::Forbidden,
+            "task_not_owned",
+            "The task belongs to another user.",
+        ));
     }
 
     // delete the task from the database
-    let entries_deleted = diesel::delete(tasks.filter(id.eq(task_id)))
-        .execute(db_connection)
-        .unwrap();
+    let entries_deleted = match diesel::delete(tasks.filter(id.eq(task_id))).execute(db_connection)
+    {
+        Ok(entries_deleted) => entries_deleted,
+        Err(error) => {
+            error!(
+                "Could not delete the task with the id {}. The error was: {}",
+                task_id, error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
 
     // if the task was not deleted, return an error
     if entries_deleted == 0 {
-        return Status::InternalServerError;
+        return Err(Status::InternalServerError.into());
     }
-    Status::NoContent
+    Ok(Status::NoContent)
 }
 
 #[get("/task/<task_id>")]
@@ -292,37 +321,50 @@ pub async fn get_task(
     db_connection_pool: &State<MinneDatabaseConnection>,
     authenticated_user: AuthenticatedUser,
     task_id: i32,
-) -> Result<Json<SimplifiedTask>, Status> {
+) -> Result<Json<SimplifiedTask>, Problem> {
     use crate::schema::tasks::{dsl::tasks, id};
     use diesel::ExpressionMethods;
     use diesel::{QueryDsl, RunQueryDsl};
     use log::{error, warn};
 
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
     // get the task DTO from the database based on the supplied task id
-    let task = match tasks
-        .filter(id.eq(task_id))
-        .first::<Task>(&mut db_connection_pool.get().unwrap())
-    {
+    let task = match tasks.filter(id.eq(task_id)).first::<Task>(db_connection) {
         Ok(task) => task,
         Err(error) => {
             if error == diesel::NotFound {
                 warn!(
-                    "The user tried to delete a task with the id {} that does not exist.",
+                    "The user tried to get a task with the id {} that does not exist.",
                     task_id
                 );
-                return Err(Status::NotFound);
+                return Err(task_not_found());
             }
             error!(
                 "Could not get the task with the id {} from the database. The error was: {}",
                 task_id, error
             );
-            return Err(Status::InternalServerError);
+            return Err(Status::InternalServerError.into());
         }
     };
 
     // if the tasks does not belong to the authenticated user, return an error
     if task.owner != authenticated_user.id {
-        return Err(Status::Forbidden);
+        return Err(Problem::new(
+            Status::Forbidden,
+            "task_not_owned",
+            "The task belongs to another user.",
+        ));
     }
 
     // convert the task DTO to a SimplifiedTask DTO
@@ -0,0 +1,625 @@
+use crate::audit::{record_audit_event, AuditEventType, RequestMetadata};
+use crate::crypto::{generate_random_bytes, sha256_hex};
+use crate::fairings::{BackendConfiguration, MinneDatabaseConnection};
+use crate::guards::AuthenticatedUser;
+use crate::problem::Problem;
+use crate::routes::user::User;
+use crate::schema::{totp_recovery_codes, users};
+use chrono::{DateTime, Utc};
+use diesel::PgConnection;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use rocket::{delete, post};
+use serde::{Deserialize, Serialize};
+
+/// The number of seconds a single TOTP code is valid (RFC 6238 default).
+const TOTP_PERIOD_IN_SECONDS: u64 = 30;
+
+/// The number of digits of a generated TOTP code.
+const TOTP_DIGITS: u32 = 6;
+
+/// The number of time steps before and after the current one which are accepted to compensate
+/// clock drift between the server and the authenticator.
+const TOTP_ALLOWED_DRIFT_IN_STEPS: u64 = 1;
+
+/// The number of bytes used for a newly generated shared secret (160 bits as recommended by RFC 4226).
+const TOTP_SECRET_LENGTH_IN_BYTES: usize = 20;
+
+/// The number of recovery codes which are generated when the second factor gets enabled.
+const NUMBER_OF_RECOVERY_CODES: usize = 10;
+
+/// The issuer which is shown in the authenticator app of the user.
+const TOTP_ISSUER: &str = "Minne";
+
+#[derive(Queryable)]
+pub struct TotpRecoveryCode {
+    pub id: i32,
+    pub user_id: i32,
+    pub code_hash: String,
+    pub used_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = totp_recovery_codes)]
+pub struct NewTotpRecoveryCode {
+    pub user_id: i32,
+    pub code_hash: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TotpEnrollmentResponse {
+    /// The base32 encoded shared secret for entering it manually into an authenticator app.
+    secret: String,
+    /// The `otpauth://` URI which can be rendered as a QR code for the authenticator app.
+    provisioning_uri: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TotpRecoveryCodesResponse {
+    /// The one-time recovery codes which can be used instead of a TOTP code.
+    recovery_codes: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct TotpConfirmationData {
+    /// The code currently shown by the authenticator app.
+    pub code: String,
+}
+
+#[derive(Deserialize)]
+pub struct TotpDisableData {
+    /// The current password of the user.
+    pub password: String,
+    /// A code of the authenticator app or one of the recovery codes.
+    pub code: String,
+}
+
+/// Get the current TOTP time step based on the system time.
+fn get_current_time_step() -> u64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() / TOTP_PERIOD_IN_SECONDS)
+        .unwrap_or(0)
+}
+
+/// Calculate the TOTP code for the supplied secret and time step as described in RFC 6238 and
+/// RFC 4226 (HMAC-SHA1 with dynamic truncation).
+fn calculate_totp_code(secret: &[u8], time_step: u64) -> String {
+    use ring::hmac;
+
+    // calculate the HMAC of the time step using the shared secret
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+    let tag = hmac::sign(&key, &time_step.to_be_bytes());
+    let digest = tag.as_ref();
+
+    // apply the dynamic truncation to get a 31 bit number out of the HMAC
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary_code = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+
+    // reduce the number to the configured amount of digits
+    format!(
+        "{:0width$}",
+        binary_code % 10u32.pow(TOTP_DIGITS),
+        width = TOTP_DIGITS as usize
+    )
+}
+
+/// Get the time step for which the supplied code is valid or `None` if the code is not valid
+/// for any time step inside the allowed clock drift window around the current time step.
+fn find_matching_time_step(
+    encoded_secret: &str,
+    supplied_code: &str,
+    current_time_step: u64,
+) -> Option<u64> {
+    use data_encoding::BASE32_NOPAD;
+    use ring::constant_time::verify_slices_are_equal;
+
+    // decode the stored secret, if this fails the secret is unusable
+    let secret = BASE32_NOPAD.decode(encoded_secret.as_bytes()).ok()?;
+
+    // check all time steps inside the allowed window
+    let first_time_step = current_time_step.saturating_sub(TOTP_ALLOWED_DRIFT_IN_STEPS);
+    let last_time_step = current_time_step + TOTP_ALLOWED_DRIFT_IN_STEPS;
+    (first_time_step..=last_time_step).find(|time_step| {
+        let expected_code = calculate_totp_code(&secret, *time_step);
+        verify_slices_are_equal(expected_code.as_bytes(), supplied_code.as_bytes()).is_ok()
+    })
+}
+
+/// Normalize a recovery code so that the user can enter it with or without dashes, spaces or
+/// in a different case.
+fn normalize_recovery_code(recovery_code: &str) -> String {
+    recovery_code
+        .chars()
+        .filter(|character| character.is_ascii_alphanumeric())
+        .map(|character| character.to_ascii_lowercase())
+        .collect()
+}
+
+/// Get the hex encoded SHA-256 hash of a (normalized) recovery code as stored in the database.
+fn hash_recovery_code(recovery_code: &str) -> String {
+    sha256_hex(&normalize_recovery_code(recovery_code))
+}
+
+/// Generate a new set of recovery codes in the format `xxxxx-xxxxx`.
+fn generate_recovery_codes() -> Vec<String> {
+    use data_encoding::BASE32_NOPAD;
+
+    (0..NUMBER_OF_RECOVERY_CODES)
+        .map(|_| {
+            let encoded = BASE32_NOPAD
+                .encode(&generate_random_bytes(10))
+                .to_lowercase();
+            format!("{}-{}", &encoded[0..5], &encoded[5..10])
+        })
+        .collect()
+}
+
+/// Verify the second factor of a user which can either be a code of the authenticator app or one
+/// of the unused recovery codes. Codes which were accepted once are marked as used and cannot be
+/// used again.
+pub fn verify_second_factor(
+    connection: &mut PgConnection,
+    user: &User,
+    supplied_code: &str,
+) -> Result<bool, diesel::result::Error> {
+    use diesel::{BoolExpressionMethods, ExpressionMethods, QueryDsl, RunQueryDsl};
+
+    // users without a configured secret cannot be verified at all
+    let encoded_secret = match &user.totp_secret {
+        Some(secret) => secret,
+        None => return Ok(false),
+    };
+
+    // a code consisting of digits only is treated as a code of the authenticator app
+    let supplied_code = supplied_code.trim();
+    if supplied_code.len() == TOTP_DIGITS as usize
+        && supplied_code
+            .chars()
+            .all(|character| character.is_ascii_digit())
+    {
+        let time_step =
+            match find_matching_time_step(encoded_secret, supplied_code, get_current_time_step()) {
+                Some(time_step) => time_step as i64,
+                None => return Ok(false),
+            };
+
+        // remember the time step to prevent replaying the same code. A code which was already used
+        // (or an older one) must not be accepted again, the check is part of the update, so two
+        // concurrent logins cannot use the same code
+        let updated_users = diesel::update(
+            users::table.find(user.id).filter(
+                users::totp_last_used_step
+                    .is_null()
+                    .or(users::totp_last_used_step.lt(time_step)),
+            ),
+        )
+        .set(users::totp_last_used_step.eq(time_step))
+        .execute(connection)?;
+        return Ok(updated_users == 1);
+    }
+
+    // otherwise try to find a matching recovery code which was not used yet and mark it as used
+    let codes_marked_as_used = diesel::update(
+        totp_recovery_codes::table
+            .filter(totp_recovery_codes::user_id.eq(user.id))
+            .filter(totp_recovery_codes::code_hash.eq(hash_recovery_code(supplied_code)))
+            .filter(totp_recovery_codes::used_at.is_null()),
+    )
+    .set(totp_recovery_codes::used_at.eq(diesel::dsl::now))
+    .execute(connection)?;
+    Ok(codes_marked_as_used > 0)
+}
+
+/// The problem which is reported if the user tries to set up a second factor while one is enabled.
+fn second_factor_already_enabled() -> Problem {
+    Problem::new(
+        Status::Conflict,
+        "second_factor_already_enabled",
+        "The second factor is already enabled and has to be disabled first.",
+    )
+}
+
+#[post("/auth/totp/enroll")]
+pub async fn enroll_totp(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    authenticated_user: AuthenticatedUser,
+) -> Result<Json<TotpEnrollmentResponse>, Problem> {
+    use data_encoding::BASE32_NOPAD;
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+    use log::error;
+    use rocket::http::RawStr;
+
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // get the user record of the authenticated user
+    let user = match users::table
+        .find(authenticated_user.id)
+        .first::<User>(db_connection)
+    {
+        Ok(user) => user,
+        Err(error) => {
+            error!(
+                "Could not get the user record for the user with the id {}. The error was: {}",
+                authenticated_user.id, error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // if the second factor is already enabled, it has to be disabled before a new one can be enrolled
+    if user.totp_enabled {
+        return Err(second_factor_already_enabled());
+    }
+
+    // generate a new secret and store it for the user, it will be used as soon as the user confirmed it
+    let encoded_secret = BASE32_NOPAD.encode(&generate_random_bytes(TOTP_SECRET_LENGTH_IN_BYTES));
+    if let Err(error) = diesel::update(users::table.find(user.id))
+        .set((
+            users::totp_secret.eq(&encoded_secret),
+            users::totp_last_used_step.eq(None::<i64>),
+        ))
+        .execute(db_connection)
+    {
+        error!(
+            "Could not store the TOTP secret for the user with the id {}. The error was: {}",
+            user.id, error
+        );
+        return Err(Status::InternalServerError.into());
+    }
+
+    // build the provisioning URI which can be used by authenticator apps (e.g. as a QR code)
+    let provisioning_uri = format!(
+        "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+        issuer = TOTP_ISSUER,
+        account = RawStr::new(&user.email).percent_encode(),
+        secret = encoded_secret,
+        digits = TOTP_DIGITS,
+        period = TOTP_PERIOD_IN_SECONDS
+    );
+
+    // return the secret as well as the provisioning URI to the calling party
+    Ok(Json(TotpEnrollmentResponse {
+        secret: encoded_secret,
+        provisioning_uri,
+    }))
+}
+
+#[post("/auth/totp/confirm", data = "<confirmation_data>")]
+pub async fn confirm_totp(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    authenticated_user: AuthenticatedUser,
+    request_metadata: RequestMetadata,
+    confirmation_data: Json<TotpConfirmationData>,
+) -> Result<Json<TotpRecoveryCodesResponse>, Problem> {
+    use diesel::{Connection, ExpressionMethods, QueryDsl, RunQueryDsl};
+    use log::{error, info};
+
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // get the user record of the authenticated user
+    let user = match users::table
+        .find(authenticated_user.id)
+        .first::<User>(db_connection)
+    {
+        Ok(user) => user,
+        Err(error) => {
+            error!(
+                "Could not get the user record for the user with the id {}. The error was: {}",
+                authenticated_user.id, error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // the second factor can only be confirmed once after it was enrolled
+    if user.totp_enabled {
+        return Err(second_factor_already_enabled());
+    }
+    let encoded_secret = match &user.totp_secret {
+        Some(secret) => secret,
+        None => {
+            return Err(Problem::new(
+                Status::BadRequest,
+                "second_factor_not_enrolled",
+                "A second factor has to be enrolled before it can be confirmed.",
+            ))
+        }
+    };
+
+    // ensure that the user configured the authenticator correctly by checking the supplied code
+    let time_step = match find_matching_time_step(
+        encoded_secret,
+        confirmation_data.code.trim(),
+        get_current_time_step(),
+    ) {
+        Some(time_step) => time_step as i64,
+        None => return Err(Problem::invalid_second_factor()),
+    };
+
+    // enable the second factor and replace all recovery codes with a fresh set of codes
+    let recovery_codes = generate_recovery_codes();
+    let new_recovery_codes = recovery_codes
+        .iter()
+        .map(|recovery_code| NewTotpRecoveryCode {
+            user_id: user.id,
+            code_hash: hash_recovery_code(recovery_code),
+        })
+        .collect::<Vec<NewTotpRecoveryCode>>();
+    let enabling_result = db_connection.transaction::<_, diesel::result::Error, _>(|connection| {
+        diesel::update(users::table.find(user.id))
+            .set((
+                users::totp_enabled.eq(true),
+                users::totp_last_used_step.eq(time_step),
+            ))
+            .execute(connection)?;
+        diesel::delete(totp_recovery_codes::table.filter(totp_recovery_codes::user_id.eq(user.id)))
+            .execute(connection)?;
+        diesel::insert_into(totp_recovery_codes::table)
+            .values(&new_recovery_codes)
+            .execute(connection)?;
+        Ok(())
+    });
+    if let Err(error) = enabling_result {
+        error!(
+            "Could not enable the second factor for the user with the id {}. The error was: {}",
+            user.id, error
+        );
+        return Err(Status::InternalServerError.into());
+    }
+    info!(
+        "The user with the id {} enabled the second factor authentication",
+        user.id
+    );
+    record_audit_event(
+        db_connection,
+        AuditEventType::TotpEnabled,
+        Some(user.id),
+        Some(user.id),
+        &request_metadata,
+        None,
+    );
+
+    // the recovery codes are only returned once, afterwards only their hashes are known
+    Ok(Json(TotpRecoveryCodesResponse { recovery_codes }))
+}
+
+#[delete("/auth/totp", data = "<disable_data>")]
+pub async fn disable_totp(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    config: &State<BackendConfiguration>,
+    authenticated_user: AuthenticatedUser,
+    request_metadata: RequestMetadata,
+    disable_data: Json<TotpDisableData>,
+) -> Result<Status, Problem> {
+    use crate::passwords::verify_password;
+    use diesel::{Connection, ExpressionMethods, QueryDsl, RunQueryDsl};
+    use log::{error, info};
+
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // get the user record of the authenticated user
+    let user = match users::table
+        .find(authenticated_user.id)
+        .first::<User>(db_connection)
+    {
+        Ok(user) => user,
+        Err(error) => {
+            error!(
+                "Could not get the user record for the user with the id {}. The error was: {}",
+                authenticated_user.id, error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // if the second factor is not enabled, there is nothing to disable
+    if !user.totp_enabled {
+        return Err(Problem::new(
+            Status::BadRequest,
+            "second_factor_not_enabled",
+            "The second factor is not enabled.",
+        ));
+    }
+
+    // the user has to re-authenticate with the password ...
+    match verify_password(
+        &config.password_hashing,
+        &disable_data.password,
+        user.password_hash.as_str(),
+    ) {
+        Ok(is_password_correct) => {
+            if !is_password_correct {
+                return Err(Problem::wrong_password());
+            }
+        }
+        Err(error) => {
+            error!("Could not verify the supplied password with the one stored in the database. The error was: {}", error);
+            return Err(Status::InternalServerError.into());
+        }
+    }
+
+    // ... as well as with the second factor which should be disabled
+    match verify_second_factor(db_connection, &user, &disable_data.code) {
+        Ok(true) => {}
+        Ok(false) => return Err(Problem::invalid_second_factor()),
+        Err(error) => {
+            error!(
+                "Could not verify the second factor of the user with the id {}. The error was: {}",
+                user.id, error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    }
+
+    // remove the secret as well as all recovery codes of the user
+    let disabling_result = db_connection.transaction::<_, diesel::result::Error, _>(|connection| {
+        diesel::update(users::table.find(user.id))
+            .set((
+                users::totp_enabled.eq(false),
+                users::totp_secret.eq(None::<String>),
+                users::totp_last_used_step.eq(None::<i64>),
+            ))
+            .execute(connection)?;
+        diesel::delete(totp_recovery_codes::table.filter(totp_recovery_codes::user_id.eq(user.id)))
+            .execute(connection)?;
+        Ok(())
+    });
+    if let Err(error) = disabling_result {
+        error!(
+            "Could not disable the second factor for the user with the id {}. The error was: {}",
+            user.id, error
+        );
+        return Err(Status::InternalServerError.into());
+    }
+    info!(
+        "The user with the id {} disabled the second factor authentication",
+        user.id
+    );
+    record_audit_event(
+        db_connection,
+        AuditEventType::TotpDisabled,
+        Some(user.id),
+        Some(user.id),
+        &request_metadata,
+        None,
+    );
+
+    // we assume that we've succeeded and can return with an appropriate status code
+    Ok(Status::NoContent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{calculate_totp_code, find_matching_time_step, TOTP_PERIOD_IN_SECONDS};
+
+    /// The SHA-1 secret of the test vectors of RFC 6238 (Appendix B).
+    const RFC_6238_SECRET: &[u8] = b"12345678901234567890";
+
+    /// The same secret as it is stored for the users (Base32 without padding).
+    const RFC_6238_ENCODED_SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    #[test]
+    fn codes_match_the_test_vectors_of_rfc_6238() {
+        // the RFC lists 8 digits, the codes with 6 digits are the last 6 of them
+        for (time, code) in [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ] {
+            assert_eq!(
+                calculate_totp_code(RFC_6238_SECRET, time / TOTP_PERIOD_IN_SECONDS),
+                code,
+                "wrong code at {}",
+                time
+            );
+        }
+    }
+
+    #[test]
+    fn code_of_the_current_time_step_is_accepted() {
+        assert_eq!(
+            find_matching_time_step(RFC_6238_ENCODED_SECRET, "050471", 37037037),
+            Some(37037037)
+        );
+    }
+
+    #[test]
+    fn codes_of_the_neighboring_time_steps_are_accepted() {
+        // the clock of the authenticator is one step behind or ahead
+        let previous_code = calculate_totp_code(RFC_6238_SECRET, 37037036);
+        let next_code = calculate_totp_code(RFC_6238_SECRET, 37037038);
+        assert_eq!(
+            find_matching_time_step(RFC_6238_ENCODED_SECRET, &previous_code, 37037037),
+            Some(37037036)
+        );
+        assert_eq!(
+            find_matching_time_step(RFC_6238_ENCODED_SECRET, &next_code, 37037037),
+            Some(37037038)
+        );
+    }
+
+    #[test]
+    fn codes_outside_of_the_window_are_rejected() {
+        let too_old_code = calculate_totp_code(RFC_6238_SECRET, 37037035);
+        let too_new_code = calculate_totp_code(RFC_6238_SECRET, 37037039);
+        assert_eq!(
+            find_matching_time_step(RFC_6238_ENCODED_SECRET, &too_old_code, 37037037),
+            None
+        );
+        assert_eq!(
+            find_matching_time_step(RFC_6238_ENCODED_SECRET, &too_new_code, 37037037),
+            None
+        );
+    }
+
+    #[test]
+    fn window_does_not_underflow_at_the_first_time_step() {
+        let first_code = calculate_totp_code(RFC_6238_SECRET, 0);
+        assert_eq!(
+            find_matching_time_step(RFC_6238_ENCODED_SECRET, &first_code, 0),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn malformed_codes_and_secrets_are_rejected() {
+        // the 8 digit code of the RFC contains the valid 6 digit code, but is not accepted
+        assert_eq!(
+            find_matching_time_step(RFC_6238_ENCODED_SECRET, "14050471", 37037037),
+            None
+        );
+        assert_eq!(
+            find_matching_time_step(RFC_6238_ENCODED_SECRET, "", 37037037),
+            None
+        );
+        assert_eq!(
+            find_matching_time_step("not base32!", "050471", 37037037),
+            None
+        );
+    }
+}
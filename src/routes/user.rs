@@ -1,10 +1,17 @@
+use crate::audit::{record_audit_event, AuditEventType, RequestMetadata};
 use crate::fairings::{BackendConfiguration, MinneDatabaseConnection};
+use crate::guards::AuthenticatedUser;
+use crate::problem::Problem;
 use crate::schema::users;
-use chrono::NaiveDateTime;
+use chrono::{DateTime, NaiveDateTime, Utc};
 use rocket::http::Status;
 use rocket::serde::json::Json;
-use rocket::{post, State};
-use serde::Deserialize;
+use rocket::{delete, get, patch, post, put, State};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// The number of hours a token for verifying a new email address is valid.
+const EMAIL_VERIFICATION_TOKEN_LIFETIME_IN_HOURS: i64 = 24;
 
 #[derive(Queryable, Clone)]
 pub struct User {
@@ -15,6 +22,19 @@ pub struct User {
     pub password_hash: String,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    pub totp_secret: Option<String>,
+    pub totp_enabled: bool,
+    pub totp_last_used_step: Option<i64>,
+    pub pending_email: Option<String>,
+    pub email_verification_token: Option<String>,
+    pub email_verification_expires_at: Option<NaiveDateTime>,
+    pub is_admin: bool,
+    pub disabled: bool,
+    pub password_change_required: bool,
+    pub tokens_revoked_at: Option<NaiveDateTime>,
+    pub invited_by: Option<i32>,
+    pub invitation_code_id: Option<i32>,
+    pub has_local_password: bool,
 }
 
 #[derive(Insertable)]
@@ -24,6 +44,9 @@ pub struct NewUser {
     pub last_name: String,
     pub email: String,
     pub password_hash: String,
+    pub invited_by: Option<i32>,
+    pub invitation_code_id: Option<i32>,
+    pub has_local_password: bool,
 }
 
 #[derive(Deserialize)]
@@ -38,27 +61,54 @@ pub struct NewUserCallData {
     pub password: String,
     /// The password of the user repeated to ensure that the user entered the password correctly.
     pub password_repeat: String,
+    /// The invitation code which is required if the open user registration is disabled.
+    pub invitation_code: Option<String>,
+}
+
+/// The problem which is reported if another account already uses the email address.
+fn email_already_registered() -> Problem {
+    Problem::new(
+        Status::BadRequest,
+        "email_already_registered",
+        "The email address is already used by another account.",
+    )
 }
 
 #[post("/user/create", data = "<new_user>")]
 pub async fn create_new_user(
     db_connection_pool: &State<MinneDatabaseConnection>,
     config: &State<BackendConfiguration>,
+    request_metadata: RequestMetadata,
     new_user: Json<NewUserCallData>,
-) -> Status {
+) -> Result<Status, Problem> {
+    use crate::password_policy::check_password_policy;
+    use crate::passwords::hash_password;
+    use crate::routes::invitation::redeem_invitation_code;
+    use diesel::Connection;
     use diesel::ExpressionMethods;
     use diesel::QueryDsl;
     use diesel::RunQueryDsl;
-    use log::error;
+    use log::{error, info};
 
-    // if user registration is disabled, exit early
-    if !config.user_registration_enabled {
-        return Status::Forbidden;
+    // if user registration is disabled, new users can only register with an invitation code
+    let invitation_code = new_user
+        .invitation_code
+        .as_ref()
+        .filter(|code| !code.trim().is_empty());
+    if !config.user_registration_enabled && invitation_code.is_none() {
+        return Err(Problem::new(
+            Status::Forbidden,
+            "invitation_code_required",
+            "The registration of new users is disabled, an invitation code is required.",
+        ));
     }
 
     // ensure that the password and the repeated password are the same
     if new_user.password != new_user.password_repeat {
-        return Status::BadRequest;
+        return Err(Problem::invalid_field(
+            "password_repeat",
+            vec!["The repeated password does not match the password.".to_string()],
+        ));
     }
 
     // check that all fields in the passed data are set and not empty
@@ -67,7 +117,21 @@ pub async fn create_new_user(
         || new_user.email.is_empty()
         || new_user.password.is_empty()
     {
-        return Status::BadRequest;
+        return Err(Problem::new(
+            Status::BadRequest,
+            "missing_fields",
+            "The first name, the last name, the email address and the password are required.",
+        ));
+    }
+
+    // the password has to fulfill the password policy of the instance
+    let policy_violations = check_password_policy(
+        &config.password_policy,
+        &new_user.password,
+        &[&new_user.first_name, &new_user.last_name, &new_user.email],
+    );
+    if !policy_violations.is_empty() {
+        return Err(Problem::invalid_field("password", policy_violations));
     }
 
     // get a connection to the database for dealing with the request
@@ -78,40 +142,830 @@ pub async fn create_new_user(
                 "Could not get a connection from the database connection pool. The error was: {}",
                 error
             );
-            return Status::InternalServerError;
+            return Err(Status::InternalServerError.into());
         }
     };
 
     // check the database if a user with the given email address already exists and return if so
-    let user_already_exists = diesel::select(diesel::dsl::exists(
+    let user_already_exists = match diesel::select(diesel::dsl::exists(
         users::table.filter(users::email.eq(new_user.email.clone())),
     ))
     .get_result::<bool>(db_connection)
-    .unwrap();
+    {
+        Ok(user_already_exists) => user_already_exists,
+        Err(error) => {
+            error!(
+                "Could not check if a user with the supplied email address already exists. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
     if user_already_exists {
-        return Status::BadRequest;
+        return Err(email_already_registered());
+    }
+
+    // hash the password with the configured algorithm
+    let password_hash = match hash_password(&config.password_hashing, &new_user.password) {
+        Ok(password_hash) => password_hash,
+        Err(error) => {
+            error!(
+                "Could not hash the password of the new user. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // add the user to the database, the invitation code (if any) is consumed in the same
+    // transaction to ensure that it is only used up if the user was created
+    let creation_result = db_connection.transaction::<_, diesel::result::Error, _>(|connection| {
+        let redeemed_code = match invitation_code {
+            Some(code) => match redeem_invitation_code(connection, code)? {
+                Some(redeemed_code) => Some(redeemed_code),
+                None => return Ok(None),
+            },
+            None => None,
+        };
+
+        // prepare the DTO for creating the new user
+        let new_user = NewUser {
+            first_name: new_user.first_name.clone(),
+            last_name: new_user.last_name.clone(),
+            email: new_user.email.clone(),
+            password_hash,
+            invited_by: redeemed_code.map(|(_, created_by)| created_by),
+            invitation_code_id: redeemed_code.map(|(code_id, _)| code_id),
+            has_local_password: true,
+        };
+
+        // add the DTO to the database
+        let user_id = diesel::insert_into(users::table)
+            .values(&new_user)
+            .returning(users::id)
+            .get_result::<i32>(connection)?;
+        Ok(Some((user_id, new_user.invited_by)))
+    });
+
+    // check if the user was added to the database
+    match creation_result {
+        Ok(None) => Err(Problem::new(
+            Status::Forbidden,
+            "invalid_invitation_code",
+            "The invitation code is unknown, expired or was already used.",
+        )),
+        Ok(Some((user_id, invited_by))) => {
+            if let Some(invited_by) = invited_by {
+                info!(
+                    "A new user registered with an invitation code of the user with the id {}",
+                    invited_by
+                );
+            }
+            let details = invited_by
+                .map(|invited_by| format!("invited by the user with the id {}", invited_by));
+            record_audit_event(
+                db_connection,
+                AuditEventType::UserRegistered,
+                Some(user_id),
+                Some(user_id),
+                &request_metadata,
+                details.as_deref(),
+            );
+            Ok(Status::NoContent)
+        }
+        Err(error) => {
+            error!("Could not create the new user. The error was: {}", error);
+            Err(Status::InternalServerError.into())
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct UserProfile {
+    /// The first name of the user.
+    pub first_name: String,
+    /// The last name of the user.
+    pub last_name: String,
+    /// The email address of the user used as the username.
+    pub email: String,
+    /// A new email address of the user which was not verified yet.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pending_email: Option<String>,
+    /// A flag which indicates if the user enabled a second factor for the login.
+    pub totp_enabled: bool,
+    /// The time when the account was created.
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<User> for UserProfile {
+    fn from(user: User) -> Self {
+        use chrono::TimeZone;
+
+        UserProfile {
+            first_name: user.first_name,
+            last_name: user.last_name,
+            email: user.email,
+            pending_email: user.pending_email,
+            totp_enabled: user.totp_enabled,
+            created_at: Utc.from_utc_datetime(&user.created_at),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct UserProfileChangeData {
+    /// The new first name of the user.
+    pub first_name: Option<String>,
+    /// The new last name of the user.
+    pub last_name: Option<String>,
+    /// The new email address of the user which has to be verified before it gets used.
+    pub email: Option<String>,
+    /// The current password of the user which is required for changing the email address.
+    pub current_password: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct EmailVerificationData {
+    /// The token which was issued when the change of the email address was requested.
+    pub token: String,
+}
+
+#[derive(Deserialize)]
+pub struct PasswordChangeData {
+    /// The current password of the user.
+    pub current_password: String,
+    /// The new password of the user.
+    pub new_password: String,
+    /// The new password of the user repeated to ensure that the user entered the password correctly.
+    pub new_password_repeat: String,
+}
+
+#[derive(Deserialize)]
+pub struct AccountDeletionData {
+    /// The current password of the user.
+    pub password: String,
+    /// The code of the authenticator app (or a recovery code) if the user enabled a second factor.
+    pub totp_code: Option<String>,
+}
+
+#[get("/user/me")]
+pub async fn get_own_profile(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    authenticated_user: AuthenticatedUser,
+) -> Result<Json<UserProfile>, Problem> {
+    use diesel::{QueryDsl, RunQueryDsl};
+    use log::error;
+
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // get the user record of the authenticated user and return it as the profile
+    match users::table
+        .find(authenticated_user.id)
+        .first::<User>(db_connection)
+    {
+        Ok(user) => Ok(Json(UserProfile::from(user))),
+        Err(error) => {
+            error!(
+                "Could not get the user record for the user with the id {}. The error was: {}",
+                authenticated_user.id, error
+            );
+            Err(Status::InternalServerError.into())
+        }
+    }
+}
+
+/// Hand the token for verifying a new email address over to the configured command, which delivers
+/// it to the address (e.g. by sending a mail). The token is written to the standard input of the
+/// command, so it neither shows up in the process list nor in the logs. The recipient and the id
+/// of the user are passed with the environment variables `MINNE_EMAIL` and `MINNE_USER_ID`.
+fn deliver_email_verification_token(
+    command: &Path,
+    user_id: i32,
+    recipient: &str,
+    token: &str,
+) -> Result<(), String> {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    // the command must not see the secrets of the backend
+    let mut child = Command::new(command)
+        .env_clear()
+        .env("PATH", std::env::var_os("PATH").unwrap_or_default())
+        .env("MINNE_EMAIL", recipient)
+        .env("MINNE_USER_ID", user_id.to_string())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .map_err(|error| format!("could not start '{}': {}", command.display(), error))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(format!("{}\n", token).as_bytes())
+            .map_err(|error| format!("could not write to '{}': {}", command.display(), error))?;
+    }
+    let exit_status = child
+        .wait()
+        .map_err(|error| format!("could not wait for '{}': {}", command.display(), error))?;
+    if !exit_status.success() {
+        return Err(format!(
+            "'{}' failed with {}",
+            command.display(),
+            exit_status
+        ));
+    }
+    Ok(())
+}
+
+#[patch("/user/me", data = "<profile_change_data>")]
+pub async fn update_own_profile(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    config: &State<BackendConfiguration>,
+    authenticated_user: AuthenticatedUser,
+    request_metadata: RequestMetadata,
+    profile_change_data: Json<UserProfileChangeData>,
+) -> Result<Json<UserProfile>, Problem> {
+    use crate::crypto::{generate_random_token, sha256_hex};
+    use crate::passwords::verify_password;
+    use chrono::Duration;
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+    use log::{error, info};
+
+    // if non of the fields for the profile were supplied, return an error
+    if profile_change_data.first_name.is_none()
+        && profile_change_data.last_name.is_none()
+        && profile_change_data.email.is_none()
+    {
+        return Err(Problem::new(
+            Status::BadRequest,
+            "missing_fields",
+            "At least one of the first name, the last name or the email address has to be supplied.",
+        ));
+    }
+
+    // all supplied fields have to be set and must fit into the database
+    let is_invalid = |value: &Option<String>, max_length: usize| {
+        value
+            .as_ref()
+            .map_or(false, |value| value.is_empty() || value.len() > max_length)
+    };
+    if is_invalid(&profile_change_data.first_name, 50)
+        || is_invalid(&profile_change_data.last_name, 50)
+        || is_invalid(&profile_change_data.email, 255)
+    {
+        return Err(Problem::new(
+            Status::BadRequest,
+            "invalid_profile",
+            "The names must not be empty or longer than 50 characters and the email address must not be empty or longer than 255 characters.",
+        ));
     }
 
-    // create a bcrypt hash of the password with a cost of 12
-    let password_hash = bcrypt::hash(new_user.password.clone(), 12).unwrap();
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
 
-    // prepare the DTO for creating the new user
-    let new_user = NewUser {
-        first_name: new_user.first_name.clone(),
-        last_name: new_user.last_name.clone(),
-        email: new_user.email.clone(),
-        password_hash,
+    // get the user record of the authenticated user
+    let user = match users::table
+        .find(authenticated_user.id)
+        .first::<User>(db_connection)
+    {
+        Ok(user) => user,
+        Err(error) => {
+            error!(
+                "Could not get the user record for the user with the id {}. The error was: {}",
+                authenticated_user.id, error
+            );
+            return Err(Status::InternalServerError.into());
+        }
     };
 
-    // add the DTO to the database
-    let entries_added = diesel::insert_into(users::table)
-        .values(&new_user)
+    // if a new email address was supplied, the user has to re-authenticate before the change
+    // can be requested
+    let requested_email = profile_change_data
+        .email
+        .as_ref()
+        .filter(|new_email| **new_email != user.email);
+    if let Some(new_email) = requested_email {
+        // the backend does not send mails itself, so the token can only be delivered if a command
+        // for delivering it is configured
+        let email_verification_command = match &config.email_verification_command {
+            Some(email_verification_command) => email_verification_command.clone(),
+            None => {
+                return Err(Problem::new(
+                    Status::ServiceUnavailable,
+                    "email_verification_unavailable",
+                    "The email address cannot be changed since the verification token cannot be delivered.",
+                ))
+            }
+        };
+        let current_password = match &profile_change_data.current_password {
+            Some(password) => password,
+            None => {
+                return Err(Problem::new(
+                    Status::BadRequest,
+                    "current_password_required",
+                    "The current password is required for changing the email address.",
+                ))
+            }
+        };
+        match verify_password(
+            &config.password_hashing,
+            current_password,
+            user.password_hash.as_str(),
+        ) {
+            Ok(is_password_correct) => {
+                if !is_password_correct {
+                    return Err(Problem::wrong_password());
+                }
+            }
+            Err(error) => {
+                error!("Could not verify the supplied password with the one stored in the database. The error was: {}", error);
+                return Err(Status::InternalServerError.into());
+            }
+        }
+
+        // the new email address must not be used by another user
+        let email_already_used = match diesel::select(diesel::dsl::exists(
+            users::table.filter(users::email.eq(new_email)),
+        ))
+        .get_result::<bool>(db_connection)
+        {
+            Ok(email_already_used) => email_already_used,
+            Err(error) => {
+                error!(
+                    "Could not check if the email address is already in use. The error was: {}",
+                    error
+                );
+                return Err(Status::InternalServerError.into());
+            }
+        };
+        if email_already_used {
+            return Err(email_already_registered());
+        }
+
+        // store the new email address together with a verification token which has to be used
+        // to confirm that the user owns the new address
+        let verification_token = generate_random_token(32);
+        if let Err(error) = diesel::update(users::table.find(user.id))
+            .set((
+                users::pending_email.eq(new_email),
+                users::email_verification_token.eq(sha256_hex(&verification_token)),
+                users::email_verification_expires_at.eq((Utc::now()
+                    + Duration::hours(EMAIL_VERIFICATION_TOKEN_LIFETIME_IN_HOURS))
+                .naive_utc()),
+                users::updated_at.eq(diesel::dsl::now),
+            ))
+            .execute(db_connection)
+        {
+            error!(
+                "Could not store the pending email address for the user with the id {}. The error was: {}",
+                user.id, error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+
+        // the token has to be delivered to the new address, it must never end up in the logs
+        let (user_id, recipient) = (user.id, new_email.clone());
+        let delivery_result = rocket::tokio::task::spawn_blocking(move || {
+            deliver_email_verification_token(
+                &email_verification_command,
+                user_id,
+                &recipient,
+                &verification_token,
+            )
+        })
+        .await
+        .unwrap_or_else(|error| Err(error.to_string()));
+        if let Err(error) = delivery_result {
+            error!(
+                "Could not deliver the email verification token for the user with the id {}. The error was: {}",
+                user.id, error
+            );
+            return Err(Problem::new(
+                Status::BadGateway,
+                "email_verification_delivery_failed",
+                "The verification token could not be delivered to the new email address.",
+            ));
+        }
+        info!(
+            "The user with the id {} requested to change the email address to '{}'",
+            user.id, new_email
+        );
+        record_audit_event(
+            db_connection,
+            AuditEventType::EmailChangeRequested,
+            Some(user.id),
+            Some(user.id),
+            &request_metadata,
+            None,
+        );
+    }
+
+    // update the names of the user if they were supplied
+    if let Some(new_first_name) = &profile_change_data.first_name {
+        if let Err(error) = diesel::update(users::table.find(user.id))
+            .set((
+                users::first_name.eq(new_first_name),
+                users::updated_at.eq(diesel::dsl::now),
+            ))
+            .execute(db_connection)
+        {
+            error!(
+                "Could not update the first name of the user with the id {}. The error was: {}",
+                user.id, error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    }
+    if let Some(new_last_name) = &profile_change_data.last_name {
+        if let Err(error) = diesel::update(users::table.find(user.id))
+            .set((
+                users::last_name.eq(new_last_name),
+                users::updated_at.eq(diesel::dsl::now),
+            ))
+            .execute(db_connection)
+        {
+            error!(
+                "Could not update the last name of the user with the id {}. The error was: {}",
+                user.id, error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    }
+
+    // return the updated profile of the user
+    match users::table.find(user.id).first::<User>(db_connection) {
+        Ok(user) => Ok(Json(UserProfile::from(user))),
+        Err(error) => {
+            error!(
+                "Could not get the user record for the user with the id {}. The error was: {}",
+                user.id, error
+            );
+            Err(Status::InternalServerError.into())
+        }
+    }
+}
+
+#[post("/user/me/email/verify", data = "<verification_data>")]
+pub async fn verify_own_email(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    authenticated_user: AuthenticatedUser,
+    request_metadata: RequestMetadata,
+    verification_data: Json<EmailVerificationData>,
+) -> Result<Status, Problem> {
+    use crate::crypto::sha256_hex;
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+    use log::{error, info};
+    use ring::constant_time::verify_slices_are_equal;
+
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // get the user record of the authenticated user
+    let user = match users::table
+        .find(authenticated_user.id)
+        .first::<User>(db_connection)
+    {
+        Ok(user) => user,
+        Err(error) => {
+            error!(
+                "Could not get the user record for the user with the id {}. The error was: {}",
+                authenticated_user.id, error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // there has to be a pending change of the email address which was not expired yet
+    let (pending_email, expected_token_hash, expires_at) = match (
+        &user.pending_email,
+        &user.email_verification_token,
+        &user.email_verification_expires_at,
+    ) {
+        (Some(pending_email), Some(token_hash), Some(expires_at)) => {
+            (pending_email, token_hash, expires_at)
+        }
+        _ => {
+            return Err(Problem::new(
+                Status::BadRequest,
+                "no_pending_email_change",
+                "There is no change of the email address which has to be verified.",
+            ))
+        }
+    };
+    if *expires_at < Utc::now().naive_utc() {
+        return Err(Problem::new(
+            Status::Gone,
+            "verification_token_expired",
+            "The verification token expired, the change of the email address has to be requested again.",
+        ));
+    }
+
+    // the supplied token has to match the one we issued for the change
+    let supplied_token_hash = sha256_hex(verification_data.token.trim());
+    if verify_slices_are_equal(
+        supplied_token_hash.as_bytes(),
+        expected_token_hash.as_bytes(),
+    )
+    .is_err()
+    {
+        return Err(Problem::new(
+            Status::Unauthorized,
+            "invalid_verification_token",
+            "The verification token is not valid.",
+        ));
+    }
+
+    // replace the email address with the verified one (the unique constraint ensures that no other
+    // user took the address in the meantime)
+    match diesel::update(users::table.find(user.id))
+        .set((
+            users::email.eq(pending_email),
+            users::pending_email.eq(None::<String>),
+            users::email_verification_token.eq(None::<String>),
+            users::email_verification_expires_at.eq(None::<NaiveDateTime>),
+            users::updated_at.eq(diesel::dsl::now),
+        ))
         .execute(db_connection)
-        .unwrap();
+    {
+        Ok(_) => {}
+        Err(diesel::result::Error::DatabaseError(
+            diesel::result::DatabaseErrorKind::UniqueViolation,
+            _,
+        )) => return Err(email_already_registered()),
+        Err(error) => {
+            error!(
+                "Could not change the email address of the user with the id {}. The error was: {}",
+                user.id, error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    }
+    info!(
+        "The user with the id {} verified and changed the email address",
+        user.id
+    );
+    record_audit_event(
+        db_connection,
+        AuditEventType::EmailChanged,
+        Some(user.id),
+        Some(user.id),
+        &request_metadata,
+        None,
+    );
 
-    // check if the user was added to the database
-    if entries_added == 0 {
-        return Status::InternalServerError;
+    // we assume that we've succeeded and can return with an appropriate status code
+    Ok(Status::NoContent)
+}
+
+#[put("/user/me/password", data = "<password_change_data>")]
+pub async fn change_own_password(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    config: &State<BackendConfiguration>,
+    authenticated_user: AuthenticatedUser,
+    request_metadata: RequestMetadata,
+    password_change_data: Json<PasswordChangeData>,
+) -> Result<Status, Problem> {
+    use crate::password_policy::check_password_policy;
+    use crate::passwords::{hash_password, verify_password};
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+    use log::{error, info};
+
+    // ensure that the new password is not empty and the same as the repeated new password
+    if password_change_data.new_password.is_empty() {
+        return Err(Problem::new(
+            Status::BadRequest,
+            "missing_fields",
+            "The new password is required.",
+        ));
+    }
+    if password_change_data.new_password != password_change_data.new_password_repeat {
+        return Err(Problem::invalid_field(
+            "new_password_repeat",
+            vec!["The repeated password does not match the new password.".to_string()],
+        ));
     }
-    Status::NoContent
+
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // get the user record of the authenticated user
+    let user = match users::table
+        .find(authenticated_user.id)
+        .first::<User>(db_connection)
+    {
+        Ok(user) => user,
+        Err(error) => {
+            error!(
+                "Could not get the user record for the user with the id {}. The error was: {}",
+                authenticated_user.id, error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // the user has to re-authenticate with the current password
+    match verify_password(
+        &config.password_hashing,
+        &password_change_data.current_password,
+        user.password_hash.as_str(),
+    ) {
+        Ok(is_password_correct) => {
+            if !is_password_correct {
+                return Err(Problem::wrong_password());
+            }
+        }
+        Err(error) => {
+            error!("Could not verify the supplied password with the one stored in the database. The error was: {}", error);
+            return Err(Status::InternalServerError.into());
+        }
+    }
+
+    // the new password has to fulfill the password policy of the instance
+    let policy_violations = check_password_policy(
+        &config.password_policy,
+        &password_change_data.new_password,
+        &[&user.first_name, &user.last_name, &user.email],
+    );
+    if !policy_violations.is_empty() {
+        return Err(Problem::invalid_field("new_password", policy_violations));
+    }
+
+    // hash the new password with the configured algorithm and store it
+    let password_hash =
+        match hash_password(&config.password_hashing, &password_change_data.new_password) {
+            Ok(password_hash) => password_hash,
+            Err(error) => {
+                error!(
+                    "Could not hash the new password of the user. The error was: {}",
+                    error
+                );
+                return Err(Status::InternalServerError.into());
+            }
+        };
+    if let Err(error) = diesel::update(users::table.find(user.id))
+        .set((
+            users::password_hash.eq(password_hash),
+            users::has_local_password.eq(true),
+            users::updated_at.eq(diesel::dsl::now),
+        ))
+        .execute(db_connection)
+    {
+        error!(
+            "Could not change the password of the user with the id {}. The error was: {}",
+            user.id, error
+        );
+        return Err(Status::InternalServerError.into());
+    }
+    info!("The user with the id {} changed the password", user.id);
+    record_audit_event(
+        db_connection,
+        AuditEventType::PasswordChanged,
+        Some(user.id),
+        Some(user.id),
+        &request_metadata,
+        None,
+    );
+
+    // we assume that we've succeeded and can return with an appropriate status code
+    Ok(Status::NoContent)
+}
+
+#[delete("/user/me", data = "<deletion_data>")]
+pub async fn delete_own_account(
+    db_connection_pool: &State<MinneDatabaseConnection>,
+    config: &State<BackendConfiguration>,
+    authenticated_user: AuthenticatedUser,
+    request_metadata: RequestMetadata,
+    deletion_data: Json<AccountDeletionData>,
+) -> Result<Status, Problem> {
+    use crate::passwords::verify_password;
+    use crate::routes::totp::verify_second_factor;
+    use diesel::{QueryDsl, RunQueryDsl};
+    use log::{error, info};
+
+    // get a connection to the database for dealing with the request
+    let db_connection = &mut match db_connection_pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Could not get a connection from the database connection pool. The error was: {}",
+                error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // get the user record of the authenticated user
+    let user = match users::table
+        .find(authenticated_user.id)
+        .first::<User>(db_connection)
+    {
+        Ok(user) => user,
+        Err(error) => {
+            error!(
+                "Could not get the user record for the user with the id {}. The error was: {}",
+                authenticated_user.id, error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // the user has to re-authenticate with the password ...
+    match verify_password(
+        &config.password_hashing,
+        &deletion_data.password,
+        user.password_hash.as_str(),
+    ) {
+        Ok(is_password_correct) => {
+            if !is_password_correct {
+                return Err(Problem::wrong_password());
+            }
+        }
+        Err(error) => {
+            error!("Could not verify the supplied password with the one stored in the database. The error was: {}", error);
+            return Err(Status::InternalServerError.into());
+        }
+    }
+
+    // ... and with the second factor if it is enabled
+    if user.totp_enabled {
+        let supplied_code = match deletion_data.totp_code.as_deref() {
+            Some(code) if !code.trim().is_empty() => code,
+            _ => return Err(Problem::second_factor_required()),
+        };
+        match verify_second_factor(db_connection, &user, supplied_code) {
+            Ok(true) => {}
+            Ok(false) => return Err(Problem::invalid_second_factor()),
+            Err(error) => {
+                error!(
+                    "Could not verify the second factor of the user with the id {}. The error was: {}",
+                    user.id, error
+                );
+                return Err(Status::InternalServerError.into());
+            }
+        }
+    }
+
+    // delete the user, all tasks, tokens and recovery codes are deleted by the database as well
+    match diesel::delete(users::table.find(user.id)).execute(db_connection) {
+        Ok(entries_deleted) if entries_deleted > 0 => {}
+        Ok(_) => return Err(Problem::user_not_found()),
+        Err(error) => {
+            error!(
+                "Could not delete the user with the id {}. The error was: {}",
+                user.id, error
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    }
+    info!("The user with the id {} deleted the own account", user.id);
+
+    // the events of the user were deleted together with the account, so only a trace without
+    // a reference to the account is kept
+    record_audit_event(
+        db_connection,
+        AuditEventType::AccountDeleted,
+        None,
+        None,
+        &request_metadata,
+        Some(&format!("deleted the account with the id {}", user.id)),
+    );
+
+    // we assume that we've succeeded and can return with an appropriate status code
+    Ok(Status::NoContent)
 }
@@ -1,5 +1,108 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    app_login_processes (id) {
+        id -> Varchar,
+        code_challenge -> Varchar,
+        state -> Nullable<Varchar>,
+        user_id -> Nullable<Int4>,
+        authorization_code_hash -> Nullable<Varchar>,
+        expires_at -> Timestamptz,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    audit_events (id) {
+        id -> Int8,
+        user_id -> Nullable<Int4>,
+        actor_id -> Nullable<Int4>,
+        event_type -> Varchar,
+        ip_address -> Nullable<Varchar>,
+        user_agent -> Nullable<Varchar>,
+        details -> Nullable<Text>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    device_authorizations (id) {
+        id -> Int4,
+        device_code_hash -> Varchar,
+        user_code -> Varchar,
+        client_name -> Nullable<Varchar>,
+        user_id -> Nullable<Int4>,
+        denied -> Bool,
+        polling_interval -> Int4,
+        last_polled_at -> Nullable<Timestamptz>,
+        expires_at -> Timestamptz,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    external_identities (id) {
+        id -> Int4,
+        user_id -> Int4,
+        provider -> Varchar,
+        subject -> Varchar,
+        email -> Varchar,
+        last_login_at -> Nullable<Timestamptz>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    external_login_states (state_hash) {
+        state_hash -> Varchar,
+        provider -> Varchar,
+        nonce -> Varchar,
+        code_verifier -> Varchar,
+        login_process_id -> Nullable<Varchar>,
+        expires_at -> Timestamptz,
+        created_at -> Timestamptz,
+        link_user_id -> Nullable<Int4>,
+    }
+}
+
+diesel::table! {
+    invitation_codes (id) {
+        id -> Int4,
+        code_hash -> Varchar,
+        created_by -> Int4,
+        max_uses -> Int4,
+        uses -> Int4,
+        expires_at -> Timestamptz,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    oidc_authorization_codes (code_hash) {
+        code_hash -> Varchar,
+        client_id -> Int4,
+        user_id -> Int4,
+        redirect_uri -> Text,
+        scope -> Varchar,
+        nonce -> Nullable<Varchar>,
+        code_challenge -> Nullable<Varchar>,
+        expires_at -> Timestamptz,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    oidc_clients (id) {
+        id -> Int4,
+        client_id -> Varchar,
+        client_secret_hash -> Nullable<Varchar>,
+        name -> Varchar,
+        redirect_uris -> Array<Text>,
+        created_by -> Nullable<Int4>,
+        created_at -> Timestamptz,
+    }
+}
+
 diesel::table! {
     personal_access_tokens (id) {
         id -> Int4,
@@ -10,6 +113,17 @@ diesel::table! {
         disabled -> Bool,
         created_at -> Timestamptz,
         updated_at -> Timestamptz,
+        user_agent -> Nullable<Varchar>,
+        last_used_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    session_access_tokens (jti) {
+        jti -> Varchar,
+        personal_access_token_id -> Int4,
+        expires_at -> Timestamptz,
+        created_at -> Timestamptz,
     }
 }
 
@@ -24,6 +138,16 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    totp_recovery_codes (id) {
+        id -> Int4,
+        user_id -> Int4,
+        code_hash -> Varchar,
+        used_at -> Nullable<Timestamptz>,
+        created_at -> Timestamptz,
+    }
+}
+
 diesel::table! {
     users (id) {
         id -> Int4,
@@ -33,10 +157,47 @@ diesel::table! {
         password_hash -> Varchar,
         created_at -> Timestamptz,
         updated_at -> Timestamptz,
+        totp_secret -> Nullable<Varchar>,
+        totp_enabled -> Bool,
+        totp_last_used_step -> Nullable<Int8>,
+        pending_email -> Nullable<Varchar>,
+        email_verification_token -> Nullable<Varchar>,
+        email_verification_expires_at -> Nullable<Timestamptz>,
+        is_admin -> Bool,
+        disabled -> Bool,
+        password_change_required -> Bool,
+        tokens_revoked_at -> Nullable<Timestamptz>,
+        invited_by -> Nullable<Int4>,
+        invitation_code_id -> Nullable<Int4>,
+        has_local_password -> Bool,
     }
 }
 
+diesel::joinable!(app_login_processes -> users (user_id));
+diesel::joinable!(device_authorizations -> users (user_id));
+diesel::joinable!(external_identities -> users (user_id));
+diesel::joinable!(external_login_states -> users (link_user_id));
+diesel::joinable!(invitation_codes -> users (created_by));
+diesel::joinable!(oidc_authorization_codes -> oidc_clients (client_id));
+diesel::joinable!(oidc_authorization_codes -> users (user_id));
+diesel::joinable!(oidc_clients -> users (created_by));
 diesel::joinable!(personal_access_tokens -> users (user_id));
+diesel::joinable!(session_access_tokens -> personal_access_tokens (personal_access_token_id));
 diesel::joinable!(tasks -> users (owner));
+diesel::joinable!(totp_recovery_codes -> users (user_id));
 
-diesel::allow_tables_to_appear_in_same_query!(personal_access_tokens, tasks, users,);
+diesel::allow_tables_to_appear_in_same_query!(
+    app_login_processes,
+    audit_events,
+    device_authorizations,
+    external_identities,
+    external_login_states,
+    invitation_codes,
+    oidc_authorization_codes,
+    oidc_clients,
+    personal_access_tokens,
+    session_access_tokens,
+    tasks,
+    totp_recovery_codes,
+    users,
+);
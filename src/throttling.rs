@@ -0,0 +1,377 @@
+use crate::fairings::BackendConfiguration;
+use crate::problem::Problem;
+use rocket::http::Status;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The number of tracked keys after which stale entries are removed from the tracker.
+const CLEANUP_THRESHOLD: usize = 10_000;
+
+/// The rejection of a login attempt because of too many failed attempts before.
+#[derive(Debug)]
+pub struct TooManyLoginAttempts {
+    /// The number of seconds the client has to wait before the next attempt is accepted.
+    pub retry_after_in_seconds: u64,
+}
+
+impl TooManyLoginAttempts {
+    fn new(retry_after: Duration) -> Self {
+        // always round up to ensure the client does not come back too early
+        TooManyLoginAttempts {
+            retry_after_in_seconds: retry_after.as_secs()
+                + u64::from(retry_after.subsec_nanos() > 0),
+        }
+    }
+}
+
+impl From<TooManyLoginAttempts> for Problem {
+    fn from(too_many_attempts: TooManyLoginAttempts) -> Self {
+        Problem::new(
+            Status::TooManyRequests,
+            "too_many_login_attempts",
+            "There were too many failed login attempts, try again later.",
+        )
+        .with_retry_after(too_many_attempts.retry_after_in_seconds)
+    }
+}
+
+/// The failed login attempts which were tracked for a single account or IP address.
+struct FailedAttempts {
+    /// The number of consecutive failed attempts.
+    count: u32,
+    /// The point in time at which the last failed attempt happened.
+    last_failure: Instant,
+    /// The point in time until which no further login attempts are accepted.
+    blocked_until: Instant,
+}
+
+/// Keeps track of failed login attempts per account and per IP address and calculates the time
+/// a client has to wait before the next login attempt is accepted.
+#[derive(Default)]
+pub struct LoginAttemptTracker {
+    attempts: Mutex<HashMap<String, FailedAttempts>>,
+}
+
+impl LoginAttemptTracker {
+    /// Get the key used to track the failed attempts of an account.
+    fn account_key(account: &str) -> String {
+        format!("account:{}", account.trim().to_lowercase())
+    }
+
+    /// Get the key used to track the failed attempts of an IP address.
+    fn ip_key(ip_address: &IpAddr) -> String {
+        format!("ip:{}", ip_address)
+    }
+
+    /// Check if a login attempt for the supplied account from the supplied IP address is allowed
+    /// right now. If not, the response which should be returned to the client is returned.
+    pub fn check(
+        &self,
+        account: &str,
+        ip_address: Option<IpAddr>,
+    ) -> Result<(), TooManyLoginAttempts> {
+        self.check_at(account, ip_address, Instant::now())
+    }
+
+    /// Check if a login attempt is allowed at the supplied point in time (see [`Self::check`]).
+    fn check_at(
+        &self,
+        account: &str,
+        ip_address: Option<IpAddr>,
+        now: Instant,
+    ) -> Result<(), TooManyLoginAttempts> {
+        let attempts = self.attempts.lock().unwrap();
+
+        // collect the keys which have to be checked for the current attempt
+        let mut keys = vec![Self::account_key(account)];
+        if let Some(ip_address) = ip_address {
+            keys.push(Self::ip_key(&ip_address));
+        }
+
+        // if any of the keys is currently blocked, the client has to wait until the longest block is over
+        let remaining_block = keys
+            .iter()
+            .filter_map(|key| attempts.get(key))
+            .filter(|failed_attempts| failed_attempts.blocked_until > now)
+            .map(|failed_attempts| failed_attempts.blocked_until - now)
+            .max();
+        match remaining_block {
+            Some(retry_after) => Err(TooManyLoginAttempts::new(retry_after)),
+            None => Ok(()),
+        }
+    }
+
+    /// Record a failed login attempt for the supplied account from the supplied IP address.
+    pub fn record_failure(
+        &self,
+        config: &BackendConfiguration,
+        account: &str,
+        ip_address: Option<IpAddr>,
+    ) {
+        self.record_failure_at(config, account, ip_address, Instant::now())
+    }
+
+    /// Record a failed login attempt at the supplied point in time (see [`Self::record_failure`]).
+    fn record_failure_at(
+        &self,
+        config: &BackendConfiguration,
+        account: &str,
+        ip_address: Option<IpAddr>,
+        now: Instant,
+    ) {
+        let mut attempts = self.attempts.lock().unwrap();
+
+        // remove all entries which did not see a failed attempt for a while to limit the memory usage
+        let failure_window = Duration::from_secs(config.login_failure_window_in_seconds);
+        if attempts.len() > CLEANUP_THRESHOLD {
+            attempts.retain(|_, failed_attempts| {
+                failed_attempts.blocked_until > now
+                    || now - failed_attempts.last_failure < failure_window
+            });
+        }
+
+        // track the failed attempt for the account as well as for the IP address (if known)
+        Self::record_failure_for_key(
+            &mut attempts,
+            Self::account_key(account),
+            config.login_max_failed_attempts_per_account,
+            config,
+            now,
+        );
+        if let Some(ip_address) = ip_address {
+            Self::record_failure_for_key(
+                &mut attempts,
+                Self::ip_key(&ip_address),
+                config.login_max_failed_attempts_per_ip,
+                config,
+                now,
+            );
+        }
+    }
+
+    /// Record a failed login attempt for a single key and calculate the time until the next
+    /// attempt is allowed (exponential backoff or lockout if the threshold was reached).
+    fn record_failure_for_key(
+        attempts: &mut HashMap<String, FailedAttempts>,
+        key: String,
+        max_failed_attempts: u32,
+        config: &BackendConfiguration,
+        now: Instant,
+    ) {
+        use log::warn;
+
+        let failure_window = Duration::from_secs(config.login_failure_window_in_seconds);
+        let lockout_duration = Duration::from_secs(config.login_lockout_duration_in_seconds);
+
+        // get the already tracked attempts or start with a fresh entry if the last failure is too old
+        let failed_attempts = attempts.entry(key.clone()).or_insert(FailedAttempts {
+            count: 0,
+            last_failure: now,
+            blocked_until: now,
+        });
+        if failed_attempts.blocked_until <= now
+            && now - failed_attempts.last_failure >= failure_window
+        {
+            failed_attempts.count = 0;
+        }
+        failed_attempts.count += 1;
+        failed_attempts.last_failure = now;
+
+        // if the threshold was reached, lock the key completely; otherwise just slow the client down
+        if max_failed_attempts > 0 && failed_attempts.count >= max_failed_attempts {
+            failed_attempts.blocked_until = now + lockout_duration;
+            warn!(
+                "Locked '{}' for {} seconds after {} failed login attempts",
+                key,
+                lockout_duration.as_secs(),
+                failed_attempts.count
+            );
+        } else {
+            let backoff = Duration::from_secs(
+                config
+                    .login_backoff_base_in_seconds
+                    .saturating_mul(1u64 << (failed_attempts.count - 1).min(20)),
+            );
+            failed_attempts.blocked_until = now + backoff.min(lockout_duration);
+        }
+    }
+
+    /// Record a successful login for the supplied account which resets its failed attempts. The
+    /// failed attempts of the IP address are kept since one valid account must not be usable to
+    /// reset the counter for guessing the passwords of other accounts.
+    pub fn record_success(&self, account: &str) {
+        self.attempts
+            .lock()
+            .unwrap()
+            .remove(&Self::account_key(account));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LoginAttemptTracker, TooManyLoginAttempts, CLEANUP_THRESHOLD};
+    use crate::fairings::BackendConfiguration;
+    use crate::problem::Problem;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::{Duration, Instant};
+
+    const ACCOUNT: &str = "jane@example.com";
+    const IP_ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+    /// Get a configuration which locks an account after 4 and an IP address after 6 failures.
+    fn config() -> BackendConfiguration {
+        BackendConfiguration {
+            login_max_failed_attempts_per_account: 4,
+            login_max_failed_attempts_per_ip: 6,
+            login_backoff_base_in_seconds: 1,
+            login_lockout_duration_in_seconds: 900,
+            login_failure_window_in_seconds: 600,
+            ..Default::default()
+        }
+    }
+
+    /// Get the number of seconds the client has to wait or 0 if the attempt is allowed.
+    fn retry_after(
+        tracker: &LoginAttemptTracker,
+        account: &str,
+        ip_address: Option<IpAddr>,
+        now: Instant,
+    ) -> u64 {
+        match tracker.check_at(account, ip_address, now) {
+            Ok(()) => 0,
+            Err(too_many_attempts) => too_many_attempts.retry_after_in_seconds,
+        }
+    }
+
+    #[test]
+    fn first_attempt_is_allowed() {
+        let tracker = LoginAttemptTracker::default();
+        assert_eq!(
+            retry_after(&tracker, ACCOUNT, Some(IP_ADDRESS), Instant::now()),
+            0
+        );
+    }
+
+    #[test]
+    fn backoff_is_doubled_with_every_failure() {
+        let tracker = LoginAttemptTracker::default();
+        let now = Instant::now();
+        for expected_backoff in [1, 2, 4] {
+            tracker.record_failure_at(&config(), ACCOUNT, None, now);
+            assert_eq!(retry_after(&tracker, ACCOUNT, None, now), expected_backoff);
+        }
+
+        // the attempt is allowed again as soon as the backoff is over
+        assert_eq!(
+            retry_after(&tracker, ACCOUNT, None, now + Duration::from_secs(4)),
+            0
+        );
+    }
+
+    #[test]
+    fn account_is_locked_at_the_threshold() {
+        let tracker = LoginAttemptTracker::default();
+        let now = Instant::now();
+        for _ in 0..4 {
+            tracker.record_failure_at(&config(), ACCOUNT, None, now);
+        }
+        assert_eq!(retry_after(&tracker, ACCOUNT, None, now), 900);
+
+        // other accounts are not affected
+        assert_eq!(retry_after(&tracker, "john@example.com", None, now), 0);
+    }
+
+    #[test]
+    fn ip_address_is_locked_at_its_own_threshold() {
+        let tracker = LoginAttemptTracker::default();
+        let now = Instant::now();
+        for attempt in 0..6 {
+            tracker.record_failure_at(
+                &config(),
+                &format!("user{}@example.com", attempt),
+                Some(IP_ADDRESS),
+                now,
+            );
+        }
+        assert_eq!(
+            retry_after(&tracker, "john@example.com", Some(IP_ADDRESS), now),
+            900
+        );
+
+        // the same account can still be used from another address
+        assert_eq!(retry_after(&tracker, "john@example.com", None, now), 0);
+    }
+
+    #[test]
+    fn retry_after_is_rounded_up() {
+        let tracker = LoginAttemptTracker::default();
+        let now = Instant::now();
+        tracker.record_failure_at(&config(), ACCOUNT, None, now);
+        tracker.record_failure_at(&config(), ACCOUNT, None, now);
+        assert_eq!(
+            retry_after(&tracker, ACCOUNT, None, now + Duration::from_millis(500)),
+            2
+        );
+        assert_eq!(
+            TooManyLoginAttempts::new(Duration::from_millis(1001)).retry_after_in_seconds,
+            2
+        );
+        assert_eq!(
+            TooManyLoginAttempts::new(Duration::from_secs(3)).retry_after_in_seconds,
+            3
+        );
+
+        // the value is passed on to the Retry-After header of the response
+        let problem = Problem::from(TooManyLoginAttempts::new(Duration::from_secs(3)));
+        assert_eq!(problem.retry_after, Some(3));
+    }
+
+    #[test]
+    fn failures_are_forgotten_after_the_window() {
+        let tracker = LoginAttemptTracker::default();
+        let now = Instant::now();
+        for _ in 0..3 {
+            tracker.record_failure_at(&config(), ACCOUNT, None, now);
+        }
+
+        // the counter starts again, so the next failure neither locks nor doubles the backoff
+        let later = now + Duration::from_secs(600);
+        tracker.record_failure_at(&config(), ACCOUNT, None, later);
+        assert_eq!(retry_after(&tracker, ACCOUNT, None, later), 1);
+    }
+
+    #[test]
+    fn success_resets_the_account_but_not_the_ip_address() {
+        let tracker = LoginAttemptTracker::default();
+        let now = Instant::now();
+        for _ in 0..6 {
+            tracker.record_failure_at(&config(), ACCOUNT, Some(IP_ADDRESS), now);
+        }
+        tracker.record_success(ACCOUNT);
+        assert_eq!(retry_after(&tracker, ACCOUNT, None, now), 0);
+        assert_eq!(retry_after(&tracker, ACCOUNT, Some(IP_ADDRESS), now), 900);
+    }
+
+    #[test]
+    fn stale_entries_are_removed() {
+        let tracker = LoginAttemptTracker::default();
+        let now = Instant::now();
+        for account in 0..=CLEANUP_THRESHOLD {
+            tracker.record_failure_at(&config(), &format!("user{}", account), None, now);
+        }
+        tracker.record_failure_at(&config(), ACCOUNT, None, now + Duration::from_secs(60));
+        assert_eq!(
+            tracker.attempts.lock().unwrap().len(),
+            CLEANUP_THRESHOLD + 2
+        );
+
+        // as soon as the failures are older than the window, they are removed with the next failure
+        let later = now + Duration::from_secs(600);
+        tracker.record_failure_at(&config(), "john@example.com", None, later);
+        let attempts = tracker.attempts.lock().unwrap();
+        assert_eq!(attempts.len(), 2);
+        assert!(attempts.contains_key(&LoginAttemptTracker::account_key(ACCOUNT)));
+    }
+}